@@ -0,0 +1,309 @@
+//! `/api/auth` and `/api/looks` endpoints. Sessions travel in the
+//! `hmb_session` cookie, so browsers need `credentials: "include"`.
+
+use super::{
+    LoginError, Look, LookError, NewLook, RegisterError, SavedImage, User, MAX_LOOK_IMAGES,
+};
+use crate::services::generator::ImageVariation;
+use crate::AppState;
+use axum::{
+    extract::{FromRequestParts, Json, Path, Query, State},
+    http::{header, request::Parts, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+
+pub const SESSION_COOKIE: &str = "hmb_session";
+
+#[derive(Deserialize)]
+pub struct Credentials {
+    email: String,
+    password: String,
+}
+
+#[derive(Serialize)]
+struct UserResponse {
+    user: User,
+}
+
+#[derive(Serialize)]
+struct MessageResponse {
+    message: String,
+}
+
+/// A look as clients see it, with freshly signed image URLs.
+#[derive(Serialize)]
+struct LookResponse {
+    #[serde(flatten)]
+    look: Look,
+    variations: Vec<ImageVariation>,
+}
+
+#[derive(Deserialize)]
+pub struct LooksQuery {
+    collection: Option<String>,
+}
+
+fn fail(status: StatusCode, message: impl Into<String>) -> Response {
+    (
+        status,
+        Json(MessageResponse {
+            message: message.into(),
+        }),
+    )
+        .into_response()
+}
+
+fn internal_error(err: impl std::fmt::Display) -> Response {
+    error!(error = %err, "Account store failed");
+    fail(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Accounts are temporarily unavailable",
+    )
+}
+
+/// The session token from the request's cookies.
+fn session_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(name, _)| *name == SESSION_COOKIE)
+        .map(|(_, value)| value)
+        .filter(|value| !value.is_empty())
+}
+
+/// `Set-Cookie` for a session token; an empty token clears the cookie.
+fn session_cookie(state: &AppState, token: &str) -> HeaderValue {
+    let auth = &state.config.auth;
+    let max_age = if token.is_empty() {
+        0
+    } else {
+        state.accounts.session_ttl().as_secs()
+    };
+    let mut cookie = format!(
+        "{}={}; Path=/; HttpOnly; SameSite={}; Max-Age={}",
+        SESSION_COOKIE, token, auth.cookie_same_site, max_age
+    );
+    if auth.secure_cookies {
+        cookie.push_str("; Secure");
+    }
+    // Tokens are hex and the config is validated, so this can't fail
+    HeaderValue::from_str(&cookie).expect("session cookie is a valid header")
+}
+
+/// The logged-in user. Handlers taking this answer 401 to anonymous callers.
+pub struct CurrentUser(pub User);
+
+impl FromRequestParts<AppState> for CurrentUser {
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let Some(token) = session_token(&parts.headers) else {
+            return Err(fail(StatusCode::UNAUTHORIZED, "Please log in"));
+        };
+        match state.accounts.session_user(token).await {
+            Ok(Some(user)) => Ok(CurrentUser(user)),
+            Ok(None) => Err(fail(
+                StatusCode::UNAUTHORIZED,
+                "Your session has expired, please log in again",
+            )),
+            Err(err) => Err(internal_error(err)),
+        }
+    }
+}
+
+/// Start a session for `user` and answer with it.
+async fn logged_in(state: &AppState, status: StatusCode, user: User) -> Response {
+    match state.accounts.create_session(&user.id).await {
+        Ok(token) => (
+            status,
+            [(header::SET_COOKIE, session_cookie(state, &token))],
+            Json(UserResponse { user }),
+        )
+            .into_response(),
+        Err(err) => internal_error(err),
+    }
+}
+
+/// `POST /api/auth/register`: create an account and log it in.
+pub async fn register(
+    State(state): State<AppState>,
+    Json(credentials): Json<Credentials>,
+) -> Response {
+    match state
+        .accounts
+        .register(&credentials.email, &credentials.password)
+        .await
+    {
+        Ok(user) => {
+            info!(user_id = %user.id, "Registered account");
+            logged_in(&state, StatusCode::CREATED, user).await
+        }
+        Err(RegisterError::Invalid(message)) => fail(StatusCode::UNPROCESSABLE_ENTITY, message),
+        Err(RegisterError::EmailTaken) => fail(
+            StatusCode::CONFLICT,
+            "An account with that email already exists",
+        ),
+        Err(RegisterError::Store(err)) => internal_error(err),
+    }
+}
+
+/// `POST /api/auth/login`
+pub async fn login(
+    State(state): State<AppState>,
+    Json(credentials): Json<Credentials>,
+) -> Response {
+    match state
+        .accounts
+        .login(&credentials.email, &credentials.password)
+        .await
+    {
+        Ok(user) => {
+            info!(user_id = %user.id, "Logged in");
+            logged_in(&state, StatusCode::OK, user).await
+        }
+        Err(LoginError::WrongCredentials) => {
+            warn!("Rejected login");
+            fail(StatusCode::UNAUTHORIZED, "Wrong email or password")
+        }
+        Err(LoginError::Store(err)) => internal_error(err),
+    }
+}
+
+/// `POST /api/auth/logout`: end the session, if any, and clear the cookie.
+pub async fn logout(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if let Some(token) = session_token(&headers) {
+        if let Err(err) = state.accounts.end_session(token).await {
+            return internal_error(err);
+        }
+    }
+    (
+        StatusCode::NO_CONTENT,
+        [(header::SET_COOKIE, session_cookie(&state, ""))],
+    )
+        .into_response()
+}
+
+/// `GET /api/auth/me`
+pub async fn me(CurrentUser(user): CurrentUser) -> Response {
+    Json(UserResponse { user }).into_response()
+}
+
+/// `POST /api/looks`: save a generation to one of the user's collections.
+pub async fn save_look(
+    State(state): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    Json(new): Json<NewLook>,
+) -> Response {
+    let mut images = Vec::with_capacity(new.variations.len());
+    // Counted again by the store; this just saves checking images for nothing
+    for variation in new.variations.iter().take(MAX_LOOK_IMAGES) {
+        let Some(image_id) = keepable_image(&state, variation) else {
+            return fail(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "Looks can only keep images generated here, before their links expire",
+            );
+        };
+        images.push(SavedImage {
+            angle: variation.angle.clone(),
+            image_id,
+        });
+    }
+    match state
+        .accounts
+        .save_look(
+            &user.id,
+            new,
+            images,
+            state.config.limits.max_prompt_chars,
+            state.config.auth.max_looks_per_user,
+        )
+        .await
+    {
+        Ok(look) => {
+            info!(user_id = %user.id, look_id = %look.id, "Saved look");
+            (StatusCode::CREATED, Json(signed(&state, look))).into_response()
+        }
+        Err(LookError::Invalid(message)) => fail(StatusCode::UNPROCESSABLE_ENTITY, message),
+        Err(LookError::Store(err)) => internal_error(err),
+    }
+}
+
+/// The blob id a look may keep for `variation`: only one behind a URL this
+/// server signed that hasn't expired. Data URLs are refused, since storing
+/// them would let anyone host images here that were never generated (or
+/// moderated) by this server. Looks never hold image bytes themselves.
+fn keepable_image(state: &AppState, variation: &ImageVariation) -> Option<String> {
+    state.images.issued_id(&variation.image)
+}
+
+/// `GET /api/looks?collection=`: the user's looks, newest first.
+pub async fn list_looks(
+    State(state): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    Query(query): Query<LooksQuery>,
+) -> Response {
+    match state.accounts.list_looks(&user.id, query.collection).await {
+        Ok(looks) => Json(
+            looks
+                .into_iter()
+                .map(|look| signed(&state, look))
+                .collect::<Vec<_>>(),
+        )
+        .into_response(),
+        Err(err) => internal_error(err),
+    }
+}
+
+/// Image URLs expire, so each response signs new ones for the look's blobs.
+fn signed(state: &AppState, look: Look) -> LookResponse {
+    let variations = look
+        .variations
+        .iter()
+        .map(|saved| ImageVariation {
+            image: state.images.signed_url(&saved.image_id),
+            angle: saved.angle.clone(),
+        })
+        .collect();
+    LookResponse { look, variations }
+}
+
+/// `DELETE /api/looks/{id}`
+pub async fn delete_look(
+    State(state): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    Path(id): Path<String>,
+) -> Response {
+    match state.accounts.delete_look(&user.id, &id).await {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => fail(StatusCode::NOT_FOUND, "Look not found"),
+        Err(err) => internal_error(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_token_from_cookies() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(session_token(&headers), None);
+
+        headers.insert(
+            header::COOKIE,
+            "theme=dark; hmb_session=abc123; other=1".parse().unwrap(),
+        );
+        assert_eq!(session_token(&headers), Some("abc123"));
+
+        headers.insert(header::COOKIE, "hmb_session=".parse().unwrap());
+        assert_eq!(session_token(&headers), None);
+    }
+}
@@ -0,0 +1,695 @@
+//! Optional email/password accounts and the looks users save.
+//!
+//! Passwords are hashed with Argon2id. Logging in creates a session whose
+//! random token lives in an HttpOnly cookie; like API keys, only the token's
+//! SHA-256 is stored. Nothing here is needed to generate images.
+
+pub mod handlers;
+
+use crate::services::generator::ImageVariation;
+use argon2::password_hash::{rand_core::OsRng, PasswordHash, SaltString};
+use argon2::{Argon2, PasswordHasher, PasswordVerifier};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::path::Path;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+pub type AccountError = Box<dyn Error + Send + Sync>;
+
+pub const MIN_PASSWORD_CHARS: usize = 8;
+const MAX_PASSWORD_CHARS: usize = 128;
+const MAX_EMAIL_CHARS: usize = 254;
+pub const MAX_COLLECTION_CHARS: usize = 64;
+const MAX_INPUT_REF_CHARS: usize = 512;
+/// More than any generation returns
+pub const MAX_LOOK_IMAGES: usize = 8;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS users (
+        id TEXT PRIMARY KEY,
+        email TEXT NOT NULL UNIQUE,
+        password_hash TEXT NOT NULL,
+        created_at INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS sessions (
+        token_hash TEXT PRIMARY KEY,
+        user_id TEXT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+        expires_at INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS looks (
+        id TEXT PRIMARY KEY,
+        user_id TEXT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+        collection TEXT NOT NULL,
+        prompt TEXT NOT NULL,
+        input_image_ref TEXT,
+        prompt_version TEXT,
+        variations TEXT NOT NULL,
+        created_at INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS looks_user_collection ON looks (user_id, collection, created_at);
+";
+
+/// Why registration failed.
+#[derive(Debug)]
+pub enum RegisterError {
+    /// A malformed email or password; shown to the user as-is
+    Invalid(String),
+    EmailTaken,
+    Store(AccountError),
+}
+
+/// Why login failed.
+#[derive(Debug)]
+pub enum LoginError {
+    /// Unknown email or wrong password, deliberately not told apart
+    WrongCredentials,
+    Store(AccountError),
+}
+
+/// Why a look wasn't saved.
+#[derive(Debug)]
+pub enum LookError {
+    /// Shown to the user as-is
+    Invalid(String),
+    Store(AccountError),
+}
+
+impl From<AccountError> for RegisterError {
+    fn from(err: AccountError) -> Self {
+        RegisterError::Store(err)
+    }
+}
+
+impl From<AccountError> for LoginError {
+    fn from(err: AccountError) -> Self {
+        LoginError::Store(err)
+    }
+}
+
+impl From<AccountError> for LookError {
+    fn from(err: AccountError) -> Self {
+        LookError::Store(err)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct User {
+    pub id: String,
+    pub email: String,
+    pub created_at: u64,
+}
+
+impl User {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(User {
+            id: row.get("id")?,
+            email: row.get("email")?,
+            created_at: row.get::<_, i64>("created_at")? as u64,
+        })
+    }
+}
+
+/// One view of a saved look. Only the blob id is kept; a fresh URL is signed
+/// for it whenever the look is listed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SavedImage {
+    pub angle: String,
+    pub image_id: String,
+}
+
+/// A saved generation.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Look {
+    pub id: String,
+    pub collection: String,
+    pub prompt: String,
+    /// Where the client keeps the original photo; the photo itself isn't stored
+    pub input_image_ref: Option<String>,
+    pub prompt_version: Option<String>,
+    #[serde(skip)]
+    pub variations: Vec<SavedImage>,
+    pub created_at: u64,
+}
+
+impl Look {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        let variations: String = row.get("variations")?;
+        Ok(Look {
+            id: row.get("id")?,
+            collection: row.get("collection")?,
+            prompt: row.get("prompt")?,
+            input_image_ref: row.get("input_image_ref")?,
+            prompt_version: row.get("prompt_version")?,
+            variations: serde_json::from_str(&variations).map_err(|err| {
+                rusqlite::Error::FromSqlConversionFailure(
+                    0,
+                    rusqlite::types::Type::Text,
+                    err.into(),
+                )
+            })?,
+            created_at: row.get::<_, i64>("created_at")? as u64,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct NewLook {
+    pub collection: String,
+    pub prompt: String,
+    #[serde(default)]
+    pub input_image_ref: Option<String>,
+    #[serde(default)]
+    pub prompt_version: Option<String>,
+    pub variations: Vec<ImageVariation>,
+}
+
+impl NewLook {
+    fn validate(&self, max_prompt_chars: usize) -> Result<(), String> {
+        let collection = self.collection.trim();
+        if collection.is_empty() || collection.chars().count() > MAX_COLLECTION_CHARS {
+            return Err(format!(
+                "Collection name must be 1-{} characters",
+                MAX_COLLECTION_CHARS
+            ));
+        }
+        if self.prompt.trim().is_empty() || self.prompt.chars().count() > max_prompt_chars {
+            return Err(format!("Prompt must be 1-{} characters", max_prompt_chars));
+        }
+        if self
+            .input_image_ref
+            .as_ref()
+            .is_some_and(|r| r.len() > MAX_INPUT_REF_CHARS)
+        {
+            return Err("Input image reference is too long".to_string());
+        }
+        if self.variations.is_empty() || self.variations.len() > MAX_LOOK_IMAGES {
+            return Err(format!("A look needs 1-{} images", MAX_LOOK_IMAGES));
+        }
+        Ok(())
+    }
+}
+
+pub struct AccountStore {
+    connection: Arc<Mutex<Connection>>,
+    session_ttl: Duration,
+}
+
+impl AccountStore {
+    pub fn open(path: &Path, session_ttl: Duration) -> Result<Self, AccountError> {
+        let connection = Connection::open(path)
+            .map_err(|err| format!("Failed to open {}: {}", path.display(), err))?;
+        Self::with_connection(connection, session_ttl)
+    }
+
+    /// A throwaway store, for tests.
+    #[cfg(test)]
+    pub fn open_in_memory() -> Result<Self, AccountError> {
+        Self::with_connection(Connection::open_in_memory()?, Duration::from_secs(3600))
+    }
+
+    fn with_connection(
+        connection: Connection,
+        session_ttl: Duration,
+    ) -> Result<Self, AccountError> {
+        connection.pragma_update(None, "foreign_keys", "ON")?;
+        connection.execute_batch(SCHEMA)?;
+        Ok(AccountStore {
+            connection: Arc::new(Mutex::new(connection)),
+            session_ttl,
+        })
+    }
+
+    pub fn session_ttl(&self) -> Duration {
+        self.session_ttl
+    }
+
+    /// Run `f` on the connection off the async runtime.
+    async fn with<T, F>(&self, f: F) -> Result<T, AccountError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let connection = Arc::clone(&self.connection);
+        let result = tokio::task::spawn_blocking(move || f(&connection.lock().unwrap())).await?;
+        Ok(result?)
+    }
+
+    pub async fn register(&self, email: &str, password: &str) -> Result<User, RegisterError> {
+        let email = normalize_email(email).map_err(RegisterError::Invalid)?;
+        let length = password.chars().count();
+        if !(MIN_PASSWORD_CHARS..=MAX_PASSWORD_CHARS).contains(&length) {
+            return Err(RegisterError::Invalid(format!(
+                "Password must be {}-{} characters",
+                MIN_PASSWORD_CHARS, MAX_PASSWORD_CHARS
+            )));
+        }
+
+        let password = password.to_string();
+        let password_hash = tokio::task::spawn_blocking(move || hash_password(&password))
+            .await
+            .map_err(AccountError::from)??;
+        let user = User {
+            id: Uuid::new_v4().to_string(),
+            email,
+            created_at: now_secs(),
+        };
+        let record = user.clone();
+        let inserted = self
+            .with(move |conn| {
+                conn.execute(
+                    "INSERT INTO users (id, email, password_hash, created_at)
+                     VALUES (?1, ?2, ?3, ?4) ON CONFLICT (email) DO NOTHING",
+                    params![
+                        record.id,
+                        record.email,
+                        password_hash,
+                        record.created_at as i64
+                    ],
+                )
+            })
+            .await?;
+        if inserted == 0 {
+            return Err(RegisterError::EmailTaken);
+        }
+        Ok(user)
+    }
+
+    /// Check credentials. Unknown emails take as long as wrong passwords, so
+    /// responses don't reveal who has an account.
+    pub async fn login(&self, email: &str, password: &str) -> Result<User, LoginError> {
+        let Ok(email) = normalize_email(email) else {
+            return Err(LoginError::WrongCredentials);
+        };
+        let found = self
+            .with(move |conn| {
+                conn.query_row(
+                    "SELECT *, password_hash FROM users WHERE email = ?1",
+                    params![email],
+                    |row| Ok((User::from_row(row)?, row.get::<_, String>("password_hash")?)),
+                )
+                .optional()
+            })
+            .await?;
+
+        let password = password.to_string();
+        let stored_hash = found
+            .as_ref()
+            .map(|(_, hash)| hash.clone())
+            .unwrap_or_else(|| dummy_hash().to_string());
+        let verified =
+            tokio::task::spawn_blocking(move || verify_password(&password, &stored_hash))
+                .await
+                .map_err(AccountError::from)?;
+        match found {
+            Some((user, _)) if verified => Ok(user),
+            _ => Err(LoginError::WrongCredentials),
+        }
+    }
+
+    /// Start a session and return its token.
+    pub async fn create_session(&self, user_id: &str) -> Result<String, AccountError> {
+        let token = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+        let token_hash = hash_token(&token);
+        let user_id = user_id.to_string();
+        let expires_at = now_secs() + self.session_ttl.as_secs();
+        self.with(move |conn| {
+            // Piggyback cleanup of expired sessions on logins
+            conn.execute(
+                "DELETE FROM sessions WHERE expires_at <= ?1",
+                params![now_secs() as i64],
+            )?;
+            conn.execute(
+                "INSERT INTO sessions (token_hash, user_id, expires_at) VALUES (?1, ?2, ?3)",
+                params![token_hash, user_id, expires_at as i64],
+            )
+        })
+        .await?;
+        Ok(token)
+    }
+
+    /// The user a live session token belongs to.
+    pub async fn session_user(&self, token: &str) -> Result<Option<User>, AccountError> {
+        let token_hash = hash_token(token);
+        self.with(move |conn| {
+            conn.query_row(
+                "SELECT users.* FROM sessions JOIN users ON users.id = sessions.user_id
+                 WHERE sessions.token_hash = ?1 AND sessions.expires_at > ?2",
+                params![token_hash, now_secs() as i64],
+                User::from_row,
+            )
+            .optional()
+        })
+        .await
+    }
+
+    pub async fn end_session(&self, token: &str) -> Result<(), AccountError> {
+        let token_hash = hash_token(token);
+        self.with(move |conn| {
+            conn.execute(
+                "DELETE FROM sessions WHERE token_hash = ?1",
+                params![token_hash],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Save `new` with `images`, its variations as blob ids. The caller
+    /// checks that the user may keep those blobs. Users with `max_looks`
+    /// looks already have to delete one first.
+    pub async fn save_look(
+        &self,
+        user_id: &str,
+        new: NewLook,
+        images: Vec<SavedImage>,
+        max_prompt_chars: usize,
+        max_looks: usize,
+    ) -> Result<Look, LookError> {
+        new.validate(max_prompt_chars).map_err(LookError::Invalid)?;
+        let look = Look {
+            id: Uuid::new_v4().to_string(),
+            collection: new.collection.trim().to_string(),
+            prompt: new.prompt,
+            input_image_ref: new.input_image_ref,
+            prompt_version: new.prompt_version,
+            variations: images,
+            created_at: now_secs(),
+        };
+        let variations = serde_json::to_string(&look.variations).map_err(AccountError::from)?;
+        let record = look.clone();
+        let user_id = user_id.to_string();
+        let saved = self
+            .with(move |conn| {
+                // Counted under the same lock as the insert, so racing saves
+                // can't overshoot
+                let count: i64 = conn.query_row(
+                    "SELECT COUNT(*) FROM looks WHERE user_id = ?1",
+                    params![user_id],
+                    |row| row.get(0),
+                )?;
+                if count as usize >= max_looks {
+                    return Ok(false);
+                }
+                conn.execute(
+                    "INSERT INTO looks (id, user_id, collection, prompt, input_image_ref,
+                                        prompt_version, variations, created_at)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                    params![
+                        record.id,
+                        user_id,
+                        record.collection,
+                        record.prompt,
+                        record.input_image_ref,
+                        record.prompt_version,
+                        variations,
+                        record.created_at as i64
+                    ],
+                )?;
+                Ok(true)
+            })
+            .await?;
+        if !saved {
+            return Err(LookError::Invalid(format!(
+                "You can keep up to {} looks; delete one to save another",
+                max_looks
+            )));
+        }
+        Ok(look)
+    }
+
+    /// A user's looks, newest first, optionally from one collection.
+    pub async fn list_looks(
+        &self,
+        user_id: &str,
+        collection: Option<String>,
+    ) -> Result<Vec<Look>, AccountError> {
+        let user_id = user_id.to_string();
+        self.with(move |conn| {
+            let mut statement = conn.prepare(
+                "SELECT * FROM looks WHERE user_id = ?1 AND (?2 IS NULL OR collection = ?2)
+                 ORDER BY created_at DESC, rowid DESC",
+            )?;
+            let looks = statement
+                .query_map(params![user_id, collection], Look::from_row)?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(looks)
+        })
+        .await
+    }
+
+    /// Delete one of the user's looks. Other users' looks count as missing.
+    pub async fn delete_look(&self, user_id: &str, look_id: &str) -> Result<bool, AccountError> {
+        let user_id = user_id.to_string();
+        let look_id = look_id.to_string();
+        self.with(move |conn| {
+            let deleted = conn.execute(
+                "DELETE FROM looks WHERE id = ?1 AND user_id = ?2",
+                params![look_id, user_id],
+            )?;
+            Ok(deleted > 0)
+        })
+        .await
+    }
+}
+
+fn normalize_email(email: &str) -> Result<String, String> {
+    let email = email.trim().to_lowercase();
+    let valid = email.len() <= MAX_EMAIL_CHARS
+        && email
+            .split_once('@')
+            .is_some_and(|(user, domain)| !user.is_empty() && domain.contains('.'))
+        && !email.chars().any(char::is_whitespace);
+    if valid {
+        Ok(email)
+    } else {
+        Err("Please enter a valid email address".to_string())
+    }
+}
+
+fn hash_password(password: &str) -> Result<String, AccountError> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|err| format!("Failed to hash password: {}", err))?
+        .to_string())
+}
+
+fn verify_password(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash)
+        .map(|parsed| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &parsed)
+                .is_ok()
+        })
+        .unwrap_or(false)
+}
+
+/// A real hash to verify against when the email is unknown.
+fn dummy_hash() -> &'static str {
+    static HASH: OnceLock<String> = OnceLock::new();
+    HASH.get_or_init(|| hash_password("not-a-real-password").unwrap_or_default())
+}
+
+fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn look(collection: &str) -> NewLook {
+        NewLook {
+            collection: collection.to_string(),
+            prompt: "Low taper fade".to_string(),
+            input_image_ref: Some("photos/1.jpg".to_string()),
+            prompt_version: Some("default".to_string()),
+            variations: vec![ImageVariation {
+                image: "data:image/png;base64,AAAA".to_string(),
+                angle: "front".to_string(),
+            }],
+        }
+    }
+
+    fn images() -> Vec<SavedImage> {
+        vec![SavedImage {
+            angle: "front".to_string(),
+            image_id: crate::images::blob_id("image/png", b"png bytes").unwrap(),
+        }]
+    }
+
+    #[tokio::test]
+    async fn test_register_then_login() {
+        let store = AccountStore::open_in_memory().unwrap();
+        let user = store
+            .register(" Sam@Example.com ", "correct horse")
+            .await
+            .unwrap();
+        assert_eq!(user.email, "sam@example.com");
+
+        let logged_in = store
+            .login("sam@example.com", "correct horse")
+            .await
+            .unwrap();
+        assert_eq!(logged_in.id, user.id);
+
+        assert!(matches!(
+            store.login("sam@example.com", "wrong horse").await,
+            Err(LoginError::WrongCredentials)
+        ));
+        assert!(matches!(
+            store.login("nobody@example.com", "correct horse").await,
+            Err(LoginError::WrongCredentials)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_passwords_are_hashed_with_argon2() {
+        let store = AccountStore::open_in_memory().unwrap();
+        store
+            .register("sam@example.com", "correct horse")
+            .await
+            .unwrap();
+        let stored: String = store
+            .with(|conn| conn.query_row("SELECT password_hash FROM users", [], |row| row.get(0)))
+            .await
+            .unwrap();
+        assert!(stored.starts_with("$argon2id$"));
+        assert!(!stored.contains("correct horse"));
+    }
+
+    #[tokio::test]
+    async fn test_register_validation() {
+        let store = AccountStore::open_in_memory().unwrap();
+        assert!(matches!(
+            store.register("not-an-email", "correct horse").await,
+            Err(RegisterError::Invalid(_))
+        ));
+        assert!(matches!(
+            store.register("sam@example.com", "short").await,
+            Err(RegisterError::Invalid(_))
+        ));
+        store
+            .register("sam@example.com", "correct horse")
+            .await
+            .unwrap();
+        assert!(matches!(
+            store.register("SAM@example.com", "another one").await,
+            Err(RegisterError::EmailTaken)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_sessions() {
+        let store = AccountStore::open_in_memory().unwrap();
+        let user = store
+            .register("sam@example.com", "correct horse")
+            .await
+            .unwrap();
+        let token = store.create_session(&user.id).await.unwrap();
+
+        assert_eq!(
+            store.session_user(&token).await.unwrap().unwrap().id,
+            user.id
+        );
+        assert!(store.session_user("forged").await.unwrap().is_none());
+
+        store.end_session(&token).await.unwrap();
+        assert!(store.session_user(&token).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_looks_are_private_to_their_owner() {
+        let store = AccountStore::open_in_memory().unwrap();
+        let sam = store
+            .register("sam@example.com", "correct horse")
+            .await
+            .unwrap();
+        let alex = store
+            .register("alex@example.com", "battery staple")
+            .await
+            .unwrap();
+
+        let saved = store
+            .save_look(&sam.id, look("Summer"), images(), 500, 100)
+            .await
+            .unwrap();
+        store
+            .save_look(&sam.id, look("Wedding"), images(), 500, 100)
+            .await
+            .unwrap();
+
+        assert_eq!(store.list_looks(&sam.id, None).await.unwrap().len(), 2);
+        let summer = store
+            .list_looks(&sam.id, Some("Summer".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(summer.len(), 1);
+        assert_eq!(summer[0].variations[0].angle, "front");
+        assert!(store.list_looks(&alex.id, None).await.unwrap().is_empty());
+
+        assert!(!store.delete_look(&alex.id, &saved.id).await.unwrap());
+        assert!(store.delete_look(&sam.id, &saved.id).await.unwrap());
+        assert_eq!(store.list_looks(&sam.id, None).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_look_validation() {
+        let store = AccountStore::open_in_memory().unwrap();
+        let user = store
+            .register("sam@example.com", "correct horse")
+            .await
+            .unwrap();
+
+        let mut empty = look("Summer");
+        empty.variations.clear();
+        assert!(store
+            .save_look(&user.id, empty, images(), 500, 100)
+            .await
+            .is_err_and(|err| matches!(err, LookError::Invalid(_))));
+        assert!(store
+            .save_look(&user.id, look("  "), images(), 500, 100)
+            .await
+            .is_err_and(|err| matches!(err, LookError::Invalid(_))));
+
+        let mut crowded = look("Summer");
+        crowded.variations = vec![crowded.variations[0].clone(); MAX_LOOK_IMAGES + 1];
+        assert!(store
+            .save_look(&user.id, crowded, images(), 500, 100)
+            .await
+            .is_err_and(|err| matches!(err, LookError::Invalid(_))));
+
+        for _ in 0..2 {
+            store
+                .save_look(&user.id, look("Summer"), images(), 500, 2)
+                .await
+                .unwrap();
+        }
+        let full = store
+            .save_look(&user.id, look("Summer"), images(), 500, 2)
+            .await;
+        assert!(
+            matches!(&full, Err(LookError::Invalid(msg)) if msg.contains("up to 2 looks")),
+            "{:?}",
+            full
+        );
+    }
+}
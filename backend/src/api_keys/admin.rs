@@ -0,0 +1,79 @@
+//! `/admin/api-keys` endpoints for issuing, listing and revoking keys. Like
+//! the rest of `/admin`, they need the admin token.
+
+use super::{ApiKey, CreateKeyError, NewApiKey};
+use crate::{require_admin, AppState};
+use axum::{
+    extract::{Json, Path, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use tracing::{error, info};
+
+#[derive(Serialize)]
+pub struct CreatedApiKey {
+    /// The plaintext key; this is the only time it is shown
+    key: String,
+    #[serde(rename = "apiKey")]
+    api_key: ApiKey,
+}
+
+fn internal_error(err: impl std::fmt::Display) -> Response {
+    error!(error = %err, "API key store failed");
+    (StatusCode::INTERNAL_SERVER_ERROR, "API key store failed").into_response()
+}
+
+/// `POST /admin/api-keys`
+pub async fn create_key(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(new): Json<NewApiKey>,
+) -> Response {
+    if let Err(rejection) = require_admin(&state, &headers) {
+        return rejection.into_response();
+    }
+    match state.api_keys.create(new).await {
+        Ok((api_key, key)) => {
+            info!(api_key = %api_key.name, id = %api_key.id, "Issued API key");
+            (StatusCode::CREATED, Json(CreatedApiKey { key, api_key })).into_response()
+        }
+        Err(CreateKeyError::Invalid(message)) => {
+            (StatusCode::UNPROCESSABLE_ENTITY, message).into_response()
+        }
+        Err(CreateKeyError::Store(err)) => internal_error(err),
+    }
+}
+
+/// `GET /admin/api-keys`: every key with its usage, revoked ones included.
+pub async fn list_keys(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if let Err(rejection) = require_admin(&state, &headers) {
+        return rejection.into_response();
+    }
+    match state.api_keys.list().await {
+        Ok(keys) => Json(keys).into_response(),
+        Err(err) => internal_error(err),
+    }
+}
+
+/// `POST /admin/api-keys/{id}/revoke`
+pub async fn revoke_key(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Response {
+    if let Err(rejection) = require_admin(&state, &headers) {
+        return rejection.into_response();
+    }
+    match state.api_keys.revoke(&id).await {
+        Ok(true) => {
+            info!(id = %id, "Revoked API key");
+            match state.api_keys.get(&id).await {
+                Ok(key) => Json(key).into_response(),
+                Err(err) => internal_error(err),
+            }
+        }
+        Ok(false) => (StatusCode::NOT_FOUND, "Not found").into_response(),
+        Err(err) => internal_error(err),
+    }
+}
@@ -0,0 +1,113 @@
+use super::Scope;
+use crate::{AppState, GenerateResponse};
+use axum::{
+    extract::{Json, Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tracing::{error, info, warn};
+
+/// The scope a `/api` path needs.
+fn required_scope(path: &str) -> Option<Scope> {
+    if path.starts_with("/api/generate") {
+        Some(Scope::Generate)
+    } else if path.starts_with("/api/jobs") {
+        Some(Scope::Jobs)
+    } else {
+        None
+    }
+}
+
+/// The key from `X-Api-Key` or `Authorization: Bearer`.
+fn presented_key(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get("x-api-key")
+        .and_then(|v| v.to_str().ok())
+        .or_else(|| {
+            headers
+                .get(header::AUTHORIZATION)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.strip_prefix("Bearer "))
+        })
+        .map(str::trim)
+}
+
+fn reject(status: StatusCode, message: &str) -> Response {
+    (status, Json(GenerateResponse::error(message))).into_response()
+}
+
+/// Check the request's API key and make it available to later layers as an
+/// [`super::ApiKey`] extension. Requests without a key pass through as
+/// anonymous unless `auth.require_api_key` is set; a key that is presented
+/// must always be valid and carry the route's scope.
+pub async fn authenticate(
+    State(state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let Some(scope) = required_scope(request.uri().path()) else {
+        return next.run(request).await;
+    };
+    let Some(secret) = presented_key(request.headers()).map(str::to_string) else {
+        if state.config.auth.require_api_key {
+            return reject(StatusCode::UNAUTHORIZED, "API key required");
+        }
+        return next.run(request).await;
+    };
+
+    match state.api_keys.authenticate(&secret).await {
+        Ok(Some(key)) if key.allows(scope) => {
+            info!(api_key = %key.name, path = request.uri().path(), "Authenticated API request");
+            request.extensions_mut().insert(key);
+            next.run(request).await
+        }
+        Ok(Some(key)) => {
+            warn!(api_key = %key.name, ?scope, "API key used outside its scopes");
+            reject(
+                StatusCode::FORBIDDEN,
+                "API key is not allowed to use this endpoint",
+            )
+        }
+        Ok(None) => {
+            warn!("Rejected unknown or revoked API key");
+            reject(StatusCode::UNAUTHORIZED, "Invalid API key")
+        }
+        // Unlike rate limiting, failing open here would let anyone in
+        Err(err) => {
+            error!(error = %err, "API key lookup failed");
+            reject(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Authentication is temporarily unavailable",
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_required_scope() {
+        assert_eq!(required_scope("/api/generate"), Some(Scope::Generate));
+        assert_eq!(
+            required_scope("/api/generate/stream"),
+            Some(Scope::Generate)
+        );
+        assert_eq!(required_scope("/api/jobs/123/cancel"), Some(Scope::Jobs));
+        assert_eq!(required_scope("/health"), None);
+    }
+
+    #[test]
+    fn test_presented_key_headers() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(presented_key(&headers), None);
+
+        headers.insert(header::AUTHORIZATION, "Bearer hmb_abc".parse().unwrap());
+        assert_eq!(presented_key(&headers), Some("hmb_abc"));
+
+        headers.insert("x-api-key", "hmb_def".parse().unwrap());
+        assert_eq!(presented_key(&headers), Some("hmb_def"));
+    }
+}
@@ -0,0 +1,453 @@
+//! Issued API keys for first-party and partner clients.
+//!
+//! Keys are random and only their SHA-256 is stored, so a leaked database
+//! doesn't leak working keys; the plaintext is shown once, when the key is
+//! created. Each key carries its scopes, optional rate-limit overrides and a
+//! usage count.
+
+pub mod admin;
+pub mod middleware;
+
+use crate::config::LimitsConfig;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+pub type ApiKeyError = Box<dyn Error + Send + Sync>;
+
+const KEY_PREFIX: &str = "hmb_";
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS api_keys (
+        id TEXT PRIMARY KEY,
+        name TEXT NOT NULL,
+        key_hash TEXT NOT NULL UNIQUE,
+        scopes TEXT NOT NULL,
+        rate_limit_requests INTEGER,
+        rate_limit_burst INTEGER,
+        created_at INTEGER NOT NULL,
+        revoked_at INTEGER,
+        last_used_at INTEGER,
+        request_count INTEGER NOT NULL DEFAULT 0
+    );
+";
+
+/// What a key may be used for.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// `/api/generate` and `/api/generate/stream`
+    Generate,
+    /// `/api/jobs` and everything under it
+    Jobs,
+}
+
+impl Scope {
+    fn as_str(self) -> &'static str {
+        match self {
+            Scope::Generate => "generate",
+            Scope::Jobs => "jobs",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "generate" => Some(Scope::Generate),
+            "jobs" => Some(Scope::Jobs),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    /// Overrides `limits.rate_limit_requests` for this key
+    pub rate_limit_requests: Option<usize>,
+    /// Overrides `limits.rate_limit_burst` for this key
+    pub rate_limit_burst: Option<u32>,
+    pub created_at: u64,
+    pub revoked_at: Option<u64>,
+    pub last_used_at: Option<u64>,
+    pub request_count: u64,
+}
+
+impl ApiKey {
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope)
+    }
+
+    /// The server's limits with this key's overrides applied.
+    pub fn limits(&self, defaults: &LimitsConfig) -> LimitsConfig {
+        LimitsConfig {
+            rate_limit_requests: self
+                .rate_limit_requests
+                .unwrap_or(defaults.rate_limit_requests),
+            rate_limit_burst: self.rate_limit_burst.or(defaults.rate_limit_burst),
+            ..defaults.clone()
+        }
+    }
+
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        let scopes: String = row.get("scopes")?;
+        Ok(ApiKey {
+            id: row.get("id")?,
+            name: row.get("name")?,
+            scopes: scopes.split(',').filter_map(Scope::parse).collect(),
+            rate_limit_requests: row
+                .get::<_, Option<i64>>("rate_limit_requests")?
+                .map(|v| v as usize),
+            rate_limit_burst: row
+                .get::<_, Option<i64>>("rate_limit_burst")?
+                .map(|v| v as u32),
+            created_at: row.get::<_, i64>("created_at")? as u64,
+            revoked_at: row.get::<_, Option<i64>>("revoked_at")?.map(|v| v as u64),
+            last_used_at: row.get::<_, Option<i64>>("last_used_at")?.map(|v| v as u64),
+            request_count: row.get::<_, i64>("request_count")? as u64,
+        })
+    }
+}
+
+/// Why a key wasn't issued.
+#[derive(Debug)]
+pub enum CreateKeyError {
+    /// Bad settings; shown to the admin as-is
+    Invalid(String),
+    Store(ApiKeyError),
+}
+
+impl From<ApiKeyError> for CreateKeyError {
+    fn from(err: ApiKeyError) -> Self {
+        CreateKeyError::Store(err)
+    }
+}
+
+/// Settings for a key about to be issued.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct NewApiKey {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    #[serde(default)]
+    pub rate_limit_requests: Option<usize>,
+    #[serde(default)]
+    pub rate_limit_burst: Option<u32>,
+}
+
+pub struct ApiKeyStore {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl ApiKeyStore {
+    pub fn open(path: &Path) -> Result<Self, ApiKeyError> {
+        let connection = Connection::open(path)
+            .map_err(|err| format!("Failed to open {}: {}", path.display(), err))?;
+        Self::with_connection(connection)
+    }
+
+    /// A throwaway store, for tests.
+    #[cfg(test)]
+    pub fn open_in_memory() -> Result<Self, ApiKeyError> {
+        Self::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(connection: Connection) -> Result<Self, ApiKeyError> {
+        connection.execute_batch(SCHEMA)?;
+        Ok(ApiKeyStore {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    /// Run `f` on the connection off the async runtime.
+    async fn with<T, F>(&self, f: F) -> Result<T, ApiKeyError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let connection = Arc::clone(&self.connection);
+        let result = tokio::task::spawn_blocking(move || f(&connection.lock().unwrap())).await?;
+        Ok(result?)
+    }
+
+    /// Issue a key. Returns the stored record and the plaintext key, which
+    /// is not kept anywhere.
+    pub async fn create(&self, new: NewApiKey) -> Result<(ApiKey, String), CreateKeyError> {
+        let name = new.name.trim().to_string();
+        if name.is_empty() {
+            return Err(CreateKeyError::Invalid(
+                "API key name is required".to_string(),
+            ));
+        }
+        if new.scopes.is_empty() {
+            return Err(CreateKeyError::Invalid(
+                "API key needs at least one scope".to_string(),
+            ));
+        }
+        if new.rate_limit_requests == Some(0) {
+            return Err(CreateKeyError::Invalid(
+                "rateLimitRequests must be at least 1".to_string(),
+            ));
+        }
+        if new.rate_limit_burst == Some(0) {
+            return Err(CreateKeyError::Invalid(
+                "rateLimitBurst must be at least 1".to_string(),
+            ));
+        }
+
+        let id = Uuid::new_v4().simple().to_string();
+        let secret = format!(
+            "{}{}{}",
+            KEY_PREFIX,
+            Uuid::new_v4().simple(),
+            Uuid::new_v4().simple()
+        );
+        let scopes = new
+            .scopes
+            .iter()
+            .map(|scope| scope.as_str())
+            .collect::<Vec<_>>()
+            .join(",");
+        let hash = hash_key(&secret);
+        let key_id = id.clone();
+        self.with(move |conn| {
+            conn.execute(
+                "INSERT INTO api_keys
+                     (id, name, key_hash, scopes, rate_limit_requests, rate_limit_burst, created_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    key_id,
+                    name,
+                    hash,
+                    scopes,
+                    new.rate_limit_requests.map(|v| v as i64),
+                    new.rate_limit_burst,
+                    now_secs() as i64
+                ],
+            )
+        })
+        .await?;
+
+        let key = self
+            .get(&id)
+            .await?
+            .ok_or_else(|| ApiKeyError::from("API key disappeared after insert"))?;
+        Ok((key, secret))
+    }
+
+    pub async fn get(&self, id: &str) -> Result<Option<ApiKey>, ApiKeyError> {
+        let id = id.to_string();
+        self.with(move |conn| {
+            conn.query_row(
+                "SELECT * FROM api_keys WHERE id = ?1",
+                params![id],
+                ApiKey::from_row,
+            )
+            .optional()
+        })
+        .await
+    }
+
+    pub async fn list(&self) -> Result<Vec<ApiKey>, ApiKeyError> {
+        self.with(|conn| {
+            let mut statement = conn.prepare("SELECT * FROM api_keys ORDER BY created_at, name")?;
+            let keys = statement
+                .query_map([], ApiKey::from_row)?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(keys)
+        })
+        .await
+    }
+
+    /// Look up an active key by its plaintext and count the request against
+    /// it. Unknown and revoked keys give `None`.
+    pub async fn authenticate(&self, secret: &str) -> Result<Option<ApiKey>, ApiKeyError> {
+        if !secret.starts_with(KEY_PREFIX) {
+            return Ok(None);
+        }
+        let hash = hash_key(secret);
+        self.with(move |conn| {
+            let updated = conn.execute(
+                "UPDATE api_keys SET request_count = request_count + 1, last_used_at = ?2
+                 WHERE key_hash = ?1 AND revoked_at IS NULL",
+                params![hash, now_secs() as i64],
+            )?;
+            if updated == 0 {
+                return Ok(None);
+            }
+            conn.query_row(
+                "SELECT * FROM api_keys WHERE key_hash = ?1",
+                params![hash],
+                ApiKey::from_row,
+            )
+            .optional()
+        })
+        .await
+    }
+
+    /// Revoke a key for good. Returns false for unknown ids.
+    pub async fn revoke(&self, id: &str) -> Result<bool, ApiKeyError> {
+        let id = id.to_string();
+        self.with(move |conn| {
+            let exists = conn.execute(
+                "UPDATE api_keys SET revoked_at = COALESCE(revoked_at, ?2) WHERE id = ?1",
+                params![id, now_secs() as i64],
+            )?;
+            Ok(exists > 0)
+        })
+        .await
+    }
+}
+
+fn hash_key(secret: &str) -> String {
+    Sha256::digest(secret.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_key(name: &str, scopes: Vec<Scope>) -> NewApiKey {
+        NewApiKey {
+            name: name.to_string(),
+            scopes,
+            rate_limit_requests: None,
+            rate_limit_burst: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_created_key_authenticates_and_counts_usage() {
+        let store = ApiKeyStore::open_in_memory().unwrap();
+        let (created, secret) = store
+            .create(new_key("web", vec![Scope::Generate]))
+            .await
+            .unwrap();
+        assert!(secret.starts_with("hmb_"));
+
+        let key = store.authenticate(&secret).await.unwrap().unwrap();
+        assert_eq!(key.id, created.id);
+        assert_eq!(key.request_count, 1);
+        assert!(key.last_used_at.is_some());
+        assert!(key.allows(Scope::Generate));
+        assert!(!key.allows(Scope::Jobs));
+
+        store.authenticate(&secret).await.unwrap();
+        assert_eq!(store.get(&key.id).await.unwrap().unwrap().request_count, 2);
+    }
+
+    #[tokio::test]
+    async fn test_only_the_hash_is_stored() {
+        let store = ApiKeyStore::open_in_memory().unwrap();
+        let (_, secret) = store
+            .create(new_key("web", vec![Scope::Generate]))
+            .await
+            .unwrap();
+
+        let stored: String = store
+            .with(|conn| conn.query_row("SELECT key_hash FROM api_keys", [], |row| row.get(0)))
+            .await
+            .unwrap();
+        assert_ne!(stored, secret);
+        assert_eq!(stored, hash_key(&secret));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_and_revoked_keys_are_rejected() {
+        let store = ApiKeyStore::open_in_memory().unwrap();
+        let (key, secret) = store
+            .create(new_key("partner", vec![Scope::Jobs]))
+            .await
+            .unwrap();
+
+        assert!(store.authenticate("hmb_nope").await.unwrap().is_none());
+        assert!(store.authenticate("not-a-key").await.unwrap().is_none());
+
+        assert!(store.revoke(&key.id).await.unwrap());
+        assert!(store.authenticate(&secret).await.unwrap().is_none());
+        assert!(store
+            .get(&key.id)
+            .await
+            .unwrap()
+            .unwrap()
+            .revoked_at
+            .is_some());
+        assert!(!store.revoke("missing").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_create_validates_input() {
+        let store = ApiKeyStore::open_in_memory().unwrap();
+        let invalid = |result: Result<(ApiKey, String), CreateKeyError>| match result {
+            Err(CreateKeyError::Invalid(message)) => message,
+            other => panic!("expected a validation error, got {:?}", other),
+        };
+        assert_eq!(
+            invalid(store.create(new_key(" ", vec![Scope::Generate])).await),
+            "API key name is required"
+        );
+        invalid(store.create(new_key("web", vec![])).await);
+        let zero = NewApiKey {
+            rate_limit_burst: Some(0),
+            ..new_key("web", vec![Scope::Generate])
+        };
+        assert_eq!(
+            invalid(store.create(zero).await),
+            "rateLimitBurst must be at least 1"
+        );
+        let zero = NewApiKey {
+            rate_limit_requests: Some(0),
+            ..new_key("web", vec![Scope::Generate])
+        };
+        assert_eq!(
+            invalid(store.create(zero).await),
+            "rateLimitRequests must be at least 1"
+        );
+
+        // A broken store is an error, not a validation message
+        store
+            .with(|conn| conn.execute_batch("DROP TABLE api_keys"))
+            .await
+            .unwrap();
+        assert!(matches!(
+            store.create(new_key("web", vec![Scope::Generate])).await,
+            Err(CreateKeyError::Store(_))
+        ));
+    }
+
+    #[test]
+    fn test_key_limits_override_defaults() {
+        let key = ApiKey {
+            id: "id".to_string(),
+            name: "partner".to_string(),
+            scopes: vec![Scope::Generate],
+            rate_limit_requests: Some(100),
+            rate_limit_burst: None,
+            created_at: 0,
+            revoked_at: None,
+            last_used_at: None,
+            request_count: 0,
+        };
+        let limits = key.limits(&LimitsConfig::default());
+        assert_eq!(limits.rate_limit_requests, 100);
+        assert_eq!(limits.burst(), 100);
+        assert_eq!(limits.rate_limit_window_secs, 60);
+    }
+}
@@ -0,0 +1,305 @@
+//! Reuse generated images when the same photo and prompt come in again,
+//! which mostly happens when a client retries after a network hiccup.
+//!
+//! Entries are keyed by a hash of everything that shapes the output: the
+//! normalized photo, the normalized rendered prompt, which views were asked for,
+//! the model, and the prompt template version. Results stay for `ttl_secs`,
+//! and the least recently used are dropped once the cached images add up to
+//! `max_bytes`.
+
+use crate::config::CacheConfig;
+use crate::services::generator::{GenerateError, ImageVariation};
+use lru::LruCache;
+use sha2::{Digest, Sha256};
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub type CacheKey = [u8; 32];
+
+/// Bookkeeping charged per entry on top of its image bytes.
+const ENTRY_OVERHEAD_BYTES: usize = 128;
+
+struct Entry {
+    variations: Vec<ImageVariation>,
+    size: usize,
+    expires_at: Instant,
+}
+
+struct Inner {
+    entries: LruCache<CacheKey, Entry>,
+    bytes: usize,
+}
+
+pub struct ResultCache {
+    inner: Mutex<Inner>,
+    ttl: Duration,
+    max_bytes: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub bytes: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// The cache key for one generator call.
+pub fn cache_key(
+    image_data: &[u8],
+    prompt: &str,
+    generate_angles: bool,
+    model: &str,
+    prompt_version: &str,
+) -> CacheKey {
+    let prompt = prompt
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase();
+    let mut hasher = Sha256::new();
+    // Length prefixes keep one field from bleeding into the next
+    for field in [
+        image_data,
+        prompt.as_bytes(),
+        &[generate_angles as u8],
+        model.as_bytes(),
+        prompt_version.as_bytes(),
+    ] {
+        hasher.update((field.len() as u64).to_le_bytes());
+        hasher.update(field);
+    }
+    hasher.finalize().into()
+}
+
+impl ResultCache {
+    pub fn new(config: &CacheConfig) -> Self {
+        ResultCache {
+            inner: Mutex::new(Inner {
+                entries: LruCache::unbounded(),
+                bytes: 0,
+            }),
+            ttl: Duration::from_secs(config.ttl_secs),
+            max_bytes: config.max_bytes,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        !self.ttl.is_zero() && self.max_bytes > 0
+    }
+
+    pub fn get(&self, key: &CacheKey) -> Option<Vec<ImageVariation>> {
+        let mut inner = self.inner.lock().unwrap();
+        let entry = inner.entries.get(key)?;
+        if entry.expires_at > Instant::now() {
+            return Some(entry.variations.clone());
+        }
+        if let Some(entry) = inner.entries.pop(key) {
+            inner.bytes -= entry.size;
+        }
+        None
+    }
+
+    pub fn insert(&self, key: CacheKey, variations: Vec<ImageVariation>) {
+        let size = variations
+            .iter()
+            .map(|v| v.image.len() + v.angle.len())
+            .sum::<usize>()
+            + ENTRY_OVERHEAD_BYTES;
+        if !self.enabled() || size > self.max_bytes {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        let entry = Entry {
+            variations,
+            size,
+            expires_at: Instant::now() + self.ttl,
+        };
+        if let Some((_, replaced)) = inner.entries.push(key, entry) {
+            inner.bytes -= replaced.size;
+        }
+        inner.bytes += size;
+        while inner.bytes > self.max_bytes {
+            match inner.entries.pop_lru() {
+                Some((_, evicted)) => inner.bytes -= evicted.size,
+                None => break,
+            }
+        }
+    }
+
+    /// The cached result for `key`, or the result of `generate`, which is
+    /// cached if it succeeds. The flag says whether it came from the cache.
+    pub async fn get_or_generate<F>(
+        &self,
+        key: CacheKey,
+        generate: F,
+    ) -> Result<(Vec<ImageVariation>, bool), GenerateError>
+    where
+        F: Future<Output = Result<Vec<ImageVariation>, GenerateError>>,
+    {
+        if !self.enabled() {
+            return Ok((generate.await?, false));
+        }
+        if let Some(variations) = self.get(&key) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok((variations, true));
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let variations = generate.await?;
+        if !variations.is_empty() {
+            self.insert(key, variations.clone());
+        }
+        Ok((variations, false))
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let inner = self.inner.lock().unwrap();
+        CacheStats {
+            entries: inner.entries.len(),
+            bytes: inner.bytes,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(ttl_secs: u64, max_bytes: usize) -> ResultCache {
+        ResultCache::new(&CacheConfig {
+            ttl_secs,
+            max_bytes,
+        })
+    }
+
+    fn variations(image: &str) -> Vec<ImageVariation> {
+        vec![ImageVariation {
+            image: image.to_string(),
+            angle: "front".to_string(),
+        }]
+    }
+
+    fn key(prompt: &str) -> CacheKey {
+        cache_key(b"photo", prompt, false, "gemini/default", "v1")
+    }
+
+    #[test]
+    fn test_key_normalizes_prompt() {
+        assert_eq!(key("Low  taper fade "), key("low taper FADE"));
+        assert_ne!(key("low taper fade"), key("high taper fade"));
+        assert_ne!(
+            key("fade"),
+            cache_key(b"photo", "fade", true, "gemini/default", "v1")
+        );
+        assert_ne!(
+            key("fade"),
+            cache_key(b"other photo", "fade", false, "gemini/default", "v1")
+        );
+        assert_ne!(
+            key("fade"),
+            cache_key(b"photo", "fade", false, "openai/default", "v1")
+        );
+        assert_ne!(
+            key("fade"),
+            cache_key(b"photo", "fade", false, "gemini/default", "v2")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_second_request_is_served_from_cache() {
+        let cache = cache(60, 1 << 20);
+        let (first, cached) = cache
+            .get_or_generate(key("fade"), async { Ok(variations("a")) })
+            .await
+            .unwrap();
+        assert!(!cached);
+
+        let (second, cached) = cache
+            .get_or_generate(key("fade"), async {
+                panic!("should not call the generator again")
+            })
+            .await
+            .unwrap();
+        assert!(cached);
+        assert_eq!(second[0].image, first[0].image);
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                entries: 1,
+                bytes: "a".len() + "front".len() + ENTRY_OVERHEAD_BYTES,
+                hits: 1,
+                misses: 1,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_failures_are_not_cached() {
+        let cache = cache(60, 1 << 20);
+        let result = cache
+            .get_or_generate(key("fade"), async { Err("provider down".into()) })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(cache.stats().entries, 0);
+    }
+
+    #[test]
+    fn test_expired_entries_are_dropped() {
+        let cache = cache(60, 1 << 20);
+        cache.insert(key("fade"), variations("a"));
+        cache
+            .inner
+            .lock()
+            .unwrap()
+            .entries
+            .get_mut(&key("fade"))
+            .unwrap()
+            .expires_at = Instant::now() - Duration::from_secs(1);
+        assert!(cache.get(&key("fade")).is_none());
+        assert_eq!(cache.stats().bytes, 0);
+    }
+
+    #[test]
+    fn test_size_bound_evicts_least_recently_used() {
+        let image = "x".repeat(1000);
+        let entry_size = image.len() + "front".len() + ENTRY_OVERHEAD_BYTES;
+        let cache = cache(60, entry_size * 2);
+
+        cache.insert(key("one"), variations(&image));
+        cache.insert(key("two"), variations(&image));
+        // Touch "one" so "two" is the least recently used
+        assert!(cache.get(&key("one")).is_some());
+        cache.insert(key("three"), variations(&image));
+
+        assert!(cache.get(&key("one")).is_some());
+        assert!(cache.get(&key("two")).is_none());
+        assert!(cache.get(&key("three")).is_some());
+        assert_eq!(cache.stats().bytes, entry_size * 2);
+
+        // Larger than the whole cache: never stored
+        cache.insert(key("huge"), variations(&"x".repeat(entry_size * 3)));
+        assert!(cache.get(&key("huge")).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_disabled_cache_always_generates() {
+        let cache = cache(0, 1 << 20);
+        for _ in 0..2 {
+            let (_, cached) = cache
+                .get_or_generate(key("fade"), async { Ok(variations("a")) })
+                .await
+                .unwrap();
+            assert!(!cached);
+        }
+        assert_eq!(cache.stats().entries, 0);
+    }
+}
@@ -0,0 +1,260 @@
+//! Finding the real client address behind reverse proxies.
+//!
+//! The forwarding header is only believed when the connection comes from a
+//! configured trusted proxy; anyone else could simply send a fake
+//! `X-Forwarded-For`. Even then, only the one header the proxy writes is
+//! read, since proxies pass every other header through from the client
+//! untouched. Its chain is read right to left and the first address that
+//! isn't a trusted proxy wins, since everything to its left was supplied by
+//! the client.
+
+use crate::config::ProxyConfig;
+use crate::AppState;
+use axum::{
+    extract::{ConnectInfo, FromRequestParts},
+    http::{request::Parts, HeaderMap, StatusCode},
+};
+use ipnet::IpNet;
+use std::net::{IpAddr, SocketAddr};
+
+/// The resolved client address, for handlers and middleware.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientIp(pub IpAddr);
+
+impl FromRequestParts<AppState> for ClientIp {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let ConnectInfo(peer) = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .copied()
+            .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+        Ok(ClientIp(resolve(
+            peer.ip(),
+            &parts.headers,
+            &state.config.proxy,
+        )))
+    }
+}
+
+/// The client address for a connection from `peer`.
+pub fn resolve(peer: IpAddr, headers: &HeaderMap, proxy: &ProxyConfig) -> IpAddr {
+    let trusted: &[IpNet] = &proxy.trusted_proxies;
+    let is_trusted = |ip: &IpAddr| trusted.iter().any(|net| net.contains(ip));
+    let peer = canonical(peer);
+    if !is_trusted(&peer) {
+        return peer;
+    }
+
+    let mut client = peer;
+    for hop in forwarded_chain(headers, &proxy.client_ip_header)
+        .iter()
+        .rev()
+    {
+        match hop {
+            Some(ip) if is_trusted(ip) => client = *ip,
+            Some(ip) => return *ip,
+            // Obfuscated or garbled; nothing further left can be trusted
+            None => break,
+        }
+    }
+    client
+}
+
+/// Addresses from the `header` the trusted proxy sets, oldest hop first.
+/// `Forwarded` is parsed for its `for=` parameters; any other header is
+/// taken as a comma-separated list of addresses.
+fn forwarded_chain(headers: &HeaderMap, header: &str) -> Vec<Option<IpAddr>> {
+    let values = headers
+        .get_all(header)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim);
+
+    if header.eq_ignore_ascii_case("forwarded") {
+        return values
+            .map(|element| {
+                element
+                    .split(';')
+                    .filter_map(|pair| pair.split_once('='))
+                    .find(|(name, _)| name.trim().eq_ignore_ascii_case("for"))
+                    .and_then(|(_, value)| parse_node(value))
+            })
+            .collect();
+    }
+    values.map(parse_node).collect()
+}
+
+/// Parse `1.2.3.4`, `1.2.3.4:80`, `2001:db8::1`, `[2001:db8::1]:80` and the
+/// quoted forms `Forwarded` uses.
+fn parse_node(value: &str) -> Option<IpAddr> {
+    let value = value.trim().trim_matches('"');
+    if let Ok(ip) = value.parse() {
+        return Some(canonical(ip));
+    }
+    if let Some(rest) = value.strip_prefix('[') {
+        let (ip, _) = rest.split_once(']')?;
+        return ip.parse().ok().map(canonical);
+    }
+    value
+        .parse::<SocketAddr>()
+        .ok()
+        .map(|addr| canonical(addr.ip()))
+}
+
+/// IPv4 clients reaching a dual-stack listener show up as `::ffff:a.b.c.d`.
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        v4 => v4,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROXY: &str = "10.0.0.7";
+
+    fn proxy(header: &str) -> ProxyConfig {
+        ProxyConfig {
+            trusted_proxies: vec!["10.0.0.0/8".parse().unwrap(), "fd00::/8".parse().unwrap()],
+            client_ip_header: header.to_string(),
+        }
+    }
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    fn resolve_from(peer: &str, pairs: &[(&'static str, &str)]) -> String {
+        resolve_with("x-forwarded-for", peer, pairs)
+    }
+
+    fn resolve_with(header: &str, peer: &str, pairs: &[(&'static str, &str)]) -> String {
+        resolve(peer.parse().unwrap(), &headers(pairs), &proxy(header)).to_string()
+    }
+
+    #[test]
+    fn test_untrusted_peer_headers_are_ignored() {
+        assert_eq!(
+            resolve_from("203.0.113.9", &[("x-forwarded-for", "198.51.100.1")]),
+            "203.0.113.9"
+        );
+    }
+
+    #[test]
+    fn test_no_trusted_proxies_means_peer_address() {
+        let headers = headers(&[("x-forwarded-for", "198.51.100.1")]);
+        let ip = resolve(PROXY.parse().unwrap(), &headers, &ProxyConfig::default());
+        assert_eq!(ip.to_string(), PROXY);
+    }
+
+    #[test]
+    fn test_x_forwarded_for_skips_trusted_hops() {
+        // Client-supplied junk on the left, then the real client, then a second proxy
+        assert_eq!(
+            resolve_from(
+                PROXY,
+                &[("x-forwarded-for", "6.6.6.6, 198.51.100.1, 10.1.2.3")]
+            ),
+            "198.51.100.1"
+        );
+    }
+
+    #[test]
+    fn test_repeated_headers_are_joined_in_order() {
+        assert_eq!(
+            resolve_from(
+                PROXY,
+                &[
+                    ("x-forwarded-for", "6.6.6.6"),
+                    ("x-forwarded-for", "198.51.100.1")
+                ]
+            ),
+            "198.51.100.1"
+        );
+    }
+
+    #[test]
+    fn test_forwarded_header() {
+        assert_eq!(
+            resolve_with(
+                "forwarded",
+                PROXY,
+                &[(
+                    "forwarded",
+                    r#"for=6.6.6.6, for="[2001:db8::1]:4711";proto=https, for=10.1.2.3"#
+                )]
+            ),
+            "2001:db8::1"
+        );
+    }
+
+    #[test]
+    fn test_spoofed_forwarded_header_is_ignored() {
+        // The proxy only appends to X-Forwarded-For and passes the client's
+        // own Forwarded header through
+        assert_eq!(
+            resolve_from(
+                PROXY,
+                &[
+                    ("forwarded", "for=6.6.6.6"),
+                    ("x-forwarded-for", "198.51.100.1")
+                ]
+            ),
+            "198.51.100.1"
+        );
+        assert_eq!(resolve_from(PROXY, &[("forwarded", "for=6.6.6.6")]), PROXY);
+        assert_eq!(resolve_from(PROXY, &[("x-real-ip", "6.6.6.6")]), PROXY);
+    }
+
+    #[test]
+    fn test_x_real_ip() {
+        assert_eq!(
+            resolve_with("x-real-ip", PROXY, &[("x-real-ip", "198.51.100.1:5000")]),
+            "198.51.100.1"
+        );
+    }
+
+    #[test]
+    fn test_unparseable_hop_stops_the_walk() {
+        assert_eq!(
+            resolve_with(
+                "forwarded",
+                PROXY,
+                &[("forwarded", "for=198.51.100.1, for=_hidden")]
+            ),
+            PROXY
+        );
+    }
+
+    #[test]
+    fn test_all_trusted_falls_back_to_leftmost_proxy() {
+        assert_eq!(
+            resolve_from(PROXY, &[("x-forwarded-for", "10.9.9.9, 10.1.1.1")]),
+            "10.9.9.9"
+        );
+    }
+
+    #[test]
+    fn test_ipv4_mapped_addresses_are_unwrapped() {
+        assert_eq!(
+            resolve_with(
+                "X-Real-IP",
+                "::ffff:10.0.0.7",
+                &[("x-real-ip", "::ffff:198.51.100.1")]
+            ),
+            "198.51.100.1"
+        );
+    }
+}
@@ -1,18 +1,1120 @@
+use clap::Parser;
+use ipnet::IpNet;
 use serde::Deserialize;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+
+const DEFAULT_CONFIG_PATH: &str = "config.toml";
+
+/// Command-line flags. Every setting here overrides both the config file and
+/// the environment.
+#[derive(Debug, Default, Parser)]
+#[command(about = "Help My Barber API server")]
+pub struct Cli {
+    /// Path to the config file (defaults to config.toml, which may be absent)
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+    #[arg(long)]
+    pub port: Option<u16>,
+    #[arg(long)]
+    pub prompts_path: Option<PathBuf>,
+    /// How often to check the prompts file for changes; 0 disables
+    #[arg(long)]
+    pub prompts_reload_secs: Option<u64>,
+    /// Image provider: gemini, openai or comfyui
+    #[arg(long)]
+    pub provider: Option<String>,
+    #[arg(long)]
+    pub model: Option<String>,
+    #[arg(long)]
+    pub max_image_bytes: Option<usize>,
+    #[arg(long)]
+    pub body_limit_bytes: Option<usize>,
+    #[arg(long)]
+    pub max_prompt_chars: Option<usize>,
+    #[arg(long)]
+    pub rate_limit_requests: Option<usize>,
+    #[arg(long)]
+    pub rate_limit_window_secs: Option<u64>,
+    #[arg(long)]
+    pub rate_limit_burst: Option<u32>,
+    /// Tokens an angles request spends; front-only requests spend one
+    #[arg(long)]
+    pub rate_limit_angles_cost: Option<u32>,
+    /// Rate-limit store: memory, redis or sqlite
+    #[arg(long)]
+    pub rate_limit_backend: Option<String>,
+}
 
 #[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
 pub struct Config {
-    pub model: String,
+    pub port: u16,
+    pub prompts_path: PathBuf,
+    /// How often to check the prompts file for changes; 0 disables watching
+    pub prompts_reload_secs: u64,
+    /// Bearer token for `/admin` endpoints; they are disabled when unset
+    pub admin_token: Option<String>,
+    /// Origins allowed to call the API with cookies. Empty allows any origin,
+    /// but then browsers won't send the session cookie cross-origin.
+    pub cors_origins: Vec<String>,
+    pub generator: GeneratorConfig,
+    pub limits: LimitsConfig,
+    pub jobs: JobsConfig,
+    pub rate_limit: RateLimitConfig,
+    pub proxy: ProxyConfig,
+    pub auth: AuthConfig,
+    pub images: ImagesConfig,
+    pub cache: CacheConfig,
+    pub normalize: NormalizeConfig,
+    pub quality: QualityConfig,
+    pub faces: FacesConfig,
+    pub crop: CropConfig,
+    pub moderation: ModerationConfig,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct GeneratorConfig {
+    /// "gemini", "openai" or "comfyui"
+    pub provider: String,
+    /// Model name; each provider has its own default
+    pub model: Option<String>,
+    /// API base URL; each provider has its own default
+    pub base_url: Option<String>,
+    pub api_key: Option<String>,
+    /// ComfyUI workflow template
+    pub workflow_path: PathBuf,
+    /// How long to wait for a ComfyUI workflow to finish
+    pub timeout_secs: u64,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Largest decoded image accepted by `/api/generate`
+    pub max_image_bytes: usize,
+    /// Request body cap; derived from `max_image_bytes` when unset
+    pub body_limit_bytes: Option<usize>,
+    /// Longest width or height an upload's headers may declare
+    pub max_image_dimension: u32,
+    pub max_image_pixels: u64,
+    /// Animation frames in a GIF, APNG or WebP
+    pub max_image_frames: u32,
+    /// Memory the decoder may allocate for one image
+    pub max_decode_bytes: u64,
+    /// How long decoding and normalizing an upload may take
+    pub decode_timeout_secs: u64,
+    pub max_prompt_chars: usize,
+    /// Tokens refilled per `rate_limit_window_secs`
+    pub rate_limit_requests: usize,
+    pub rate_limit_window_secs: u64,
+    /// Bucket size; defaults to `rate_limit_requests`
+    pub rate_limit_burst: Option<u32>,
+    /// Tokens a `generateAngles` request spends, since it makes a second,
+    /// bigger generation call. Front-only requests spend one, and streamed
+    /// angles requests, which make the front view as well, spend both.
+    pub rate_limit_angles_cost: u32,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct JobsConfig {
+    /// Generation jobs allowed to run at once
+    pub workers: usize,
+    /// Queued plus running jobs before new submissions are refused
+    pub max_queued: usize,
+    /// How long finished jobs stay available for polling
+    pub result_ttl_secs: u64,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// "memory", "redis" or "sqlite"; only the latter two are shared between
+    /// replicas and survive restarts
+    pub backend: String,
+    pub redis_url: String,
+    pub sqlite_path: PathBuf,
+    /// Most clients the memory backend tracks; past this the least recently
+    /// active are dropped
+    pub max_keys: usize,
+    /// How often the memory and SQLite backends drop clients whose buckets
+    /// have refilled; 0 disables the sweeper
+    pub sweep_interval_secs: u64,
+    /// IPv6 clients in the same prefix share a bucket, since one host can
+    /// usually pick any address in its /64
+    pub ipv6_prefix: u8,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Turn away `/api` requests that don't carry an API key
+    pub require_api_key: bool,
+    /// SQLite file holding issued API keys, user accounts and saved looks
+    pub database_path: PathBuf,
+    /// How long a login lasts
+    pub session_ttl_secs: u64,
+    /// Mark the session cookie `Secure`; turn on whenever serving over HTTPS
+    pub secure_cookies: bool,
+    /// `SameSite` for the session cookie. Use `none` (with `secure_cookies`)
+    /// when the frontend lives on a different site than the API.
+    pub cookie_same_site: SameSite,
+    /// Looks one account may keep
+    pub max_looks_per_user: usize,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl std::str::FromStr for SameSite {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "strict" => Ok(SameSite::Strict),
+            "lax" => Ok(SameSite::Lax),
+            "none" => Ok(SameSite::None),
+            _ => Err(()),
+        }
+    }
+}
+
+impl std::fmt::Display for SameSite {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            SameSite::Strict => "Strict",
+            SameSite::Lax => "Lax",
+            SameSite::None => "None",
+        })
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    /// How long a generated result can be reused; 0 disables the cache
+    pub ttl_secs: u64,
+    /// Total size of cached images before the least recently used go
+    pub max_bytes: usize,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct NormalizeConfig {
+    /// Decode, rotate, downscale and re-encode uploads before generation
+    pub enabled: bool,
+    /// Longest edge after downscaling; smaller photos keep their size
+    pub max_edge: u32,
+    /// Re-encoded format: "jpeg" or "png"
+    pub format: String,
+    /// 1-100
+    pub jpeg_quality: u8,
+}
+
+/// Photo quality thresholds. Falling below a `warn_` value adds advice to the
+/// response; below a `reject_` value the request fails with a 422.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct QualityConfig {
+    pub enabled: bool,
+    /// Shorter side of the upload, in pixels
+    pub reject_short_edge: u32,
+    pub warn_short_edge: u32,
+    /// Variance of the Laplacian on a 512px copy; lower is blurrier
+    pub reject_sharpness: f64,
+    pub warn_sharpness: f64,
+    /// Mean brightness, 0-255
+    pub reject_dark: f64,
+    pub warn_dark: f64,
+    pub reject_bright: f64,
+    pub warn_bright: f64,
+    /// Share of the frame the subject should cover; only ever a warning
+    pub warn_subject_fraction: f64,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct FacesConfig {
+    /// Reject photos without a face and ask which person to restyle in
+    /// group photos. Off by default: the built-in detector is a heuristic
+    /// that misses some real faces
+    pub enabled: bool,
+    /// Required when enabled; only "skin" for now
+    pub detector: String,
+    /// Smallest face width, as a share of the photo's shorter side
+    pub min_face_size: f64,
+}
+
+/// The head-and-shoulders crop around the chosen face, measured in face
+/// widths and heights.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct CropConfig {
+    pub enabled: bool,
+    /// Total width, centred on the face
+    pub width: f64,
+    /// Room above the face for the hair
+    pub above: f64,
+    /// Room below the face for the neck and shoulders
+    pub below: f64,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModerationConfig {
+    pub enabled: bool,
+    /// "rules" or "http"
+    pub provider: String,
+    /// Endpoint for the http provider
+    pub url: Option<String>,
+    pub api_key: Option<String>,
+    pub timeout_secs: u64,
+    /// Let requests through when the moderator fails instead of blocking
+    pub fail_open: bool,
+    /// Prompt words the rules provider blocks outright
+    pub block_terms: Vec<String>,
+    /// Prompt words the rules provider lets through but records
+    pub flag_terms: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ImagesConfig {
+    /// "local" or "s3"
+    pub store: String,
+    /// Directory for the local store
+    pub local_dir: PathBuf,
+    pub s3: S3Config,
+    /// Prefix for image URLs, e.g. `https://api.example.com`. Unset gives
+    /// root-relative `/images/...` URLs.
+    pub public_base_url: Option<String>,
+    /// How long an image URL stays valid
+    pub url_ttl_secs: u64,
+    /// Secret for signing image URLs; a random one is used when unset, which
+    /// breaks URLs on restart and across replicas
+    pub signing_key: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct S3Config {
+    /// Any S3-compatible server, e.g. `http://127.0.0.1:9000` for MinIO;
+    /// AWS when unset
+    pub endpoint: Option<String>,
+    pub bucket: String,
+    pub region: String,
+    pub access_key_id: Option<String>,
+    pub secret_access_key: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxyConfig {
+    /// Networks whose forwarding header is believed. Empty means connections
+    /// are taken at face value.
+    pub trusted_proxies: Vec<IpNet>,
+    /// The one header the trusted proxy sets: `x-forwarded-for`,
+    /// `forwarded`, `x-real-ip` or similar
+    pub client_ip_header: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            port: 3001,
+            prompts_path: PathBuf::from("prompts.toml"),
+            prompts_reload_secs: 5,
+            admin_token: None,
+            cors_origins: Vec::new(),
+            generator: GeneratorConfig::default(),
+            limits: LimitsConfig::default(),
+            jobs: JobsConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            proxy: ProxyConfig::default(),
+            auth: AuthConfig::default(),
+            images: ImagesConfig::default(),
+            cache: CacheConfig::default(),
+            normalize: NormalizeConfig::default(),
+            quality: QualityConfig::default(),
+            faces: FacesConfig::default(),
+            crop: CropConfig::default(),
+            moderation: ModerationConfig::default(),
+        }
+    }
+}
+
+impl Default for GeneratorConfig {
+    fn default() -> Self {
+        GeneratorConfig {
+            provider: "gemini".to_string(),
+            model: None,
+            base_url: None,
+            api_key: None,
+            workflow_path: PathBuf::from("comfyui_workflow.json"),
+            timeout_secs: 300,
+        }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            max_image_bytes: 10 * 1024 * 1024,
+            body_limit_bytes: None,
+            max_image_dimension: 16384,
+            max_image_pixels: 64_000_000,
+            max_image_frames: 100,
+            max_decode_bytes: 256 * 1024 * 1024,
+            decode_timeout_secs: 10,
+            max_prompt_chars: 500,
+            rate_limit_requests: 10,
+            rate_limit_window_secs: 60,
+            rate_limit_burst: None,
+            rate_limit_angles_cost: 2,
+        }
+    }
+}
+
+impl Default for JobsConfig {
+    fn default() -> Self {
+        JobsConfig {
+            workers: 4,
+            max_queued: 100,
+            result_ttl_secs: 600,
+        }
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            backend: "memory".to_string(),
+            redis_url: "redis://127.0.0.1:6379".to_string(),
+            sqlite_path: PathBuf::from("rate_limits.db"),
+            max_keys: 100_000,
+            sweep_interval_secs: 60,
+            ipv6_prefix: 64,
+        }
+    }
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            require_api_key: false,
+            database_path: PathBuf::from("auth.db"),
+            session_ttl_secs: 30 * 24 * 60 * 60,
+            secure_cookies: false,
+            cookie_same_site: SameSite::Lax,
+            max_looks_per_user: 500,
+        }
+    }
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            ttl_secs: 60 * 60,
+            max_bytes: 256 * 1024 * 1024,
+        }
+    }
+}
+
+impl Default for NormalizeConfig {
+    fn default() -> Self {
+        NormalizeConfig {
+            enabled: true,
+            max_edge: 1536,
+            format: "jpeg".to_string(),
+            jpeg_quality: 90,
+        }
+    }
+}
+
+impl Default for QualityConfig {
+    fn default() -> Self {
+        QualityConfig {
+            enabled: true,
+            reject_short_edge: 256,
+            warn_short_edge: 512,
+            reject_sharpness: 15.0,
+            warn_sharpness: 60.0,
+            reject_dark: 25.0,
+            warn_dark: 60.0,
+            reject_bright: 235.0,
+            warn_bright: 200.0,
+            warn_subject_fraction: 0.05,
+        }
+    }
+}
+
+impl Default for FacesConfig {
+    fn default() -> Self {
+        FacesConfig {
+            enabled: false,
+            detector: String::new(),
+            min_face_size: 0.1,
+        }
+    }
+}
+
+impl Default for CropConfig {
+    fn default() -> Self {
+        CropConfig {
+            enabled: true,
+            width: 3.0,
+            above: 0.7,
+            below: 1.3,
+        }
+    }
+}
+
+impl Default for ModerationConfig {
+    fn default() -> Self {
+        let terms = |terms: &[&str]| terms.iter().map(|term| term.to_string()).collect();
+        ModerationConfig {
+            enabled: true,
+            provider: "rules".to_string(),
+            url: None,
+            api_key: None,
+            timeout_secs: 10,
+            fail_open: false,
+            block_terms: terms(&[
+                "nude", "naked", "nsfw", "topless", "lingerie", "gore", "corpse", "swastika",
+            ]),
+            flag_terms: terms(&["blood", "gun", "knife", "weapon", "cigarette"]),
+        }
+    }
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        ProxyConfig {
+            trusted_proxies: Vec::new(),
+            client_ip_header: "x-forwarded-for".to_string(),
+        }
+    }
+}
+
+impl Default for ImagesConfig {
+    fn default() -> Self {
+        ImagesConfig {
+            store: "local".to_string(),
+            local_dir: PathBuf::from("images"),
+            s3: S3Config::default(),
+            public_base_url: None,
+            url_ttl_secs: 24 * 60 * 60,
+            signing_key: None,
+        }
+    }
+}
+
+impl Default for S3Config {
+    fn default() -> Self {
+        S3Config {
+            endpoint: None,
+            bucket: String::new(),
+            region: "us-east-1".to_string(),
+            access_key_id: None,
+            secret_access_key: None,
+        }
+    }
+}
+
+impl LimitsConfig {
+    /// The request body limit: base64 inflates the image by 4/3, plus room for
+    /// the prompt and JSON framing.
+    pub fn body_limit(&self) -> usize {
+        self.body_limit_bytes
+            .unwrap_or(self.max_image_bytes / 3 * 4 + 64 * 1024)
+    }
+
+    pub fn burst(&self) -> u32 {
+        self.rate_limit_burst
+            .unwrap_or(self.rate_limit_requests as u32)
+    }
+
+    /// Reject limits that cannot mean what they say: a bucket of zero tokens
+    /// or no refill would block every client for good.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.rate_limit_requests == 0 {
+            return Err("limits.rate_limit_requests must be at least 1".to_string());
+        }
+        if self.rate_limit_burst == Some(0) {
+            return Err("limits.rate_limit_burst must be at least 1".to_string());
+        }
+        Ok(())
+    }
 }
 
 impl Config {
-    /// Load configuration from the default config directory
-    pub fn load_default() -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
-        let config_path = Path::new("config/prompts.toml");
-        let contents = fs::read_to_string(config_path)?;
-        let config: Config = toml::from_str(&contents)?;
+    /// Load configuration from the config file, then the environment, then
+    /// CLI flags, each layer overriding the previous one.
+    pub fn load(cli: &Cli) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let env_path = std::env::var("CONFIG_PATH").ok().map(PathBuf::from);
+        let (path, required) = match cli.config.clone().or(env_path) {
+            Some(path) => (path, true),
+            None => (PathBuf::from(DEFAULT_CONFIG_PATH), false),
+        };
+
+        let mut config = if required || path.exists() {
+            Self::from_file(&path)?
+        } else {
+            Config::default()
+        };
+        config.apply_env(|key| std::env::var(key).ok())?;
+        config.apply_cli(cli);
+        config.apply_provider_key(|key| std::env::var(key).ok());
+        config.limits.validate()?;
         Ok(config)
     }
-}
\ No newline at end of file
+
+    /// Parse a config file. Relative paths inside it resolve against the
+    /// file's own directory rather than the working directory.
+    pub fn from_file(path: &Path) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let contents = fs::read_to_string(path)
+            .map_err(|err| format!("Failed to read {}: {}", path.display(), err))?;
+        let mut config: Config = toml::from_str(&contents)
+            .map_err(|err| format!("Invalid config in {}: {}", path.display(), err))?;
+
+        let base = path.parent().unwrap_or(Path::new(""));
+        config.prompts_path = base.join(&config.prompts_path);
+        config.generator.workflow_path = base.join(&config.generator.workflow_path);
+        config.rate_limit.sqlite_path = base.join(&config.rate_limit.sqlite_path);
+        config.auth.database_path = base.join(&config.auth.database_path);
+        config.images.local_dir = base.join(&config.images.local_dir);
+        Ok(config)
+    }
+
+    fn apply_env(
+        &mut self,
+        lookup: impl Fn(&str) -> Option<String>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        fn parse<T: std::str::FromStr>(
+            key: &str,
+            value: String,
+        ) -> Result<T, Box<dyn std::error::Error + Send + Sync>> {
+            value
+                .parse()
+                .map_err(|_| format!("Invalid value for {}: {}", key, value).into())
+        }
+
+        if let Some(v) = lookup("PORT") {
+            self.port = parse("PORT", v)?;
+        }
+        if let Some(v) = lookup("PROMPTS_PATH") {
+            self.prompts_path = PathBuf::from(v);
+        }
+        if let Some(v) = lookup("PROMPTS_RELOAD_SECS") {
+            self.prompts_reload_secs = parse("PROMPTS_RELOAD_SECS", v)?;
+        }
+        if let Some(v) = lookup("ADMIN_TOKEN") {
+            self.admin_token = Some(v);
+        }
+        if let Some(v) = lookup("IMAGE_PROVIDER") {
+            self.generator.provider = v;
+        }
+        if let Some(v) = lookup("IMAGE_MODEL") {
+            self.generator.model = Some(v);
+        }
+        if let Some(v) = lookup("IMAGE_API_BASE_URL") {
+            self.generator.base_url = Some(v);
+        }
+        if let Some(v) = lookup("IMAGE_API_KEY") {
+            self.generator.api_key = Some(v);
+        }
+        if let Some(v) = lookup("COMFYUI_WORKFLOW") {
+            self.generator.workflow_path = PathBuf::from(v);
+        }
+        if let Some(v) = lookup("GENERATOR_TIMEOUT_SECS") {
+            self.generator.timeout_secs = parse("GENERATOR_TIMEOUT_SECS", v)?;
+        }
+        if let Some(v) = lookup("MAX_IMAGE_BYTES") {
+            self.limits.max_image_bytes = parse("MAX_IMAGE_BYTES", v)?;
+        }
+        if let Some(v) = lookup("BODY_LIMIT_BYTES") {
+            self.limits.body_limit_bytes = Some(parse("BODY_LIMIT_BYTES", v)?);
+        }
+        if let Some(v) = lookup("MAX_IMAGE_DIMENSION") {
+            self.limits.max_image_dimension = parse("MAX_IMAGE_DIMENSION", v)?;
+        }
+        if let Some(v) = lookup("MAX_IMAGE_PIXELS") {
+            self.limits.max_image_pixels = parse("MAX_IMAGE_PIXELS", v)?;
+        }
+        if let Some(v) = lookup("MAX_IMAGE_FRAMES") {
+            self.limits.max_image_frames = parse("MAX_IMAGE_FRAMES", v)?;
+        }
+        if let Some(v) = lookup("MAX_DECODE_BYTES") {
+            self.limits.max_decode_bytes = parse("MAX_DECODE_BYTES", v)?;
+        }
+        if let Some(v) = lookup("DECODE_TIMEOUT_SECS") {
+            self.limits.decode_timeout_secs = parse("DECODE_TIMEOUT_SECS", v)?;
+        }
+        if let Some(v) = lookup("MAX_PROMPT_CHARS") {
+            self.limits.max_prompt_chars = parse("MAX_PROMPT_CHARS", v)?;
+        }
+        if let Some(v) = lookup("RATE_LIMIT_REQUESTS") {
+            self.limits.rate_limit_requests = parse("RATE_LIMIT_REQUESTS", v)?;
+        }
+        if let Some(v) = lookup("RATE_LIMIT_WINDOW_SECS") {
+            self.limits.rate_limit_window_secs = parse("RATE_LIMIT_WINDOW_SECS", v)?;
+        }
+        if let Some(v) = lookup("RATE_LIMIT_BURST") {
+            self.limits.rate_limit_burst = Some(parse("RATE_LIMIT_BURST", v)?);
+        }
+        if let Some(v) = lookup("RATE_LIMIT_ANGLES_COST") {
+            self.limits.rate_limit_angles_cost = parse("RATE_LIMIT_ANGLES_COST", v)?;
+        }
+        if let Some(v) = lookup("JOB_WORKERS") {
+            self.jobs.workers = parse("JOB_WORKERS", v)?;
+        }
+        if let Some(v) = lookup("JOB_MAX_QUEUED") {
+            self.jobs.max_queued = parse("JOB_MAX_QUEUED", v)?;
+        }
+        if let Some(v) = lookup("JOB_RESULT_TTL_SECS") {
+            self.jobs.result_ttl_secs = parse("JOB_RESULT_TTL_SECS", v)?;
+        }
+        if let Some(v) = lookup("RATE_LIMIT_BACKEND") {
+            self.rate_limit.backend = v;
+        }
+        if let Some(v) = lookup("REDIS_URL") {
+            self.rate_limit.redis_url = v;
+        }
+        if let Some(v) = lookup("RATE_LIMIT_SQLITE_PATH") {
+            self.rate_limit.sqlite_path = PathBuf::from(v);
+        }
+        if let Some(v) = lookup("RATE_LIMIT_MAX_KEYS") {
+            self.rate_limit.max_keys = parse("RATE_LIMIT_MAX_KEYS", v)?;
+        }
+        if let Some(v) = lookup("RATE_LIMIT_SWEEP_SECS") {
+            self.rate_limit.sweep_interval_secs = parse("RATE_LIMIT_SWEEP_SECS", v)?;
+        }
+        if let Some(v) = lookup("RATE_LIMIT_IPV6_PREFIX") {
+            self.rate_limit.ipv6_prefix = parse("RATE_LIMIT_IPV6_PREFIX", v)?;
+        }
+        if let Some(v) = lookup("REQUIRE_API_KEY") {
+            self.auth.require_api_key = parse("REQUIRE_API_KEY", v)?;
+        }
+        if let Some(v) = lookup("AUTH_DB_PATH") {
+            self.auth.database_path = PathBuf::from(v);
+        }
+        if let Some(v) = lookup("SESSION_TTL_SECS") {
+            self.auth.session_ttl_secs = parse("SESSION_TTL_SECS", v)?;
+        }
+        if let Some(v) = lookup("SECURE_COOKIES") {
+            self.auth.secure_cookies = parse("SECURE_COOKIES", v)?;
+        }
+        if let Some(v) = lookup("COOKIE_SAME_SITE") {
+            self.auth.cookie_same_site = parse("COOKIE_SAME_SITE", v)?;
+        }
+        if let Some(v) = lookup("MAX_LOOKS_PER_USER") {
+            self.auth.max_looks_per_user = parse("MAX_LOOKS_PER_USER", v)?;
+        }
+        if let Some(v) = lookup("CORS_ORIGINS") {
+            self.cors_origins = v
+                .split(',')
+                .map(str::trim)
+                .filter(|origin| !origin.is_empty())
+                .map(str::to_string)
+                .collect();
+        }
+        if let Some(v) = lookup("RESULT_CACHE_TTL_SECS") {
+            self.cache.ttl_secs = parse("RESULT_CACHE_TTL_SECS", v)?;
+        }
+        if let Some(v) = lookup("RESULT_CACHE_MAX_BYTES") {
+            self.cache.max_bytes = parse("RESULT_CACHE_MAX_BYTES", v)?;
+        }
+        if let Some(v) = lookup("NORMALIZE_IMAGES") {
+            self.normalize.enabled = parse("NORMALIZE_IMAGES", v)?;
+        }
+        if let Some(v) = lookup("NORMALIZE_MAX_EDGE") {
+            self.normalize.max_edge = parse("NORMALIZE_MAX_EDGE", v)?;
+        }
+        if let Some(v) = lookup("NORMALIZE_FORMAT") {
+            self.normalize.format = v;
+        }
+        if let Some(v) = lookup("NORMALIZE_JPEG_QUALITY") {
+            self.normalize.jpeg_quality = parse("NORMALIZE_JPEG_QUALITY", v)?;
+        }
+        if let Some(v) = lookup("QUALITY_CHECKS") {
+            self.quality.enabled = parse("QUALITY_CHECKS", v)?;
+        }
+        if let Some(v) = lookup("QUALITY_REJECT_SHORT_EDGE") {
+            self.quality.reject_short_edge = parse("QUALITY_REJECT_SHORT_EDGE", v)?;
+        }
+        if let Some(v) = lookup("QUALITY_WARN_SHORT_EDGE") {
+            self.quality.warn_short_edge = parse("QUALITY_WARN_SHORT_EDGE", v)?;
+        }
+        if let Some(v) = lookup("QUALITY_REJECT_SHARPNESS") {
+            self.quality.reject_sharpness = parse("QUALITY_REJECT_SHARPNESS", v)?;
+        }
+        if let Some(v) = lookup("QUALITY_WARN_SHARPNESS") {
+            self.quality.warn_sharpness = parse("QUALITY_WARN_SHARPNESS", v)?;
+        }
+        if let Some(v) = lookup("QUALITY_REJECT_DARK") {
+            self.quality.reject_dark = parse("QUALITY_REJECT_DARK", v)?;
+        }
+        if let Some(v) = lookup("QUALITY_WARN_DARK") {
+            self.quality.warn_dark = parse("QUALITY_WARN_DARK", v)?;
+        }
+        if let Some(v) = lookup("QUALITY_REJECT_BRIGHT") {
+            self.quality.reject_bright = parse("QUALITY_REJECT_BRIGHT", v)?;
+        }
+        if let Some(v) = lookup("QUALITY_WARN_BRIGHT") {
+            self.quality.warn_bright = parse("QUALITY_WARN_BRIGHT", v)?;
+        }
+        if let Some(v) = lookup("QUALITY_WARN_SUBJECT_FRACTION") {
+            self.quality.warn_subject_fraction = parse("QUALITY_WARN_SUBJECT_FRACTION", v)?;
+        }
+        if let Some(v) = lookup("FACE_DETECTION") {
+            self.faces.enabled = parse("FACE_DETECTION", v)?;
+        }
+        if let Some(v) = lookup("FACE_DETECTOR") {
+            self.faces.detector = v;
+        }
+        if let Some(v) = lookup("FACE_MIN_SIZE") {
+            self.faces.min_face_size = parse("FACE_MIN_SIZE", v)?;
+        }
+        if let Some(v) = lookup("CROP_TO_FACE") {
+            self.crop.enabled = parse("CROP_TO_FACE", v)?;
+        }
+        if let Some(v) = lookup("CROP_WIDTH") {
+            self.crop.width = parse("CROP_WIDTH", v)?;
+        }
+        if let Some(v) = lookup("CROP_ABOVE") {
+            self.crop.above = parse("CROP_ABOVE", v)?;
+        }
+        if let Some(v) = lookup("CROP_BELOW") {
+            self.crop.below = parse("CROP_BELOW", v)?;
+        }
+        if let Some(v) = lookup("MODERATION") {
+            self.moderation.enabled = parse("MODERATION", v)?;
+        }
+        if let Some(v) = lookup("MODERATION_PROVIDER") {
+            self.moderation.provider = v;
+        }
+        if let Some(v) = lookup("MODERATION_URL") {
+            self.moderation.url = Some(v);
+        }
+        if let Some(v) = lookup("MODERATION_API_KEY") {
+            self.moderation.api_key = Some(v);
+        }
+        if let Some(v) = lookup("MODERATION_TIMEOUT_SECS") {
+            self.moderation.timeout_secs = parse("MODERATION_TIMEOUT_SECS", v)?;
+        }
+        if let Some(v) = lookup("MODERATION_FAIL_OPEN") {
+            self.moderation.fail_open = parse("MODERATION_FAIL_OPEN", v)?;
+        }
+        if let Some(v) = lookup("IMAGE_STORE") {
+            self.images.store = v;
+        }
+        if let Some(v) = lookup("IMAGE_DIR") {
+            self.images.local_dir = PathBuf::from(v);
+        }
+        if let Some(v) = lookup("PUBLIC_BASE_URL") {
+            self.images.public_base_url = Some(v);
+        }
+        if let Some(v) = lookup("IMAGE_URL_TTL_SECS") {
+            self.images.url_ttl_secs = parse("IMAGE_URL_TTL_SECS", v)?;
+        }
+        if let Some(v) = lookup("IMAGE_SIGNING_KEY") {
+            self.images.signing_key = Some(v);
+        }
+        if let Some(v) = lookup("S3_ENDPOINT") {
+            self.images.s3.endpoint = Some(v);
+        }
+        if let Some(v) = lookup("S3_BUCKET") {
+            self.images.s3.bucket = v;
+        }
+        if let Some(v) = lookup("S3_REGION") {
+            self.images.s3.region = v;
+        }
+        if let Some(v) = lookup("S3_ACCESS_KEY_ID") {
+            self.images.s3.access_key_id = Some(v);
+        }
+        if let Some(v) = lookup("S3_SECRET_ACCESS_KEY") {
+            self.images.s3.secret_access_key = Some(v);
+        }
+        if let Some(v) = lookup("TRUSTED_PROXIES") {
+            self.proxy.trusted_proxies = v
+                .split(',')
+                .map(str::trim)
+                .filter(|net| !net.is_empty())
+                .map(|net| parse("TRUSTED_PROXIES", net.to_string()))
+                .collect::<Result<_, _>>()?;
+        }
+        if let Some(v) = lookup("CLIENT_IP_HEADER") {
+            self.proxy.client_ip_header = v;
+        }
+        Ok(())
+    }
+
+    /// Fall back to the provider's own key variable, so existing .env files
+    /// keep working. Runs last since the provider may come from any layer.
+    fn apply_provider_key(&mut self, lookup: impl Fn(&str) -> Option<String>) {
+        if self.generator.api_key.is_some() {
+            return;
+        }
+        let key_var = match self.generator.provider.as_str() {
+            "openai" => "OPENAI_API_KEY",
+            _ => "GEMINI_API_KEY",
+        };
+        self.generator.api_key = lookup(key_var);
+    }
+
+    fn apply_cli(&mut self, cli: &Cli) {
+        if let Some(port) = cli.port {
+            self.port = port;
+        }
+        if let Some(path) = &cli.prompts_path {
+            self.prompts_path = path.clone();
+        }
+        if let Some(v) = cli.prompts_reload_secs {
+            self.prompts_reload_secs = v;
+        }
+        if let Some(provider) = &cli.provider {
+            self.generator.provider = provider.clone();
+        }
+        if let Some(model) = &cli.model {
+            self.generator.model = Some(model.clone());
+        }
+        if let Some(v) = cli.max_image_bytes {
+            self.limits.max_image_bytes = v;
+        }
+        if let Some(v) = cli.body_limit_bytes {
+            self.limits.body_limit_bytes = Some(v);
+        }
+        if let Some(v) = cli.max_prompt_chars {
+            self.limits.max_prompt_chars = v;
+        }
+        if let Some(v) = cli.rate_limit_requests {
+            self.limits.rate_limit_requests = v;
+        }
+        if let Some(v) = cli.rate_limit_window_secs {
+            self.limits.rate_limit_window_secs = v;
+        }
+        if let Some(v) = cli.rate_limit_burst {
+            self.limits.rate_limit_burst = Some(v);
+        }
+        if let Some(v) = cli.rate_limit_angles_cost {
+            self.limits.rate_limit_angles_cost = v;
+        }
+        if let Some(backend) = &cli.rate_limit_backend {
+            self.rate_limit.backend = backend.clone();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        move |key| vars.get(key).cloned()
+    }
+
+    #[test]
+    fn test_defaults_match_previous_hardcoded_values() {
+        let config = Config::default();
+        assert_eq!(config.port, 3001);
+        assert_eq!(config.prompts_path, PathBuf::from("prompts.toml"));
+        assert_eq!(config.generator.provider, "gemini");
+        assert_eq!(config.limits.max_image_bytes, 10 * 1024 * 1024);
+        assert_eq!(config.limits.max_prompt_chars, 500);
+        assert_eq!(config.limits.rate_limit_requests, 10);
+        assert_eq!(config.limits.rate_limit_window_secs, 60);
+    }
+
+    #[test]
+    fn test_body_limit_fits_largest_allowed_image() {
+        let limits = LimitsConfig::default();
+        let base64_len = limits.max_image_bytes.div_ceil(3) * 4;
+        assert!(limits.body_limit() > base64_len);
+
+        let limits = LimitsConfig {
+            body_limit_bytes: Some(1024),
+            ..LimitsConfig::default()
+        };
+        assert_eq!(limits.body_limit(), 1024);
+    }
+
+    #[test]
+    fn test_layers_override_in_order() {
+        let mut config: Config = toml::from_str(
+            r#"
+            port = 4000
+
+            [generator]
+            model = "from-file"
+
+            [limits]
+            max_prompt_chars = 300
+            rate_limit_requests = 20
+            "#,
+        )
+        .unwrap();
+
+        config
+            .apply_env(env(&[("PORT", "5000"), ("RATE_LIMIT_REQUESTS", "30")]))
+            .unwrap();
+        config.apply_cli(&Cli {
+            port: Some(6000),
+            ..Cli::default()
+        });
+
+        assert_eq!(config.port, 6000); // CLI beats env and file
+        assert_eq!(config.limits.rate_limit_requests, 30); // env beats file
+        assert_eq!(config.limits.max_prompt_chars, 300); // file beats default
+        assert_eq!(config.generator.model.as_deref(), Some("from-file"));
+    }
+
+    #[test]
+    fn test_provider_specific_api_key_env() {
+        let vars = [
+            ("OPENAI_API_KEY", "sk-openai"),
+            ("GEMINI_API_KEY", "gemini-key"),
+        ];
+
+        let mut config = Config::default();
+        config.apply_env(env(&vars)).unwrap();
+        config.apply_cli(&Cli {
+            provider: Some("openai".to_string()),
+            ..Cli::default()
+        });
+        config.apply_provider_key(env(&vars));
+        assert_eq!(config.generator.api_key.as_deref(), Some("sk-openai"));
+
+        let mut config = Config::default();
+        config.apply_provider_key(env(&vars));
+        assert_eq!(config.generator.api_key.as_deref(), Some("gemini-key"));
+    }
+
+    #[test]
+    fn test_invalid_env_value_is_an_error() {
+        let mut config = Config::default();
+        let err = config
+            .apply_env(env(&[("PORT", "not-a-port")]))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Invalid value for PORT: not-a-port");
+    }
+
+    #[test]
+    fn test_zero_rate_limits_are_rejected() {
+        let mut config = Config::default();
+        assert!(config.limits.validate().is_ok());
+        config.apply_env(env(&[("RATE_LIMIT_BURST", "0")])).unwrap();
+        assert_eq!(
+            config.limits.validate().unwrap_err(),
+            "limits.rate_limit_burst must be at least 1"
+        );
+        config
+            .apply_env(env(&[
+                ("RATE_LIMIT_BURST", "5"),
+                ("RATE_LIMIT_REQUESTS", "0"),
+            ]))
+            .unwrap();
+        assert_eq!(
+            config.limits.validate().unwrap_err(),
+            "limits.rate_limit_requests must be at least 1"
+        );
+    }
+
+    #[test]
+    fn test_trusted_proxies() {
+        let config: Config = toml::from_str(
+            r#"
+            [proxy]
+            trusted_proxies = ["10.0.0.0/8", "fd00::/8"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.proxy.trusted_proxies.len(), 2);
+        assert_eq!(config.proxy.client_ip_header, "x-forwarded-for");
+
+        let mut config = Config::default();
+        config
+            .apply_env(env(&[("TRUSTED_PROXIES", "100.64.0.0/10, 127.0.0.1/32")]))
+            .unwrap();
+        assert_eq!(
+            config.proxy.trusted_proxies,
+            vec![
+                "100.64.0.0/10".parse::<IpNet>().unwrap(),
+                "127.0.0.1/32".parse().unwrap()
+            ]
+        );
+
+        let err = config
+            .apply_env(env(&[("TRUSTED_PROXIES", "10.0.0.0/33")]))
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid value for TRUSTED_PROXIES: 10.0.0.0/33"
+        );
+    }
+
+    #[test]
+    fn test_session_cookie_settings() {
+        let config: Config = toml::from_str(
+            r#"
+            cors_origins = ["https://app.example.com"]
+
+            [auth]
+            secure_cookies = true
+            cookie_same_site = "none"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.cors_origins, vec!["https://app.example.com"]);
+        assert!(config.auth.secure_cookies);
+        assert_eq!(config.auth.cookie_same_site, SameSite::None);
+
+        let mut config = Config::default();
+        config
+            .apply_env(env(&[
+                ("COOKIE_SAME_SITE", "Strict"),
+                ("CORS_ORIGINS", "http://localhost:3000, https://a.example"),
+            ]))
+            .unwrap();
+        assert_eq!(config.auth.cookie_same_site, SameSite::Strict);
+        assert_eq!(config.cors_origins.len(), 2);
+        assert!(config
+            .apply_env(env(&[("COOKIE_SAME_SITE", "sometimes")]))
+            .is_err());
+    }
+
+    #[test]
+    fn test_unknown_keys_are_rejected() {
+        assert!(toml::from_str::<Config>("prot = 3000").is_err());
+    }
+
+    #[test]
+    fn test_file_paths_resolve_against_config_dir() {
+        let dir = std::env::temp_dir().join(format!("hmb-config-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        fs::write(&path, "prompts_path = \"prompts/live.toml\"\n").unwrap();
+
+        let config = Config::from_file(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(config.prompts_path, dir.join("prompts/live.toml"));
+        assert_eq!(
+            config.generator.workflow_path,
+            dir.join("comfyui_workflow.json")
+        );
+        assert_eq!(config.rate_limit.sqlite_path, dir.join("rate_limits.db"));
+        assert_eq!(config.images.local_dir, dir.join("images"));
+    }
+}
@@ -0,0 +1,104 @@
+//! Bounds-checked readers for the image container formats we look inside
+//! without decoding: JPEG segments, PNG and RIFF chunks, GIF blocks and
+//! ISOBMFF (HEIC) boxes.
+
+use std::error::Error;
+
+pub type ContainerError = Box<dyn Error + Send + Sync>;
+
+pub fn truncated() -> ContainerError {
+    "Truncated image data".into()
+}
+
+pub fn slice(data: &[u8], at: usize, len: usize) -> Result<&[u8], ContainerError> {
+    let end = at.checked_add(len).ok_or_else(truncated)?;
+    data.get(at..end).ok_or_else(truncated)
+}
+
+pub fn u16_be(data: &[u8], at: usize) -> Result<u16, ContainerError> {
+    Ok(u16::from_be_bytes(slice(data, at, 2)?.try_into().unwrap()))
+}
+
+pub fn u16_le(data: &[u8], at: usize) -> Result<u16, ContainerError> {
+    Ok(u16::from_le_bytes(slice(data, at, 2)?.try_into().unwrap()))
+}
+
+pub fn u32_be(data: &[u8], at: usize) -> Result<u32, ContainerError> {
+    Ok(u32::from_be_bytes(slice(data, at, 4)?.try_into().unwrap()))
+}
+
+pub fn u32_le(data: &[u8], at: usize) -> Result<u32, ContainerError> {
+    Ok(u32::from_le_bytes(slice(data, at, 4)?.try_into().unwrap()))
+}
+
+pub fn u64_be(data: &[u8], at: usize) -> Result<u64, ContainerError> {
+    Ok(u64::from_be_bytes(slice(data, at, 8)?.try_into().unwrap()))
+}
+
+/// The size of a GIF color table from its packed flags byte.
+pub fn color_table_len(flags: u8) -> usize {
+    if flags & 0x80 == 0 {
+        0
+    } else {
+        3 << ((flags & 0x07) + 1)
+    }
+}
+
+/// Skip a chain of length-prefixed sub-blocks ending in an empty one.
+pub fn sub_blocks_end(data: &[u8], mut at: usize) -> Result<usize, ContainerError> {
+    loop {
+        let len = *data.get(at).ok_or_else(truncated)? as usize;
+        at += 1 + len;
+        if len == 0 {
+            return Ok(at);
+        }
+    }
+}
+
+/// An ISOBMFF box: where its contents start and where it ends.
+#[derive(Debug, Clone, Copy)]
+pub struct BoxRange {
+    pub content: usize,
+    pub end: usize,
+}
+
+pub fn boxes(
+    data: &[u8],
+    mut at: usize,
+    end: usize,
+) -> Result<Vec<([u8; 4], BoxRange)>, ContainerError> {
+    let mut found = Vec::new();
+    while at + 8 <= end {
+        let (header, size) = match u32_be(data, at)? {
+            0 => (8, end - at),
+            1 => (16, u64_be(data, at + 8)? as usize),
+            size => (8, size as usize),
+        };
+        let box_end = at.checked_add(size).ok_or_else(truncated)?;
+        if size < header || box_end > end {
+            return Err("Malformed ISOBMFF box".into());
+        }
+        let kind = data[at + 4..at + 8].try_into().unwrap();
+        found.push((
+            kind,
+            BoxRange {
+                content: at + header,
+                end: box_end,
+            },
+        ));
+        at = box_end;
+    }
+    Ok(found)
+}
+
+pub fn find_box(
+    data: &[u8],
+    at: usize,
+    end: usize,
+    kind: &[u8; 4],
+) -> Result<Option<BoxRange>, ContainerError> {
+    Ok(boxes(data, at, end)?
+        .into_iter()
+        .find(|(found, _)| found == kind)
+        .map(|(_, range)| range))
+}
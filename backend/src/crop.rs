@@ -0,0 +1,163 @@
+//! Frame the chosen face before generation.
+//!
+//! Full-body and group photos spend most of their pixels on things that
+//! aren't hair, and providers downscale whatever they get. Cropping to a
+//! padded head-and-shoulders region around the face keeps the detail where
+//! the haircut is. The crop is recorded as a [`CropTransform`] so a client
+//! can put the result back into the original photo.
+
+use crate::config::CropConfig;
+use crate::faces::FaceBox;
+use serde::Serialize;
+use std::error::Error;
+
+pub type CropError = Box<dyn Error + Send + Sync>;
+
+/// Crops that would keep more of the photo than this aren't worth the
+/// re-encode.
+const MAX_KEPT_AREA: f64 = 0.9;
+
+/// The part of the upright upload the provider was given. To composite a
+/// result back, scale it to `width` x `height` and draw it at (`x`, `y`) over
+/// the original, which is `sourceWidth` x `sourceHeight`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct CropTransform {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    #[serde(rename = "sourceWidth")]
+    pub source_width: u32,
+    #[serde(rename = "sourceHeight")]
+    pub source_height: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct Cropper {
+    enabled: bool,
+    width: f64,
+    above: f64,
+    below: f64,
+}
+
+impl Cropper {
+    pub fn from_config(config: &CropConfig) -> Result<Self, CropError> {
+        if config.width <= 0.0 || config.above < 0.0 || config.below < 0.0 {
+            return Err("crop width must be positive and above/below not negative".into());
+        }
+        Ok(Cropper {
+            enabled: config.enabled,
+            width: config.width,
+            above: config.above,
+            below: config.below,
+        })
+    }
+
+    /// The head-and-shoulders region around `face` in a photo of the given
+    /// size, or `None` if cropping is off or would hardly change anything.
+    pub fn region(
+        &self,
+        face: &FaceBox,
+        source_width: u32,
+        source_height: u32,
+    ) -> Option<CropTransform> {
+        if !self.enabled {
+            return None;
+        }
+        let (face_width, face_height) = (face.width as f64, face.height as f64);
+        let centre = face.x as f64 + face_width / 2.0;
+        let half_width = face_width * self.width / 2.0;
+        let clamp = |value: f64, max: u32| value.round().clamp(0.0, max as f64) as u32;
+
+        let left = clamp(centre - half_width, source_width);
+        let right = clamp(centre + half_width, source_width);
+        let top = clamp(face.y as f64 - face_height * self.above, source_height);
+        let bottom = clamp(
+            face.y as f64 + face_height * (1.0 + self.below),
+            source_height,
+        );
+        if left >= right || top >= bottom {
+            return None;
+        }
+
+        let kept = ((right - left) as f64 * (bottom - top) as f64)
+            / (source_width as f64 * source_height as f64);
+        (kept < MAX_KEPT_AREA).then_some(CropTransform {
+            x: left,
+            y: top,
+            width: right - left,
+            height: bottom - top,
+            source_width,
+            source_height,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cropper() -> Cropper {
+        Cropper::from_config(&CropConfig::default()).unwrap()
+    }
+
+    fn face(x: u32, y: u32, size: u32) -> FaceBox {
+        FaceBox {
+            x,
+            y,
+            width: size,
+            height: size,
+        }
+    }
+
+    #[test]
+    fn test_pads_around_the_face() {
+        let crop = cropper().region(&face(900, 300, 100), 2000, 1500).unwrap();
+        assert_eq!(
+            crop,
+            CropTransform {
+                x: 800,
+                y: 230,
+                width: 300,
+                height: 300,
+                source_width: 2000,
+                source_height: 1500,
+            }
+        );
+    }
+
+    #[test]
+    fn test_stays_inside_the_photo() {
+        let crop = cropper().region(&face(20, 10, 100), 2000, 1500).unwrap();
+        assert_eq!((crop.x, crop.y), (0, 0));
+        assert_eq!((crop.width, crop.height), (220, 240));
+
+        let crop = cropper()
+            .region(&face(1900, 1400, 100), 2000, 1500)
+            .unwrap();
+        assert_eq!(crop.x + crop.width, 2000);
+        assert_eq!(crop.y + crop.height, 1500);
+    }
+
+    #[test]
+    fn test_skips_crops_that_keep_most_of_the_photo() {
+        // A selfie where the face already fills the frame
+        assert_eq!(cropper().region(&face(300, 250, 400), 1000, 1000), None);
+    }
+
+    #[test]
+    fn test_disabled_and_invalid_config() {
+        let disabled = Cropper::from_config(&CropConfig {
+            enabled: false,
+            ..CropConfig::default()
+        })
+        .unwrap();
+        assert_eq!(disabled.region(&face(900, 300, 100), 2000, 1500), None);
+
+        assert!(Cropper::from_config(&CropConfig {
+            width: 0.0,
+            ..CropConfig::default()
+        })
+        .is_err());
+    }
+}
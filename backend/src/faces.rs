@@ -0,0 +1,495 @@
+//! Find the person in an upload before generating, so photos with nobody in
+//! them are turned away and group photos ask which person to restyle.
+//!
+//! Detectors implement [`FaceDetector`]. The built-in [`SkinToneDetector`]
+//! needs no model file: it looks for skin-toned regions shaped like a head
+//! with darker features (eyes, brows, mouth) inside them. It is a heuristic
+//! that errs towards missing faces (warm light, beards and glasses all trip
+//! it up), so face checks are off unless a deployment opts in and names it
+//! as the detector; a model-backed detector can slot in behind the same trait
+//! once one is bundled. When checks are on, every upload is checked: a box drawn by the client must cover a
+//! detected face, and photos that can't be decoded are turned away.
+
+use crate::config::FacesConfig;
+use crate::skin;
+use image::imageops::FilterType;
+use image::{DynamicImage, GrayImage};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+
+pub type DetectError = Box<dyn Error + Send + Sync>;
+
+/// Detection runs on a copy whose longest edge is this size.
+const DETECTION_EDGE: u32 = 192;
+
+/// A face, in pixels from the top left.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FaceBox {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl FaceBox {
+    /// The same box in an image `factor` times the size.
+    pub fn scaled(&self, factor: f64) -> FaceBox {
+        let scale = |value: u32| (value as f64 * factor).round() as u32;
+        FaceBox {
+            x: scale(self.x),
+            y: scale(self.y),
+            width: scale(self.width).max(1),
+            height: scale(self.height).max(1),
+        }
+    }
+
+    /// Pixels shared with `other`.
+    pub fn overlap(&self, other: &FaceBox) -> u64 {
+        let left = self.x.max(other.x);
+        let top = self.y.max(other.y);
+        let right = (self.x + self.width).min(other.x + other.width);
+        let bottom = (self.y + self.height).min(other.y + other.height);
+        if left >= right || top >= bottom {
+            return 0;
+        }
+        (right - left) as u64 * (bottom - top) as u64
+    }
+
+    fn area(&self) -> u64 {
+        self.width as u64 * self.height as u64
+    }
+
+    /// The part of the box inside a `width` x `height` image, if any.
+    pub fn clamped(&self, width: u32, height: u32) -> Option<FaceBox> {
+        let right = self.x.saturating_add(self.width).min(width);
+        let bottom = self.y.saturating_add(self.height).min(height);
+        (self.x < right && self.y < bottom).then(|| FaceBox {
+            x: self.x,
+            y: self.y,
+            width: right - self.x,
+            height: bottom - self.y,
+        })
+    }
+}
+
+/// Which face the client wants restyled.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FaceSelection {
+    /// The only face in the photo
+    Auto,
+    /// One of the faces from a previous `multiple_faces` response
+    Index(usize),
+    /// A box drawn by the client around one of the faces
+    Box(FaceBox),
+}
+
+/// A photo the generation can't go ahead with until the client acts.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FaceError {
+    NoFace,
+    /// The faces found, left to right, for the client to choose from
+    MultipleFaces(Vec<FaceBox>),
+    IndexOutOfRange {
+        index: usize,
+        count: usize,
+    },
+    InvalidBox,
+    /// The client's box doesn't cover any face that was found
+    BoxWithoutFace,
+    /// The photo's format can't be decoded, so it can't be checked
+    UncheckableFormat,
+    /// A `faceIndex` was sent, but there are no detected faces to index
+    DetectionOff,
+}
+
+impl FaceError {
+    /// Machine-readable reason returned alongside the message.
+    pub fn code(&self) -> &'static str {
+        match self {
+            FaceError::NoFace => "no_face_detected",
+            FaceError::MultipleFaces(_) => "multiple_faces",
+            FaceError::IndexOutOfRange { .. } => "face_index_out_of_range",
+            FaceError::InvalidBox => "invalid_face_box",
+            FaceError::BoxWithoutFace => "face_box_without_face",
+            FaceError::UncheckableFormat => "face_check_unsupported_format",
+            FaceError::DetectionOff => "face_detection_off",
+        }
+    }
+}
+
+impl fmt::Display for FaceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FaceError::NoFace => write!(
+                f,
+                "No face found. Use a photo where your face is clearly visible."
+            ),
+            FaceError::MultipleFaces(faces) => write!(
+                f,
+                "Found {} faces. Choose which person to restyle with faceIndex.",
+                faces.len()
+            ),
+            FaceError::IndexOutOfRange { index, count } => write!(
+                f,
+                "faceIndex {} is out of range; the photo has {} faces",
+                index, count
+            ),
+            FaceError::InvalidBox => write!(f, "faceBox is outside the photo"),
+            FaceError::BoxWithoutFace => {
+                write!(f, "faceBox doesn't cover a face found in the photo")
+            }
+            FaceError::UncheckableFormat => write!(
+                f,
+                "This photo can't be checked for a face. Send a JPEG, PNG, WebP or GIF instead."
+            ),
+            FaceError::DetectionOff => write!(
+                f,
+                "faceIndex needs face detection, which is off. Send faceBox instead."
+            ),
+        }
+    }
+}
+
+impl Error for FaceError {}
+
+/// Finds faces in decoded photos.
+pub trait FaceDetector: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Faces in `pixels`, in its coordinates. CPU-bound; called from a
+    /// blocking task.
+    fn detect(&self, pixels: &DynamicImage) -> Result<Vec<FaceBox>, DetectError>;
+}
+
+/// Build the detector selected by `config.detector`, or `None` when face
+/// checks are turned off.
+pub fn build_detector(config: &FacesConfig) -> Result<Option<Arc<dyn FaceDetector>>, DetectError> {
+    if !config.enabled {
+        return Ok(None);
+    }
+    match config.detector.as_str() {
+        "" => Err("Face detection needs a detector (FACE_DETECTOR)".into()),
+        "skin" => Ok(Some(Arc::new(SkinToneDetector::from_config(config)?))),
+        other => Err(format!("Unknown face detector: {}", other).into()),
+    }
+}
+
+/// Pick the face to restyle from `faces`, which should be sorted left to
+/// right. A client box is kept as drawn, as long as it covers at least half
+/// of one of them.
+pub fn select(faces: &[FaceBox], selection: FaceSelection) -> Result<FaceBox, FaceError> {
+    match (selection, faces) {
+        (_, []) => Err(FaceError::NoFace),
+        (FaceSelection::Box(chosen), faces) => faces
+            .iter()
+            .any(|face| chosen.overlap(face) * 2 >= face.area())
+            .then_some(chosen)
+            .ok_or(FaceError::BoxWithoutFace),
+        (FaceSelection::Index(index), faces) => {
+            faces.get(index).copied().ok_or(FaceError::IndexOutOfRange {
+                index,
+                count: faces.len(),
+            })
+        }
+        (_, [face]) => Ok(*face),
+        (_, faces) => Err(FaceError::MultipleFaces(faces.to_vec())),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SkinToneDetector {
+    /// Smallest face width, as a share of the photo's shorter side
+    min_size: f64,
+}
+
+impl SkinToneDetector {
+    pub fn from_config(config: &FacesConfig) -> Result<Self, DetectError> {
+        if !(0.0..1.0).contains(&config.min_face_size) {
+            return Err(format!(
+                "min_face_size must be between 0 and 1, got {}",
+                config.min_face_size
+            )
+            .into());
+        }
+        Ok(SkinToneDetector {
+            min_size: config.min_face_size,
+        })
+    }
+
+    /// Whether the skin inside `face` looks like a face: mostly skin, with
+    /// darker features across the middle. Dark brown eyes and brows pass as
+    /// skin by colour alone, so features are anything not skin or clearly
+    /// darker than the skin around it. Hands, arms and skin-coloured walls
+    /// have no features and fail.
+    fn looks_like_face(mask: &[bool], luma: &GrayImage, face: &FaceBox) -> bool {
+        let stride = luma.width() as usize;
+        let index = |x: u32, y: u32| y as usize * stride + x as usize;
+        let (x, y, w, h) = (face.x, face.y, face.width, face.height);
+
+        let (mut skin, mut skin_luma) = (0usize, 0u64);
+        for py in y..y + h {
+            for px in x..x + w {
+                if mask[index(px, py)] {
+                    skin += 1;
+                    skin_luma += luma.as_raw()[index(px, py)] as u64;
+                }
+            }
+        }
+        let fill = skin as f64 / (w * h).max(1) as f64;
+        let dark = skin_luma as f64 / skin.max(1) as f64 * 0.6;
+
+        // Eyes to mouth, inset from the cheeks
+        let (x0, y0, x1, y1) = (
+            x + w * 15 / 100,
+            y + h / 5,
+            x + w * 85 / 100,
+            y + h * 7 / 10,
+        );
+        let mut features = 0;
+        for py in y0..y1 {
+            for px in x0..x1 {
+                let i = index(px, py);
+                features += (!mask[i] || (luma.as_raw()[i] as f64) < dark) as usize;
+            }
+        }
+        let features = features as f64 / ((x1 - x0) * (y1 - y0)).max(1) as f64;
+        fill >= 0.45 && (0.02..=0.35).contains(&features)
+    }
+}
+
+impl FaceDetector for SkinToneDetector {
+    fn name(&self) -> &'static str {
+        "skin"
+    }
+
+    fn detect(&self, pixels: &DynamicImage) -> Result<Vec<FaceBox>, DetectError> {
+        let small = if pixels.width().max(pixels.height()) > DETECTION_EDGE {
+            pixels.resize(DETECTION_EDGE, DETECTION_EDGE, FilterType::Triangle)
+        } else {
+            pixels.clone()
+        };
+        let rgb = small.to_rgb8();
+        let luma = small.to_luma8();
+        let (mask, regions) = skin::regions(&rgb);
+        let min_width = self.min_size * rgb.width().min(rgb.height()) as f64;
+
+        let faces = regions
+            .iter()
+            .filter(|region| region.width() >= 4 && region.width() as f64 >= min_width)
+            .filter_map(|region| {
+                let width = region.width();
+                // Skin usually runs on down the neck; a head is at most
+                // about 1.4 times as tall as it is wide
+                let height = region.height().min(width * 7 / 5);
+                if (height as f64) < width as f64 * 0.8 {
+                    return None;
+                }
+                let face = FaceBox {
+                    x: region.min_x as u32,
+                    y: region.min_y as u32,
+                    width: width as u32,
+                    height: height as u32,
+                };
+                Self::looks_like_face(&mask, &luma, &face).then_some(face)
+            })
+            .map(|face| face.scaled(pixels.width() as f64 / rgb.width() as f64))
+            .collect();
+        Ok(faces)
+    }
+}
+
+/// Returns the same faces for every photo.
+#[cfg(test)]
+pub struct FixedDetector(pub Vec<FaceBox>);
+
+#[cfg(test)]
+impl FaceDetector for FixedDetector {
+    fn name(&self) -> &'static str {
+        "fixed"
+    }
+
+    fn detect(&self, _pixels: &DynamicImage) -> Result<Vec<FaceBox>, DetectError> {
+        Ok(self.0.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+
+    const SKIN: Rgb<u8> = Rgb([224, 172, 140]);
+    const BACKGROUND: Rgb<u8> = Rgb([60, 80, 100]);
+    const FEATURE: Rgb<u8> = Rgb([50, 35, 30]);
+
+    fn detector() -> SkinToneDetector {
+        SkinToneDetector::from_config(&FacesConfig::default()).unwrap()
+    }
+
+    /// An oval face centred at (`cx`, `cy`), `size` wide, with eyes and a
+    /// mouth.
+    fn draw_face(image: &mut RgbImage, cx: u32, cy: u32, size: u32) {
+        let (rx, ry) = (size as f64 / 2.0, size as f64 * 0.65);
+        for (x, y, pixel) in image.enumerate_pixels_mut() {
+            let dx = (x as f64 - cx as f64) / rx;
+            let dy = (y as f64 - cy as f64) / ry;
+            if dx * dx + dy * dy > 1.0 {
+                continue;
+            }
+            let eye = |ex: f64| ((dx - ex).powi(2) + (dy + 0.25).powi(2)) < 0.012;
+            let mouth = dx.abs() < 0.3 && (dy - 0.35).abs() < 0.04;
+            *pixel = if eye(-0.35) || eye(0.35) || mouth {
+                FEATURE
+            } else {
+                SKIN
+            };
+        }
+    }
+
+    fn scene(faces: &[(u32, u32, u32)]) -> DynamicImage {
+        let mut image = RgbImage::from_pixel(640, 480, BACKGROUND);
+        for &(cx, cy, size) in faces {
+            draw_face(&mut image, cx, cy, size);
+        }
+        DynamicImage::ImageRgb8(image)
+    }
+
+    #[test]
+    fn test_finds_a_single_face() {
+        let faces = detector().detect(&scene(&[(320, 240, 200)])).unwrap();
+        assert_eq!(faces.len(), 1);
+        let face = faces[0];
+        // Roughly where it was drawn, in the original coordinates
+        assert!((210..=230).contains(&face.x), "{:?}", face);
+        assert!((100..=120).contains(&face.y), "{:?}", face);
+        assert!((190..=210).contains(&face.width), "{:?}", face);
+    }
+
+    #[test]
+    fn test_finds_several_faces() {
+        let faces = detector()
+            .detect(&scene(&[(160, 240, 160), (480, 240, 160)]))
+            .unwrap();
+        assert_eq!(faces.len(), 2);
+    }
+
+    #[test]
+    fn test_ignores_featureless_skin_and_empty_photos() {
+        assert_eq!(detector().detect(&scene(&[])).unwrap(), vec![]);
+
+        // A hand or a skin-coloured wall: the right colour, but no features
+        let mut image = RgbImage::from_pixel(640, 480, BACKGROUND);
+        for (x, y, pixel) in image.enumerate_pixels_mut() {
+            if (220..420).contains(&x) && (100..380).contains(&y) {
+                *pixel = SKIN;
+            }
+        }
+        let faces = detector().detect(&DynamicImage::ImageRgb8(image)).unwrap();
+        assert_eq!(faces, vec![]);
+    }
+
+    #[test]
+    fn test_ignores_faces_below_min_size() {
+        let faces = detector().detect(&scene(&[(320, 240, 30)])).unwrap();
+        assert_eq!(faces, vec![]);
+    }
+
+    #[test]
+    fn test_select() {
+        let left = FaceBox {
+            x: 10,
+            y: 10,
+            width: 50,
+            height: 60,
+        };
+        let right = FaceBox { x: 200, ..left };
+
+        assert_eq!(select(&[], FaceSelection::Auto), Err(FaceError::NoFace));
+        assert_eq!(select(&[left], FaceSelection::Auto), Ok(left));
+        assert_eq!(
+            select(&[left, right], FaceSelection::Auto),
+            Err(FaceError::MultipleFaces(vec![left, right]))
+        );
+        assert_eq!(select(&[left, right], FaceSelection::Index(1)), Ok(right));
+        assert_eq!(
+            select(&[left, right], FaceSelection::Index(2)),
+            Err(FaceError::IndexOutOfRange { index: 2, count: 2 })
+        );
+        assert_eq!(
+            FaceError::MultipleFaces(vec![left, right]).code(),
+            "multiple_faces"
+        );
+
+        // A client box has to cover one of the faces
+        let around_right = FaceBox {
+            x: 190,
+            y: 0,
+            width: 80,
+            height: 80,
+        };
+        assert_eq!(
+            select(&[left, right], FaceSelection::Box(around_right)),
+            Ok(around_right)
+        );
+        let background = FaceBox {
+            x: 400,
+            ..around_right
+        };
+        assert_eq!(
+            select(&[left, right], FaceSelection::Box(background)),
+            Err(FaceError::BoxWithoutFace)
+        );
+        assert_eq!(
+            select(&[], FaceSelection::Box(around_right)),
+            Err(FaceError::NoFace)
+        );
+    }
+
+    #[test]
+    fn test_box_scaling_and_clamping() {
+        let face = FaceBox {
+            x: 100,
+            y: 50,
+            width: 200,
+            height: 300,
+        };
+        assert_eq!(
+            face.scaled(0.5),
+            FaceBox {
+                x: 50,
+                y: 25,
+                width: 100,
+                height: 150,
+            }
+        );
+        assert_eq!(
+            face.clamped(250, 1000),
+            Some(FaceBox { width: 150, ..face })
+        );
+        assert_eq!(face.clamped(100, 1000), None);
+    }
+
+    #[test]
+    fn test_build_detector() {
+        // Opt-in
+        assert!(build_detector(&FacesConfig::default()).unwrap().is_none());
+        let enabled = FacesConfig {
+            enabled: true,
+            ..FacesConfig::default()
+        };
+        // The heuristic has to be asked for by name
+        assert!(build_detector(&enabled).is_err());
+        let skin = FacesConfig {
+            detector: "skin".to_string(),
+            ..enabled.clone()
+        };
+        assert_eq!(build_detector(&skin).unwrap().unwrap().name(), "skin");
+        let unknown = FacesConfig {
+            detector: "magic".to_string(),
+            ..enabled
+        };
+        assert!(build_detector(&unknown).is_err());
+    }
+}
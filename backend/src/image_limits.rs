@@ -0,0 +1,464 @@
+//! Refuse decompression bombs before anything decodes or forwards them.
+//!
+//! `max_image_bytes` only bounds the upload, and a few kilobytes of PNG can
+//! declare a 50000x50000 canvas. Width, height and frame count are read
+//! straight from the container headers, so an oversized image is turned away
+//! without allocating a single pixel. The decoder also gets the memory cap,
+//! in case the headers lie.
+
+use crate::config::LimitsConfig;
+use crate::container::{
+    boxes, color_table_len, find_box, slice, sub_blocks_end, truncated, u16_be, u16_le, u32_be,
+    u32_le, ContainerError,
+};
+use crate::services::generator::InputImage;
+use crate::services::image_format::ImageFormat;
+use std::fmt;
+
+/// What an image's headers say about it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ImageInfo {
+    pub width: u32,
+    pub height: u32,
+    pub frames: u32,
+}
+
+/// An image over one of the limits. Reported to clients as a 422 with
+/// [`LimitError::code`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum LimitError {
+    Dimensions { width: u32, height: u32, max: u32 },
+    Pixels { pixels: u64, max: u64 },
+    Frames { frames: u32, max: u32 },
+    DecodeMemory { max_bytes: u64 },
+    DecodeTimeout,
+}
+
+impl LimitError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            LimitError::Dimensions { .. } => "image_dimensions_exceeded",
+            LimitError::Pixels { .. } => "image_pixels_exceeded",
+            LimitError::Frames { .. } => "image_frames_exceeded",
+            LimitError::DecodeMemory { .. } => "image_memory_exceeded",
+            LimitError::DecodeTimeout => "image_decode_timeout",
+        }
+    }
+}
+
+impl fmt::Display for LimitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitError::Dimensions { width, height, max } => write!(
+                f,
+                "Image is {}x{} pixels (max {} on either side)",
+                width, height, max
+            ),
+            LimitError::Pixels { pixels, max } => write!(
+                f,
+                "Image has {:.1} megapixels (max {:.1})",
+                *pixels as f64 / 1e6,
+                *max as f64 / 1e6
+            ),
+            LimitError::Frames { frames, max } => {
+                write!(f, "Image has {} frames (max {})", frames, max)
+            }
+            LimitError::DecodeMemory { max_bytes } => write!(
+                f,
+                "Image needs more than {}MB to decode",
+                max_bytes / (1024 * 1024)
+            ),
+            LimitError::DecodeTimeout => f.write_str("Image took too long to decode"),
+        }
+    }
+}
+
+impl std::error::Error for LimitError {}
+
+/// Bytes per pixel assumed when estimating decode memory: 8-bit RGBA.
+const BYTES_PER_PIXEL: u64 = 4;
+
+#[derive(Debug, Clone, Copy)]
+pub struct ImageLimits {
+    pub max_dimension: u32,
+    pub max_pixels: u64,
+    pub max_frames: u32,
+    pub max_decode_bytes: u64,
+}
+
+impl ImageLimits {
+    pub fn from_config(config: &LimitsConfig) -> Self {
+        ImageLimits {
+            max_dimension: config.max_image_dimension,
+            max_pixels: config.max_image_pixels,
+            max_frames: config.max_image_frames,
+            max_decode_bytes: config.max_decode_bytes,
+        }
+    }
+
+    /// Read `image`'s headers and check them against the limits. Errors that
+    /// aren't a [`LimitError`] mean the headers couldn't be read.
+    pub fn check(&self, image: &InputImage) -> Result<ImageInfo, ContainerError> {
+        let info = probe(&image.data, image.format)?;
+        let pixels = info.width as u64 * info.height as u64;
+        if info.width > self.max_dimension || info.height > self.max_dimension {
+            return Err(LimitError::Dimensions {
+                width: info.width,
+                height: info.height,
+                max: self.max_dimension,
+            }
+            .into());
+        }
+        if pixels > self.max_pixels {
+            return Err(LimitError::Pixels {
+                pixels,
+                max: self.max_pixels,
+            }
+            .into());
+        }
+        if info.frames > self.max_frames {
+            return Err(LimitError::Frames {
+                frames: info.frames,
+                max: self.max_frames,
+            }
+            .into());
+        }
+        // Only the first frame is ever decoded
+        if pixels * BYTES_PER_PIXEL > self.max_decode_bytes {
+            return Err(LimitError::DecodeMemory {
+                max_bytes: self.max_decode_bytes,
+            }
+            .into());
+        }
+        Ok(info)
+    }
+
+    /// The same caps, for the decoder itself.
+    pub fn decoder_limits(&self) -> image::Limits {
+        let mut limits = image::Limits::default();
+        limits.max_image_width = Some(self.max_dimension);
+        limits.max_image_height = Some(self.max_dimension);
+        limits.max_alloc = Some(self.max_decode_bytes);
+        limits
+    }
+}
+
+/// Read dimensions and frame count from `data`'s headers without decoding.
+pub fn probe(data: &[u8], format: ImageFormat) -> Result<ImageInfo, ContainerError> {
+    let info = match format {
+        ImageFormat::Jpeg => probe_jpeg(data)?,
+        ImageFormat::Png => probe_png(data)?,
+        ImageFormat::Webp => probe_webp(data)?,
+        ImageFormat::Gif => probe_gif(data)?,
+        ImageFormat::Heic => probe_heic(data)?,
+    };
+    if info.width == 0 || info.height == 0 {
+        return Err("Image has no size".into());
+    }
+    Ok(info)
+}
+
+fn probe_jpeg(data: &[u8]) -> Result<ImageInfo, ContainerError> {
+    let mut at = 2;
+    loop {
+        if *data.get(at).ok_or_else(truncated)? != 0xFF {
+            return Err("Malformed JPEG marker".into());
+        }
+        while data.get(at + 1) == Some(&0xFF) {
+            at += 1;
+        }
+        let marker = *data.get(at + 1).ok_or_else(truncated)?;
+        match marker {
+            // Start of frame, any coding; C4, C8 and CC are other tables
+            0xC0..=0xCF if !matches!(marker, 0xC4 | 0xC8 | 0xCC) => {
+                return Ok(ImageInfo {
+                    height: u16_be(data, at + 5)? as u32,
+                    width: u16_be(data, at + 7)? as u32,
+                    frames: 1,
+                });
+            }
+            0xDA | 0xD9 => return Err("JPEG has no frame header".into()),
+            _ => at += 2 + u16_be(data, at + 2)? as usize,
+        }
+    }
+}
+
+fn probe_png(data: &[u8]) -> Result<ImageInfo, ContainerError> {
+    if slice(data, 12, 4)? != b"IHDR" {
+        return Err("PNG doesn't start with IHDR".into());
+    }
+    let mut info = ImageInfo {
+        width: u32_be(data, 16)?,
+        height: u32_be(data, 20)?,
+        frames: 1,
+    };
+    // An APNG announces its frame count before the image data
+    let mut at = 8;
+    loop {
+        let length = u32_be(data, at)? as usize;
+        match slice(data, at + 4, 4)? {
+            b"acTL" => {
+                info.frames = u32_be(data, at + 8)?;
+                return Ok(info);
+            }
+            b"IDAT" | b"IEND" => return Ok(info),
+            _ => at = at.checked_add(length + 12).ok_or_else(truncated)?,
+        }
+    }
+}
+
+fn probe_webp(data: &[u8]) -> Result<ImageInfo, ContainerError> {
+    let riff_end = (u32_le(data, 4)? as usize)
+        .saturating_add(8)
+        .min(data.len());
+    let mut info = None;
+    let mut frames = 0;
+    let mut at = 12;
+    while at + 8 <= riff_end {
+        let size = u32_le(data, at + 4)? as usize;
+        let payload = at + 8;
+        match slice(data, at, 4)? {
+            // 24-bit little-endian canvas size, minus one
+            b"VP8X" => {
+                let dims = slice(data, payload + 4, 6)?;
+                info = Some((
+                    1 + u32::from_le_bytes([dims[0], dims[1], dims[2], 0]),
+                    1 + u32::from_le_bytes([dims[3], dims[4], dims[5], 0]),
+                ));
+            }
+            b"VP8 " if info.is_none() => {
+                if slice(data, payload + 3, 3)? != b"\x9d\x01\x2a" {
+                    return Err("Malformed VP8 frame".into());
+                }
+                info = Some((
+                    (u16_le(data, payload + 6)? & 0x3FFF) as u32,
+                    (u16_le(data, payload + 8)? & 0x3FFF) as u32,
+                ));
+            }
+            // 14-bit width and height, minus one, after a signature byte
+            b"VP8L" if info.is_none() => {
+                let bits = u32_le(data, payload + 1)?;
+                info = Some((1 + (bits & 0x3FFF), 1 + ((bits >> 14) & 0x3FFF)));
+            }
+            b"ANMF" => frames += 1,
+            _ => {}
+        }
+        at = payload
+            .checked_add(size + (size & 1))
+            .ok_or_else(truncated)?;
+    }
+    let (width, height) = info.ok_or("WebP has no image")?;
+    Ok(ImageInfo {
+        width,
+        height,
+        frames: frames.max(1),
+    })
+}
+
+fn probe_gif(data: &[u8]) -> Result<ImageInfo, ContainerError> {
+    let mut info = ImageInfo {
+        width: u16_le(data, 6)? as u32,
+        height: u16_le(data, 8)? as u32,
+        frames: 0,
+    };
+    let mut at = 13 + color_table_len(*data.get(10).ok_or_else(truncated)?);
+    loop {
+        match *data.get(at).ok_or_else(truncated)? {
+            0x21 => at = sub_blocks_end(data, at + 2)?,
+            0x2C => {
+                // Frames may reach past the logical screen, and decoders
+                // allocate for them all the same
+                let right = u16_le(data, at + 1)? as u32 + u16_le(data, at + 5)? as u32;
+                let bottom = u16_le(data, at + 3)? as u32 + u16_le(data, at + 7)? as u32;
+                info.width = info.width.max(right);
+                info.height = info.height.max(bottom);
+                info.frames += 1;
+                let flags = *data.get(at + 9).ok_or_else(truncated)?;
+                at = sub_blocks_end(data, at + 10 + color_table_len(flags) + 1)?;
+            }
+            0x3B => return Ok(info),
+            _ => return Err("Malformed GIF block".into()),
+        }
+    }
+}
+
+/// The largest `ispe` (image spatial extent) property. Grid images list
+/// their tiles too, but the grid itself is the biggest. HEIC is never decoded
+/// here, so frames aren't counted.
+fn probe_heic(data: &[u8]) -> Result<ImageInfo, ContainerError> {
+    let meta = find_box(data, 0, data.len(), b"meta")?.ok_or("HEIC file has no meta box")?;
+    let iprp = find_box(data, meta.content + 4, meta.end, b"iprp")?
+        .ok_or("HEIC file has no item properties")?;
+    let ipco = find_box(data, iprp.content, iprp.end, b"ipco")?
+        .ok_or("HEIC file has no item properties")?;
+    let mut size = None;
+    for (kind, property) in boxes(data, ipco.content, ipco.end)? {
+        if &kind == b"ispe" {
+            let width = u32_be(data, property.content + 4)?;
+            let height = u32_be(data, property.content + 8)?;
+            let (max_width, max_height) = size.unwrap_or((0, 0));
+            size = Some((width.max(max_width), height.max(max_height)));
+        }
+    }
+    let (width, height) = size.ok_or("HEIC file has no image size")?;
+    Ok(ImageInfo {
+        width,
+        height,
+        frames: 1,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage, Rgba, RgbaImage};
+    use std::io::Cursor;
+
+    fn limits() -> ImageLimits {
+        ImageLimits::from_config(&LimitsConfig::default())
+    }
+
+    fn encode(width: u32, height: u32, format: image::ImageFormat) -> Vec<u8> {
+        let mut data = Vec::new();
+        image::DynamicImage::ImageRgb8(RgbImage::from_pixel(width, height, Rgb([1, 2, 3])))
+            .write_to(&mut Cursor::new(&mut data), format)
+            .unwrap();
+        data
+    }
+
+    /// A PNG whose IHDR claims `width`x`height` over a single pixel of data.
+    fn png_claiming(width: u32, height: u32) -> InputImage {
+        let mut data = encode(1, 1, image::ImageFormat::Png);
+        data[16..20].copy_from_slice(&width.to_be_bytes());
+        data[20..24].copy_from_slice(&height.to_be_bytes());
+        InputImage {
+            data,
+            format: ImageFormat::Png,
+        }
+    }
+
+    fn limit_error(result: Result<ImageInfo, ContainerError>) -> LimitError {
+        result
+            .unwrap_err()
+            .downcast::<LimitError>()
+            .map(|err| *err)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_probe_reads_headers_of_every_format() {
+        for (format, codec) in [
+            (ImageFormat::Jpeg, image::ImageFormat::Jpeg),
+            (ImageFormat::Png, image::ImageFormat::Png),
+            (ImageFormat::Webp, image::ImageFormat::WebP),
+            (ImageFormat::Gif, image::ImageFormat::Gif),
+        ] {
+            let info = probe(&encode(37, 21, codec), format).unwrap();
+            assert_eq!(
+                info,
+                ImageInfo {
+                    width: 37,
+                    height: 21,
+                    frames: 1
+                },
+                "{:?}",
+                format
+            );
+        }
+    }
+
+    #[test]
+    fn test_probe_reads_heic_spatial_extent() {
+        fn sized_box(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+            let mut data = ((body.len() + 8) as u32).to_be_bytes().to_vec();
+            data.extend_from_slice(kind);
+            data.extend_from_slice(body);
+            data
+        }
+        let ispe = |width: u32, height: u32| {
+            let body = [&[0u8; 4][..], &width.to_be_bytes(), &height.to_be_bytes()].concat();
+            sized_box(b"ispe", &body)
+        };
+        let ipco = sized_box(b"ipco", &[ispe(512, 512), ispe(4032, 3024)].concat());
+        let meta = sized_box(
+            b"meta",
+            &[&[0u8; 4][..], &sized_box(b"iprp", &ipco)].concat(),
+        );
+        let data = [b"\x00\x00\x00\x10ftypheic\x00\x00\x00\x00".to_vec(), meta].concat();
+
+        let info = probe(&data, ImageFormat::Heic).unwrap();
+        assert_eq!((info.width, info.height), (4032, 3024));
+    }
+
+    #[test]
+    fn test_counts_animation_frames() {
+        let mut frames = Vec::new();
+        for _ in 0..3 {
+            frames.push(image::Frame::new(RgbaImage::from_pixel(
+                8,
+                8,
+                Rgba([0, 0, 0, 255]),
+            )));
+        }
+        let mut gif = Vec::new();
+        image::codecs::gif::GifEncoder::new(&mut gif)
+            .encode_frames(frames)
+            .unwrap();
+        assert_eq!(probe(&gif, ImageFormat::Gif).unwrap().frames, 3);
+
+        let config = LimitsConfig {
+            max_image_frames: 2,
+            ..LimitsConfig::default()
+        };
+        let image = InputImage {
+            data: gif,
+            format: ImageFormat::Gif,
+        };
+        assert_eq!(
+            limit_error(ImageLimits::from_config(&config).check(&image)).code(),
+            "image_frames_exceeded"
+        );
+    }
+
+    #[test]
+    fn test_rejects_bomb_without_decoding() {
+        // 40000x40000 would take 6.4GB to decode
+        let err = limit_error(limits().check(&png_claiming(40_000, 40_000)));
+        assert_eq!(err.code(), "image_dimensions_exceeded");
+        assert_eq!(
+            err.to_string(),
+            "Image is 40000x40000 pixels (max 16384 on either side)"
+        );
+
+        let err = limit_error(limits().check(&png_claiming(16_000, 16_000)));
+        assert_eq!(err.code(), "image_pixels_exceeded");
+    }
+
+    #[test]
+    fn test_decode_memory_limit() {
+        let config = LimitsConfig {
+            max_decode_bytes: 1024 * 1024,
+            ..LimitsConfig::default()
+        };
+        let err = limit_error(ImageLimits::from_config(&config).check(&png_claiming(1024, 512)));
+        assert_eq!(
+            err,
+            LimitError::DecodeMemory {
+                max_bytes: 1024 * 1024
+            }
+        );
+        assert!(ImageLimits::from_config(&config)
+            .check(&png_claiming(256, 256))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_unreadable_headers_are_not_limit_errors() {
+        let image = InputImage {
+            data: b"\x89PNG\r\n\x1a\n\x00\x00".to_vec(),
+            format: ImageFormat::Png,
+        };
+        let err = limits().check(&image).unwrap_err();
+        assert!(err.downcast_ref::<LimitError>().is_none());
+    }
+}
@@ -0,0 +1,70 @@
+use super::{is_valid_id, now_secs};
+use crate::AppState;
+use axum::{
+    extract::{rejection::QueryRejection, Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+use tracing::error;
+
+#[derive(Deserialize)]
+pub struct SignedQuery {
+    expires: u64,
+    signature: String,
+}
+
+/// `GET /images/{id}?expires=&signature=`: a stored image, for as long as its
+/// signed URL is valid. Images never change, so browsers may cache them until
+/// the URL expires.
+pub async fn serve_image(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    query: Result<Query<SignedQuery>, QueryRejection>,
+    headers: HeaderMap,
+) -> Response {
+    if !is_valid_id(&id) {
+        return (StatusCode::NOT_FOUND, "Not found").into_response();
+    }
+    let Ok(Query(query)) = query else {
+        return (StatusCode::FORBIDDEN, "Missing signature").into_response();
+    };
+    if !state.images.verify(&id, query.expires, &query.signature) {
+        return (StatusCode::FORBIDDEN, "Invalid or expired link").into_response();
+    }
+
+    let etag = format!("\"{}\"", id);
+    let cache_control = format!(
+        "private, max-age={}, immutable",
+        query.expires.saturating_sub(now_secs())
+    );
+    let not_modified = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.split(',').any(|tag| tag.trim() == etag));
+    if not_modified {
+        return (
+            StatusCode::NOT_MODIFIED,
+            [(header::ETAG, etag), (header::CACHE_CONTROL, cache_control)],
+        )
+            .into_response();
+    }
+
+    match state.images.blobs().get(&id).await {
+        Ok(Some(blob)) => (
+            [
+                (header::CONTENT_TYPE, blob.content_type),
+                (header::ETAG, etag),
+                (header::CACHE_CONTROL, cache_control),
+                (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+            ],
+            blob.data,
+        )
+            .into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, "Not found").into_response(),
+        Err(err) => {
+            error!(store = state.images.blobs().name(), error = %err, "Failed to read image");
+            (StatusCode::INTERNAL_SERVER_ERROR, "Failed to read image").into_response()
+        }
+    }
+}
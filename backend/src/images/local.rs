@@ -0,0 +1,85 @@
+use super::{content_type, Blob, BlobError, BlobStore};
+use async_trait::async_trait;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// Images as files in one directory. Only suitable for a single replica, or
+/// replicas sharing a volume.
+pub struct LocalStore {
+    dir: PathBuf,
+}
+
+impl LocalStore {
+    pub fn new(dir: &Path) -> Result<Self, BlobError> {
+        std::fs::create_dir_all(dir)
+            .map_err(|err| format!("Failed to create {}: {}", dir.display(), err))?;
+        Ok(LocalStore {
+            dir: dir.to_path_buf(),
+        })
+    }
+}
+
+#[async_trait]
+impl BlobStore for LocalStore {
+    fn name(&self) -> &'static str {
+        "local"
+    }
+
+    async fn put(&self, id: &str, _content_type: &str, data: Vec<u8>) -> Result<(), BlobError> {
+        let path = self.dir.join(id);
+        if tokio::fs::try_exists(&path).await? {
+            return Ok(());
+        }
+        // Write then rename, so a concurrent reader never sees half a file
+        let partial = self
+            .dir
+            .join(format!(".{}.{}", id, Uuid::new_v4().simple()));
+        tokio::fs::write(&partial, data).await?;
+        tokio::fs::rename(&partial, &path).await?;
+        Ok(())
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<Blob>, BlobError> {
+        let Some(content_type) = content_type(id) else {
+            return Ok(None);
+        };
+        match tokio::fs::read(self.dir.join(id)).await {
+            Ok(data) => Ok(Some(Blob {
+                content_type: content_type.to_string(),
+                data,
+            })),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::images::blob_id;
+
+    #[tokio::test]
+    async fn test_put_then_get() {
+        let dir = std::env::temp_dir().join(format!("hmb-images-{}", Uuid::new_v4().simple()));
+        let store = LocalStore::new(&dir).unwrap();
+        let id = blob_id("image/webp", b"webp bytes").unwrap();
+
+        assert_eq!(store.get(&id).await.unwrap(), None);
+        store
+            .put(&id, "image/webp", b"webp bytes".to_vec())
+            .await
+            .unwrap();
+        // Rewriting an existing id is a no-op
+        store
+            .put(&id, "image/webp", b"webp bytes".to_vec())
+            .await
+            .unwrap();
+
+        let blob = store.get(&id).await.unwrap().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(blob.content_type, "image/webp");
+        assert_eq!(blob.data, b"webp bytes");
+    }
+}
@@ -0,0 +1,494 @@
+//! Storage for generated images.
+//!
+//! Providers hand back `data:` URLs. Rather than shipping megabytes of base64
+//! in every response, each image is written once to a [`BlobStore`] under the
+//! hash of its bytes and replaced with a signed, expiring `/images/{id}` URL.
+//! The same image (a cached result, say) always gets the same id, and is only
+//! written the first time this process sees it.
+//! Clients that still want inline images ask for `responseFormat: "dataUrl"`.
+
+pub mod handlers;
+pub mod local;
+pub mod s3;
+
+use crate::config::ImagesConfig;
+use crate::services::generator::ImageVariation;
+use async_trait::async_trait;
+use base64::{engine::general_purpose, Engine as _};
+use hmac::{Hmac, Mac};
+use lru::LruCache;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+use uuid::Uuid;
+
+pub type BlobError = Box<dyn Error + Send + Sync>;
+
+/// How many written ids [`ImageStore`] remembers, at about 70 bytes each.
+const REMEMBERED_IDS: usize = 4096;
+
+/// A stored image and its content type.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Blob {
+    pub content_type: String,
+    pub data: Vec<u8>,
+}
+
+/// Where image bytes live. Ids are produced by [`blob_id`] and already carry
+/// the file extension, so stores never need to look inside them.
+#[async_trait]
+pub trait BlobStore: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Store `data` under `id`. Ids are content hashes, so writing an id that
+    /// already exists is harmless.
+    async fn put(&self, id: &str, content_type: &str, data: Vec<u8>) -> Result<(), BlobError>;
+
+    async fn get(&self, id: &str) -> Result<Option<Blob>, BlobError>;
+}
+
+/// Build the store selected by `config.store`.
+pub fn build_store(config: &ImagesConfig) -> Result<Arc<dyn BlobStore>, BlobError> {
+    match config.store.as_str() {
+        "local" => Ok(Arc::new(local::LocalStore::new(&config.local_dir)?)),
+        "s3" => Ok(Arc::new(s3::S3Store::from_config(&config.s3)?)),
+        other => Err(format!("Unknown image store: {}", other).into()),
+    }
+}
+
+/// How `/api/generate` and friends return images.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ResponseFormat {
+    /// Signed `/images/{id}` URLs
+    #[default]
+    Url,
+    /// The provider's `data:` URLs, unchanged
+    DataUrl,
+}
+
+const EXTENSIONS: [(&str, &str); 4] = [
+    ("image/png", "png"),
+    ("image/jpeg", "jpg"),
+    ("image/webp", "webp"),
+    ("image/gif", "gif"),
+];
+
+/// The content type for an id's extension.
+pub fn content_type(id: &str) -> Option<&'static str> {
+    let (_, extension) = id.rsplit_once('.')?;
+    EXTENSIONS
+        .iter()
+        .find(|(_, ext)| *ext == extension)
+        .map(|(mime, _)| *mime)
+}
+
+/// The id an image is stored under: the SHA-256 of its bytes plus an
+/// extension for its type. `None` for types we don't serve.
+pub fn blob_id(content_type: &str, data: &[u8]) -> Option<String> {
+    let (_, extension) = EXTENSIONS.iter().find(|(mime, _)| *mime == content_type)?;
+    Some(format!("{}.{}", hex(&Sha256::digest(data)), extension))
+}
+
+/// Whether `id` could have come from [`blob_id`]. Checked before an id goes
+/// anywhere near a file path or bucket key.
+pub fn is_valid_id(id: &str) -> bool {
+    id.split_once('.').is_some_and(|(hash, _)| {
+        hash.len() == 64
+            && hash
+                .bytes()
+                .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+    }) && content_type(id).is_some()
+}
+
+/// Split `data:{mime};base64,{payload}` into its type and decoded bytes.
+pub(crate) fn parse_data_url(url: &str) -> Option<(&str, Vec<u8>)> {
+    let (content_type, payload) = url.strip_prefix("data:")?.split_once(";base64,")?;
+    let data = general_purpose::STANDARD.decode(payload).ok()?;
+    Some((content_type, data))
+}
+
+/// Writes generated images to the blob store and signs URLs for them.
+pub struct ImageStore {
+    blobs: Arc<dyn BlobStore>,
+    signing_key: Vec<u8>,
+    url_ttl: Duration,
+    public_base_url: String,
+    /// Ids already written by this process, so presenting a cached result
+    /// again doesn't rewrite its images
+    written: Mutex<LruCache<String, ()>>,
+}
+
+impl ImageStore {
+    pub fn new(blobs: Arc<dyn BlobStore>, config: &ImagesConfig) -> Self {
+        let signing_key = match &config.signing_key {
+            Some(key) => key.as_bytes().to_vec(),
+            None => {
+                warn!("IMAGE_SIGNING_KEY is not set; image URLs won't survive a restart or work across replicas");
+                let mut key = Uuid::new_v4().as_bytes().to_vec();
+                key.extend_from_slice(Uuid::new_v4().as_bytes());
+                key
+            }
+        };
+        ImageStore {
+            blobs,
+            signing_key,
+            url_ttl: Duration::from_secs(config.url_ttl_secs),
+            public_base_url: config
+                .public_base_url
+                .as_deref()
+                .unwrap_or("")
+                .trim_end_matches('/')
+                .to_string(),
+            written: Mutex::new(LruCache::new(
+                NonZeroUsize::new(REMEMBERED_IDS).expect("REMEMBERED_IDS is not zero"),
+            )),
+        }
+    }
+
+    pub fn blobs(&self) -> &dyn BlobStore {
+        self.blobs.as_ref()
+    }
+
+    /// Store each variation's image and swap it for a signed URL, unless the
+    /// client asked for data URLs. Anything that isn't a data URL of a known
+    /// image type is passed through untouched.
+    pub async fn present(
+        &self,
+        variations: Vec<ImageVariation>,
+        format: ResponseFormat,
+    ) -> Result<Vec<ImageVariation>, BlobError> {
+        if format == ResponseFormat::DataUrl {
+            return Ok(variations);
+        }
+        let mut presented = Vec::with_capacity(variations.len());
+        for variation in variations {
+            presented.push(self.present_one(variation).await?);
+        }
+        Ok(presented)
+    }
+
+    async fn present_one(&self, variation: ImageVariation) -> Result<ImageVariation, BlobError> {
+        let Some((content_type, data)) = parse_data_url(&variation.image) else {
+            return Ok(variation);
+        };
+        let Some(id) = blob_id(content_type, &data) else {
+            return Ok(variation);
+        };
+        // Checked and recorded around the write rather than held across it;
+        // two requests racing on the same new image both write, harmlessly
+        let written = self.written.lock().unwrap().get(&id).is_some();
+        if !written {
+            self.blobs.put(&id, content_type, data).await?;
+            self.written.lock().unwrap().put(id.clone(), ());
+        }
+        Ok(ImageVariation {
+            image: self.signed_url(&id),
+            angle: variation.angle,
+        })
+    }
+
+    /// A URL for `id` that works for `url_ttl`.
+    pub fn signed_url(&self, id: &str) -> String {
+        let expires = now_secs() + self.url_ttl.as_secs();
+        format!(
+            "{}/images/{}?expires={}&signature={}",
+            self.public_base_url,
+            id,
+            expires,
+            self.signature(id, expires)
+        )
+    }
+
+    /// The blob id behind a URL this store signed, while the link is still
+    /// good. Saved looks only keep ids that come through here, so a client
+    /// can't hold on to an image it was never handed, or one whose link ran out.
+    pub fn issued_id(&self, url: &str) -> Option<String> {
+        let (id, query) = url
+            .strip_prefix(self.public_base_url.as_str())?
+            .strip_prefix("/images/")?
+            .split_once('?')?;
+        let (mut expires, mut signature) = (None, None);
+        for (name, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+            match name {
+                "expires" => expires = value.parse().ok(),
+                "signature" => signature = Some(value),
+                _ => {}
+            }
+        }
+        (is_valid_id(id) && self.verify(id, expires?, signature?)).then(|| id.to_string())
+    }
+
+    /// Check a signature from a URL. Expired links fail even when the
+    /// signature matches.
+    pub fn verify(&self, id: &str, expires: u64, signature: &str) -> bool {
+        if expires < now_secs() {
+            return false;
+        }
+        let Some(signature) = unhex(signature) else {
+            return false;
+        };
+        self.mac(id, expires).verify_slice(&signature).is_ok()
+    }
+
+    fn signature(&self, id: &str, expires: u64) -> String {
+        hex(&self.mac(id, expires).finalize().into_bytes())
+    }
+
+    fn mac(&self, id: &str, expires: u64) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.signing_key)
+            .expect("HMAC accepts keys of any length");
+        mac.update(format!("{}:{}", id, expires).as_bytes());
+        mac
+    }
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unhex(value: &str) -> Option<Vec<u8>> {
+    if !value.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(value.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// A store that keeps blobs in a map, for tests.
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemoryStore {
+    blobs: std::sync::Mutex<std::collections::HashMap<String, Blob>>,
+    puts: std::sync::atomic::AtomicUsize,
+}
+
+#[cfg(test)]
+impl MemoryStore {
+    /// How many writes the store has seen, repeats included.
+    pub fn puts(&self) -> usize {
+        self.puts.load(std::sync::atomic::Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl BlobStore for MemoryStore {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    async fn put(&self, id: &str, content_type: &str, data: Vec<u8>) -> Result<(), BlobError> {
+        self.puts.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        self.blobs.lock().unwrap().insert(
+            id.to_string(),
+            Blob {
+                content_type: content_type.to_string(),
+                data,
+            },
+        );
+        Ok(())
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<Blob>, BlobError> {
+        Ok(self.blobs.lock().unwrap().get(id).cloned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(config: ImagesConfig) -> ImageStore {
+        ImageStore::new(Arc::new(MemoryStore::default()), &config)
+    }
+
+    fn signed() -> ImageStore {
+        store(ImagesConfig {
+            signing_key: Some("test-key".to_string()),
+            ..ImagesConfig::default()
+        })
+    }
+
+    fn variation(image: &str) -> ImageVariation {
+        ImageVariation {
+            image: image.to_string(),
+            angle: "front".to_string(),
+        }
+    }
+
+    /// The id and signature query parameters from a URL.
+    fn parts(url: &str) -> (String, u64, String) {
+        let (_, rest) = url.split_once("/images/").unwrap();
+        let (id, query) = rest.split_once("?expires=").unwrap();
+        let (expires, signature) = query.split_once("&signature=").unwrap();
+        (
+            id.to_string(),
+            expires.parse().unwrap(),
+            signature.to_string(),
+        )
+    }
+
+    #[test]
+    fn test_blob_ids() {
+        let id = blob_id("image/png", b"png bytes").unwrap();
+        assert!(id.ends_with(".png"));
+        assert!(is_valid_id(&id));
+        assert_eq!(content_type(&id), Some("image/png"));
+        assert_eq!(blob_id("image/png", b"png bytes").unwrap(), id);
+        assert_eq!(blob_id("text/html", b"<script>"), None);
+
+        assert!(!is_valid_id("../../etc/passwd"));
+        assert!(!is_valid_id(&id.replace(".png", ".html")));
+        assert!(!is_valid_id(&format!("../{}", id)));
+    }
+
+    #[tokio::test]
+    async fn test_present_stores_images_and_signs_urls() {
+        let images = signed();
+        let variations = images
+            .present(
+                vec![variation("data:image/png;base64,AAAA")],
+                ResponseFormat::Url,
+            )
+            .await
+            .unwrap();
+
+        let url = &variations[0].image;
+        assert!(url.starts_with("/images/"));
+        let (id, expires, signature) = parts(url);
+        assert!(images.verify(&id, expires, &signature));
+        assert_eq!(
+            images.blobs().get(&id).await.unwrap().unwrap(),
+            Blob {
+                content_type: "image/png".to_string(),
+                data: vec![0, 0, 0],
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_repeated_images_are_written_once() {
+        let blobs = Arc::new(MemoryStore::default());
+        let images = ImageStore::new(
+            Arc::clone(&blobs) as Arc<dyn BlobStore>,
+            &ImagesConfig::default(),
+        );
+        let mut ids = Vec::new();
+        for _ in 0..3 {
+            let presented = images
+                .present(
+                    vec![variation("data:image/png;base64,AAAA")],
+                    ResponseFormat::Url,
+                )
+                .await
+                .unwrap();
+            ids.push(parts(&presented[0].image).0);
+        }
+        assert!(ids.iter().all(|id| *id == ids[0]));
+        assert_eq!(blobs.puts(), 1);
+
+        images
+            .present(
+                vec![variation("data:image/png;base64,BBBB")],
+                ResponseFormat::Url,
+            )
+            .await
+            .unwrap();
+        assert_eq!(blobs.puts(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_data_urls_are_opt_in() {
+        let images = signed();
+        let variations = images
+            .present(
+                vec![variation("data:image/png;base64,AAAA")],
+                ResponseFormat::DataUrl,
+            )
+            .await
+            .unwrap();
+        assert_eq!(variations[0].image, "data:image/png;base64,AAAA");
+    }
+
+    #[tokio::test]
+    async fn test_unknown_images_pass_through() {
+        let images = signed();
+        let variations = images
+            .present(
+                vec![
+                    variation("https://cdn.example.com/a.png"),
+                    variation("data:text/html;base64,AAAA"),
+                ],
+                ResponseFormat::Url,
+            )
+            .await
+            .unwrap();
+        assert_eq!(variations[0].image, "https://cdn.example.com/a.png");
+        assert_eq!(variations[1].image, "data:text/html;base64,AAAA");
+    }
+
+    #[test]
+    fn test_signatures() {
+        let images = signed();
+        let id = blob_id("image/png", b"png bytes").unwrap();
+        let (_, expires, signature) = parts(&images.signed_url(&id));
+
+        assert!(images.verify(&id, expires, &signature));
+        assert!(!images.verify(&id, expires + 1, &signature));
+        assert!(!images.verify(
+            &blob_id("image/png", b"other").unwrap(),
+            expires,
+            &signature
+        ));
+        assert!(!images.verify(&id, expires, "zz"));
+        assert!(!images.verify(&id, now_secs() - 1, &images.signature(&id, now_secs() - 1)));
+
+        // A different key can't forge it
+        let other = store(ImagesConfig {
+            signing_key: Some("other-key".to_string()),
+            ..ImagesConfig::default()
+        });
+        assert!(!other.verify(&id, expires, &signature));
+    }
+
+    #[test]
+    fn test_issued_ids() {
+        let images = store(ImagesConfig {
+            signing_key: Some("test-key".to_string()),
+            public_base_url: Some("https://api.example.com/".to_string()),
+            ..ImagesConfig::default()
+        });
+        let id = blob_id("image/jpeg", b"jpeg bytes").unwrap();
+        let url = images.signed_url(&id);
+        assert!(url.starts_with(&format!("https://api.example.com/images/{}?", id)));
+        assert_eq!(images.issued_id(&url), Some(id.clone()));
+
+        for refused in [
+            // Expired, forged, unsigned, or not ours
+            format!(
+                "https://api.example.com/images/{}?expires=1&signature=00",
+                id
+            ),
+            url.replace(&id[..8], "00000000"),
+            format!("https://api.example.com/images/{}", id),
+            url.replace("api.example.com", "evil.example"),
+            "data:image/png;base64,AAAA".to_string(),
+        ] {
+            assert_eq!(images.issued_id(&refused), None, "{}", refused);
+        }
+    }
+}
@@ -0,0 +1,318 @@
+use crate::config::JobsConfig;
+use crate::GenerateResponse;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use tokio::task::AbortHandle;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+impl JobStatus {
+    fn is_finished(self) -> bool {
+        matches!(
+            self,
+            JobStatus::Succeeded | JobStatus::Failed | JobStatus::Cancelled
+        )
+    }
+}
+
+/// What `GET /api/jobs/{id}` returns: the job's state alongside every field
+/// `/api/generate` would have answered with.
+#[derive(Debug, Clone, Serialize)]
+pub struct JobSnapshot {
+    #[serde(rename = "jobId")]
+    pub id: String,
+    pub status: JobStatus,
+    /// Rough completion percentage, 0-100
+    pub progress: u8,
+    /// Empty until the job finishes
+    #[serde(flatten)]
+    pub result: GenerateResponse,
+}
+
+struct Job {
+    snapshot: JobSnapshot,
+    abort: Option<AbortHandle>,
+    finished_at: Option<Instant>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SubmitError {
+    QueueFull,
+}
+
+/// Runs generation jobs in the background on a bounded number of workers.
+///
+/// Jobs are tasks on the shared tokio runtime; a semaphore caps how many run at
+/// once and the rest wait as "queued". Finished jobs are kept for
+/// `result_ttl_secs` so clients can collect them.
+pub struct JobManager {
+    jobs: Mutex<HashMap<String, Job>>,
+    workers: Arc<Semaphore>,
+    max_queued: usize,
+    result_ttl: Duration,
+}
+
+impl JobManager {
+    pub fn new(config: &JobsConfig) -> Self {
+        JobManager {
+            jobs: Mutex::new(HashMap::new()),
+            workers: Arc::new(Semaphore::new(config.workers.max(1))),
+            max_queued: config.max_queued,
+            result_ttl: Duration::from_secs(config.result_ttl_secs),
+        }
+    }
+
+    /// Queue `work` and return the job id. `work` resolves to the response
+    /// that `/api/generate` would have sent, successful or not.
+    pub fn submit<F>(self: &Arc<Self>, work: F) -> Result<String, SubmitError>
+    where
+        F: Future<Output = GenerateResponse> + Send + 'static,
+    {
+        let id = Uuid::new_v4().to_string();
+        let mut jobs = self.jobs.lock().unwrap();
+        self.evict_expired(&mut jobs);
+
+        let pending = jobs
+            .values()
+            .filter(|job| !job.snapshot.status.is_finished())
+            .count();
+        if pending >= self.max_queued {
+            warn!(pending, "Job queue full");
+            return Err(SubmitError::QueueFull);
+        }
+
+        jobs.insert(
+            id.clone(),
+            Job {
+                snapshot: JobSnapshot {
+                    id: id.clone(),
+                    status: JobStatus::Queued,
+                    progress: 0,
+                    result: GenerateResponse::pending(),
+                },
+                abort: None,
+                finished_at: None,
+            },
+        );
+
+        let manager = Arc::clone(self);
+        let job_id = id.clone();
+        let task = tokio::spawn(async move {
+            let Ok(_permit) = Arc::clone(&manager.workers).acquire_owned().await else {
+                return;
+            };
+            if !manager.update(&job_id, |job| {
+                job.status = JobStatus::Running;
+                job.progress = 10;
+            }) {
+                return;
+            }
+            info!(job_id = %job_id, "Job started");
+
+            let response = work.await;
+            manager.finish(&job_id, response);
+        });
+        // The task can't finish before this since we still hold the lock
+        if let Some(job) = jobs.get_mut(&id) {
+            job.abort = Some(task.abort_handle());
+        }
+
+        Ok(id)
+    }
+
+    pub fn get(&self, id: &str) -> Option<JobSnapshot> {
+        let mut jobs = self.jobs.lock().unwrap();
+        self.evict_expired(&mut jobs);
+        jobs.get(id).map(|job| job.snapshot.clone())
+    }
+
+    /// Cancel a queued or running job. Returns the job's state afterwards, or
+    /// `None` for unknown ids. Finished jobs are left as they are.
+    pub fn cancel(&self, id: &str) -> Option<JobSnapshot> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let job = jobs.get_mut(id)?;
+        if !job.snapshot.status.is_finished() {
+            if let Some(abort) = job.abort.take() {
+                abort.abort();
+            }
+            job.snapshot.status = JobStatus::Cancelled;
+            job.snapshot.result = GenerateResponse::error("Job cancelled");
+            job.finished_at = Some(Instant::now());
+            info!(job_id = %id, "Job cancelled");
+        }
+        Some(job.snapshot.clone())
+    }
+
+    /// Apply `f` to a job that hasn't finished. Returns false if it has (for
+    /// example because it was cancelled), so the worker can stop.
+    fn update(&self, id: &str, f: impl FnOnce(&mut JobSnapshot)) -> bool {
+        let mut jobs = self.jobs.lock().unwrap();
+        match jobs.get_mut(id) {
+            Some(job) if !job.snapshot.status.is_finished() => {
+                f(&mut job.snapshot);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn finish(&self, id: &str, response: GenerateResponse) {
+        let mut jobs = self.jobs.lock().unwrap();
+        let Some(job) = jobs.get_mut(id) else {
+            return;
+        };
+        if job.snapshot.status.is_finished() {
+            return;
+        }
+        job.snapshot.status = if response.success {
+            JobStatus::Succeeded
+        } else {
+            JobStatus::Failed
+        };
+        job.snapshot.progress = 100;
+        job.snapshot.result = response;
+        job.abort = None;
+        job.finished_at = Some(Instant::now());
+        info!(job_id = %id, status = ?job.snapshot.status, "Job finished");
+    }
+
+    fn evict_expired(&self, jobs: &mut HashMap<String, Job>) {
+        jobs.retain(|_, job| {
+            job.finished_at
+                .is_none_or(|finished| finished.elapsed() < self.result_ttl)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::generator::ImageVariation;
+    use tokio::sync::oneshot;
+
+    fn manager(workers: usize, max_queued: usize) -> Arc<JobManager> {
+        Arc::new(JobManager::new(&JobsConfig {
+            workers,
+            max_queued,
+            result_ttl_secs: 60,
+        }))
+    }
+
+    fn success() -> GenerateResponse {
+        GenerateResponse {
+            success: true,
+            variations: vec![ImageVariation {
+                image: "data:image/png;base64,AAAA".to_string(),
+                angle: "front".to_string(),
+            }],
+            prompt_version: Some("default".to_string()),
+            ..GenerateResponse::pending()
+        }
+    }
+
+    async fn wait_for(manager: &JobManager, id: &str, status: JobStatus) -> JobSnapshot {
+        for _ in 0..200 {
+            let snapshot = manager.get(id).unwrap();
+            if snapshot.status == status {
+                return snapshot;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        panic!("job {} never reached {:?}", id, status);
+    }
+
+    #[tokio::test]
+    async fn test_job_runs_to_completion() {
+        let manager = manager(1, 10);
+        let id = manager.submit(async { success() }).unwrap();
+
+        let snapshot = wait_for(&manager, &id, JobStatus::Succeeded).await;
+        assert_eq!(snapshot.progress, 100);
+        assert_eq!(snapshot.result.variations.len(), 1);
+        assert_eq!(snapshot.result.prompt_version.as_deref(), Some("default"));
+    }
+
+    #[tokio::test]
+    async fn test_failed_generation_marks_job_failed() {
+        let manager = manager(1, 10);
+        let id = manager
+            .submit(async { GenerateResponse::error("Failed to generate images") })
+            .unwrap();
+
+        let snapshot = wait_for(&manager, &id, JobStatus::Failed).await;
+        assert_eq!(
+            snapshot.result.message.as_deref(),
+            Some("Failed to generate images")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_worker_pool_is_bounded() {
+        let manager = manager(1, 10);
+        let (release, blocked) = oneshot::channel::<()>();
+        let first = manager
+            .submit(async move {
+                blocked.await.ok();
+                success()
+            })
+            .unwrap();
+        let second = manager.submit(async { success() }).unwrap();
+
+        wait_for(&manager, &first, JobStatus::Running).await;
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(manager.get(&second).unwrap().status, JobStatus::Queued);
+
+        release.send(()).unwrap();
+        wait_for(&manager, &second, JobStatus::Succeeded).await;
+    }
+
+    #[tokio::test]
+    async fn test_cancel_stops_running_job() {
+        let manager = manager(1, 10);
+        let id = manager
+            .submit(std::future::pending::<GenerateResponse>())
+            .unwrap();
+        wait_for(&manager, &id, JobStatus::Running).await;
+
+        let snapshot = manager.cancel(&id).unwrap();
+        assert_eq!(snapshot.status, JobStatus::Cancelled);
+
+        // The freed worker picks up the next job
+        let next = manager.submit(async { success() }).unwrap();
+        wait_for(&manager, &next, JobStatus::Succeeded).await;
+        assert_eq!(manager.get(&id).unwrap().status, JobStatus::Cancelled);
+    }
+
+    #[tokio::test]
+    async fn test_queue_limit_rejects_new_jobs() {
+        let manager = manager(1, 1);
+        manager
+            .submit(std::future::pending::<GenerateResponse>())
+            .unwrap();
+        assert_eq!(
+            manager.submit(async { success() }),
+            Err(SubmitError::QueueFull)
+        );
+    }
+
+    #[test]
+    fn test_unknown_job() {
+        let manager = manager(1, 1);
+        assert!(manager.get("missing").is_none());
+        assert!(manager.cancel("missing").is_none());
+    }
+}
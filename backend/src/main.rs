@@ -1,76 +1,95 @@
 use axum::{
-    extract::{connect_info::IntoMakeServiceWithConnectInfo, ConnectInfo, DefaultBodyLimit, Json},
-    http::StatusCode,
-    routing::{get, post},
+    extract::{DefaultBodyLimit, Json, Path, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware,
+    response::IntoResponse,
+    routing::{delete, get, post},
     Router,
 };
 use base64::{engine::general_purpose, Engine as _};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::sync::Mutex;
-use std::time::{SystemTime, UNIX_EPOCH};
-use tower_http::{cors::CorsLayer, trace::TraceLayer};
+use std::time::Duration;
+use tower_http::{
+    cors::{AllowHeaders, AllowMethods, CorsLayer, ExposeHeaders},
+    trace::TraceLayer,
+};
 use tracing::{error, info, warn};
 use tracing_subscriber::EnvFilter;
 
-// Simple in-memory rate limiter
-type RateLimitStore = Mutex<HashMap<String, Vec<u64>>>;
+// Input validation functions
+fn validate_image_data(data: &str, max_bytes: usize) -> Result<InputImage, String> {
+    // Check size when decoded
+    let decoded_size = (data.len() * 3) / 4;
+    if decoded_size > max_bytes {
+        return Err(format!(
+            "Image too large (max {}MB)",
+            max_bytes / (1024 * 1024)
+        ));
+    }
 
-fn get_rate_limit_key(ip: &std::net::IpAddr) -> String {
-    format!("{}", ip)
-}
+    // Check if it's valid base64
+    let data = general_purpose::STANDARD
+        .decode(data)
+        .map_err(|_| "Invalid image data format".to_string())?;
 
-fn check_rate_limit(store: &RateLimitStore, ip: &std::net::IpAddr) -> bool {
-    let key = get_rate_limit_key(ip);
-    let mut store = store.lock().unwrap();
-    let now = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs();
+    // Trust the bytes, not the client, about what kind of file this is
+    let format = ImageFormat::sniff(&data)
+        .ok_or_else(|| "Unsupported image type (use JPEG, PNG, WebP, GIF or HEIC)".to_string())?;
 
-    // Clean up old entries (older than 60 seconds)
-    let entries = store.entry(key.clone()).or_insert_with(Vec::new);
-    entries.retain(|&timestamp| now - timestamp < 60);
+    Ok(InputImage { data, format })
+}
 
-    // Check if under limit (10 requests per minute)
-    if entries.len() >= 10 {
-        return false;
+fn validate_prompt(prompt: &str, max_chars: usize) -> Result<(), String> {
+    // Check length
+    if prompt.trim().is_empty() {
+        return Err("Prompt cannot be empty".to_string());
     }
 
-    // Add current request
-    entries.push(now);
-    true
-}
-
-// Input validation functions
-fn validate_image_data(data: &str) -> Result<(), String> {
-    // Check if it's valid base64
-    if let Err(_) = general_purpose::STANDARD.decode(data) {
-        return Err("Invalid image data format".to_string());
+    if prompt.len() > max_chars {
+        return Err(format!("Prompt too long (max {} characters)", max_chars));
     }
 
-    // Check size (max 10MB when decoded)
-    let decoded_size = (data.len() * 3) / 4;
-    if decoded_size > 10 * 1024 * 1024 {
-        return Err("Image too large (max 10MB)".to_string());
+    if contains_blocked_content(prompt) {
+        return Err("Prompt contains invalid content".to_string());
     }
 
     Ok(())
 }
 
-fn validate_prompt(prompt: &str) -> Result<(), String> {
-    // Check length
-    if prompt.trim().is_empty() {
-        return Err("Prompt cannot be empty".to_string());
-    }
+/// Validate the optional style fields that feed the prompt template.
+fn validate_style_details(request: &GenerateRequest, max_chars: usize) -> Result<(), String> {
+    let fields = [
+        ("hairTexture", &request.hair_texture),
+        ("desiredLength", &request.desired_length),
+        ("hairColor", &request.hair_color),
+        ("styleNotes", &request.style_notes),
+    ];
+    let values = fields
+        .into_iter()
+        .filter_map(|(name, value)| value.as_deref().map(|v| (name, v)))
+        .chain(request.avoid.iter().map(|v| ("avoid", v.as_str())));
 
-    if prompt.len() > 500 {
-        return Err("Prompt too long (max 500 characters)".to_string());
+    if request.avoid.len() > MAX_AVOID_ITEMS {
+        return Err(format!("Too many avoid items (max {})", MAX_AVOID_ITEMS));
+    }
+    for (name, value) in values {
+        if value.len() > max_chars {
+            return Err(format!("{} too long (max {} characters)", name, max_chars));
+        }
+        if contains_blocked_content(value) {
+            return Err(format!("{} contains invalid content", name));
+        }
     }
 
-    // Basic content filtering - reject obviously malicious prompts
-    let lower_prompt = prompt.to_lowercase();
+    Ok(())
+}
+
+const MAX_AVOID_ITEMS: usize = 10;
+
+// Basic content filtering - reject obviously malicious text
+fn contains_blocked_content(text: &str) -> bool {
+    let lower_text = text.to_lowercase();
     let blocked_words = [
         "script",
         "javascript",
@@ -82,34 +101,232 @@ fn validate_prompt(prompt: &str) -> Result<(), String> {
         "www",
     ];
 
-    for word in blocked_words {
-        if lower_prompt.contains(word) {
-            return Err("Prompt contains invalid content".to_string());
-        }
-    }
-
-    Ok(())
+    blocked_words.iter().any(|word| lower_text.contains(word))
 }
 
+mod accounts;
+mod api_keys;
+mod cache;
+mod client_ip;
+mod config;
+mod container;
+mod crop;
+mod faces;
+mod image_limits;
+mod images;
+mod jobs;
+mod metadata;
+mod moderation;
+mod normalize;
+mod quality;
+mod rate_limit;
 mod services;
-use services::gemini::ImageVariation;
-use services::prompts::Prompts;
+mod skin;
+mod stream;
+mod upload;
+use accounts::AccountStore;
+use api_keys::ApiKeyStore;
+use cache::ResultCache;
+use clap::Parser;
+use client_ip::ClientIp;
+use config::{Cli, Config};
+use crop::CropTransform;
+use faces::{FaceBox, FaceError, FaceSelection};
+use image_limits::LimitError;
+use images::{ImageStore, ResponseFormat};
+use jobs::{JobManager, JobSnapshot, SubmitError};
+use moderation::{AuditLog, Content, Moderation};
+use quality::{QualityError, QualityIssue};
+use rate_limit::RateLimitBackend;
+use services::generator::{ImageGenerator, ImageVariation, InputImage};
+use services::image_format::ImageFormat;
+use services::prompts::PromptStore;
+use services::template::PromptVars;
 use std::sync::Arc;
+use upload::{Upload, UploadPipeline};
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 struct GenerateResponse {
     success: bool,
     variations: Vec<ImageVariation>,
     message: Option<String>,
+    #[serde(rename = "promptVersion", skip_serializing_if = "Option::is_none")]
+    prompt_version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    experiment: Option<String>,
+    /// Served from the result cache rather than a fresh generation
+    cached: bool,
+    /// Machine-readable reason for some rejections, e.g. `image_pixels_exceeded`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<String>,
+    /// Advice about the photo that didn't stop the generation
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    warnings: Vec<QualityIssue>,
+    /// Every face found when the photo has several, left to right; resend
+    /// with `faceIndex` to pick one
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    faces: Vec<FaceBox>,
+    /// Where the photo was cropped before generation, for compositing the
+    /// results back into it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    crop: Option<CropTransform>,
+}
+
+impl GenerateResponse {
+    /// No outcome yet, as for a job that hasn't finished.
+    fn pending() -> Self {
+        GenerateResponse {
+            success: false,
+            variations: vec![],
+            message: None,
+            prompt_version: None,
+            experiment: None,
+            cached: false,
+            code: None,
+            warnings: vec![],
+            faces: vec![],
+            crop: None,
+        }
+    }
+
+    fn error(message: impl Into<String>) -> Self {
+        GenerateResponse {
+            message: Some(message.into()),
+            ..GenerateResponse::pending()
+        }
+    }
+
+    fn rejected(code: &str, message: impl Into<String>) -> Self {
+        GenerateResponse {
+            code: Some(code.to_string()),
+            ..GenerateResponse::error(message)
+        }
+    }
 }
 
-#[derive(Debug, Deserialize)]
+/// All routes. `/api` routes go through API key checks, and of those only
+/// the ones that start a generation are rate limited; polling, health checks
+/// and admin calls are free. Account routes use session cookies instead of
+/// API keys, and logging in or registering has a rate limit of its own.
+fn app(state: AppState) -> Router {
+    let credentials = Router::new()
+        .route("/api/auth/register", post(accounts::handlers::register))
+        .route("/api/auth/login", post(accounts::handlers::login))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            rate_limit::middleware::limit_auth,
+        ));
+    Router::new()
+        .route("/api/generate", post(generate))
+        .route("/api/generate/stream", post(stream::generate_stream))
+        .route("/api/jobs", post(submit_job))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            rate_limit::middleware::limit_generation,
+        ))
+        .route("/api/jobs/{id}", get(get_job))
+        .route("/api/jobs/{id}/cancel", post(cancel_job))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            api_keys::middleware::authenticate,
+        ))
+        .merge(credentials)
+        .route("/api/auth/logout", post(accounts::handlers::logout))
+        .route("/api/auth/me", get(accounts::handlers::me))
+        .route(
+            "/api/looks",
+            get(accounts::handlers::list_looks).post(accounts::handlers::save_look),
+        )
+        .route("/api/looks/{id}", delete(accounts::handlers::delete_look))
+        .route("/images/{id}", get(images::handlers::serve_image))
+        .route("/", get(|| async { "Hello, World!" }))
+        .route("/health", get(health_check))
+        .route("/metrics", get(metrics))
+        .route("/admin/reload-prompts", post(reload_prompts))
+        .route(
+            "/admin/api-keys",
+            get(api_keys::admin::list_keys).post(api_keys::admin::create_key),
+        )
+        .route(
+            "/admin/api-keys/{id}/revoke",
+            post(api_keys::admin::revoke_key),
+        )
+        .route("/admin/moderation", get(moderation::admin::list_records))
+        .with_state(state)
+}
+
+/// Shared state injected into every handler.
+#[derive(Clone)]
+struct AppState {
+    config: Arc<Config>,
+    prompts: Arc<PromptStore>,
+    generator: Arc<dyn ImageGenerator>,
+    rate_limiter: Arc<dyn RateLimitBackend>,
+    jobs: Arc<JobManager>,
+    api_keys: Arc<ApiKeyStore>,
+    accounts: Arc<AccountStore>,
+    images: Arc<ImageStore>,
+    cache: Arc<ResultCache>,
+    uploads: Arc<UploadPipeline>,
+    moderation: Arc<Moderation>,
+}
+
+#[derive(Debug, Default, Deserialize)]
 struct GenerateRequest {
     prompt: String,
     #[serde(rename = "imageData")]
     image_data: String, // base64 encoded
     #[serde(rename = "generateAngles", default)]
     generate_angles: bool,
+    #[serde(rename = "hairTexture", default)]
+    hair_texture: Option<String>,
+    #[serde(rename = "desiredLength", default)]
+    desired_length: Option<String>,
+    #[serde(rename = "hairColor", default)]
+    hair_color: Option<String>,
+    #[serde(rename = "styleNotes", default)]
+    style_notes: Option<String>,
+    #[serde(default)]
+    avoid: Vec<String>,
+    /// Signed image URLs by default; "dataUrl" for inline base64
+    #[serde(rename = "responseFormat", default)]
+    response_format: ResponseFormat,
+    /// Which face to restyle, from the `faces` of a `multiple_faces` reply
+    #[serde(rename = "faceIndex", default)]
+    face_index: Option<usize>,
+    /// Or a box drawn by the client, in the upright photo's pixels
+    #[serde(rename = "faceBox", default)]
+    face_box: Option<FaceBox>,
+}
+
+impl GenerateRequest {
+    /// Everything the client wrote, for moderation.
+    fn moderation_text(&self) -> String {
+        [
+            Some(&self.prompt),
+            self.hair_texture.as_ref(),
+            self.desired_length.as_ref(),
+            self.hair_color.as_ref(),
+            self.style_notes.as_ref(),
+        ]
+        .into_iter()
+        .flatten()
+        .chain(&self.avoid)
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .join("\n")
+    }
+
+    fn prompt_vars(&self) -> PromptVars {
+        PromptVars {
+            texture: self.hair_texture.clone(),
+            length: self.desired_length.clone(),
+            color: self.hair_color.clone(),
+            style_notes: self.style_notes.clone(),
+            avoid: self.avoid.clone(),
+            ..PromptVars::new(&self.prompt)
+        }
+    }
 }
 
 #[tokio::main]
@@ -125,47 +342,93 @@ async fn main() {
         .compact()
         .init();
 
-    let prompts = Arc::new(Prompts::load().expect("Failed to load prompts.toml"));
-    let rate_limit_store = Arc::new(RateLimitStore::new(HashMap::new()));
+    let config = match Config::load(&Cli::parse()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Config error: {}", e);
+            return;
+        }
+    };
 
-    let app = Router::new()
-        .route("/", get(|| async { "Hello, World!" }))
-        .route("/health", get(health_check))
-        .route("/api/generate", post({
-            let prompts_clone = Arc::clone(&prompts);
-            let rate_limit_clone = Arc::clone(&rate_limit_store);
-            move |ConnectInfo(addr): ConnectInfo<SocketAddr>, body: Json<GenerateRequest>| async move {
-                info!(
-                    client_ip = %addr.ip(),
-                    generate_angles = body.generate_angles,
-                    prompt_len = body.prompt.len(),
-                    "Incoming /api/generate request"
-                );
-                // Check rate limit
-                if !check_rate_limit(&rate_limit_clone, &addr.ip()) {
-                    warn!(client_ip = %addr.ip(), "Rate limit exceeded for IP");
-                    return Err((
-                        StatusCode::TOO_MANY_REQUESTS,
-                        Json(GenerateResponse {
-                            success: false,
-                            variations: vec![],
-                            message: Some("Rate limit exceeded. Please wait a minute before trying again.".to_string()),
-                        }),
-                    ));
-                }
+    let prompts =
+        Arc::new(PromptStore::load(&config.prompts_path).expect("Failed to load prompts"));
+    if config.prompts_reload_secs > 0 {
+        Arc::clone(&prompts).spawn_watcher(Duration::from_secs(config.prompts_reload_secs));
+    }
+    let generator =
+        services::build_generator(&config.generator).expect("Failed to configure image generator");
+    info!(provider = generator.name(), "Image generator configured");
+    let rate_limiter = rate_limit::build_backend(&config.rate_limit)
+        .await
+        .expect("Failed to configure rate limiting");
+    info!(backend = rate_limiter.name(), "Rate limiting configured");
+    let api_keys = Arc::new(
+        ApiKeyStore::open(&config.auth.database_path).expect("Failed to open API key store"),
+    );
+    if config.auth.require_api_key {
+        info!("API keys are required");
+    }
+    let accounts = Arc::new(
+        AccountStore::open(
+            &config.auth.database_path,
+            Duration::from_secs(config.auth.session_ttl_secs),
+        )
+        .expect("Failed to open account store"),
+    );
+    let blobs = images::build_store(&config.images).expect("Failed to configure image storage");
+    info!(store = blobs.name(), "Image storage configured");
+    let images = Arc::new(ImageStore::new(blobs, &config.images));
+    let cors = cors_layer(&config.cors_origins).expect("Invalid CORS origin");
+
+    let cache = Arc::new(ResultCache::new(&config.cache));
+    let detector =
+        faces::build_detector(&config.faces).expect("Failed to configure face detection");
+    match &detector {
+        Some(detector) => info!(detector = detector.name(), "Face detection configured"),
+        None => info!("Face detection is off"),
+    }
+    let uploads = Arc::new(
+        UploadPipeline::from_config(&config, detector).expect("Invalid image processing config"),
+    );
+    let moderator =
+        moderation::build_moderator(&config.moderation).expect("Failed to configure moderation");
+    match &moderator {
+        Some(moderator) => info!(
+            moderator = moderator.name(),
+            "Content moderation configured"
+        ),
+        None => info!("Content moderation is off"),
+    }
+    let moderation = Arc::new(Moderation::new(
+        moderator,
+        Arc::new(
+            AuditLog::open(&config.auth.database_path)
+                .expect("Failed to open moderation audit log"),
+        ),
+        config.moderation.fail_open,
+    ));
+
+    let port = config.port;
+    let body_limit = config.limits.body_limit();
+    let state = AppState {
+        jobs: Arc::new(JobManager::new(&config.jobs)),
+        config: Arc::new(config),
+        prompts,
+        generator,
+        rate_limiter,
+        api_keys,
+        accounts,
+        images,
+        cache,
+        uploads,
+        moderation,
+    };
 
-                generate_haircut_image(body, prompts_clone).await
-            }
-        }))
-        .layer(DefaultBodyLimit::max(3 * 1024 * 1024)) // 3MB, output images generally are 2MB
-        .layer(CorsLayer::permissive())
+    let app = app(state)
+        .layer(DefaultBodyLimit::max(body_limit))
+        .layer(cors)
         .layer(TraceLayer::new_for_http());
 
-    let port = std::env::var("PORT")
-        .unwrap_or_else(|_| "3001".to_string())
-        .parse::<u16>()
-        .unwrap_or(3001);
-
     let addr = SocketAddr::from(([0, 0, 0, 0], port));
     println!("Server running on http://{}", addr);
 
@@ -187,109 +450,524 @@ async fn main() {
     }
 }
 
+/// Any origin may call the API unless `cors_origins` is set. Session cookies
+/// need the explicit list, since browsers won't send credentials to a
+/// wildcard origin.
+fn cors_layer(origins: &[String]) -> Result<CorsLayer, header::InvalidHeaderValue> {
+    if origins.is_empty() {
+        return Ok(CorsLayer::permissive());
+    }
+    let origins = origins
+        .iter()
+        .map(|origin| origin.parse())
+        .collect::<Result<Vec<header::HeaderValue>, _>>()?;
+    Ok(CorsLayer::new()
+        .allow_origin(origins)
+        .allow_methods(AllowMethods::mirror_request())
+        .allow_headers(AllowHeaders::mirror_request())
+        .expose_headers(ExposeHeaders::any())
+        .allow_credentials(true))
+}
+
 async fn health_check() -> &'static str {
     "OK"
 }
 
+/// `GET /metrics` in the Prometheus text format.
+async fn metrics(State(state): State<AppState>) -> impl IntoResponse {
+    let mut body = String::new();
+    if let Some(stats) = state.rate_limiter.stats() {
+        let backend = state.rate_limiter.name();
+        body.push_str(&format!(
+            "# HELP rate_limit_tracked_keys Clients with a rate limit bucket in this process.\n\
+             # TYPE rate_limit_tracked_keys gauge\n\
+             rate_limit_tracked_keys{{backend=\"{backend}\"}} {}\n\
+             # HELP rate_limit_evicted_keys_total Buckets dropped by the sweeper or the key cap.\n\
+             # TYPE rate_limit_evicted_keys_total counter\n\
+             rate_limit_evicted_keys_total{{backend=\"{backend}\"}} {}\n",
+            stats.tracked_keys, stats.evicted_keys
+        ));
+    }
+    let cache = state.cache.stats();
+    body.push_str(&format!(
+        "# HELP result_cache_entries Generated results held for reuse.\n\
+         # TYPE result_cache_entries gauge\n\
+         result_cache_entries {}\n\
+         # HELP result_cache_bytes Size of the cached images.\n\
+         # TYPE result_cache_bytes gauge\n\
+         result_cache_bytes {}\n\
+         # HELP result_cache_hits_total Generations answered from the cache.\n\
+         # TYPE result_cache_hits_total counter\n\
+         result_cache_hits_total {}\n\
+         # HELP result_cache_misses_total Generations that had to call the provider.\n\
+         # TYPE result_cache_misses_total counter\n\
+         result_cache_misses_total {}\n",
+        cache.entries, cache.bytes, cache.hits, cache.misses
+    ));
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body)
+}
+
+fn is_admin(state: &AppState, headers: &HeaderMap) -> bool {
+    let Some(expected) = &state.config.admin_token else {
+        return false;
+    };
+    let provided = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .unwrap_or("");
+    // Constant-time comparison so the token can't be guessed byte by byte
+    provided.len() == expected.len()
+        && provided
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+/// Admin endpoints 404 when no admin token is configured and 401 for a
+/// wrong one.
+fn require_admin(state: &AppState, headers: &HeaderMap) -> Result<(), (StatusCode, String)> {
+    if state.config.admin_token.is_none() {
+        return Err((StatusCode::NOT_FOUND, "Not found".to_string()));
+    }
+    if !is_admin(state, headers) {
+        warn!("Rejected unauthorized admin request");
+        return Err((StatusCode::UNAUTHORIZED, "Unauthorized".to_string()));
+    }
+    Ok(())
+}
+
+async fn reload_prompts(State(state): State<AppState>, headers: HeaderMap) -> (StatusCode, String) {
+    if let Err(rejection) = require_admin(&state, &headers) {
+        return rejection;
+    }
+    match state.prompts.reload() {
+        Ok(()) => (StatusCode::OK, "Prompts reloaded".to_string()),
+        Err(err) => (StatusCode::UNPROCESSABLE_ENTITY, err.to_string()),
+    }
+}
+
+/// Identify the client for sticky experiment assignment: the `X-Client-Id`
+/// header when it looks sane, otherwise the client IP.
+fn client_key(headers: &HeaderMap, ip: &std::net::IpAddr) -> String {
+    headers
+        .get("x-client-id")
+        .and_then(|v| v.to_str().ok())
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= 64
+                && id
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })
+        .map(str::to_string)
+        .unwrap_or_else(|| ip.to_string())
+}
+
+type ErrorResponse = (StatusCode, Json<GenerateResponse>);
+
+async fn generate(
+    State(state): State<AppState>,
+    ClientIp(client_ip): ClientIp,
+    headers: HeaderMap,
+    body: Json<GenerateRequest>,
+) -> Result<Json<GenerateResponse>, ErrorResponse> {
+    info!(
+        %client_ip,
+        generate_angles = body.generate_angles,
+        prompt_len = body.prompt.len(),
+        "Incoming /api/generate request"
+    );
+    generate_haircut_image(body, &state, &client_key(&headers, &client_ip)).await
+}
+
+/// `POST /api/jobs`: validate like `/api/generate`, then return a job id
+/// right away and generate in the background.
+async fn submit_job(
+    State(state): State<AppState>,
+    ClientIp(client_ip): ClientIp,
+    headers: HeaderMap,
+    Json(request): Json<GenerateRequest>,
+) -> Result<(StatusCode, Json<JobSnapshot>), ErrorResponse> {
+    info!(
+        %client_ip,
+        generate_angles = request.generate_angles,
+        prompt_len = request.prompt.len(),
+        "Incoming /api/jobs request"
+    );
+    let client_key = client_key(&headers, &client_ip);
+    let (request, upload) = prepare_request(request, &state, &client_key).await?;
+    let job_state = state.clone();
+    let submitted = state.jobs.submit(async move {
+        match run_generation(&request, &upload, &job_state, &client_key).await {
+            Ok(response) => response,
+            Err((_, Json(response))) => response,
+        }
+    });
+
+    match submitted {
+        Ok(id) => {
+            info!(job_id = %id, "Queued generation job");
+            let snapshot = state.jobs.get(&id).ok_or_else(|| {
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(GenerateResponse::error("Job disappeared")),
+                )
+            })?;
+            Ok((StatusCode::ACCEPTED, Json(snapshot)))
+        }
+        Err(SubmitError::QueueFull) => Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(GenerateResponse::error(
+                "Too many images are being generated right now. Please try again shortly.",
+            )),
+        )),
+    }
+}
+
+async fn get_job(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<JobSnapshot>, StatusCode> {
+    state.jobs.get(&id).map(Json).ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn cancel_job(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<JobSnapshot>, StatusCode> {
+    state
+        .jobs
+        .cancel(&id)
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
 async fn generate_haircut_image(
     Json(request): Json<GenerateRequest>,
-    prompts: Arc<Prompts>,
-) -> Result<Json<GenerateResponse>, (StatusCode, Json<GenerateResponse>)> {
+    state: &AppState,
+    client_key: &str,
+) -> Result<Json<GenerateResponse>, ErrorResponse> {
+    let (request, upload) = prepare_request(request, state, client_key).await?;
+    run_generation(&request, &upload, state, client_key)
+        .await
+        .map(Json)
+}
+
+/// Validate and moderate a request, then identify its image and run it
+/// through the upload pipeline.
+async fn prepare_request(
+    request: GenerateRequest,
+    state: &AppState,
+    client_key: &str,
+) -> Result<(GenerateRequest, Upload), ErrorResponse> {
+    let limits = &state.config.limits;
+
     // Validate inputs
-    if let Err(msg) = validate_image_data(&request.image_data) {
-        warn!(reason = %msg, "Image data validation failed");
-        return Err((
-            StatusCode::BAD_REQUEST,
-            Json(GenerateResponse {
-                success: false,
-                variations: vec![],
-                message: Some(msg),
-            }),
-        ));
-    }
+    let image = match validate_image_data(&request.image_data, limits.max_image_bytes) {
+        Ok(image) => image,
+        Err(msg) => {
+            warn!(reason = %msg, "Image data validation failed");
+            return Err((StatusCode::BAD_REQUEST, Json(GenerateResponse::error(msg))));
+        }
+    };
 
-    if let Err(msg) = validate_prompt(&request.prompt) {
+    if let Err(msg) = validate_prompt(&request.prompt, limits.max_prompt_chars) {
         warn!(reason = %msg, "Prompt validation failed");
-        return Err((
-            StatusCode::BAD_REQUEST,
-            Json(GenerateResponse {
-                success: false,
-                variations: vec![],
-                message: Some(msg),
-            }),
-        ));
+        return Err((StatusCode::BAD_REQUEST, Json(GenerateResponse::error(msg))));
+    }
+
+    if let Err(msg) = validate_style_details(&request, limits.max_prompt_chars) {
+        warn!(reason = %msg, "Style detail validation failed");
+        return Err((StatusCode::BAD_REQUEST, Json(GenerateResponse::error(msg))));
+    }
+
+    // Checked before the photo, which is the expensive part
+    let text = request.moderation_text();
+    let decision = state
+        .moderation
+        .check(Content::Prompt(&text), client_key)
+        .await;
+    if decision.is_blocked() {
+        return Err(content_blocked(decision.reasons));
     }
 
-    let image_data = match general_purpose::STANDARD.decode(&request.image_data) {
-        Ok(data) => data,
-        Err(_) => {
-            warn!("Failed to decode base64 image data");
+    let selection = match (request.face_index, request.face_box) {
+        (None, None) => FaceSelection::Auto,
+        (Some(index), None) => FaceSelection::Index(index),
+        (None, Some(face_box)) => FaceSelection::Box(face_box),
+        (Some(_), Some(_)) => {
             return Err((
                 StatusCode::BAD_REQUEST,
-                Json(GenerateResponse {
-                    success: false,
-                    variations: vec![],
-                    message: Some("Invalid image data".to_string()),
-                }),
+                Json(GenerateResponse::error(
+                    "Send faceIndex or faceBox, not both",
+                )),
             ));
         }
     };
 
+    let uploads = Arc::clone(&state.uploads);
+    let prepared = tokio::task::spawn_blocking(move || uploads.process(image, selection));
+    let timeout = Duration::from_secs(limits.decode_timeout_secs);
+    let prepared = match tokio::time::timeout(timeout, prepared).await {
+        Ok(prepared) => prepared,
+        // The blocking thread can't be stopped, but the client needn't wait
+        Err(_) => Ok(Err(LimitError::DecodeTimeout.into())),
+    };
+    let upload = match prepared {
+        Ok(Ok(upload)) => upload,
+        Ok(Err(err)) => {
+            if let Some(limit) = err.downcast_ref::<LimitError>() {
+                warn!(code = limit.code(), reason = %limit, "Image over limits");
+                return Err((
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    Json(GenerateResponse::rejected(limit.code(), limit.to_string())),
+                ));
+            }
+            if let Some(QualityError(issue)) = err.downcast_ref::<QualityError>() {
+                warn!(code = issue.code, "Photo quality too low");
+                return Err((
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    Json(GenerateResponse::rejected(issue.code, issue.message)),
+                ));
+            }
+            if let Some(face) = err.downcast_ref::<FaceError>() {
+                warn!(code = face.code(), "Face check failed");
+                let faces = match face {
+                    FaceError::MultipleFaces(faces) => faces.clone(),
+                    _ => vec![],
+                };
+                return Err((
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    Json(GenerateResponse {
+                        faces,
+                        ..GenerateResponse::rejected(face.code(), face.to_string())
+                    }),
+                ));
+            }
+            warn!(error = %err, "Failed to prepare uploaded image");
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(GenerateResponse::error("Could not read image")),
+            ));
+        }
+        Err(err) => {
+            error!(error = %err, "Image preparation task failed");
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(GenerateResponse::error("Failed to process image")),
+            ));
+        }
+    };
+
+    let decision = state
+        .moderation
+        .check(Content::Image(&upload.image), client_key)
+        .await;
+    if decision.is_blocked() {
+        return Err(content_blocked(decision.reasons));
+    }
+
+    Ok((request, upload))
+}
+
+fn content_blocked(reasons: Vec<String>) -> ErrorResponse {
+    let message = if reasons.is_empty() {
+        "Request blocked by content moderation".to_string()
+    } else {
+        format!(
+            "Request blocked by content moderation: {}",
+            reasons.join("; ")
+        )
+    };
+    (
+        StatusCode::UNPROCESSABLE_ENTITY,
+        Json(GenerateResponse::rejected("content_blocked", message)),
+    )
+}
+
+/// Render the prompt for this client and call the image generator.
+async fn run_generation(
+    request: &GenerateRequest,
+    upload: &Upload,
+    state: &AppState,
+    client_key: &str,
+) -> Result<GenerateResponse, ErrorResponse> {
+    let image = &upload.image;
+    // Snapshot the templates so a reload can't change them mid-request
+    let prompts = state.prompts.current();
+    let generator = &state.generator;
+
+    let selection = prompts.select(client_key);
+
     info!(
+        provider = generator.name(),
+        prompt_version = selection.version,
+        experiment = selection.experiment,
         prompt_len = request.prompt.len(),
         generate_angles = request.generate_angles,
-        "Invoking Gemini to generate haircut images"
+        face = ?upload.face,
+        crop = ?upload.crop,
+        "Invoking image generator to generate haircut images"
     );
 
-    let image_variations = match services::gemini::generate_haircut_images(
-        &request.prompt,
-        &image_data,
+    let templates = selection.templates;
+    let vars = request.prompt_vars();
+    let angle_prompts = request
+        .generate_angles
+        .then(|| templates.angle_views(&vars));
+    let generation_prompt = match &angle_prompts {
+        Some(prompts) => prompts.joined(),
+        None => templates.front_view(&vars),
+    };
+    let cache_key = cache::cache_key(
+        &image.data,
+        &generation_prompt,
         request.generate_angles,
-        &prompts,
-    )
-    .await
-    {
-        Ok(variations) => {
+        &model_id(state),
+        selection.version,
+    );
+    let generation = state
+        .cache
+        .get_or_generate(cache_key, async {
+            match &angle_prompts {
+                Some(prompts) => generator.generate_angle_views(prompts, image).await,
+                None => {
+                    generator
+                        .generate_front_view(&generation_prompt, image)
+                        .await
+                }
+            }
+        })
+        .await;
+
+    let (image_variations, cached) = match generation {
+        Ok((variations, cached)) => {
             info!(
                 count = variations.len(),
+                prompt_version = selection.version,
+                cached,
                 "Generated haircut image variations"
             );
-            variations
+            (variations, cached)
         }
         Err(err) => {
-            error!(error = %err, "Gemini generation failed");
+            error!(
+                provider = generator.name(),
+                prompt_version = selection.version,
+                error = %err,
+                "Image generation failed"
+            );
             return Err((
                 StatusCode::INTERNAL_SERVER_ERROR,
-                Json(GenerateResponse {
-                    success: false,
-                    variations: vec![],
-                    message: Some("Failed to generate images".to_string()),
-                }),
+                Json(GenerateResponse::error("Failed to generate images")),
             ));
         }
     };
 
-    Ok(Json(GenerateResponse {
+    let image_variations = state
+        .moderation
+        .filter_outputs(image_variations, client_key)
+        .await;
+    if image_variations.is_empty() {
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(GenerateResponse::rejected(
+                "output_blocked",
+                "Every generated image was blocked by content moderation",
+            )),
+        ));
+    }
+
+    let image_variations = state
+        .images
+        .present(image_variations, request.response_format)
+        .await
+        .map_err(|err| {
+            error!(error = %err, "Failed to store generated images");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(GenerateResponse::error("Failed to store images")),
+            )
+        })?;
+
+    Ok(GenerateResponse {
         success: true,
         variations: image_variations,
         message: None,
-    }))
+        prompt_version: Some(selection.version.to_string()),
+        experiment: selection.experiment.map(str::to_string),
+        cached,
+        code: None,
+        warnings: upload.warnings.clone(),
+        faces: vec![],
+        crop: upload.crop,
+    })
+}
+
+/// The provider and model, for cache keys: the same prompt on another model
+/// is a different result.
+fn model_id(state: &AppState) -> String {
+    format!(
+        "{}/{}",
+        state.generator.name(),
+        state.config.generator.model.as_deref().unwrap_or("default")
+    )
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use async_trait::async_trait;
+    use services::generator::{AnglePrompts, GenerateError};
+    use services::prompts::Prompts;
     use std::net::IpAddr;
     use std::str::FromStr;
+    use std::sync::Mutex;
+
+    const MAX_IMAGE_BYTES: usize = 10 * 1024 * 1024;
+    const MAX_PROMPT_CHARS: usize = 500;
 
     // Helper function to create a test IP
     fn test_ip() -> IpAddr {
         IpAddr::from_str("127.0.0.1").unwrap()
     }
 
-    // Helper function to create valid base64 data
+    // Helper function to create valid base64 data that sniffs as a JPEG
     fn create_valid_base64(size_kb: usize) -> String {
-        let data = vec![65u8; size_kb * 1024]; // 'A' repeated
+        let mut data = vec![65u8; size_kb * 1024]; // 'A' repeated
+        data[..3].copy_from_slice(&[0xFF, 0xD8, 0xFF]);
+        general_purpose::STANDARD.encode(&data)
+    }
+
+    // Helper function to create a photo that passes the quality checks
+    fn create_test_photo() -> String {
+        create_photo(512, 1.0)
+    }
+
+    /// A face-sized skin-toned square on a checked background, scaled in
+    /// brightness by `exposure`.
+    fn create_photo(size: u32, exposure: f64) -> String {
+        let image = image::RgbImage::from_fn(size, size, |x, y| {
+            let centre =
+                (size / 4..size * 3 / 4).contains(&x) && (size / 4..size * 3 / 4).contains(&y);
+            let base = if centre {
+                [224, 172, 140]
+            } else if (x / 16 + y / 16) % 2 == 0 {
+                [60, 80, 100]
+            } else {
+                [160, 170, 180]
+            };
+            image::Rgb(base.map(|c| (c as f64 * exposure).min(255.0) as u8))
+        });
+        let mut data = Vec::new();
+        image
+            .write_to(
+                &mut std::io::Cursor::new(&mut data),
+                image::ImageFormat::Jpeg,
+            )
+            .unwrap();
         general_purpose::STANDARD.encode(&data)
     }
 
@@ -298,74 +976,154 @@ mod tests {
         "invalid-base64-data!@#$%".to_string()
     }
 
-    // ===== RATE LIMITING TESTS =====
+    /// A PNG signature: enough to pass the rules moderator's image check.
+    const GENERATED_IMAGE: &str = "data:image/png;base64,iVBORw0KGgo=";
 
-    #[test]
-    fn test_rate_limit_under_limit() {
-        let store = RateLimitStore::new(HashMap::new());
-        let ip = test_ip();
-
-        // Should allow first 10 requests
-        for _ in 0..10 {
-            assert!(check_rate_limit(&store, &ip));
-        }
+    // Generator that records the prompt it was given instead of calling a provider
+    #[derive(Default)]
+    struct MockGenerator {
+        prompts: Mutex<Vec<String>>,
+        fail_angles: bool,
     }
 
-    #[test]
-    fn test_rate_limit_over_limit() {
-        let store = RateLimitStore::new(HashMap::new());
-        let ip = test_ip();
+    #[async_trait]
+    impl ImageGenerator for MockGenerator {
+        fn name(&self) -> &'static str {
+            "mock"
+        }
 
-        // Make 10 requests (should all pass)
-        for _ in 0..10 {
-            assert!(check_rate_limit(&store, &ip));
+        async fn generate_front_view(
+            &self,
+            prompt: &str,
+            _image: &InputImage,
+        ) -> Result<Vec<ImageVariation>, GenerateError> {
+            self.prompts.lock().unwrap().push(prompt.to_string());
+            Ok(vec![ImageVariation {
+                image: GENERATED_IMAGE.to_string(),
+                angle: "front".to_string(),
+            }])
         }
 
-        // 11th request should be blocked
-        assert!(!check_rate_limit(&store, &ip));
+        async fn generate_angle_views(
+            &self,
+            prompts: &AnglePrompts,
+            _image: &InputImage,
+        ) -> Result<Vec<ImageVariation>, GenerateError> {
+            self.prompts
+                .lock()
+                .unwrap()
+                .push(prompts.side_and_back.clone());
+            // Angle views are the slow call in practice
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            if self.fail_angles {
+                return Err("angle generation failed".into());
+            }
+            Ok(["side", "back"]
+                .iter()
+                .map(|angle| ImageVariation {
+                    image: GENERATED_IMAGE.to_string(),
+                    angle: angle.to_string(),
+                })
+                .collect())
+        }
     }
 
-    #[test]
-    fn test_rate_limit_different_ips() {
-        let store = RateLimitStore::new(HashMap::new());
-        let ip1 = IpAddr::from_str("127.0.0.1").unwrap();
-        let ip2 = IpAddr::from_str("127.0.0.2").unwrap();
-
-        // Make 10 requests with IP1 (should all pass)
-        for _ in 0..10 {
-            assert!(check_rate_limit(&store, &ip1));
+    fn test_state(generator: Arc<MockGenerator>) -> AppState {
+        AppState {
+            config: Arc::new(Config::default()),
+            prompts: test_prompts(),
+            generator,
+            rate_limiter: Arc::new(rate_limit::memory::MemoryBackend::new(1000)),
+            jobs: Arc::new(JobManager::new(&config::JobsConfig::default())),
+            api_keys: Arc::new(ApiKeyStore::open_in_memory().unwrap()),
+            accounts: Arc::new(AccountStore::open_in_memory().unwrap()),
+            images: Arc::new(ImageStore::new(
+                Arc::new(images::MemoryStore::default()),
+                &config::ImagesConfig::default(),
+            )),
+            cache: Arc::new(ResultCache::new(&config::CacheConfig::default())),
+            uploads: test_uploads(vec![test_face()]),
+            moderation: test_moderation(Some(Arc::new(
+                moderation::rules::RulesModerator::from_config(&Default::default()),
+            ))),
         }
+    }
 
-        // IP1 should now be blocked
-        assert!(!check_rate_limit(&store, &ip1));
+    fn test_moderation(moderator: Option<Arc<dyn moderation::Moderator>>) -> Arc<Moderation> {
+        Arc::new(Moderation::new(
+            moderator,
+            Arc::new(AuditLog::open_in_memory().unwrap()),
+            false,
+        ))
+    }
 
-        // IP2 should still be able to make requests
-        for _ in 0..10 {
-            assert!(check_rate_limit(&store, &ip2));
+    /// Blocks the generated views for one angle.
+    struct BlockAngle(&'static str);
+
+    #[async_trait]
+    impl moderation::Moderator for BlockAngle {
+        fn name(&self) -> &'static str {
+            "block-angle"
         }
 
-        // Now IP2 should also be blocked
-        assert!(!check_rate_limit(&store, &ip2));
+        async fn moderate(
+            &self,
+            content: Content<'_>,
+        ) -> Result<moderation::Decision, moderation::ModerationError> {
+            Ok(match content {
+                Content::Output(variation) if variation.angle == self.0 => moderation::Decision {
+                    verdict: moderation::Verdict::Block,
+                    reasons: vec![format!("no {} views", self.0)],
+                },
+                _ => moderation::Decision::allow(),
+            })
+        }
     }
 
-    #[test]
-    fn test_rate_limit_cleanup() {
-        let store = RateLimitStore::new(HashMap::new());
-        let ip = test_ip();
+    fn test_prompts() -> Arc<PromptStore> {
+        Arc::new(PromptStore::new(
+            std::path::Path::new("prompts.toml"),
+            Prompts::parse(
+                r#"
+                [front_view]
+                template = "front: {haircut}{?color} in {color}{/color}"
+
+                [side_and_back_views]
+                template = "angles: {haircut}"
+
+                [side_view]
+                template = "side: {haircut}"
+
+                [back_view]
+                template = "back: {haircut}"
+                "#,
+            )
+            .unwrap(),
+        ))
+    }
 
-        // Simulate old timestamps (61 seconds ago)
-        {
-            let mut store_lock = store.lock().unwrap();
-            let old_timestamp = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs()
-                - 61;
-            store_lock.insert(get_rate_limit_key(&ip), vec![old_timestamp; 10]);
+    fn test_face() -> FaceBox {
+        FaceBox {
+            x: 128,
+            y: 128,
+            width: 256,
+            height: 256,
         }
+    }
+
+    /// The upload pipeline with a detector that always finds `faces`.
+    fn test_uploads(faces: Vec<FaceBox>) -> Arc<UploadPipeline> {
+        let detector = Arc::new(faces::FixedDetector(faces));
+        Arc::new(UploadPipeline::from_config(&Config::default(), Some(detector)).unwrap())
+    }
 
-        // Should allow new requests since old ones are cleaned up
-        assert!(check_rate_limit(&store, &ip));
+    fn test_request(generate_angles: bool) -> Json<GenerateRequest> {
+        Json(GenerateRequest {
+            prompt: "Low taper fade".to_string(),
+            image_data: create_test_photo(),
+            generate_angles,
+            ..Default::default()
+        })
     }
 
     // ===== IMAGE VALIDATION TESTS =====
@@ -373,15 +1131,32 @@ mod tests {
     #[test]
     fn test_validate_image_data_valid() {
         let valid_base64 = create_valid_base64(100); // 100KB
-        assert!(validate_image_data(&valid_base64).is_ok());
+        assert!(validate_image_data(&valid_base64, MAX_IMAGE_BYTES).is_ok());
+    }
+
+    #[test]
+    fn test_validate_image_data_detects_format() {
+        let png = general_purpose::STANDARD.encode(b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR");
+        let image = validate_image_data(&png, MAX_IMAGE_BYTES).unwrap();
+        assert_eq!(image.format, ImageFormat::Png);
+        assert_eq!(image.format.mime_type(), "image/png");
+    }
+
+    #[test]
+    fn test_validate_image_data_rejects_non_images() {
+        let text = general_purpose::STANDARD.encode(vec![65u8; 1024]);
+        assert_eq!(
+            validate_image_data(&text, MAX_IMAGE_BYTES).unwrap_err(),
+            "Unsupported image type (use JPEG, PNG, WebP, GIF or HEIC)"
+        );
     }
 
     #[test]
     fn test_validate_image_data_invalid_base64() {
         let invalid_base64 = create_invalid_base64();
-        assert!(validate_image_data(&invalid_base64).is_err());
+        assert!(validate_image_data(&invalid_base64, MAX_IMAGE_BYTES).is_err());
         assert_eq!(
-            validate_image_data(&invalid_base64).unwrap_err(),
+            validate_image_data(&invalid_base64, MAX_IMAGE_BYTES).unwrap_err(),
             "Invalid image data format"
         );
     }
@@ -389,9 +1164,9 @@ mod tests {
     #[test]
     fn test_validate_image_data_too_large() {
         let large_base64 = create_valid_base64(10240); // ~10MB (should fail)
-        assert!(validate_image_data(&large_base64).is_err());
+        assert!(validate_image_data(&large_base64, MAX_IMAGE_BYTES).is_err());
         assert_eq!(
-            validate_image_data(&large_base64).unwrap_err(),
+            validate_image_data(&large_base64, MAX_IMAGE_BYTES).unwrap_err(),
             "Image too large (max 10MB)"
         );
     }
@@ -399,7 +1174,7 @@ mod tests {
     #[test]
     fn test_validate_image_data_boundary_size() {
         let boundary_base64 = create_valid_base64(8192); // ~8MB (should pass)
-        assert!(validate_image_data(&boundary_base64).is_ok());
+        assert!(validate_image_data(&boundary_base64, MAX_IMAGE_BYTES).is_ok());
     }
 
     // ===== PROMPT VALIDATION TESTS =====
@@ -407,15 +1182,15 @@ mod tests {
     #[test]
     fn test_validate_prompt_valid() {
         let valid_prompt = "Low taper fade with textured top";
-        assert!(validate_prompt(valid_prompt).is_ok());
+        assert!(validate_prompt(valid_prompt, MAX_PROMPT_CHARS).is_ok());
     }
 
     #[test]
     fn test_validate_prompt_empty() {
         let empty_prompt = "";
-        assert!(validate_prompt(empty_prompt).is_err());
+        assert!(validate_prompt(empty_prompt, MAX_PROMPT_CHARS).is_err());
         assert_eq!(
-            validate_prompt(empty_prompt).unwrap_err(),
+            validate_prompt(empty_prompt, MAX_PROMPT_CHARS).unwrap_err(),
             "Prompt cannot be empty"
         );
     }
@@ -423,9 +1198,9 @@ mod tests {
     #[test]
     fn test_validate_prompt_whitespace_only() {
         let whitespace_prompt = "   \n\t   ";
-        assert!(validate_prompt(whitespace_prompt).is_err());
+        assert!(validate_prompt(whitespace_prompt, MAX_PROMPT_CHARS).is_err());
         assert_eq!(
-            validate_prompt(whitespace_prompt).unwrap_err(),
+            validate_prompt(whitespace_prompt, MAX_PROMPT_CHARS).unwrap_err(),
             "Prompt cannot be empty"
         );
     }
@@ -433,9 +1208,9 @@ mod tests {
     #[test]
     fn test_validate_prompt_too_long() {
         let long_prompt = "a".repeat(501);
-        assert!(validate_prompt(&long_prompt).is_err());
+        assert!(validate_prompt(&long_prompt, MAX_PROMPT_CHARS).is_err());
         assert_eq!(
-            validate_prompt(&long_prompt).unwrap_err(),
+            validate_prompt(&long_prompt, MAX_PROMPT_CHARS).unwrap_err(),
             "Prompt too long (max 500 characters)"
         );
     }
@@ -443,7 +1218,7 @@ mod tests {
     #[test]
     fn test_validate_prompt_boundary_length() {
         let boundary_prompt = "a".repeat(500);
-        assert!(validate_prompt(&boundary_prompt).is_ok());
+        assert!(validate_prompt(&boundary_prompt, MAX_PROMPT_CHARS).is_ok());
     }
 
     #[test]
@@ -459,12 +1234,12 @@ mod tests {
 
         for prompt in blocked_prompts {
             assert!(
-                validate_prompt(prompt).is_err(),
+                validate_prompt(prompt, MAX_PROMPT_CHARS).is_err(),
                 "Prompt '{}' should be blocked",
                 prompt
             );
             assert_eq!(
-                validate_prompt(prompt).unwrap_err(),
+                validate_prompt(prompt, MAX_PROMPT_CHARS).unwrap_err(),
                 "Prompt contains invalid content"
             );
         }
@@ -473,9 +1248,9 @@ mod tests {
     #[test]
     fn test_validate_prompt_case_insensitive() {
         let mixed_case_prompt = "I want a JaVaScRiPt haircut";
-        assert!(validate_prompt(mixed_case_prompt).is_err());
+        assert!(validate_prompt(mixed_case_prompt, MAX_PROMPT_CHARS).is_err());
         assert_eq!(
-            validate_prompt(mixed_case_prompt).unwrap_err(),
+            validate_prompt(mixed_case_prompt, MAX_PROMPT_CHARS).unwrap_err(),
             "Prompt contains invalid content"
         );
     }
@@ -492,23 +1267,727 @@ mod tests {
 
         for prompt in allowed_prompts {
             assert!(
-                validate_prompt(prompt).is_ok(),
+                validate_prompt(prompt, MAX_PROMPT_CHARS).is_ok(),
                 "Prompt '{}' should be allowed",
                 prompt
             );
         }
     }
 
-    // ===== INTEGRATION TESTS =====
+    // ===== GENERATION TESTS =====
+
+    #[tokio::test]
+    async fn test_generate_front_view_with_mock_generator() {
+        let generator = Arc::new(MockGenerator::default());
+        let Json(response) = generate_haircut_image(
+            test_request(false),
+            &test_state(generator.clone()),
+            "client",
+        )
+        .await
+        .unwrap();
+
+        assert!(response.success);
+        assert_eq!(response.variations.len(), 1);
+        assert_eq!(response.variations[0].angle, "front");
+        assert_eq!(response.prompt_version.as_deref(), Some("default"));
+        assert!(response.experiment.is_none());
+        assert_eq!(
+            *generator.prompts.lock().unwrap(),
+            vec!["front: Low taper fade"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_generate_angles_with_mock_generator() {
+        let generator = Arc::new(MockGenerator::default());
+        let Json(response) =
+            generate_haircut_image(test_request(true), &test_state(generator.clone()), "client")
+                .await
+                .unwrap();
+
+        let angles: Vec<&str> = response
+            .variations
+            .iter()
+            .map(|v| v.angle.as_str())
+            .collect();
+        assert_eq!(angles, vec!["side", "back"]);
+        assert_eq!(
+            *generator.prompts.lock().unwrap(),
+            vec!["angles: Low taper fade"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_generate_renders_style_details() {
+        let generator = Arc::new(MockGenerator::default());
+        let mut request = test_request(false);
+        request.hair_color = Some("copper".to_string());
+
+        let Json(response) =
+            generate_haircut_image(request, &test_state(generator.clone()), "client")
+                .await
+                .unwrap();
+
+        assert!(response.success);
+        assert_eq!(
+            *generator.prompts.lock().unwrap(),
+            vec!["front: Low taper fade in copper"]
+        );
+    }
 
     #[test]
-    fn test_rate_limit_integration() {
-        let store = RateLimitStore::new(HashMap::new());
+    fn test_client_key_prefers_sane_client_id() {
         let ip = test_ip();
+        let mut headers = HeaderMap::new();
+        assert_eq!(client_key(&headers, &ip), "127.0.0.1");
+
+        headers.insert("x-client-id", "c0ffee-42".parse().unwrap());
+        assert_eq!(client_key(&headers, &ip), "c0ffee-42");
+
+        headers.insert("x-client-id", "not ok!".parse().unwrap());
+        assert_eq!(client_key(&headers, &ip), "127.0.0.1");
+    }
+
+    #[test]
+    fn test_validate_style_details() {
+        let mut request = GenerateRequest {
+            hair_texture: Some("coily".to_string()),
+            avoid: vec!["bangs".to_string()],
+            ..Default::default()
+        };
+        assert!(validate_style_details(&request, MAX_PROMPT_CHARS).is_ok());
+
+        request.style_notes = Some("see www.example.com".to_string());
+        assert_eq!(
+            validate_style_details(&request, MAX_PROMPT_CHARS).unwrap_err(),
+            "styleNotes contains invalid content"
+        );
+
+        request.style_notes = None;
+        request.avoid = vec!["x".to_string(); MAX_AVOID_ITEMS + 1];
+        assert_eq!(
+            validate_style_details(&request, MAX_PROMPT_CHARS).unwrap_err(),
+            "Too many avoid items (max 10)"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_generate_rejects_invalid_prompt_before_generator() {
+        let generator = Arc::new(MockGenerator::default());
+        let mut request = test_request(false);
+        request.prompt = String::new();
+
+        let (status, _) = generate_haircut_image(request, &test_state(generator.clone()), "client")
+            .await
+            .unwrap_err();
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(generator.prompts.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_generate_rejects_undecodable_image() {
+        let generator = Arc::new(MockGenerator::default());
+        let mut request = test_request(false);
+        // JPEG magic bytes, but no JPEG after them
+        request.image_data = create_valid_base64(1);
+
+        let (status, Json(response)) =
+            generate_haircut_image(request, &test_state(generator.clone()), "client")
+                .await
+                .unwrap_err();
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(response.message.as_deref(), Some("Could not read image"));
+        assert!(generator.prompts.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_generate_rejects_decompression_bomb_with_422() {
+        let generator = Arc::new(MockGenerator::default());
+        // A one-pixel PNG whose header claims 60000x60000
+        let mut png = Vec::new();
+        image::RgbImage::new(1, 1)
+            .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        png[16..20].copy_from_slice(&60_000u32.to_be_bytes());
+        png[20..24].copy_from_slice(&60_000u32.to_be_bytes());
+        let mut request = test_request(false);
+        request.image_data = general_purpose::STANDARD.encode(&png);
+
+        let (status, Json(response)) =
+            generate_haircut_image(request, &test_state(generator.clone()), "client")
+                .await
+                .unwrap_err();
+
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(response.code.as_deref(), Some("image_dimensions_exceeded"));
+        assert!(generator.prompts.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_generate_rejects_dark_photo_with_422() {
+        let generator = Arc::new(MockGenerator::default());
+        let mut request = test_request(false);
+        request.image_data = create_photo(512, 0.1);
+
+        let (status, Json(response)) =
+            generate_haircut_image(request, &test_state(generator.clone()), "client")
+                .await
+                .unwrap_err();
+
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(response.code.as_deref(), Some("photo_too_dark"));
+        assert_eq!(response.message.as_deref(), Some(quality::TOO_DARK.message));
+        assert!(generator.prompts.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_generate_returns_quality_warnings() {
+        let generator = Arc::new(MockGenerator::default());
+        let mut request = test_request(false);
+        request.image_data = create_photo(400, 1.0);
+
+        let Json(response) = generate_haircut_image(request, &test_state(generator), "client")
+            .await
+            .unwrap();
+
+        assert!(response.success);
+        assert_eq!(response.warnings, vec![quality::LOW_RESOLUTION]);
+        let json = serde_json::to_value(&response).unwrap();
+        assert_eq!(json["warnings"][0]["code"], "photo_low_resolution");
+
+        let Json(clean) = generate_haircut_image(
+            test_request(false),
+            &test_state(Arc::new(MockGenerator::default())),
+            "client",
+        )
+        .await
+        .unwrap();
+        assert!(clean.warnings.is_empty());
+        assert!(serde_json::to_value(&clean)
+            .unwrap()
+            .get("warnings")
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_generate_rejects_photo_without_face() {
+        let generator = Arc::new(MockGenerator::default());
+        let mut state = test_state(generator.clone());
+        state.uploads = test_uploads(vec![]);
+
+        let (status, Json(response)) =
+            generate_haircut_image(test_request(false), &state, "client")
+                .await
+                .unwrap_err();
+
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(response.code.as_deref(), Some("no_face_detected"));
+        assert!(generator.prompts.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_generate_asks_which_face_in_group_photos() {
+        let generator = Arc::new(MockGenerator::default());
+        let mut state = test_state(generator.clone());
+        let right = FaceBox {
+            x: 300,
+            ..test_face()
+        };
+        let left = FaceBox {
+            x: 10,
+            ..test_face()
+        };
+        state.uploads = test_uploads(vec![right, left]);
+
+        let (status, Json(response)) =
+            generate_haircut_image(test_request(false), &state, "client")
+                .await
+                .unwrap_err();
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(response.code.as_deref(), Some("multiple_faces"));
+        // Numbered left to right
+        assert_eq!(response.faces, vec![left, right]);
+        let json = serde_json::to_value(&response).unwrap();
+        assert_eq!(json["faces"][1]["x"], 300);
+
+        let mut request = test_request(false);
+        request.face_index = Some(1);
+        let Json(response) = generate_haircut_image(request, &state, "client")
+            .await
+            .unwrap();
+        assert!(response.success);
+
+        let mut request = test_request(false);
+        request.face_index = Some(2);
+        let (_, Json(response)) = generate_haircut_image(request, &state, "client")
+            .await
+            .unwrap_err();
+        assert_eq!(response.code.as_deref(), Some("face_index_out_of_range"));
+    }
+
+    #[tokio::test]
+    async fn test_generate_accepts_client_face_box() {
+        let generator = Arc::new(MockGenerator::default());
+        let mut state = test_state(generator.clone());
+
+        let mut request = test_request(false);
+        request.face_box = Some(FaceBox {
+            x: 100,
+            y: 100,
+            ..test_face()
+        });
+        let Json(response) = generate_haircut_image(request, &state, "client")
+            .await
+            .unwrap();
+        assert!(response.success);
+
+        // The box has to be around a face that was found
+        let mut request = test_request(false);
+        request.face_box = Some(FaceBox {
+            x: 0,
+            y: 0,
+            width: 100,
+            height: 100,
+        });
+        let (status, Json(response)) = generate_haircut_image(request, &state, "client")
+            .await
+            .unwrap_err();
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(response.code.as_deref(), Some("face_box_without_face"));
+
+        let mut request = test_request(false);
+        request.face_box = Some(FaceBox {
+            x: 600,
+            ..test_face()
+        });
+        let (status, Json(response)) = generate_haircut_image(request, &state, "client")
+            .await
+            .unwrap_err();
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(response.code.as_deref(), Some("invalid_face_box"));
+
+        let mut request = test_request(false);
+        request.face_box = Some(test_face());
+        request.face_index = Some(0);
+        let (status, _) = generate_haircut_image(request, &state, "client")
+            .await
+            .unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        // Drawing a box doesn't skip the face check
+        state.uploads = test_uploads(vec![]);
+        let mut request = test_request(false);
+        request.face_box = Some(test_face());
+        let (_, Json(response)) = generate_haircut_image(request, &state, "client")
+            .await
+            .unwrap_err();
+        assert_eq!(response.code.as_deref(), Some("no_face_detected"));
+    }
+
+    #[tokio::test]
+    async fn test_generate_reports_crop_for_compositing() {
+        let mut state = test_state(Arc::new(MockGenerator::default()));
+        state.uploads = test_uploads(vec![FaceBox {
+            x: 200,
+            y: 100,
+            width: 50,
+            height: 50,
+        }]);
+
+        let Json(response) = generate_haircut_image(test_request(false), &state, "client")
+            .await
+            .unwrap();
+
+        let crop = response.crop.unwrap();
+        assert_eq!(
+            (crop.x, crop.y, crop.width, crop.height),
+            (150, 65, 150, 150)
+        );
+        let json = serde_json::to_value(&response).unwrap();
+        assert_eq!(json["crop"]["sourceWidth"], 512);
+
+        // A face that fills the frame isn't worth cropping
+        let Json(response) = generate_haircut_image(
+            test_request(false),
+            &test_state(Arc::new(MockGenerator::default())),
+            "client",
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.crop, None);
+    }
+
+    // ===== MODERATION TESTS =====
+
+    #[tokio::test]
+    async fn test_generate_rejects_blocked_prompt() {
+        let generator = Arc::new(MockGenerator::default());
+        let state = test_state(generator.clone());
+        let mut request = test_request(false);
+        request.style_notes = Some("topless, beach vibe".to_string());
+
+        let (status, Json(response)) = generate_haircut_image(request, &state, "client-9")
+            .await
+            .unwrap_err();
+
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(response.code.as_deref(), Some("content_blocked"));
+        assert!(response.message.unwrap().contains("\"topless\""));
+        assert!(generator.prompts.lock().unwrap().is_empty());
+
+        let records = state.moderation.audit().recent(10).await.unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].client, "client-9");
+        assert_eq!(records[0].kind, "prompt");
+    }
+
+    #[tokio::test]
+    async fn test_flagged_prompt_is_generated_and_audited() {
+        let state = test_state(Arc::new(MockGenerator::default()));
+        let mut request = test_request(false);
+        request.prompt = "Slick back with a cigarette".to_string();
+
+        let Json(response) = generate_haircut_image(request, &state, "client")
+            .await
+            .unwrap();
+
+        assert!(response.success);
+        let records = state.moderation.audit().recent(10).await.unwrap();
+        assert_eq!(records[0].verdict, moderation::Verdict::Flag);
+    }
+
+    #[tokio::test]
+    async fn test_blocked_outputs_never_reach_the_client() {
+        let mut state = test_state(Arc::new(MockGenerator::default()));
+        state.moderation = test_moderation(Some(Arc::new(BlockAngle("side"))));
+
+        let Json(response) = generate_haircut_image(test_request(true), &state, "client")
+            .await
+            .unwrap();
+        let angles: Vec<&str> = response
+            .variations
+            .iter()
+            .map(|variation| variation.angle.as_str())
+            .collect();
+        assert_eq!(angles, vec!["back"]);
+
+        let records = state.moderation.audit().recent(10).await.unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].angle.as_deref(), Some("side"));
+
+        // Nothing left to show
+        state.moderation = test_moderation(Some(Arc::new(BlockAngle("front"))));
+        let (status, Json(response)) =
+            generate_haircut_image(test_request(false), &state, "client")
+                .await
+                .unwrap_err();
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(response.code.as_deref(), Some("output_blocked"));
+        assert!(response.variations.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_admin_lists_moderation_records() {
+        let mut state = test_state(Arc::new(MockGenerator::default()));
+        let (status, _) = admin_request(&state, "GET", "/admin/moderation", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        state.config = Arc::new(Config {
+            admin_token: Some("secret".to_string()),
+            ..Config::default()
+        });
+        let mut request = test_request(false);
+        request.prompt = "Mohawk, nsfw".to_string();
+        assert!(generate_haircut_image(request, &state, "client")
+            .await
+            .is_err());
+
+        let (status, records) =
+            admin_request(&state, "GET", "/admin/moderation?limit=5", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(records[0]["verdict"], "block");
+        assert_eq!(records[0]["kind"], "prompt");
+        assert_eq!(records[0]["moderator"], "rules");
+    }
+
+    // ===== JOB TESTS =====
+
+    #[tokio::test]
+    async fn test_submit_job_and_poll_until_done() {
+        let state = test_state(Arc::new(MockGenerator::default()));
+
+        let (status, Json(submitted)) = submit_job(
+            State(state.clone()),
+            ClientIp(test_ip()),
+            HeaderMap::new(),
+            test_request(true),
+        )
+        .await
+        .unwrap();
+        assert_eq!(status, StatusCode::ACCEPTED);
+
+        let mut snapshot = submitted;
+        for _ in 0..200 {
+            if snapshot.status == jobs::JobStatus::Succeeded {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            snapshot = get_job(State(state.clone()), Path(snapshot.id.clone()))
+                .await
+                .unwrap()
+                .0;
+        }
+
+        assert_eq!(snapshot.status, jobs::JobStatus::Succeeded);
+        let angles: Vec<&str> = snapshot
+            .result
+            .variations
+            .iter()
+            .map(|v| v.angle.as_str())
+            .collect();
+        assert_eq!(angles, vec!["side", "back"]);
+    }
+
+    /// Submit `request` as a job and poll `GET /api/jobs/{id}` until it
+    /// finishes.
+    async fn run_job(state: &AppState, request: Json<GenerateRequest>) -> serde_json::Value {
+        use tower::ServiceExt;
+
+        let (_, Json(submitted)) = submit_job(
+            State(state.clone()),
+            ClientIp(test_ip()),
+            HeaderMap::new(),
+            request,
+        )
+        .await
+        .unwrap();
+        for _ in 0..200 {
+            let mut request = axum::http::Request::get(format!("/api/jobs/{}", submitted.id))
+                .body(axum::body::Body::empty())
+                .unwrap();
+            request
+                .extensions_mut()
+                .insert(axum::extract::ConnectInfo(SocketAddr::new(test_ip(), 4000)));
+            let response = app(state.clone()).oneshot(request).await.unwrap();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let snapshot: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            if snapshot["status"] == "succeeded" || snapshot["status"] == "failed" {
+                return snapshot;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        panic!("job {} never finished", submitted.id);
+    }
+
+    #[tokio::test]
+    async fn test_job_results_carry_the_whole_response() {
+        let mut state = test_state(Arc::new(MockGenerator::default()));
+        state.uploads = test_uploads(vec![FaceBox {
+            x: 150,
+            y: 100,
+            width: 40,
+            height: 40,
+        }]);
+        let mut request = test_request(false);
+        request.image_data = create_photo(400, 1.0);
+
+        let snapshot = run_job(&state, request).await;
+        assert_eq!(snapshot["status"], "succeeded");
+        assert_eq!(snapshot["success"], true);
+        assert_eq!(snapshot["warnings"][0]["code"], "photo_low_resolution");
+        assert_eq!(snapshot["crop"]["sourceWidth"], 400);
+        assert_eq!(snapshot["promptVersion"], "default");
+        assert_eq!(snapshot["cached"], false);
+
+        // Failures keep their code
+        state.moderation = test_moderation(Some(Arc::new(BlockAngle("front"))));
+        let snapshot = run_job(&state, test_request(false)).await;
+        assert_eq!(snapshot["status"], "failed");
+        assert_eq!(snapshot["code"], "output_blocked");
+        assert!(snapshot["message"].is_string());
+    }
+
+    #[tokio::test]
+    async fn test_submit_job_validates_before_queueing() {
+        let state = test_state(Arc::new(MockGenerator::default()));
+        let mut request = test_request(false);
+        request.prompt = String::new();
+
+        let (status, _) = submit_job(State(state), ClientIp(test_ip()), HeaderMap::new(), request)
+            .await
+            .unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_job_is_not_found() {
+        let state = test_state(Arc::new(MockGenerator::default()));
+        assert_eq!(
+            get_job(State(state.clone()), Path("nope".to_string()))
+                .await
+                .unwrap_err(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            cancel_job(State(state), Path("nope".to_string()))
+                .await
+                .unwrap_err(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    // ===== STREAM TESTS =====
+
+    async fn stream_body(generator: MockGenerator, generate_angles: bool) -> String {
+        let response = stream::generate_stream(
+            State(test_state(Arc::new(generator))),
+            ClientIp(test_ip()),
+            HeaderMap::new(),
+            test_request(generate_angles),
+        )
+        .await
+        .unwrap()
+        .into_response();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    fn event_names(body: &str) -> Vec<&str> {
+        body.lines()
+            .filter_map(|line| line.strip_prefix("event: "))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_stream_sends_front_before_angles() {
+        let body = stream_body(MockGenerator::default(), true).await;
+        assert_eq!(
+            event_names(&body),
+            vec!["validated", "front", "side", "back", "done"]
+        );
+        assert!(body.contains(r#""promptVersion":"default""#));
+    }
+
+    #[tokio::test]
+    async fn test_stream_front_only() {
+        let body = stream_body(MockGenerator::default(), false).await;
+        assert_eq!(event_names(&body), vec!["validated", "front", "done"]);
+    }
+
+    #[tokio::test]
+    async fn test_stream_reports_generation_error() {
+        let generator = MockGenerator {
+            fail_angles: true,
+            ..Default::default()
+        };
+        let body = stream_body(generator, true).await;
+        assert_eq!(event_names(&body), vec!["validated", "front", "error"]);
+        assert!(body.contains("Failed to generate images"));
+    }
+
+    #[tokio::test]
+    async fn test_stream_rejects_invalid_request_as_json() {
+        let mut request = test_request(false);
+        request.prompt = String::new();
+        let (status, _) = stream::generate_stream(
+            State(test_state(Arc::new(MockGenerator::default()))),
+            ClientIp(test_ip()),
+            HeaderMap::new(),
+            request,
+        )
+        .await
+        .err()
+        .unwrap();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    // ===== ADMIN TESTS =====
+
+    #[tokio::test]
+    async fn test_reload_prompts_requires_admin_token() {
+        let mut state = test_state(Arc::new(MockGenerator::default()));
+
+        let (status, _) = reload_prompts(State(state.clone()), HeaderMap::new()).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        state.config = Arc::new(Config {
+            admin_token: Some("secret".to_string()),
+            ..Config::default()
+        });
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, "Bearer wrong".parse().unwrap());
+        let (status, _) = reload_prompts(State(state.clone()), headers).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, "Bearer secret".parse().unwrap());
+        let (status, _) = reload_prompts(State(state), headers).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    // ===== INTEGRATION TESTS =====
+
+    async fn post_generate(state: &AppState, generate_angles: bool) -> axum::response::Response {
+        post_generate_with(state, generate_angles, &[]).await
+    }
+
+    async fn post_generate_with(
+        state: &AppState,
+        generate_angles: bool,
+        headers: &[(&str, &str)],
+    ) -> axum::response::Response {
+        post_generation(state, "/api/generate", generate_angles, headers).await
+    }
+
+    async fn post_generation(
+        state: &AppState,
+        uri: &str,
+        generate_angles: bool,
+        headers: &[(&str, &str)],
+    ) -> axum::response::Response {
+        use tower::ServiceExt;
+
+        let body = serde_json::json!({
+            "prompt": "Low taper fade",
+            // Small, so image processing doesn't eat into the rate limit windows
+            "imageData": create_photo(256, 1.0),
+            "generateAngles": generate_angles,
+        });
+        let mut request =
+            axum::http::Request::post(uri).header(header::CONTENT_TYPE, "application/json");
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        let mut request = request
+            .body(axum::body::Body::from(body.to_string()))
+            .unwrap();
+        request
+            .extensions_mut()
+            .insert(axum::extract::ConnectInfo(SocketAddr::new(test_ip(), 4000)));
+        app(state.clone()).oneshot(request).await.unwrap()
+    }
+
+    fn header_value(response: &axum::response::Response, name: &str) -> Option<String> {
+        response
+            .headers()
+            .get(name)
+            .map(|v| v.to_str().unwrap().to_string())
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_integration() {
+        let state = test_state(Arc::new(MockGenerator::default()));
 
         // Simulate rapid requests
         for i in 0..15 {
-            let allowed = check_rate_limit(&store, &ip);
+            let allowed = post_generate(&state, false).await.status() == StatusCode::OK;
             if i < 10 {
                 assert!(allowed, "Request {} should be allowed", i + 1);
             } else {
@@ -517,12 +1996,711 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_rate_limit_headers_on_success_and_429() {
+        let state = test_state(Arc::new(MockGenerator::default()));
+
+        let response = post_generate(&state, false).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            header_value(&response, "ratelimit-limit").as_deref(),
+            Some("10")
+        );
+        assert_eq!(
+            header_value(&response, "ratelimit-remaining").as_deref(),
+            Some("9")
+        );
+        assert_eq!(
+            header_value(&response, "ratelimit-reset").as_deref(),
+            Some("6")
+        );
+        assert_eq!(header_value(&response, "retry-after"), None);
+
+        for _ in 0..9 {
+            post_generate(&state, false).await;
+        }
+        let response = post_generate(&state, false).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(
+            header_value(&response, "ratelimit-remaining").as_deref(),
+            Some("0")
+        );
+        assert_eq!(header_value(&response, "retry-after").as_deref(), Some("6"));
+    }
+
+    #[tokio::test]
+    async fn test_angles_requests_spend_more_tokens() {
+        let state = test_state(Arc::new(MockGenerator::default()));
+
+        let response = post_generate(&state, true).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            header_value(&response, "ratelimit-remaining").as_deref(),
+            Some("8")
+        );
+
+        for _ in 0..4 {
+            assert_eq!(post_generate(&state, true).await.status(), StatusCode::OK);
+        }
+        // No room for another angles request, which needs two tokens
+        let response = post_generate(&state, true).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(
+            header_value(&response, "retry-after").as_deref(),
+            Some("12")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_streamed_angles_spend_front_and_angles_tokens() {
+        let state = test_state(Arc::new(MockGenerator::default()));
+
+        let response = post_generation(&state, "/api/generate/stream", true, &[]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            header_value(&response, "ratelimit-remaining").as_deref(),
+            Some("7")
+        );
+        let response = post_generation(&state, "/api/generate/stream", false, &[]).await;
+        assert_eq!(
+            header_value(&response, "ratelimit-remaining").as_deref(),
+            Some("6")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_clients_behind_trusted_proxy_get_their_own_buckets() {
+        let mut state = test_state(Arc::new(MockGenerator::default()));
+        state.config = Arc::new(Config {
+            proxy: config::ProxyConfig {
+                trusted_proxies: vec!["127.0.0.0/8".parse().unwrap()],
+                ..Default::default()
+            },
+            ..Config::default()
+        });
+
+        for _ in 0..10 {
+            let response =
+                post_generate_with(&state, false, &[("x-forwarded-for", "198.51.100.1")]).await;
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        let response =
+            post_generate_with(&state, false, &[("x-forwarded-for", "198.51.100.1")]).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        let response =
+            post_generate_with(&state, false, &[("x-forwarded-for", "198.51.100.2")]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_forwarded_for_is_ignored_without_trusted_proxies() {
+        let state = test_state(Arc::new(MockGenerator::default()));
+
+        // Rotating a spoofed header doesn't buy a fresh bucket
+        for i in 0..10 {
+            let spoofed = format!("198.51.100.{}", i);
+            post_generate_with(&state, false, &[("x-forwarded-for", &spoofed)]).await;
+        }
+        let response =
+            post_generate_with(&state, false, &[("x-forwarded-for", "198.51.100.99")]).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    // ===== API KEY TESTS =====
+
+    async fn issue_key(
+        state: &AppState,
+        scopes: Vec<api_keys::Scope>,
+        requests: Option<usize>,
+    ) -> String {
+        let (_, secret) = state
+            .api_keys
+            .create(api_keys::NewApiKey {
+                name: "partner".to_string(),
+                scopes,
+                rate_limit_requests: requests,
+                rate_limit_burst: None,
+            })
+            .await
+            .unwrap();
+        secret
+    }
+
+    fn require_keys(state: &mut AppState) {
+        state.config = Arc::new(Config {
+            auth: config::AuthConfig {
+                require_api_key: true,
+                ..config::AuthConfig::default()
+            },
+            ..Config::default()
+        });
+    }
+
+    #[tokio::test]
+    async fn test_required_api_key() {
+        let mut state = test_state(Arc::new(MockGenerator::default()));
+        require_keys(&mut state);
+        let key = issue_key(&state, vec![api_keys::Scope::Generate], None).await;
+
+        let response = post_generate(&state, false).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = post_generate_with(&state, false, &[("x-api-key", "hmb_wrong")]).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = post_generate_with(&state, false, &[("x-api-key", &key)]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_api_key_scopes_are_enforced() {
+        let state = test_state(Arc::new(MockGenerator::default()));
+        let key = issue_key(&state, vec![api_keys::Scope::Jobs], None).await;
+
+        let bearer = format!("Bearer {}", key);
+        let response = post_generate_with(&state, false, &[("authorization", &bearer)]).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_api_key_has_its_own_rate_limit() {
+        let state = test_state(Arc::new(MockGenerator::default()));
+        let key = issue_key(&state, vec![api_keys::Scope::Generate], Some(20)).await;
+
+        // The key's bucket is separate from the IP's and twice the size
+        for _ in 0..20 {
+            let response = post_generate_with(&state, false, &[("x-api-key", &key)]).await;
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        let response = post_generate_with(&state, false, &[("x-api-key", &key)]).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(
+            header_value(&response, "ratelimit-limit").as_deref(),
+            Some("20")
+        );
+        assert_eq!(post_generate(&state, false).await.status(), StatusCode::OK);
+    }
+
+    async fn admin_request(
+        state: &AppState,
+        method: &str,
+        uri: &str,
+        body: Option<serde_json::Value>,
+    ) -> (StatusCode, serde_json::Value) {
+        use tower::ServiceExt;
+
+        let request = axum::http::Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::AUTHORIZATION, "Bearer secret")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(axum::body::Body::from(
+                body.map(|b| b.to_string()).unwrap_or_default(),
+            ))
+            .unwrap();
+        let response = app(state.clone()).oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    #[tokio::test]
+    async fn test_admin_issues_lists_and_revokes_keys() {
+        let mut state = test_state(Arc::new(MockGenerator::default()));
+        state.config = Arc::new(Config {
+            admin_token: Some("secret".to_string()),
+            ..Config::default()
+        });
+
+        let (status, created) = admin_request(
+            &state,
+            "POST",
+            "/admin/api-keys",
+            Some(serde_json::json!({ "name": "Fade Factory", "scopes": ["generate"] })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+        let key = created["key"].as_str().unwrap().to_string();
+        let (status, _) = admin_request(
+            &state,
+            "POST",
+            "/admin/api-keys",
+            Some(serde_json::json!({ "name": " ", "scopes": ["generate"] })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        let id = created["apiKey"]["id"].as_str().unwrap().to_string();
+
+        let response = post_generate_with(&state, false, &[("x-api-key", &key)]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let (status, listed) = admin_request(&state, "GET", "/admin/api-keys", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(listed[0]["name"], "Fade Factory");
+        assert_eq!(listed[0]["requestCount"], 1);
+        assert!(listed[0].get("keyHash").is_none());
+
+        let (status, revoked) = admin_request(
+            &state,
+            "POST",
+            &format!("/admin/api-keys/{}/revoke", id),
+            None,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(revoked["revokedAt"].is_u64());
+
+        let response = post_generate_with(&state, false, &[("x-api-key", &key)]).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_admin_key_endpoints_need_admin_token() {
+        let state = test_state(Arc::new(MockGenerator::default()));
+        let (status, _) = admin_request(&state, "GET", "/admin/api-keys", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_metrics_report_rate_limit_store_size() {
+        let state = test_state(Arc::new(MockGenerator::default()));
+        post_generate(&state, false).await;
+
+        let response = metrics(State(state)).await.into_response();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("# TYPE rate_limit_tracked_keys gauge"));
+        assert!(body.contains(r#"rate_limit_tracked_keys{backend="memory"} 1"#));
+        assert!(body.contains(r#"rate_limit_evicted_keys_total{backend="memory"} 0"#));
+    }
+
+    struct BrokenBackend;
+
+    #[async_trait]
+    impl RateLimitBackend for BrokenBackend {
+        fn name(&self) -> &'static str {
+            "broken"
+        }
+
+        async fn acquire(
+            &self,
+            _key: &str,
+            _cost: u32,
+            _policy: &rate_limit::BucketPolicy,
+        ) -> Result<rate_limit::RateLimitDecision, rate_limit::RateLimitError> {
+            Err("connection refused".into())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_backend_failure_allows_request() {
+        let state = AppState {
+            rate_limiter: Arc::new(BrokenBackend),
+            ..test_state(Arc::new(MockGenerator::default()))
+        };
+        let response = post_generate(&state, false).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header_value(&response, "ratelimit-limit"), None);
+    }
+
+    /// Call an account route, optionally with a session cookie; returns the
+    /// status, any `Set-Cookie` and the JSON body.
+    async fn account_request(
+        state: &AppState,
+        method: &str,
+        uri: &str,
+        cookie: Option<&str>,
+        body: Option<serde_json::Value>,
+    ) -> (StatusCode, Option<String>, serde_json::Value) {
+        use tower::ServiceExt;
+
+        let mut request = axum::http::Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::CONTENT_TYPE, "application/json");
+        if let Some(cookie) = cookie {
+            request = request.header(header::COOKIE, cookie);
+        }
+        let mut request = request
+            .body(axum::body::Body::from(
+                body.map(|b| b.to_string()).unwrap_or_default(),
+            ))
+            .unwrap();
+        request
+            .extensions_mut()
+            .insert(axum::extract::ConnectInfo(SocketAddr::new(test_ip(), 4000)));
+        let response = app(state.clone()).oneshot(request).await.unwrap();
+        let status = response.status();
+        let set_cookie = header_value(&response, "set-cookie");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (
+            status,
+            set_cookie,
+            serde_json::from_slice(&body).unwrap_or_default(),
+        )
+    }
+
+    /// Register `email` and return its session cookie as a `Cookie` value.
+    async fn sign_up(state: &AppState, email: &str) -> String {
+        let (status, set_cookie, body) = account_request(
+            state,
+            "POST",
+            "/api/auth/register",
+            None,
+            Some(serde_json::json!({"email": email, "password": "correct horse"})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(body["user"]["email"], email);
+        let set_cookie = set_cookie.unwrap();
+        assert!(set_cookie.contains("HttpOnly"));
+        assert!(set_cookie.contains("SameSite=Lax"));
+        set_cookie.split(';').next().unwrap().to_string()
+    }
+
+    /// A look with one image, as a URL the backend signed.
+    fn look_body(state: &AppState, collection: &str) -> serde_json::Value {
+        let id = images::blob_id("image/png", b"png bytes").unwrap();
+        look_with_image(collection, &state.images.signed_url(&id))
+    }
+
+    fn look_with_image(collection: &str, image: &str) -> serde_json::Value {
+        serde_json::json!({
+            "collection": collection,
+            "prompt": "Low taper fade",
+            "inputImageRef": "uploads/selfie.jpg",
+            "variations": [{"image": image, "angle": "front"}],
+        })
+    }
+
+    #[tokio::test]
+    async fn test_accounts_save_list_and_delete_looks() {
+        let state = test_state(Arc::new(MockGenerator::default()));
+        let cookie = sign_up(&state, "sam@example.com").await;
+
+        let (status, _, body) =
+            account_request(&state, "GET", "/api/auth/me", Some(&cookie), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["user"]["email"], "sam@example.com");
+
+        let (status, _, saved) = account_request(
+            &state,
+            "POST",
+            "/api/looks",
+            Some(&cookie),
+            Some(look_body(&state, "Summer")),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(saved["inputImageRef"], "uploads/selfie.jpg");
+        account_request(
+            &state,
+            "POST",
+            "/api/looks",
+            Some(&cookie),
+            Some(look_body(&state, "Wedding")),
+        )
+        .await;
+
+        let (_, _, looks) = account_request(
+            &state,
+            "GET",
+            "/api/looks?collection=Summer",
+            Some(&cookie),
+            None,
+        )
+        .await;
+        assert_eq!(looks.as_array().unwrap().len(), 1);
+        assert_eq!(looks[0]["variations"][0]["angle"], "front");
+        let listed = looks[0]["variations"][0]["image"].as_str().unwrap();
+        assert_eq!(
+            state.images.issued_id(listed),
+            images::blob_id("image/png", b"png bytes")
+        );
+
+        let uri = format!("/api/looks/{}", saved["id"].as_str().unwrap());
+        let (status, _, _) = account_request(&state, "DELETE", &uri, Some(&cookie), None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _, _) = account_request(&state, "DELETE", &uri, Some(&cookie), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (_, _, looks) = account_request(&state, "GET", "/api/looks", Some(&cookie), None).await;
+        assert_eq!(looks.as_array().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_looks_need_a_session_and_stay_private() {
+        let state = test_state(Arc::new(MockGenerator::default()));
+        let (status, _, _) = account_request(
+            &state,
+            "POST",
+            "/api/looks",
+            None,
+            Some(look_body(&state, "Summer")),
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let sam = sign_up(&state, "sam@example.com").await;
+        let alex = sign_up(&state, "alex@example.com").await;
+        let (_, _, saved) = account_request(
+            &state,
+            "POST",
+            "/api/looks",
+            Some(&sam),
+            Some(look_body(&state, "Summer")),
+        )
+        .await;
+
+        let (_, _, looks) = account_request(&state, "GET", "/api/looks", Some(&alex), None).await;
+        assert!(looks.as_array().unwrap().is_empty());
+        let uri = format!("/api/looks/{}", saved["id"].as_str().unwrap());
+        let (status, _, _) = account_request(&state, "DELETE", &uri, Some(&alex), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_looks_only_keep_images_handed_out_here() {
+        let state = test_state(Arc::new(MockGenerator::default()));
+        let cookie = sign_up(&state, "sam@example.com").await;
+        let id = images::blob_id("image/png", b"png bytes").unwrap();
+        for image in [
+            format!("/images/{}?expires=1&signature=00", id),
+            format!("/images/{}", id),
+            format!("https://cdn.example.com/images/{}", id),
+            // Inline images are refused too, so looks can't host arbitrary uploads
+            GENERATED_IMAGE.to_string(),
+        ] {
+            let (status, _, body) = account_request(
+                &state,
+                "POST",
+                "/api/looks",
+                Some(&cookie),
+                Some(look_with_image("Summer", &image)),
+            )
+            .await;
+            assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY, "{}", image);
+            assert!(body["message"].as_str().unwrap().contains("expire"));
+        }
+        let (_, _, looks) = account_request(&state, "GET", "/api/looks", Some(&cookie), None).await;
+        assert!(looks.as_array().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_logins_are_rate_limited_apart_from_generation() {
+        let state = test_state(Arc::new(MockGenerator::default()));
+        let wrong = serde_json::json!({"email": "sam@example.com", "password": "wrong horse"});
+        for _ in 0..10 {
+            let (status, _, _) =
+                account_request(&state, "POST", "/api/auth/login", None, Some(wrong.clone())).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+        }
+        let (status, _, body) =
+            account_request(&state, "POST", "/api/auth/login", None, Some(wrong)).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert!(body["message"].as_str().unwrap().contains("Rate limit"));
+        let (status, _, _) = account_request(
+            &state,
+            "POST",
+            "/api/auth/register",
+            None,
+            Some(serde_json::json!({"email": "alex@example.com", "password": "correct horse"})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+
+        assert_eq!(post_generate(&state, false).await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_login_and_logout() {
+        let state = test_state(Arc::new(MockGenerator::default()));
+        sign_up(&state, "sam@example.com").await;
+
+        let (status, _, _) = account_request(
+            &state,
+            "POST",
+            "/api/auth/login",
+            None,
+            Some(serde_json::json!({"email": "sam@example.com", "password": "wrong horse"})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, set_cookie, _) = account_request(
+            &state,
+            "POST",
+            "/api/auth/login",
+            None,
+            Some(serde_json::json!({"email": "sam@example.com", "password": "correct horse"})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let cookie = set_cookie.unwrap().split(';').next().unwrap().to_string();
+
+        let (status, set_cookie, _) =
+            account_request(&state, "POST", "/api/auth/logout", Some(&cookie), None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert!(set_cookie.unwrap().contains("Max-Age=0"));
+
+        let (status, _, _) =
+            account_request(&state, "GET", "/api/auth/me", Some(&cookie), None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_registration_conflicts() {
+        let state = test_state(Arc::new(MockGenerator::default()));
+        sign_up(&state, "sam@example.com").await;
+        let (status, set_cookie, _) = account_request(
+            &state,
+            "POST",
+            "/api/auth/register",
+            None,
+            Some(serde_json::json!({"email": "sam@example.com", "password": "another one"})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(set_cookie, None);
+    }
+
+    #[tokio::test]
+    async fn test_generate_works_without_an_account() {
+        let state = test_state(Arc::new(MockGenerator::default()));
+        assert_eq!(post_generate(&state, false).await.status(), StatusCode::OK);
+    }
+
     #[test]
-    fn test_get_rate_limit_key() {
-        let ipv4 = IpAddr::from_str("192.168.1.1").unwrap();
-        let ipv6 = IpAddr::from_str("::1").unwrap();
+    fn test_cors_layer_rejects_bad_origins() {
+        assert!(cors_layer(&[]).is_ok());
+        assert!(cors_layer(&["http://localhost:3000".to_string()]).is_ok());
+        assert!(cors_layer(&["bad\norigin".to_string()]).is_err());
+    }
+
+    async fn get_image(
+        state: &AppState,
+        uri: &str,
+        headers: &[(&str, &str)],
+    ) -> axum::response::Response {
+        use tower::ServiceExt;
+
+        let mut request = axum::http::Request::get(uri);
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        let request = request.body(axum::body::Body::empty()).unwrap();
+        app(state.clone()).oneshot(request).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_generate_returns_signed_image_urls() {
+        let state = test_state(Arc::new(MockGenerator::default()));
+        let response = post_generate(&state, false).await;
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let url = body["variations"][0]["image"].as_str().unwrap().to_string();
+        assert!(url.starts_with("/images/"), "{}", url);
+
+        let response = get_image(&state, &url, &[]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            header_value(&response, "content-type").as_deref(),
+            Some("image/png")
+        );
+        let cache_control = header_value(&response, "cache-control").unwrap();
+        assert!(cache_control.starts_with("private, max-age="));
+        let etag = header_value(&response, "etag").unwrap();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(bytes.as_ref(), b"\x89PNG\r\n\x1a\n");
+
+        let response = get_image(&state, &url, &[("if-none-match", &etag)]).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    }
 
-        assert_eq!(get_rate_limit_key(&ipv4), "192.168.1.1");
-        assert_eq!(get_rate_limit_key(&ipv6), "::1");
+    #[tokio::test]
+    async fn test_image_urls_must_be_signed() {
+        let state = test_state(Arc::new(MockGenerator::default()));
+        let id = images::blob_id("image/png", &[0, 0, 0]).unwrap();
+        state
+            .images
+            .blobs()
+            .put(&id, "image/png", vec![0, 0, 0])
+            .await
+            .unwrap();
+
+        let unsigned = format!("/images/{}", id);
+        assert_eq!(
+            get_image(&state, &unsigned, &[]).await.status(),
+            StatusCode::FORBIDDEN
+        );
+        let forged = format!("/images/{}?expires=99999999999&signature=00", id);
+        assert_eq!(
+            get_image(&state, &forged, &[]).await.status(),
+            StatusCode::FORBIDDEN
+        );
+        let url = state.images.signed_url(&id);
+        assert_eq!(get_image(&state, &url, &[]).await.status(), StatusCode::OK);
+        assert_eq!(
+            get_image(&state, "/images/..%2Fauth.db?expires=1&signature=00", &[])
+                .await
+                .status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[tokio::test]
+    async fn test_data_urls_on_request() {
+        let state = test_state(Arc::new(MockGenerator::default()));
+        let response = generate_haircut_image(
+            Json(GenerateRequest {
+                response_format: ResponseFormat::DataUrl,
+                ..test_request(false).0
+            }),
+            &state,
+            "client",
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.variations[0].image, GENERATED_IMAGE);
+    }
+
+    #[tokio::test]
+    async fn test_identical_requests_are_served_from_cache() {
+        let generator = Arc::new(MockGenerator::default());
+        let state = test_state(generator.clone());
+
+        let first = generate_haircut_image(test_request(false), &state, "client")
+            .await
+            .unwrap();
+        let second = generate_haircut_image(test_request(false), &state, "client")
+            .await
+            .unwrap();
+        assert!(!first.cached);
+        assert!(second.cached);
+        // Same stored image; the signed expiry can tick over between requests
+        let path = |url: &str| url.split('?').next().unwrap().to_string();
+        assert_eq!(
+            path(&second.variations[0].image),
+            path(&first.variations[0].image)
+        );
+        assert_eq!(generator.prompts.lock().unwrap().len(), 1);
+
+        // Asking for angles is a different result
+        let angles = generate_haircut_image(test_request(true), &state, "client")
+            .await
+            .unwrap();
+        assert!(!angles.cached);
+        assert_eq!(generator.prompts.lock().unwrap().len(), 2);
     }
 }
@@ -0,0 +1,699 @@
+//! Remove metadata from uploads before their bytes leave the server.
+//!
+//! Phone photos carry GPS coordinates, device serials and capture times in
+//! EXIF, XMP and IPTC blocks. Each container is rewritten keeping only what
+//! changes how the pixels render (color profiles, animation control) plus a
+//! fresh EXIF block holding nothing but the orientation, so the photo still
+//! displays upright. Data appended after the image, like the video in a
+//! motion photo, is dropped. HEIC boxes point at each other by byte offset,
+//! so there the metadata items are zeroed in place instead.
+
+use crate::container::{
+    boxes, color_table_len, find_box, slice, sub_blocks_end, truncated, u16_be, u32_be, u32_le,
+    u64_be, BoxRange,
+};
+use crate::services::generator::InputImage;
+use crate::services::image_format::ImageFormat;
+use std::error::Error;
+
+pub type MetadataError = Box<dyn Error + Send + Sync>;
+
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+const ORIENTATION_TAG: u16 = 0x0112;
+
+/// Strip `image` of everything but its pixels and orientation.
+pub fn strip(image: InputImage) -> Result<InputImage, MetadataError> {
+    let data = match image.format {
+        ImageFormat::Jpeg => strip_jpeg(&image.data)?,
+        ImageFormat::Png => strip_png(&image.data)?,
+        ImageFormat::Webp => strip_webp(&image.data)?,
+        ImageFormat::Gif => strip_gif(&image.data)?,
+        ImageFormat::Heic => scrub_heic(image.data)?,
+    };
+    Ok(InputImage {
+        data,
+        format: image.format,
+    })
+}
+
+/// The orientation in a TIFF-structured EXIF block, if it rotates or flips.
+fn exif_orientation(tiff: &[u8]) -> Option<u16> {
+    let big_endian = match tiff.get(..2)? {
+        b"MM" => true,
+        b"II" => false,
+        _ => return None,
+    };
+    let read16 = |at: usize| {
+        let bytes = tiff.get(at..at.checked_add(2)?)?.try_into().ok()?;
+        Some(if big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    };
+    let ifd = {
+        let bytes = tiff.get(4..8)?.try_into().ok()?;
+        if big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        }
+    } as usize;
+    let count = read16(ifd)? as usize;
+    (0..count)
+        .find_map(|i| {
+            let entry = ifd + 2 + i * 12;
+            if read16(entry)? != ORIENTATION_TAG {
+                return None;
+            }
+            read16(entry + 8)
+        })
+        .filter(|orientation| (2..=8).contains(orientation))
+}
+
+/// A TIFF block whose only tag is the orientation.
+fn orientation_exif(orientation: u16) -> Vec<u8> {
+    let mut tiff = b"MM\x00\x2a\x00\x00\x00\x08\x00\x01".to_vec();
+    tiff.extend_from_slice(&ORIENTATION_TAG.to_be_bytes());
+    // SHORT, one value, padded to four bytes, then no next IFD
+    tiff.extend_from_slice(b"\x00\x03\x00\x00\x00\x01");
+    tiff.extend_from_slice(&orientation.to_be_bytes());
+    tiff.extend_from_slice(&[0; 6]);
+    tiff
+}
+
+fn strip_jpeg(data: &[u8]) -> Result<Vec<u8>, MetadataError> {
+    const APP0: u8 = 0xE0;
+    const APP1: u8 = 0xE1;
+    const APP2: u8 = 0xE2;
+    const APP14: u8 = 0xEE;
+    const COM: u8 = 0xFE;
+    const SOS: u8 = 0xDA;
+    const EOI: u8 = 0xD9;
+
+    let mut kept: Vec<(u8, &[u8])> = Vec::new();
+    let mut orientation = None;
+    let mut at = 2;
+    loop {
+        if *data.get(at).ok_or_else(truncated)? != 0xFF {
+            return Err("Malformed JPEG marker".into());
+        }
+        // Markers may be padded with any number of fill bytes
+        while data.get(at + 1) == Some(&0xFF) {
+            at += 1;
+        }
+        let marker = *data.get(at + 1).ok_or_else(truncated)?;
+        if marker == EOI {
+            kept.push((marker, &data[at..at + 2]));
+            break;
+        }
+        let length = u16_be(data, at + 2)? as usize;
+        if length < 2 {
+            return Err("Malformed JPEG segment".into());
+        }
+        let payload = slice(data, at + 4, length - 2)?;
+        let end = if marker == SOS {
+            scan_end(data, at + 2 + length)?
+        } else {
+            at + 2 + length
+        };
+        match marker {
+            APP1 if payload.starts_with(EXIF_HEADER) => {
+                orientation =
+                    orientation.or_else(|| exif_orientation(&payload[EXIF_HEADER.len()..]));
+            }
+            APP0 if payload.starts_with(b"JFIF\0") => kept.push((marker, &data[at..end])),
+            APP2 if payload.starts_with(b"ICC_PROFILE\0") => kept.push((marker, &data[at..end])),
+            APP14 if payload.starts_with(b"Adobe") => kept.push((marker, &data[at..end])),
+            // XMP, IPTC, maker data, thumbnails and comments
+            0xE0..=0xEF | COM => {}
+            _ => kept.push((marker, &data[at..end])),
+        }
+        at = end;
+    }
+
+    let mut out = data[..2].to_vec();
+    // JFIF wants to come first; the orientation goes right after it
+    let leading = kept
+        .iter()
+        .take_while(|(marker, _)| *marker == APP0)
+        .count();
+    for (_, segment) in &kept[..leading] {
+        out.extend_from_slice(segment);
+    }
+    if let Some(orientation) = orientation {
+        let tiff = orientation_exif(orientation);
+        out.extend_from_slice(&[0xFF, APP1]);
+        out.extend_from_slice(&((2 + EXIF_HEADER.len() + tiff.len()) as u16).to_be_bytes());
+        out.extend_from_slice(EXIF_HEADER);
+        out.extend_from_slice(&tiff);
+    }
+    for (_, segment) in &kept[leading..] {
+        out.extend_from_slice(segment);
+    }
+    Ok(out)
+}
+
+/// Where the entropy-coded data starting at `at` ends. It escapes 0xFF as
+/// `FF 00` and has restart markers, so any other `FF xx` is the next marker.
+fn scan_end(data: &[u8], mut at: usize) -> Result<usize, MetadataError> {
+    loop {
+        at += data
+            .get(at..)
+            .and_then(|rest| rest.iter().position(|&b| b == 0xFF))
+            .ok_or_else(truncated)?;
+        match data.get(at + 1) {
+            Some(0x00 | 0xD0..=0xD7) => at += 2,
+            Some(_) => return Ok(at),
+            None => return Err(truncated()),
+        }
+    }
+}
+
+/// Ancillary PNG chunks that affect rendering or animation.
+const PNG_KEEP: [&[u8]; 12] = [
+    b"tRNS", b"gAMA", b"cHRM", b"sRGB", b"iCCP", b"cICP", b"sBIT", b"bKGD", b"pHYs", b"acTL",
+    b"fcTL", b"fdAT",
+];
+
+fn strip_png(data: &[u8]) -> Result<Vec<u8>, MetadataError> {
+    let mut out = data[..8].to_vec();
+    let mut at = 8;
+    loop {
+        let length = u32_be(data, at)? as usize;
+        let chunk = slice(data, at, length.checked_add(12).ok_or_else(truncated)?)?;
+        let kind = &chunk[4..8];
+        match kind {
+            b"eXIf" => {
+                if let Some(orientation) = exif_orientation(&chunk[8..8 + length]) {
+                    out.extend_from_slice(&png_chunk(b"eXIf", &orientation_exif(orientation)));
+                }
+            }
+            // Critical chunks are the uppercase ones
+            _ if kind[0].is_ascii_uppercase() || PNG_KEEP.contains(&kind) => {
+                out.extend_from_slice(chunk)
+            }
+            _ => {}
+        }
+        at += chunk.len();
+        if kind == b"IEND" {
+            return Ok(out);
+        }
+    }
+}
+
+fn png_chunk(kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(kind);
+    crc.update(data);
+    let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+    chunk.extend_from_slice(kind);
+    chunk.extend_from_slice(data);
+    chunk.extend_from_slice(&crc.finalize().to_be_bytes());
+    chunk
+}
+
+fn strip_webp(data: &[u8]) -> Result<Vec<u8>, MetadataError> {
+    const VP8X_EXIF: u8 = 0x08;
+    const VP8X_XMP: u8 = 0x04;
+
+    let riff_end = (u32_le(data, 4)? as usize)
+        .checked_add(8)
+        .ok_or_else(truncated)?;
+    slice(data, 0, riff_end)?;
+
+    let mut out = data[..12].to_vec();
+    let mut orientation = None;
+    let mut vp8x_flags = None;
+    let mut at = 12;
+    while at + 8 <= riff_end {
+        let kind = &data[at..at + 4];
+        let size = u32_le(data, at + 4)? as usize;
+        let payload = slice(data, at + 8, size)?;
+        // Chunks are padded to an even size, though some writers drop the
+        // final pad byte
+        let end = (at + 8 + size + (size & 1)).min(riff_end);
+        match kind {
+            b"EXIF" => {
+                let tiff = payload.strip_prefix(EXIF_HEADER).unwrap_or(payload);
+                orientation = orientation.or_else(|| exif_orientation(tiff));
+            }
+            b"VP8X" if size > 0 => {
+                // The metadata flags are set again once we know what's left
+                let flags = out.len() + 8;
+                out.extend_from_slice(&data[at..end]);
+                out[flags] &= !(VP8X_EXIF | VP8X_XMP);
+                vp8x_flags = Some(flags);
+            }
+            b"VP8 " | b"VP8L" | b"ALPH" | b"ANIM" | b"ANMF" | b"ICCP" => {
+                out.extend_from_slice(&data[at..end])
+            }
+            _ => {}
+        }
+        if (end - at) % 2 == 1 {
+            out.push(0);
+        }
+        at = end;
+    }
+
+    // Only the extended format can carry EXIF, and it goes after the image
+    if let (Some(orientation), Some(flags)) = (orientation, vp8x_flags) {
+        let tiff = orientation_exif(orientation);
+        out.extend_from_slice(b"EXIF");
+        out.extend_from_slice(&(tiff.len() as u32).to_le_bytes());
+        out.extend_from_slice(&tiff);
+        out[flags] |= VP8X_EXIF;
+    }
+    let riff_size = (out.len() - 8) as u32;
+    out[4..8].copy_from_slice(&riff_size.to_le_bytes());
+    Ok(out)
+}
+
+fn strip_gif(data: &[u8]) -> Result<Vec<u8>, MetadataError> {
+    let mut at = 13 + color_table_len(*data.get(10).ok_or_else(truncated)?);
+    let mut out = slice(data, 0, at)?.to_vec();
+    loop {
+        match *data.get(at).ok_or_else(truncated)? {
+            // Extension
+            0x21 => {
+                let label = *data.get(at + 1).ok_or_else(truncated)?;
+                let end = sub_blocks_end(data, at + 2)?;
+                let keep = match label {
+                    // Graphic control and plain text
+                    0xF9 | 0x01 => true,
+                    // Application blocks: keep only animation looping
+                    0xFF => matches!(
+                        data.get(at + 3..at + 14),
+                        Some(b"NETSCAPE2.0" | b"ANIMEXTS1.0")
+                    ),
+                    // Comments
+                    _ => false,
+                };
+                if keep {
+                    out.extend_from_slice(&data[at..end]);
+                }
+                at = end;
+            }
+            // Image descriptor, local color table, LZW code size, image data
+            0x2C => {
+                let flags = *data.get(at + 9).ok_or_else(truncated)?;
+                let end = sub_blocks_end(data, at + 10 + color_table_len(flags) + 1)?;
+                out.extend_from_slice(&data[at..end]);
+                at = end;
+            }
+            0x3B => {
+                out.push(0x3B);
+                return Ok(out);
+            }
+            _ => return Err("Malformed GIF block".into()),
+        }
+    }
+}
+
+/// Zero the EXIF and XMP items of a HEIF file, leaving every offset intact.
+/// Orientation lives in `irot`/`imir` properties there, not EXIF.
+fn scrub_heic(mut data: Vec<u8>) -> Result<Vec<u8>, MetadataError> {
+    let meta = find_box(&data, 0, data.len(), b"meta")?.ok_or("HEIC file has no meta box")?;
+    // meta is a full box: version and flags come first
+    let children = meta.content + 4;
+    let iinf = find_box(&data, children, meta.end, b"iinf")?;
+    let iloc = find_box(&data, children, meta.end, b"iloc")?;
+    let idat = find_box(&data, children, meta.end, b"idat")?;
+    let (Some(iinf), Some(iloc)) = (iinf, iloc) else {
+        return Err("HEIC file has no item table".into());
+    };
+
+    let items = metadata_items(&data, iinf)?;
+    for (method, offset, length) in item_extents(&data, iloc, &items)? {
+        let start = match (method, idat) {
+            (0, _) => offset,
+            (1, Some(idat)) => idat.content.checked_add(offset).ok_or_else(truncated)?,
+            _ => return Err("Unsupported HEIC item location".into()),
+        };
+        let end = start.checked_add(length).ok_or_else(truncated)?;
+        data.get_mut(start..end).ok_or_else(truncated)?.fill(0);
+    }
+    Ok(data)
+}
+
+/// Ids of the EXIF and XMP items listed in `iinf`.
+fn metadata_items(data: &[u8], iinf: BoxRange) -> Result<Vec<u32>, MetadataError> {
+    let version = *data.get(iinf.content).ok_or_else(truncated)?;
+    let first_entry = iinf.content + if version == 0 { 6 } else { 8 };
+    let mut items = Vec::new();
+    for (kind, infe) in boxes(data, first_entry, iinf.end)? {
+        let infe_version = *data.get(infe.content).ok_or_else(truncated)?;
+        if &kind != b"infe" || infe_version < 2 {
+            continue;
+        }
+        let (id, mut at) = if infe_version == 2 {
+            (u16_be(data, infe.content + 4)? as u32, infe.content + 6)
+        } else {
+            (u32_be(data, infe.content + 4)?, infe.content + 8)
+        };
+        at += 2; // protection index
+        let item_type = slice(data, at, 4)?;
+        let strings = data.get(at + 4..infe.end).ok_or_else(truncated)?;
+        // item_name, then content_type for MIME items
+        let content_type = strings.split(|&b| b == 0).nth(1).unwrap_or_default();
+        if item_type == b"Exif" || (item_type == b"mime" && content_type == b"application/rdf+xml")
+        {
+            items.push(id);
+        }
+    }
+    Ok(items)
+}
+
+/// `(construction method, offset, length)` of every extent of `items`.
+fn item_extents(
+    data: &[u8],
+    iloc: BoxRange,
+    items: &[u32],
+) -> Result<Vec<(u16, usize, usize)>, MetadataError> {
+    fn sized(data: &[u8], at: &mut usize, size: u16) -> Result<usize, MetadataError> {
+        let value = match size {
+            0 => 0,
+            4 => u32_be(data, *at)? as usize,
+            8 => u64_be(data, *at)? as usize,
+            _ => return Err("Unsupported HEIC offset size".into()),
+        };
+        *at += size as usize;
+        Ok(value)
+    }
+
+    let version = *data.get(iloc.content).ok_or_else(truncated)?;
+    let sizes = u16_be(data, iloc.content + 4)?;
+    let (offset_size, length_size, base_offset_size) =
+        (sizes >> 12, (sizes >> 8) & 0xF, (sizes >> 4) & 0xF);
+    let index_size = if version == 1 || version == 2 {
+        sizes & 0xF
+    } else {
+        0
+    };
+    let mut at = iloc.content + 6;
+    let item_count = if version < 2 {
+        at += 2;
+        u16_be(data, at - 2)? as u32
+    } else {
+        at += 4;
+        u32_be(data, at - 4)?
+    };
+
+    let mut extents = Vec::new();
+    for _ in 0..item_count {
+        let id = if version < 2 {
+            at += 2;
+            u16_be(data, at - 2)? as u32
+        } else {
+            at += 4;
+            u32_be(data, at - 4)?
+        };
+        let method = if version == 1 || version == 2 {
+            at += 2;
+            u16_be(data, at - 2)? & 0xF
+        } else {
+            0
+        };
+        at += 2; // data reference index
+        let base = sized(data, &mut at, base_offset_size)?;
+        let extent_count = u16_be(data, at)?;
+        at += 2;
+        for _ in 0..extent_count {
+            sized(data, &mut at, index_size)?;
+            let offset = sized(data, &mut at, offset_size)?;
+            let length = sized(data, &mut at, length_size)?;
+            if items.contains(&id) {
+                if length == 0 {
+                    return Err("Unsupported HEIC item location".into());
+                }
+                extents.push((
+                    method,
+                    base.checked_add(offset).ok_or_else(truncated)?,
+                    length,
+                ));
+            }
+        }
+    }
+    Ok(extents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::codecs::jpeg::JpegEncoder;
+    use image::{ImageDecoder, Rgb, RgbImage};
+    use std::io::Cursor;
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack
+            .windows(needle.len())
+            .any(|window| window == needle)
+    }
+
+    /// Big-endian EXIF with orientation 6 and a GPS IFD holding a map datum.
+    fn exif_with_gps() -> Vec<u8> {
+        let mut tiff = b"MM\x00\x2a\x00\x00\x00\x08\x00\x02".to_vec();
+        tiff.extend_from_slice(b"\x01\x12\x00\x03\x00\x00\x00\x01\x00\x06\x00\x00");
+        // GPSInfo pointer to the IFD at 38
+        tiff.extend_from_slice(b"\x88\x25\x00\x04\x00\x00\x00\x01\x00\x00\x00\x26");
+        tiff.extend_from_slice(&[0, 0, 0, 0]);
+        // GPSMapDatum, ASCII, stored at 56
+        tiff.extend_from_slice(b"\x00\x01\x00\x12\x00\x02\x00\x00\x00\x0e\x00\x00\x00\x38");
+        tiff.extend_from_slice(&[0, 0, 0, 0]);
+        tiff.extend_from_slice(b"WGS-84-SECRET\0");
+        tiff
+    }
+
+    fn jpeg_segment(marker: u8, payload: &[u8]) -> Vec<u8> {
+        let mut segment = vec![0xFF, marker];
+        segment.extend_from_slice(&((payload.len() + 2) as u16).to_be_bytes());
+        segment.extend_from_slice(payload);
+        segment
+    }
+
+    fn plain_jpeg() -> Vec<u8> {
+        let mut jpeg = Vec::new();
+        RgbImage::from_pixel(16, 8, Rgb([90, 60, 40]))
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut jpeg, 90))
+            .unwrap();
+        jpeg
+    }
+
+    fn phone_jpeg() -> Vec<u8> {
+        let jpeg = plain_jpeg();
+
+        let mut exif = EXIF_HEADER.to_vec();
+        exif.extend_from_slice(&exif_with_gps());
+        let mut data = jpeg[..2].to_vec();
+        data.extend(jpeg_segment(0xE1, &exif));
+        data.extend(jpeg_segment(
+            0xE1,
+            b"http://ns.adobe.com/xap/1.0/\0<exif:GPSLatitude>47,36.5N</exif:GPSLatitude>",
+        ));
+        data.extend(jpeg_segment(0xED, b"Photoshop 3.0\0IPTC city: Seattle"));
+        data.extend(jpeg_segment(0xFE, b"shot on serial 0xC0FFEE"));
+        data.extend_from_slice(&jpeg[2..]);
+        // Motion photos append a video after the image
+        data.extend_from_slice(b"ftypmp42 trailing video with location");
+        data
+    }
+
+    fn assert_no_private_data(data: &[u8]) {
+        for needle in [
+            &b"WGS-84"[..],
+            b"GPSLatitude",
+            b"Seattle",
+            b"C0FFEE",
+            b"trailing video",
+        ] {
+            assert!(
+                !contains(data, needle),
+                "{}",
+                String::from_utf8_lossy(needle)
+            );
+        }
+    }
+
+    #[test]
+    fn test_jpeg_gps_is_removed_and_orientation_kept() {
+        let stripped = strip(InputImage {
+            data: phone_jpeg(),
+            format: ImageFormat::Jpeg,
+        })
+        .unwrap();
+        assert_no_private_data(&stripped.data);
+        assert!(!contains(&stripped.data, &[0x88, 0x25]));
+
+        let mut decoder =
+            image::codecs::jpeg::JpegDecoder::new(Cursor::new(&stripped.data)).unwrap();
+        assert_eq!(
+            decoder.orientation().unwrap(),
+            image::metadata::Orientation::Rotate90
+        );
+        assert_eq!(decoder.dimensions(), (16, 8));
+    }
+
+    #[test]
+    fn test_jpeg_without_orientation_gets_no_exif() {
+        let mut exif = EXIF_HEADER.to_vec();
+        exif.extend_from_slice(&orientation_exif(6));
+        exif[EXIF_HEADER.len() + 19] = 1; // upright
+        let mut data = b"\xFF\xD8".to_vec();
+        data.extend(jpeg_segment(0xE1, &exif));
+        data.extend_from_slice(&plain_jpeg()[2..]);
+
+        let stripped = strip_jpeg(&data).unwrap();
+        assert!(!contains(&stripped, EXIF_HEADER));
+        assert!(image::load_from_memory(&stripped).is_ok());
+    }
+
+    #[test]
+    fn test_png_text_and_exif_chunks_are_removed() {
+        let mut png = Vec::new();
+        RgbImage::from_pixel(4, 4, Rgb([1, 2, 3]))
+            .write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        // Insert metadata chunks right after IHDR
+        let ihdr_end = 8 + 12 + 13;
+        let mut data = png[..ihdr_end].to_vec();
+        data.extend(png_chunk(b"eXIf", &exif_with_gps()));
+        data.extend(png_chunk(b"tEXt", b"Comment\0shot on serial 0xC0FFEE"));
+        data.extend(png_chunk(
+            b"iTXt",
+            b"XML:com.adobe.xmp\0\0\0\0\0<GPSLatitude>",
+        ));
+        data.extend_from_slice(&png[ihdr_end..]);
+
+        let stripped = strip_png(&data).unwrap();
+        assert_no_private_data(&stripped);
+        assert!(!contains(&stripped, b"tEXt"));
+        assert_eq!(
+            exif_orientation(&stripped[ihdr_end + 8..]),
+            Some(6),
+            "eXIf with just the orientation follows IHDR"
+        );
+        assert!(image::load_from_memory(&stripped).is_ok());
+    }
+
+    #[test]
+    fn test_webp_exif_and_xmp_chunks_are_removed() {
+        fn chunk(kind: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+            let mut chunk = kind.to_vec();
+            chunk.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+            chunk.extend_from_slice(payload);
+            if payload.len() % 2 == 1 {
+                chunk.push(0);
+            }
+            chunk
+        }
+        // VP8X with EXIF and XMP flags set, canvas 1x1
+        let mut body = b"WEBP".to_vec();
+        body.extend(chunk(b"VP8X", &[0x0C, 0, 0, 0, 0, 0, 0, 0, 0, 0]));
+        body.extend(chunk(b"VP8L", b"\x2f\x00\x00\x00\x00"));
+        body.extend(chunk(b"EXIF", &exif_with_gps()));
+        body.extend(chunk(b"XMP ", b"<GPSLatitude>47</GPSLatitude>"));
+        let mut data = b"RIFF".to_vec();
+        data.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        data.extend(body);
+
+        let stripped = strip_webp(&data).unwrap();
+        assert_no_private_data(&stripped);
+        assert!(!contains(&stripped, b"XMP "));
+        assert_eq!(stripped[20], 0x08, "only the EXIF flag stays");
+        assert_eq!(u32_le(&stripped, 4).unwrap() as usize, stripped.len() - 8);
+        let exif = stripped.len() - orientation_exif(6).len();
+        assert_eq!(exif_orientation(&stripped[exif..]), Some(6));
+    }
+
+    #[test]
+    fn test_gif_comments_and_xmp_are_removed() {
+        let mut gif = Vec::new();
+        image::RgbaImage::from_pixel(2, 2, image::Rgba([5, 5, 5, 255]))
+            .write_to(&mut Cursor::new(&mut gif), image::ImageFormat::Gif)
+            .unwrap();
+        let header_end = 13 + color_table_len(gif[10]);
+        let mut data = gif[..header_end].to_vec();
+        data.extend_from_slice(b"\x21\xFE\x17shot on serial 0xC0FFEE\x00");
+        data.extend_from_slice(b"\x21\xFF\x0BXMP DataXMP\x0D<GPSLatitude>\x00");
+        data.extend_from_slice(&gif[header_end..]);
+
+        let stripped = strip_gif(&data).unwrap();
+        assert_no_private_data(&stripped);
+        assert_eq!(stripped, gif);
+    }
+
+    #[test]
+    fn test_heic_exif_and_xmp_items_are_zeroed() {
+        fn full_box(kind: &[u8; 4], version: u8, body: &[u8]) -> Vec<u8> {
+            let mut data = ((body.len() + 12) as u32).to_be_bytes().to_vec();
+            data.extend_from_slice(kind);
+            data.extend_from_slice(&[version, 0, 0, 0]);
+            data.extend_from_slice(body);
+            data
+        }
+        fn infe(id: u16, item_type: &[u8; 4], strings: &[u8]) -> Vec<u8> {
+            let mut body = id.to_be_bytes().to_vec();
+            body.extend_from_slice(&[0, 0]);
+            body.extend_from_slice(item_type);
+            body.extend_from_slice(strings);
+            full_box(b"infe", 2, &body)
+        }
+        let image = b"hevc-coded-pixels".to_vec();
+        let exif = [
+            b"\0\0\0\x06".to_vec(),
+            EXIF_HEADER.to_vec(),
+            exif_with_gps(),
+        ]
+        .concat();
+        let xmp = b"<x:xmpmeta><GPSLatitude>47</GPSLatitude></x:xmpmeta>".to_vec();
+        let build = |mdat_start: u32| {
+            let mut iinf = 3u16.to_be_bytes().to_vec();
+            iinf.extend(infe(1, b"hvc1", b"\0"));
+            iinf.extend(infe(2, b"Exif", b"\0"));
+            iinf.extend(infe(3, b"mime", b"\0application/rdf+xml\0"));
+            let mut iloc = vec![0x44, 0x00, 0, 3];
+            let mut offset = mdat_start;
+            for (id, item) in [(1u16, &image), (2, &exif), (3, &xmp)] {
+                iloc.extend_from_slice(&id.to_be_bytes());
+                iloc.extend_from_slice(&[0, 0, 0, 1]);
+                iloc.extend_from_slice(&offset.to_be_bytes());
+                iloc.extend_from_slice(&(item.len() as u32).to_be_bytes());
+                offset += item.len() as u32;
+            }
+            let meta_body = [
+                full_box(b"hdlr", 0, b"\0\0\0\0pict\0\0\0\0\0\0\0\0\0\0\0\0\0"),
+                full_box(b"iinf", 0, &iinf),
+                full_box(b"iloc", 0, &iloc),
+            ]
+            .concat();
+            let mut data = b"\x00\x00\x00\x10ftypheic\x00\x00\x00\x00".to_vec();
+            data.extend(full_box(b"meta", 0, &meta_body));
+            let mdat = [image.clone(), exif.clone(), xmp.clone()].concat();
+            data.extend_from_slice(&((mdat.len() + 8) as u32).to_be_bytes());
+            data.extend_from_slice(b"mdat");
+            data.extend(mdat);
+            data
+        };
+        let draft = build(0);
+        let mdat_start = (draft.len() - image.len() - exif.len() - xmp.len()) as u32;
+        let data = build(mdat_start);
+
+        let stripped = strip(InputImage {
+            data: data.clone(),
+            format: ImageFormat::Heic,
+        })
+        .unwrap();
+        assert_eq!(stripped.data.len(), data.len());
+        assert_no_private_data(&stripped.data);
+        assert!(contains(&stripped.data, &image));
+        assert_eq!(ImageFormat::sniff(&stripped.data), Some(ImageFormat::Heic));
+    }
+
+    #[test]
+    fn test_truncated_files_are_errors() {
+        let jpeg = phone_jpeg();
+        assert!(strip_jpeg(&jpeg[..40]).is_err());
+        assert!(strip_png(b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR").is_err());
+        assert!(strip_gif(b"GIF89a\x01\x00\x01\x00\x00\x00\x00").is_err());
+    }
+}
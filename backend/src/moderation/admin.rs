@@ -0,0 +1,42 @@
+//! `GET /admin/moderation` for reviewing flagged and blocked content. Like
+//! the rest of `/admin`, it needs the admin token.
+
+use crate::{require_admin, AppState};
+use axum::{
+    extract::{Json, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+use tracing::error;
+
+const DEFAULT_LIMIT: usize = 100;
+const MAX_LIMIT: usize = 1000;
+
+#[derive(Deserialize)]
+pub struct AuditQuery {
+    limit: Option<usize>,
+}
+
+/// `GET /admin/moderation?limit=N`: the latest records, newest first.
+pub async fn list_records(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<AuditQuery>,
+) -> Response {
+    if let Err(rejection) = require_admin(&state, &headers) {
+        return rejection.into_response();
+    }
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+    match state.moderation.audit().recent(limit).await {
+        Ok(records) => Json(records).into_response(),
+        Err(err) => {
+            error!(error = %err, "Moderation audit log failed");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Moderation audit log failed",
+            )
+                .into_response()
+        }
+    }
+}
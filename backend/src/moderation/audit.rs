@@ -0,0 +1,188 @@
+//! Flag and block verdicts kept for review, in the same SQLite database as
+//! API keys and accounts.
+
+use super::{Decision, ModerationError, Verdict};
+use rusqlite::{params, Connection, Row};
+use serde::Serialize;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS moderation_audit (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        created_at INTEGER NOT NULL,
+        client TEXT NOT NULL,
+        kind TEXT NOT NULL,
+        angle TEXT,
+        verdict TEXT NOT NULL,
+        reasons TEXT NOT NULL,
+        moderator TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS moderation_audit_created_at
+        ON moderation_audit (created_at);
+";
+
+/// A verdict about to be recorded.
+pub struct NewRecord {
+    pub client: String,
+    /// "prompt", "image" or "output"
+    pub kind: &'static str,
+    /// The view, for outputs
+    pub angle: Option<String>,
+    pub moderator: &'static str,
+    pub decision: Decision,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditRecord {
+    pub id: i64,
+    pub created_at: u64,
+    /// The client key, as used for rate limiting and experiments
+    pub client: String,
+    pub kind: String,
+    pub angle: Option<String>,
+    pub verdict: Verdict,
+    pub reasons: Vec<String>,
+    pub moderator: String,
+}
+
+impl AuditRecord {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        let verdict: String = row.get("verdict")?;
+        let reasons: String = row.get("reasons")?;
+        Ok(AuditRecord {
+            id: row.get("id")?,
+            created_at: row.get::<_, i64>("created_at")? as u64,
+            client: row.get("client")?,
+            kind: row.get("kind")?,
+            angle: row.get("angle")?,
+            verdict: if verdict == "block" {
+                Verdict::Block
+            } else {
+                Verdict::Flag
+            },
+            reasons: serde_json::from_str(&reasons).unwrap_or_default(),
+            moderator: row.get("moderator")?,
+        })
+    }
+}
+
+pub struct AuditLog {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl AuditLog {
+    pub fn open(path: &Path) -> Result<Self, ModerationError> {
+        let connection = Connection::open(path)
+            .map_err(|err| format!("Failed to open {}: {}", path.display(), err))?;
+        Self::with_connection(connection)
+    }
+
+    /// A throwaway log, for tests.
+    #[cfg(test)]
+    pub fn open_in_memory() -> Result<Self, ModerationError> {
+        Self::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(connection: Connection) -> Result<Self, ModerationError> {
+        connection.execute_batch(SCHEMA)?;
+        Ok(AuditLog {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    /// Run `f` on the connection off the async runtime.
+    async fn with<T, F>(&self, f: F) -> Result<T, ModerationError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let connection = Arc::clone(&self.connection);
+        let result = tokio::task::spawn_blocking(move || f(&connection.lock().unwrap())).await?;
+        Ok(result?)
+    }
+
+    pub async fn record(&self, record: NewRecord) -> Result<(), ModerationError> {
+        let reasons = serde_json::to_string(&record.decision.reasons)?;
+        let verdict = record.decision.verdict.as_str();
+        self.with(move |conn| {
+            conn.execute(
+                "INSERT INTO moderation_audit
+                     (created_at, client, kind, angle, verdict, reasons, moderator)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    now_secs() as i64,
+                    record.client,
+                    record.kind,
+                    record.angle,
+                    verdict,
+                    reasons,
+                    record.moderator
+                ],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// The latest `limit` records, newest first.
+    pub async fn recent(&self, limit: usize) -> Result<Vec<AuditRecord>, ModerationError> {
+        self.with(move |conn| {
+            let mut statement =
+                conn.prepare("SELECT * FROM moderation_audit ORDER BY id DESC LIMIT ?1")?;
+            let records = statement
+                .query_map(params![limit as i64], AuditRecord::from_row)?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(records)
+        })
+        .await
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(client: &str, verdict: Verdict, reason: &str) -> NewRecord {
+        NewRecord {
+            client: client.to_string(),
+            kind: "prompt",
+            angle: None,
+            moderator: "rules",
+            decision: Decision {
+                verdict,
+                reasons: vec![reason.to_string()],
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn test_records_come_back_newest_first() {
+        let log = AuditLog::open_in_memory().unwrap();
+        log.record(record("a", Verdict::Flag, "mentions \"gun\""))
+            .await
+            .unwrap();
+        log.record(record("b", Verdict::Block, "mentions \"nude\""))
+            .await
+            .unwrap();
+
+        let records = log.recent(10).await.unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].client, "b");
+        assert_eq!(records[0].verdict, Verdict::Block);
+        assert_eq!(records[0].reasons, vec!["mentions \"nude\""]);
+        assert_eq!(records[1].verdict, Verdict::Flag);
+        assert!(records[1].created_at > 0);
+
+        assert_eq!(log.recent(1).await.unwrap().len(), 1);
+    }
+}
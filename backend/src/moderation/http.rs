@@ -0,0 +1,238 @@
+//! A moderator that asks an external service.
+//!
+//! Each check is one `POST` of JSON to the configured URL:
+//!
+//! - `{"kind": "prompt", "text": ...}`
+//! - `{"kind": "image", "mimeType": ..., "data": <base64>}`
+//! - `{"kind": "output", "angle": ..., "mimeType": ..., "data": <base64>}`
+//!
+//! and the service answers `{"verdict": "allow" | "flag" | "block",
+//! "reasons": [...]}`. Anything that speaks this, or a small adapter in
+//! front of a vendor API, can be plugged in.
+
+use super::{Content, Decision, ModerationError, Moderator};
+use crate::config::ModerationConfig;
+use async_trait::async_trait;
+use base64::{engine::general_purpose, Engine as _};
+use serde_json::{json, Value};
+use std::time::Duration;
+use tracing::error;
+
+pub struct HttpModerator {
+    client: reqwest::Client,
+    url: String,
+    api_key: Option<String>,
+}
+
+impl HttpModerator {
+    pub fn new(
+        url: &str,
+        api_key: Option<String>,
+        timeout: Duration,
+    ) -> Result<Self, ModerationError> {
+        Ok(HttpModerator {
+            client: reqwest::Client::builder().timeout(timeout).build()?,
+            url: url.to_string(),
+            api_key,
+        })
+    }
+
+    pub fn from_config(config: &ModerationConfig) -> Result<Self, ModerationError> {
+        let url = config
+            .url
+            .as_deref()
+            .ok_or("MODERATION_URL is required for the http moderation provider")?;
+        Self::new(
+            url,
+            config.api_key.clone(),
+            Duration::from_secs(config.timeout_secs),
+        )
+    }
+
+    fn body(content: Content<'_>) -> Result<Value, ModerationError> {
+        Ok(match content {
+            Content::Prompt(text) => json!({ "kind": "prompt", "text": text }),
+            Content::Image(image) => json!({
+                "kind": "image",
+                "mimeType": image.format.mime_type(),
+                "data": general_purpose::STANDARD.encode(&image.data),
+            }),
+            Content::Output(variation) => {
+                // Already base64, so passed through as is
+                let (mime_type, data) = variation
+                    .image
+                    .strip_prefix("data:")
+                    .and_then(|url| url.split_once(";base64,"))
+                    .ok_or("Generated image is not a data URL")?;
+                json!({
+                    "kind": "output",
+                    "angle": variation.angle,
+                    "mimeType": mime_type,
+                    "data": data,
+                })
+            }
+        })
+    }
+}
+
+#[async_trait]
+impl Moderator for HttpModerator {
+    fn name(&self) -> &'static str {
+        "http"
+    }
+
+    async fn moderate(&self, content: Content<'_>) -> Result<Decision, ModerationError> {
+        let mut request = self.client.post(&self.url).json(&Self::body(content)?);
+        if let Some(api_key) = &self.api_key {
+            request = request.bearer_auth(api_key);
+        }
+        let response = request.send().await?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            let error_text = response
+                .text()
+                .await
+                .unwrap_or_else(|err| format!("Failed to read error body: {}", err));
+            error!(%status, body = %error_text, "Moderation service error");
+            return Err(format!("Moderation service error: {} - {}", status, error_text).into());
+        }
+
+        let response_text = response.text().await?;
+        let decision: Decision = serde_json::from_str(&response_text)
+            .map_err(|err| format!("Unexpected moderation response: {}", err))?;
+        Ok(decision)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::moderation::Verdict;
+    use crate::services::generator::{ImageVariation, InputImage};
+    use crate::services::image_format::ImageFormat;
+    use axum::{extract::State, http::HeaderMap, routing::post, Json, Router};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Default)]
+    struct Received {
+        bodies: Vec<Value>,
+        authorization: Option<String>,
+    }
+
+    /// Blocks anything mentioning "nude", flags outputs and allows the rest.
+    async fn moderate(
+        State(received): State<Arc<Mutex<Received>>>,
+        headers: HeaderMap,
+        Json(body): Json<Value>,
+    ) -> Json<Value> {
+        let mut received = received.lock().unwrap();
+        received.authorization = headers
+            .get("authorization")
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+        let reply = match body["kind"].as_str() {
+            Some("prompt") if body["text"].as_str().unwrap().contains("nude") => {
+                json!({ "verdict": "block", "reasons": ["sexual content"] })
+            }
+            Some("output") => json!({ "verdict": "flag", "reasons": ["needs review"] }),
+            _ => json!({ "verdict": "allow" }),
+        };
+        received.bodies.push(body);
+        Json(reply)
+    }
+
+    async fn spawn_stand_in() -> (String, Arc<Mutex<Received>>) {
+        let received = Arc::new(Mutex::new(Received::default()));
+        let app = Router::new()
+            .route("/moderate", post(moderate))
+            .route(
+                "/broken",
+                post(|| async { (axum::http::StatusCode::BAD_GATEWAY, "upstream down") }),
+            )
+            .with_state(Arc::clone(&received));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (format!("http://{}", addr), received)
+    }
+
+    fn moderator(url: &str) -> HttpModerator {
+        HttpModerator::new(url, Some("mod-key".to_string()), Duration::from_secs(5)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_prompt_verdicts_and_auth() {
+        let (base_url, received) = spawn_stand_in().await;
+        let moderator = moderator(&format!("{}/moderate", base_url));
+
+        let blocked = moderator
+            .moderate(Content::Prompt("nude portrait"))
+            .await
+            .unwrap();
+        assert_eq!(blocked.verdict, Verdict::Block);
+        assert_eq!(blocked.reasons, vec!["sexual content"]);
+
+        let allowed = moderator
+            .moderate(Content::Prompt("buzz cut"))
+            .await
+            .unwrap();
+        assert_eq!(allowed, Decision::allow());
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.authorization.as_deref(), Some("Bearer mod-key"));
+        assert_eq!(
+            received.bodies[1],
+            json!({ "kind": "prompt", "text": "buzz cut" })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_images_are_sent_as_base64() {
+        let (base_url, received) = spawn_stand_in().await;
+        let moderator = moderator(&format!("{}/moderate", base_url));
+
+        let image = InputImage {
+            data: vec![0xFF, 0xD8, 0xFF, 0xE0],
+            format: ImageFormat::Jpeg,
+        };
+        moderator.moderate(Content::Image(&image)).await.unwrap();
+        let output = ImageVariation {
+            image: "data:image/png;base64,iVBORw0KGgo=".to_string(),
+            angle: "side".to_string(),
+        };
+        let flagged = moderator.moderate(Content::Output(&output)).await.unwrap();
+        assert_eq!(flagged.verdict, Verdict::Flag);
+
+        let received = received.lock().unwrap();
+        assert_eq!(
+            received.bodies[0],
+            json!({ "kind": "image", "mimeType": "image/jpeg", "data": "/9j/4A==" })
+        );
+        assert_eq!(
+            received.bodies[1],
+            json!({
+                "kind": "output",
+                "angle": "side",
+                "mimeType": "image/png",
+                "data": "iVBORw0KGgo=",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_service_errors_are_errors() {
+        let (base_url, _) = spawn_stand_in().await;
+        let err = moderator(&format!("{}/broken", base_url))
+            .moderate(Content::Prompt("buzz cut"))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("502"), "{}", err);
+
+        // Nothing listening
+        assert!(moderator("http://127.0.0.1:1/moderate")
+            .moderate(Content::Prompt("buzz cut"))
+            .await
+            .is_err());
+    }
+}
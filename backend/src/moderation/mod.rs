@@ -0,0 +1,302 @@
+//! Content moderation on what goes into a generation and what comes out.
+//!
+//! The prompt (with the style fields), the uploaded photo and every generated
+//! view are each handed to a [`Moderator`], which answers allow, flag or
+//! block with its reasons. Blocked requests are turned away and blocked
+//! outputs dropped before anything is stored or sent. Flags let the content
+//! through but, like blocks, leave a record in the [`AuditLog`].
+//!
+//! Which moderator runs is a deployment choice: the built-in word rules, or
+//! any moderation service behind [`http::HttpModerator`].
+
+pub mod admin;
+pub mod audit;
+pub mod http;
+pub mod rules;
+
+use crate::config::ModerationConfig;
+use crate::services::generator::{ImageVariation, InputImage};
+use async_trait::async_trait;
+pub use audit::AuditLog;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::sync::Arc;
+use tracing::{debug, error, warn};
+
+pub type ModerationError = Box<dyn Error + Send + Sync>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Verdict {
+    Allow,
+    /// Let through, but recorded for review
+    Flag,
+    Block,
+}
+
+impl Verdict {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Verdict::Allow => "allow",
+            Verdict::Flag => "flag",
+            Verdict::Block => "block",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Decision {
+    pub verdict: Verdict,
+    #[serde(default)]
+    pub reasons: Vec<String>,
+}
+
+impl Decision {
+    pub fn allow() -> Self {
+        Decision {
+            verdict: Verdict::Allow,
+            reasons: Vec::new(),
+        }
+    }
+
+    pub fn is_blocked(&self) -> bool {
+        self.verdict == Verdict::Block
+    }
+}
+
+/// Something to moderate.
+#[derive(Debug, Clone, Copy)]
+pub enum Content<'a> {
+    /// The client's own text: prompt and style fields
+    Prompt(&'a str),
+    /// The photo as it will be sent to the provider
+    Image(&'a InputImage),
+    /// A generated view, as a data URL
+    Output(&'a ImageVariation),
+}
+
+impl Content<'_> {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Content::Prompt(_) => "prompt",
+            Content::Image(_) => "image",
+            Content::Output(_) => "output",
+        }
+    }
+}
+
+#[async_trait]
+pub trait Moderator: Send + Sync {
+    /// Short name used in logs and audit records.
+    fn name(&self) -> &'static str;
+
+    async fn moderate(&self, content: Content<'_>) -> Result<Decision, ModerationError>;
+}
+
+/// Build the moderator selected by `config.provider`, or `None` when
+/// moderation is off.
+pub fn build_moderator(
+    config: &ModerationConfig,
+) -> Result<Option<Arc<dyn Moderator>>, ModerationError> {
+    if !config.enabled {
+        return Ok(None);
+    }
+    match config.provider.as_str() {
+        "rules" => Ok(Some(Arc::new(rules::RulesModerator::from_config(config)))),
+        "http" => Ok(Some(Arc::new(http::HttpModerator::from_config(config)?))),
+        other => Err(format!("Unknown moderation provider: {}", other).into()),
+    }
+}
+
+/// A moderator with its audit log and failure policy, as handlers use it.
+pub struct Moderation {
+    moderator: Option<Arc<dyn Moderator>>,
+    audit: Arc<AuditLog>,
+    fail_open: bool,
+}
+
+impl Moderation {
+    pub fn new(
+        moderator: Option<Arc<dyn Moderator>>,
+        audit: Arc<AuditLog>,
+        fail_open: bool,
+    ) -> Self {
+        Moderation {
+            moderator,
+            audit,
+            fail_open,
+        }
+    }
+
+    pub fn audit(&self) -> &AuditLog {
+        &self.audit
+    }
+
+    /// Moderate `content` on behalf of `client`. A moderator that fails
+    /// counts as a block, unless `fail_open` is set.
+    pub async fn check(&self, content: Content<'_>, client: &str) -> Decision {
+        let Some(moderator) = &self.moderator else {
+            return Decision::allow();
+        };
+        let decision = match moderator.moderate(content).await {
+            Ok(decision) => decision,
+            Err(err) if self.fail_open => {
+                warn!(moderator = moderator.name(), kind = content.kind(), error = %err, "Moderation failed, allowing");
+                return Decision::allow();
+            }
+            Err(err) => {
+                error!(moderator = moderator.name(), kind = content.kind(), error = %err, "Moderation failed, blocking");
+                Decision {
+                    verdict: Verdict::Block,
+                    reasons: vec!["moderation unavailable".to_string()],
+                }
+            }
+        };
+
+        if decision.verdict == Verdict::Allow {
+            debug!(kind = content.kind(), "Content allowed by moderation");
+            return decision;
+        }
+        warn!(
+            moderator = moderator.name(),
+            kind = content.kind(),
+            verdict = decision.verdict.as_str(),
+            reasons = ?decision.reasons,
+            "Content moderation verdict"
+        );
+        let record = audit::NewRecord {
+            client: client.to_string(),
+            kind: content.kind(),
+            angle: match content {
+                Content::Output(variation) => Some(variation.angle.clone()),
+                _ => None,
+            },
+            moderator: moderator.name(),
+            decision: decision.clone(),
+        };
+        // Losing a record shouldn't undo the verdict
+        if let Err(err) = self.audit.record(record).await {
+            error!(error = %err, "Failed to write moderation audit record");
+        }
+        decision
+    }
+
+    /// The generated views that aren't blocked, in their original order.
+    pub async fn filter_outputs(
+        &self,
+        variations: Vec<ImageVariation>,
+        client: &str,
+    ) -> Vec<ImageVariation> {
+        let mut allowed = Vec::with_capacity(variations.len());
+        for variation in variations {
+            if !self
+                .check(Content::Output(&variation), client)
+                .await
+                .is_blocked()
+            {
+                allowed.push(variation);
+            }
+        }
+        allowed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Blocks outputs for one angle and fails on images.
+    struct PickyModerator;
+
+    #[async_trait]
+    impl Moderator for PickyModerator {
+        fn name(&self) -> &'static str {
+            "picky"
+        }
+
+        async fn moderate(&self, content: Content<'_>) -> Result<Decision, ModerationError> {
+            match content {
+                Content::Output(variation) if variation.angle == "side" => Ok(Decision {
+                    verdict: Verdict::Block,
+                    reasons: vec!["side views are off limits".to_string()],
+                }),
+                Content::Image(_) => Err("service down".into()),
+                _ => Ok(Decision::allow()),
+            }
+        }
+    }
+
+    fn moderation(fail_open: bool) -> Moderation {
+        Moderation::new(
+            Some(Arc::new(PickyModerator)),
+            Arc::new(AuditLog::open_in_memory().unwrap()),
+            fail_open,
+        )
+    }
+
+    fn variation(angle: &str) -> ImageVariation {
+        ImageVariation {
+            image: "data:image/png;base64,AAAA".to_string(),
+            angle: angle.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_blocked_outputs_are_dropped_and_audited() {
+        let moderation = moderation(false);
+        let kept = moderation
+            .filter_outputs(vec![variation("side"), variation("back")], "client-1")
+            .await;
+        assert_eq!(kept.len(), 1);
+        assert_eq!(kept[0].angle, "back");
+
+        let records = moderation.audit.recent(10).await.unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].client, "client-1");
+        assert_eq!(records[0].kind, "output");
+        assert_eq!(records[0].angle.as_deref(), Some("side"));
+        assert_eq!(records[0].verdict, Verdict::Block);
+        assert_eq!(records[0].reasons, vec!["side views are off limits"]);
+    }
+
+    #[tokio::test]
+    async fn test_failures_block_unless_fail_open() {
+        let image = InputImage {
+            data: vec![0xFF, 0xD8, 0xFF],
+            format: crate::services::image_format::ImageFormat::Jpeg,
+        };
+        let closed = moderation(false).check(Content::Image(&image), "c").await;
+        assert!(closed.is_blocked());
+        let open = moderation(true).check(Content::Image(&image), "c").await;
+        assert_eq!(open, Decision::allow());
+    }
+
+    #[tokio::test]
+    async fn test_allows_are_not_audited() {
+        let moderation = moderation(false);
+        let decision = moderation.check(Content::Prompt("fade"), "c").await;
+        assert_eq!(decision, Decision::allow());
+        assert!(moderation.audit.recent(10).await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_build_moderator() {
+        let config = ModerationConfig::default();
+        assert_eq!(build_moderator(&config).unwrap().unwrap().name(), "rules");
+        let disabled = ModerationConfig {
+            enabled: false,
+            ..config.clone()
+        };
+        assert!(build_moderator(&disabled).unwrap().is_none());
+        let http_without_url = ModerationConfig {
+            provider: "http".to_string(),
+            ..config.clone()
+        };
+        assert!(build_moderator(&http_without_url).is_err());
+        let unknown = ModerationConfig {
+            provider: "magic".to_string(),
+            ..config
+        };
+        assert!(build_moderator(&unknown).is_err());
+    }
+}
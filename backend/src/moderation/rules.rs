@@ -0,0 +1,162 @@
+//! The built-in moderator: configured word lists for prompts and sanity
+//! checks for images, with no service to call.
+
+use super::{Content, Decision, ModerationError, Moderator, Verdict};
+use crate::config::ModerationConfig;
+use crate::images::parse_data_url;
+use crate::services::image_format::ImageFormat;
+use async_trait::async_trait;
+
+pub struct RulesModerator {
+    block_terms: Vec<String>,
+    flag_terms: Vec<String>,
+}
+
+impl RulesModerator {
+    pub fn from_config(config: &ModerationConfig) -> Self {
+        let normalize = |terms: &[String]| {
+            terms
+                .iter()
+                .map(|term| words(term))
+                .filter(|term| !term.trim().is_empty())
+                .collect()
+        };
+        RulesModerator {
+            block_terms: normalize(&config.block_terms),
+            flag_terms: normalize(&config.flag_terms),
+        }
+    }
+
+    fn moderate_text(&self, text: &str) -> Decision {
+        let text = words(text);
+        let matches = |terms: &[String]| -> Vec<String> {
+            terms
+                .iter()
+                .filter(|term| text.contains(term.as_str()))
+                .map(|term| format!("mentions \"{}\"", term.trim()))
+                .collect()
+        };
+        let blocked = matches(&self.block_terms);
+        if !blocked.is_empty() {
+            return Decision {
+                verdict: Verdict::Block,
+                reasons: blocked,
+            };
+        }
+        let flagged = matches(&self.flag_terms);
+        if !flagged.is_empty() {
+            return Decision {
+                verdict: Verdict::Flag,
+                reasons: flagged,
+            };
+        }
+        Decision::allow()
+    }
+}
+
+/// Lowercase `text` as space-separated words with a space at each end, so a
+/// term matches whole words only ("gun" but not "begun").
+fn words(text: &str) -> String {
+    let lower = text.to_lowercase();
+    let words: Vec<&str> = lower
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect();
+    format!(" {} ", words.join(" "))
+}
+
+#[async_trait]
+impl Moderator for RulesModerator {
+    fn name(&self) -> &'static str {
+        "rules"
+    }
+
+    async fn moderate(&self, content: Content<'_>) -> Result<Decision, ModerationError> {
+        Ok(match content {
+            Content::Prompt(text) => self.moderate_text(text),
+            // Uploads have already been decoded and checked by the pipeline
+            Content::Image(_) => Decision::allow(),
+            // Whatever a provider sends back must at least be an image
+            Content::Output(variation) => match parse_data_url(&variation.image) {
+                Some((content_type, data))
+                    if content_type.starts_with("image/")
+                        && ImageFormat::sniff(&data).is_some() =>
+                {
+                    Decision::allow()
+                }
+                _ => Decision {
+                    verdict: Verdict::Block,
+                    reasons: vec!["output is not an image".to_string()],
+                },
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::generator::ImageVariation;
+
+    fn moderator() -> RulesModerator {
+        RulesModerator::from_config(&ModerationConfig::default())
+    }
+
+    async fn prompt(text: &str) -> Decision {
+        moderator().moderate(Content::Prompt(text)).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_prompt_terms() {
+        assert_eq!(prompt("Textured crop, skin fade").await, Decision::allow());
+
+        let blocked = prompt("a NUDE portrait with a knife").await;
+        assert_eq!(blocked.verdict, Verdict::Block);
+        assert_eq!(blocked.reasons, vec!["mentions \"nude\""]);
+
+        let flagged = prompt("mullet, holding a cigarette").await;
+        assert_eq!(flagged.verdict, Verdict::Flag);
+        assert_eq!(flagged.reasons, vec!["mentions \"cigarette\""]);
+    }
+
+    #[tokio::test]
+    async fn test_terms_match_whole_words() {
+        assert_eq!(prompt("the fade has begun").await, Decision::allow());
+        let moderator = RulesModerator::from_config(&ModerationConfig {
+            block_terms: vec!["Shaved Eyebrows".to_string()],
+            ..ModerationConfig::default()
+        });
+        let decision = moderator
+            .moderate(Content::Prompt("buzz cut, shaved-eyebrows"))
+            .await
+            .unwrap();
+        assert!(decision.is_blocked());
+    }
+
+    #[tokio::test]
+    async fn test_outputs_must_be_images() {
+        let output = |image: &str| ImageVariation {
+            image: image.to_string(),
+            angle: "front".to_string(),
+        };
+        // The PNG signature is all sniffing looks at
+        let png = "data:image/png;base64,iVBORw0KGgo=";
+        let decision = moderator()
+            .moderate(Content::Output(&output(png)))
+            .await
+            .unwrap();
+        assert_eq!(decision, Decision::allow());
+
+        for bad in [
+            "data:text/html;base64,PGgxPg==",
+            "data:image/png;base64,AAAA",
+            "https://example.com/x.png",
+        ] {
+            let decision = moderator()
+                .moderate(Content::Output(&output(bad)))
+                .await
+                .unwrap();
+            assert!(decision.is_blocked(), "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,336 @@
+//! Bring uploaded photos into one consistent shape before they reach a
+//! provider.
+//!
+//! The web client already compresses in the browser, but API clients send
+//! whatever their camera produced: sideways phone shots that rely on an EXIF
+//! orientation tag, or 8000px originals. Each upload is decoded, turned
+//! upright, downscaled so its longest edge fits `max_edge`, and re-encoded in
+//! one format and quality. HEIC can't be decoded here and is passed through.
+
+use crate::config::NormalizeConfig;
+use crate::image_limits::{ImageLimits, LimitError};
+use crate::services::generator::InputImage;
+use crate::services::image_format::ImageFormat;
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageDecoder, ImageError, ImageReader};
+use std::error::Error;
+use std::io::Cursor;
+use tracing::debug;
+
+pub type NormalizeError = Box<dyn Error + Send + Sync>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Output {
+    Jpeg { quality: u8 },
+    Png,
+}
+
+#[derive(Debug, Clone)]
+pub struct Normalizer {
+    enabled: bool,
+    max_edge: u32,
+    output: Output,
+    limits: ImageLimits,
+}
+
+impl Normalizer {
+    pub fn from_config(
+        config: &NormalizeConfig,
+        limits: ImageLimits,
+    ) -> Result<Self, NormalizeError> {
+        let output = match config.format.as_str() {
+            "jpeg" => {
+                if !(1..=100).contains(&config.jpeg_quality) {
+                    return Err(format!(
+                        "jpeg_quality must be between 1 and 100, got {}",
+                        config.jpeg_quality
+                    )
+                    .into());
+                }
+                Output::Jpeg {
+                    quality: config.jpeg_quality,
+                }
+            }
+            "png" => Output::Png,
+            other => return Err(format!("Unknown normalized image format: {}", other).into()),
+        };
+        if config.max_edge == 0 {
+            return Err("max_edge must be greater than 0".into());
+        }
+        Ok(Normalizer {
+            enabled: config.enabled,
+            max_edge: config.max_edge,
+            output,
+            limits,
+        })
+    }
+
+    /// Downscale and re-encode `pixels`, decoded from `original`. CPU-heavy;
+    /// call it from a blocking task. When disabled, `original` goes on as it came unless `pixels` was `edited`,
+    /// e.g. cropped.
+    pub fn finish(
+        &self,
+        original: InputImage,
+        mut pixels: DynamicImage,
+        edited: bool,
+    ) -> Result<InputImage, NormalizeError> {
+        if !self.enabled && !edited {
+            return Ok(original);
+        }
+        let (width, height) = (pixels.width(), pixels.height());
+        if width.max(height) > self.max_edge {
+            // `resize` keeps the aspect ratio, fitting inside the box
+            pixels = pixels.resize(self.max_edge, self.max_edge, FilterType::Lanczos3);
+        }
+
+        let normalized = self.encode(&pixels)?;
+        debug!(
+            from = ?original.format,
+            from_bytes = original.data.len(),
+            from_size = %format!("{}x{}", width, height),
+            to_bytes = normalized.data.len(),
+            to_size = %format!("{}x{}", pixels.width(), pixels.height()),
+            "Normalized input image"
+        );
+        Ok(normalized)
+    }
+
+    /// The upright pixels of `image` at full size, or `None` if this build
+    /// can't decode its format (HEIC), in which case it is passed through.
+    /// Call it after [`ImageLimits::check`].
+    pub fn decode(&self, image: &InputImage) -> Result<Option<DynamicImage>, NormalizeError> {
+        let Some(codec) = codec(image.format) else {
+            debug!(format = ?image.format, "Passing through image format that can't be decoded");
+            return Ok(None);
+        };
+        let mut reader = ImageReader::with_format(Cursor::new(&image.data), codec);
+        reader.limits(self.limits.decoder_limits());
+        let mut decoder = reader
+            .into_decoder()
+            .map_err(|err| self.decode_error(err))?;
+        let orientation = decoder.orientation()?;
+        if decoder.total_bytes() > self.limits.max_decode_bytes {
+            return Err(LimitError::DecodeMemory {
+                max_bytes: self.limits.max_decode_bytes,
+            }
+            .into());
+        }
+        let mut decoded =
+            DynamicImage::from_decoder(decoder).map_err(|err| self.decode_error(err))?;
+        decoded.apply_orientation(orientation);
+        Ok(Some(decoded))
+    }
+
+    /// The decoder checks the limits too, in case our header parsing and
+    /// its own disagree.
+    fn decode_error(&self, err: ImageError) -> NormalizeError {
+        match err {
+            ImageError::Limits(_) => LimitError::DecodeMemory {
+                max_bytes: self.limits.max_decode_bytes,
+            }
+            .into(),
+            err => err.into(),
+        }
+    }
+
+    fn encode(&self, image: &DynamicImage) -> Result<InputImage, NormalizeError> {
+        let mut data = Vec::new();
+        let format = match self.output {
+            Output::Jpeg { quality } => {
+                // JPEG has no alpha channel
+                image
+                    .to_rgb8()
+                    .write_with_encoder(JpegEncoder::new_with_quality(&mut data, quality))?;
+                ImageFormat::Jpeg
+            }
+            Output::Png => {
+                image.write_to(&mut Cursor::new(&mut data), image::ImageFormat::Png)?;
+                ImageFormat::Png
+            }
+        };
+        Ok(InputImage { data, format })
+    }
+}
+
+/// The decoder for a sniffed format, if this build has one.
+fn codec(format: ImageFormat) -> Option<image::ImageFormat> {
+    match format {
+        ImageFormat::Jpeg => Some(image::ImageFormat::Jpeg),
+        ImageFormat::Png => Some(image::ImageFormat::Png),
+        ImageFormat::Webp => Some(image::ImageFormat::WebP),
+        ImageFormat::Gif => Some(image::ImageFormat::Gif),
+        ImageFormat::Heic => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::LimitsConfig;
+    use image::{Rgb, RgbImage, Rgba, RgbaImage};
+
+    fn default_limits() -> ImageLimits {
+        ImageLimits::from_config(&LimitsConfig::default())
+    }
+
+    fn normalizer(max_edge: u32) -> Normalizer {
+        Normalizer::from_config(
+            &NormalizeConfig {
+                max_edge,
+                ..NormalizeConfig::default()
+            },
+            default_limits(),
+        )
+        .unwrap()
+    }
+
+    fn png(width: u32, height: u32) -> InputImage {
+        let mut data = Vec::new();
+        RgbaImage::from_pixel(width, height, Rgba([200, 120, 80, 255]))
+            .write_to(&mut Cursor::new(&mut data), image::ImageFormat::Png)
+            .unwrap();
+        InputImage {
+            data,
+            format: ImageFormat::Png,
+        }
+    }
+
+    /// A JPEG whose EXIF says to rotate it 90° clockwise for display.
+    fn sideways_jpeg(width: u32, height: u32) -> InputImage {
+        let mut jpeg = Vec::new();
+        RgbImage::from_pixel(width, height, Rgb([10, 20, 30]))
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut jpeg, 90))
+            .unwrap();
+
+        // Big-endian TIFF with one IFD entry: Orientation (0x0112) = 6
+        let mut tiff = b"MM\x00\x2a\x00\x00\x00\x08\x00\x01".to_vec();
+        tiff.extend_from_slice(b"\x01\x12\x00\x03\x00\x00\x00\x01\x00\x06\x00\x00");
+        tiff.extend_from_slice(&[0, 0, 0, 0]);
+        let mut app1 = b"Exif\x00\x00".to_vec();
+        app1.extend_from_slice(&tiff);
+
+        let mut data = jpeg[..2].to_vec();
+        data.extend_from_slice(&[0xFF, 0xE1]);
+        data.extend_from_slice(&((app1.len() + 2) as u16).to_be_bytes());
+        data.extend_from_slice(&app1);
+        data.extend_from_slice(&jpeg[2..]);
+        InputImage {
+            data,
+            format: ImageFormat::Jpeg,
+        }
+    }
+
+    fn normalize(normalizer: &Normalizer, image: InputImage) -> InputImage {
+        let pixels = normalizer.decode(&image).unwrap().unwrap();
+        normalizer.finish(image, pixels, false).unwrap()
+    }
+
+    fn dimensions(image: &InputImage) -> (u32, u32) {
+        let decoded = image::load_from_memory(&image.data).unwrap();
+        (decoded.width(), decoded.height())
+    }
+
+    #[test]
+    fn test_downscales_to_max_edge_keeping_aspect_ratio() {
+        let normalized = normalize(&normalizer(100), png(400, 200));
+        assert_eq!(normalized.format, ImageFormat::Jpeg);
+        assert_eq!(dimensions(&normalized), (100, 50));
+    }
+
+    #[test]
+    fn test_small_images_keep_their_size() {
+        let normalized = normalize(&normalizer(1024), png(300, 200));
+        assert_eq!(dimensions(&normalized), (300, 200));
+    }
+
+    #[test]
+    fn test_applies_exif_orientation() {
+        let normalized = normalize(&normalizer(1024), sideways_jpeg(64, 32));
+        assert_eq!(dimensions(&normalized), (32, 64));
+    }
+
+    #[test]
+    fn test_png_output() {
+        let normalizer = Normalizer::from_config(
+            &NormalizeConfig {
+                format: "png".to_string(),
+                ..NormalizeConfig::default()
+            },
+            default_limits(),
+        )
+        .unwrap();
+        let normalized = normalize(&normalizer, sideways_jpeg(64, 32));
+        assert_eq!(ImageFormat::sniff(&normalized.data), Some(ImageFormat::Png));
+        assert_eq!(normalized.format, ImageFormat::Png);
+    }
+
+    #[test]
+    fn test_disabled_and_heic_pass_through() {
+        let disabled = Normalizer::from_config(
+            &NormalizeConfig {
+                enabled: false,
+                ..NormalizeConfig::default()
+            },
+            default_limits(),
+        )
+        .unwrap();
+        let original = png(400, 200);
+        assert_eq!(normalize(&disabled, original.clone()).data, original.data);
+        // Edited pixels have to be encoded anyway
+        let pixels = disabled.decode(&original).unwrap().unwrap();
+        let cropped = disabled
+            .finish(original, pixels.crop_imm(0, 0, 100, 100), true)
+            .unwrap();
+        assert_eq!(dimensions(&cropped), (100, 100));
+
+        let heic = InputImage {
+            data: b"\x00\x00\x00\x18ftypheic\x00\x00\x00\x00".to_vec(),
+            format: ImageFormat::Heic,
+        };
+        assert!(normalizer(100).decode(&heic).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_undecodable_image_is_an_error() {
+        let truncated = InputImage {
+            data: b"\x89PNG\r\n\x1a\n\x00\x00".to_vec(),
+            format: ImageFormat::Png,
+        };
+        assert!(normalizer(100).decode(&truncated).is_err());
+    }
+
+    #[test]
+    fn test_decoder_enforces_memory_limit() {
+        let limits = ImageLimits {
+            max_decode_bytes: 1000,
+            ..default_limits()
+        };
+        let normalizer = Normalizer::from_config(&NormalizeConfig::default(), limits).unwrap();
+        let err = normalizer.decode(&png(100, 100)).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<LimitError>(),
+            Some(&LimitError::DecodeMemory { max_bytes: 1000 })
+        );
+    }
+
+    #[test]
+    fn test_invalid_config_is_rejected() {
+        for config in [
+            NormalizeConfig {
+                format: "tiff".to_string(),
+                ..NormalizeConfig::default()
+            },
+            NormalizeConfig {
+                jpeg_quality: 0,
+                ..NormalizeConfig::default()
+            },
+            NormalizeConfig {
+                max_edge: 0,
+                ..NormalizeConfig::default()
+            },
+        ] {
+            assert!(Normalizer::from_config(&config, default_limits()).is_err());
+        }
+    }
+}
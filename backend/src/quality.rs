@@ -0,0 +1,324 @@
+//! Catch photos that won't make a good result before paying for a
+//! generation.
+//!
+//! Everything here is cheap CPU work on the decoded upload, shrunk to
+//! [`ANALYSIS_EDGE`]: Laplacian variance for focus, a luma histogram for
+//! exposure, the original resolution, and the share of the frame taken by the
+//! largest skin-toned region as a rough stand-in for how close the subject
+//! is. Each signal has a warning threshold and, except for the subject size,
+//! a stricter one that rejects the photo outright. The subject estimate is
+//! too crude to turn anyone away on its own.
+
+use crate::config::QualityConfig;
+use crate::image_limits::ImageInfo;
+use crate::skin;
+use image::imageops::FilterType;
+use image::{DynamicImage, GrayImage, RgbImage};
+use serde::Serialize;
+use std::fmt;
+use tracing::debug;
+
+/// Longest edge of the copy the signals are computed on. Thresholds for
+/// sharpness are calibrated at this size.
+pub const ANALYSIS_EDGE: u32 = 512;
+
+/// Edge of the coarser grid used to find the subject region.
+const SUBJECT_GRID_EDGE: u32 = 128;
+
+/// Something about the photo worth telling the user, with advice.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct QualityIssue {
+    pub code: &'static str,
+    pub message: &'static str,
+}
+
+pub const LOW_RESOLUTION: QualityIssue = QualityIssue {
+    code: "photo_low_resolution",
+    message: "Photo resolution is low. Use a sharper, higher-resolution photo.",
+};
+pub const BLURRY: QualityIssue = QualityIssue {
+    code: "photo_blurry",
+    message: "Photo is blurry. Hold the camera steady and make sure your face is in focus.",
+};
+pub const TOO_DARK: QualityIssue = QualityIssue {
+    code: "photo_too_dark",
+    message: "Photo is too dark. Face a window or turn on more lights.",
+};
+pub const TOO_BRIGHT: QualityIssue = QualityIssue {
+    code: "photo_too_bright",
+    message: "Photo is overexposed. Move out of direct light.",
+};
+pub const SUBJECT_TOO_SMALL: QualityIssue = QualityIssue {
+    code: "subject_too_small",
+    message: "Move closer so your head and shoulders fill more of the frame.",
+};
+
+/// A photo under a rejection threshold.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QualityError(pub QualityIssue);
+
+impl fmt::Display for QualityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0.message)
+    }
+}
+
+impl std::error::Error for QualityError {}
+
+/// The raw measurements, logged for tuning thresholds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QualitySignals {
+    /// Shorter side of the original upload
+    pub short_edge: u32,
+    /// Variance of the Laplacian at [`ANALYSIS_EDGE`]
+    pub sharpness: f64,
+    /// Mean luma, 0-255
+    pub brightness: f64,
+    /// Share of the frame covered by the largest skin-toned region
+    pub subject_fraction: f64,
+}
+
+impl QualitySignals {
+    pub fn measure(pixels: &DynamicImage, original: &ImageInfo) -> Self {
+        let analysis = if pixels.width().max(pixels.height()) > ANALYSIS_EDGE {
+            pixels.resize(ANALYSIS_EDGE, ANALYSIS_EDGE, FilterType::Triangle)
+        } else {
+            pixels.clone()
+        };
+        let luma = analysis.to_luma8();
+        let subject = analysis.resize(SUBJECT_GRID_EDGE, SUBJECT_GRID_EDGE, FilterType::Triangle);
+        QualitySignals {
+            short_edge: original.width.min(original.height),
+            sharpness: laplacian_variance(&luma),
+            brightness: mean_luma(&luma),
+            subject_fraction: largest_skin_region(&subject.to_rgb8()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct QualityChecker {
+    config: QualityConfig,
+}
+
+impl QualityChecker {
+    pub fn new(config: &QualityConfig) -> Self {
+        QualityChecker {
+            config: config.clone(),
+        }
+    }
+
+    /// Warnings for `pixels`, or the first problem bad enough to reject it.
+    pub fn assess(
+        &self,
+        pixels: &DynamicImage,
+        original: &ImageInfo,
+    ) -> Result<Vec<QualityIssue>, QualityError> {
+        if !self.config.enabled {
+            return Ok(Vec::new());
+        }
+        let signals = QualitySignals::measure(pixels, original);
+        debug!(?signals, "Measured photo quality");
+        self.judge(&signals)
+    }
+
+    fn judge(&self, signals: &QualitySignals) -> Result<Vec<QualityIssue>, QualityError> {
+        let config = &self.config;
+        // (issue, rejected, warned)
+        let checks = [
+            (
+                LOW_RESOLUTION,
+                signals.short_edge < config.reject_short_edge,
+                signals.short_edge < config.warn_short_edge,
+            ),
+            (
+                BLURRY,
+                signals.sharpness < config.reject_sharpness,
+                signals.sharpness < config.warn_sharpness,
+            ),
+            (
+                TOO_DARK,
+                signals.brightness < config.reject_dark,
+                signals.brightness < config.warn_dark,
+            ),
+            (
+                TOO_BRIGHT,
+                signals.brightness > config.reject_bright,
+                signals.brightness > config.warn_bright,
+            ),
+            (
+                SUBJECT_TOO_SMALL,
+                false,
+                signals.subject_fraction < config.warn_subject_fraction,
+            ),
+        ];
+
+        let mut warnings = Vec::new();
+        for (issue, rejected, warned) in checks {
+            if rejected {
+                return Err(QualityError(issue));
+            }
+            if warned {
+                warnings.push(issue);
+            }
+        }
+        Ok(warnings)
+    }
+}
+
+/// Variance of the 4-neighbour Laplacian. Edges give large responses, so a
+/// sharp photo has a wide spread and a blurry one stays near zero.
+fn laplacian_variance(luma: &GrayImage) -> f64 {
+    let (width, height) = (luma.width() as usize, luma.height() as usize);
+    if width < 3 || height < 3 {
+        return 0.0;
+    }
+    let pixels = luma.as_raw();
+    let (mut sum, mut sum_sq) = (0i64, 0i64);
+    for y in 1..height - 1 {
+        for x in 1..width - 1 {
+            let i = y * width + x;
+            let response = pixels[i - 1] as i64
+                + pixels[i + 1] as i64
+                + pixels[i - width] as i64
+                + pixels[i + width] as i64
+                - 4 * pixels[i] as i64;
+            sum += response;
+            sum_sq += response * response;
+        }
+    }
+    let count = ((width - 2) * (height - 2)) as f64;
+    let mean = sum as f64 / count;
+    sum_sq as f64 / count - mean * mean
+}
+
+fn mean_luma(luma: &GrayImage) -> f64 {
+    let mut histogram = [0u64; 256];
+    for pixel in luma.pixels() {
+        histogram[pixel.0[0] as usize] += 1;
+    }
+    let total: u64 = histogram.iter().sum();
+    let weighted: u64 = histogram
+        .iter()
+        .enumerate()
+        .map(|(level, count)| level as u64 * count)
+        .sum();
+    weighted as f64 / total.max(1) as f64
+}
+
+/// The share of the image covered by its largest skin region.
+fn largest_skin_region(rgb: &RgbImage) -> f64 {
+    let (_, regions) = skin::regions(rgb);
+    let largest = regions.iter().map(|region| region.area).max().unwrap_or(0);
+    largest as f64 / (rgb.width() * rgb.height()).max(1) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgb;
+
+    const SKIN: Rgb<u8> = Rgb([224, 172, 140]);
+
+    fn info(width: u32, height: u32) -> ImageInfo {
+        ImageInfo {
+            width,
+            height,
+            frames: 1,
+        }
+    }
+
+    fn checker() -> QualityChecker {
+        QualityChecker::new(&QualityConfig::default())
+    }
+
+    /// A textured background with a skin-toned block covering `subject` of
+    /// the frame, brightened or darkened by `exposure`.
+    fn photo(size: u32, subject: f64, exposure: f64) -> DynamicImage {
+        let side = (size as f64 * subject.sqrt()) as u32;
+        let offset = (size - side) / 2;
+        let image = RgbImage::from_fn(size, size, |x, y| {
+            let inside =
+                (offset..offset + side).contains(&x) && (offset..offset + side).contains(&y);
+            let base = if inside {
+                SKIN
+            } else if (x / 8 + y / 8) % 2 == 0 {
+                Rgb([70, 90, 110])
+            } else {
+                Rgb([150, 160, 170])
+            };
+            // Fine texture so every region has some edges
+            let grain = if (x + y) % 2 == 0 { 1.0 } else { 0.9 };
+            Rgb(base
+                .0
+                .map(|c| (c as f64 * grain * exposure).min(255.0) as u8))
+        });
+        DynamicImage::ImageRgb8(image)
+    }
+
+    #[test]
+    fn test_good_photo_has_no_warnings() {
+        let warnings = checker()
+            .assess(&photo(512, 0.3, 1.0), &info(1024, 1024))
+            .unwrap();
+        assert_eq!(warnings, vec![]);
+    }
+
+    #[test]
+    fn test_blur_lowers_sharpness() {
+        let sharp = photo(512, 0.3, 1.0);
+        let blurry = sharp.blur(6.0);
+        let sharp_signals = QualitySignals::measure(&sharp, &info(512, 512));
+        let blurry_signals = QualitySignals::measure(&blurry, &info(512, 512));
+        assert!(sharp_signals.sharpness > 10.0 * blurry_signals.sharpness);
+
+        assert_eq!(
+            checker().assess(&blurry, &info(1024, 1024)),
+            Err(QualityError(BLURRY))
+        );
+    }
+
+    #[test]
+    fn test_exposure_warnings_and_rejections() {
+        let dim = checker().assess(&photo(512, 0.3, 0.45), &info(1024, 1024));
+        assert_eq!(dim, Ok(vec![TOO_DARK]));
+        let black = checker().assess(&photo(512, 0.3, 0.1), &info(1024, 1024));
+        assert_eq!(black, Err(QualityError(TOO_DARK)));
+        let blown_out = checker().assess(&photo(512, 0.3, 3.0), &info(1024, 1024));
+        assert_eq!(blown_out, Err(QualityError(TOO_BRIGHT)));
+    }
+
+    #[test]
+    fn test_resolution_uses_the_original_size() {
+        let pixels = photo(512, 0.3, 1.0);
+        assert_eq!(
+            checker().assess(&pixels, &info(640, 400)),
+            Ok(vec![LOW_RESOLUTION])
+        );
+        assert_eq!(
+            checker().assess(&pixels, &info(300, 160)),
+            Err(QualityError(LOW_RESOLUTION))
+        );
+    }
+
+    #[test]
+    fn test_small_subject_asks_to_move_closer() {
+        let signals = QualitySignals::measure(&photo(512, 0.01, 1.0), &info(1024, 1024));
+        assert!(signals.subject_fraction < 0.02, "{:?}", signals);
+        let warnings = checker()
+            .assess(&photo(512, 0.01, 1.0), &info(1024, 1024))
+            .unwrap();
+        assert_eq!(warnings, vec![SUBJECT_TOO_SMALL]);
+        assert_eq!(warnings[0].message, SUBJECT_TOO_SMALL.message);
+    }
+
+    #[test]
+    fn test_disabled_checker_accepts_anything() {
+        let checker = QualityChecker::new(&QualityConfig {
+            enabled: false,
+            ..QualityConfig::default()
+        });
+        let black = photo(64, 0.3, 0.0);
+        assert_eq!(checker.assess(&black, &info(64, 64)), Ok(vec![]));
+    }
+}
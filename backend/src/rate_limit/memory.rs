@@ -0,0 +1,308 @@
+use super::{
+    now_millis, Bucket, BucketPolicy, RateLimitBackend, RateLimitDecision, RateLimitError,
+    RateLimitStats,
+};
+use async_trait::async_trait;
+use lru::LruCache;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{debug, warn};
+
+/// Independent locks, so clients only contend with the few that hash alike.
+const SHARDS: usize = 16;
+
+struct Entry {
+    bucket: Bucket,
+    /// Once passed, the bucket is full and the entry can be dropped
+    full_at_ms: u64,
+}
+
+/// Clients in least recently seen order, so making room is O(1).
+type Shard = Mutex<LruCache<String, Entry>>;
+
+/// Buckets kept in this process only.
+///
+/// Memory stays bounded two ways: a sweeper drops clients whose buckets have
+/// refilled, and each shard holds at most its share of `max_keys`, making
+/// room by dropping whichever client has been idle longest.
+pub struct MemoryBackend {
+    shards: Vec<Shard>,
+    // Seeded per process so clients can't pick keys that pile into one shard
+    hasher: RandomState,
+    evicted: AtomicU64,
+}
+
+impl MemoryBackend {
+    pub fn new(max_keys: usize) -> Self {
+        let max_keys_per_shard =
+            NonZeroUsize::new(max_keys.div_ceil(SHARDS)).unwrap_or(NonZeroUsize::MIN);
+        MemoryBackend {
+            shards: (0..SHARDS)
+                .map(|_| Mutex::new(LruCache::new(max_keys_per_shard)))
+                .collect(),
+            hasher: RandomState::new(),
+            evicted: AtomicU64::new(0),
+        }
+    }
+
+    fn shard(&self, key: &str) -> &Shard {
+        let index = self.hasher.hash_one(key) as usize % SHARDS;
+        &self.shards[index]
+    }
+
+    /// Drop every client whose bucket has refilled. Returns how many went.
+    pub fn sweep(&self) -> usize {
+        let now = now_millis();
+        let mut removed = 0;
+        for shard in &self.shards {
+            let mut entries = shard.lock().unwrap();
+            let refilled: Vec<String> = entries
+                .iter()
+                .filter(|(_, entry)| entry.full_at_ms <= now)
+                .map(|(key, _)| key.clone())
+                .collect();
+            for key in &refilled {
+                entries.pop(key);
+            }
+            removed += refilled.len();
+        }
+        self.evicted.fetch_add(removed as u64, Ordering::Relaxed);
+        removed
+    }
+
+    pub fn spawn_sweeper(self: Arc<Self>, interval: Duration) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let removed = self.sweep();
+                debug!(removed, "Swept idle rate limit buckets");
+            }
+        })
+    }
+
+    /// Count a client pushed out of a full shard.
+    fn record_eviction(&self) {
+        let evicted = self.evicted.fetch_add(1, Ordering::Relaxed) + 1;
+        // Logging every eviction would flood the logs during a scrape
+        if evicted.is_power_of_two() {
+            warn!(evicted, "Rate limit store full, evicting idle clients");
+        }
+    }
+}
+
+#[async_trait]
+impl RateLimitBackend for MemoryBackend {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    async fn acquire(
+        &self,
+        key: &str,
+        cost: u32,
+        policy: &BucketPolicy,
+    ) -> Result<RateLimitDecision, RateLimitError> {
+        let now = now_millis();
+        let mut entries = self.shard(key).lock().unwrap();
+        if !entries.contains(key) {
+            let new = Entry {
+                bucket: Bucket::full(policy, now),
+                full_at_ms: now,
+            };
+            // A full shard hands back its least recently seen client
+            if entries.push(key.to_string(), new).is_some() {
+                self.record_eviction();
+            }
+        }
+        let entry = entries.get_mut(key).expect("entry was just ensured");
+        let decision = entry.bucket.take(cost, policy, now);
+        entry.full_at_ms = entry.bucket.full_at_ms(policy);
+        Ok(decision)
+    }
+
+    fn stats(&self) -> Option<RateLimitStats> {
+        Some(RateLimitStats {
+            tracked_keys: self
+                .shards
+                .iter()
+                .map(|shard| shard.lock().unwrap().len())
+                .sum(),
+            evicted_keys: self.evicted.load(Ordering::Relaxed),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::LimitsConfig;
+
+    fn policy() -> BucketPolicy {
+        BucketPolicy::from_limits(&LimitsConfig::default())
+    }
+
+    async fn check(backend: &MemoryBackend, key: &str) -> bool {
+        backend.acquire(key, 1, &policy()).await.unwrap().allowed
+    }
+
+    fn tracked(backend: &MemoryBackend) -> usize {
+        backend.stats().unwrap().tracked_keys
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_under_limit() {
+        let backend = MemoryBackend::new(1000);
+
+        // Should allow first 10 requests
+        for _ in 0..10 {
+            assert!(check(&backend, "127.0.0.1").await);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_over_limit() {
+        let backend = MemoryBackend::new(1000);
+
+        // Make 10 requests (should all pass)
+        for _ in 0..10 {
+            assert!(check(&backend, "127.0.0.1").await);
+        }
+
+        // 11th request should be blocked
+        assert!(!check(&backend, "127.0.0.1").await);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_different_keys() {
+        let backend = MemoryBackend::new(1000);
+
+        // Make 10 requests with the first key (should all pass)
+        for _ in 0..10 {
+            assert!(check(&backend, "127.0.0.1").await);
+        }
+
+        // The first key should now be blocked
+        assert!(!check(&backend, "127.0.0.1").await);
+
+        // The second key should still be able to make requests
+        for _ in 0..10 {
+            assert!(check(&backend, "127.0.0.2").await);
+        }
+
+        // Now the second key should also be blocked
+        assert!(!check(&backend, "127.0.0.2").await);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_cleanup() {
+        let backend = MemoryBackend::new(1000);
+
+        // Simulate a bucket drained 61 seconds ago
+        let bucket = Bucket {
+            tokens: 0.0,
+            updated_ms: now_millis() - 61_000,
+        };
+        backend.shard("127.0.0.1").lock().unwrap().put(
+            "127.0.0.1".to_string(),
+            Entry {
+                bucket,
+                full_at_ms: bucket.full_at_ms(&policy()),
+            },
+        );
+
+        // The sweeper drops it, since it has refilled since
+        assert_eq!(backend.sweep(), 1);
+        assert_eq!(tracked(&backend), 0);
+
+        // Should allow new requests since the bucket has refilled
+        assert!(check(&backend, "127.0.0.1").await);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_integration() {
+        let backend = MemoryBackend::new(1000);
+
+        // Simulate rapid requests
+        for i in 0..15 {
+            let allowed = check(&backend, "127.0.0.1").await;
+            if i < 10 {
+                assert!(allowed, "Request {} should be allowed", i + 1);
+            } else {
+                assert!(!allowed, "Request {} should be blocked", i + 1);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sweep_keeps_active_clients() {
+        let backend = MemoryBackend::new(1000);
+        check(&backend, "busy").await;
+
+        assert_eq!(backend.sweep(), 0);
+        assert_eq!(tracked(&backend), 1);
+    }
+
+    #[tokio::test]
+    async fn test_key_cap_bounds_memory() {
+        let backend = MemoryBackend::new(SHARDS * 2);
+
+        // A scraper rotating through addresses
+        for i in 0..1000 {
+            check(&backend, &format!("10.0.{}.{}", i / 256, i % 256)).await;
+        }
+
+        assert!(tracked(&backend) <= SHARDS * 2);
+        assert_eq!(
+            backend.stats().unwrap().evicted_keys as usize,
+            1000 - tracked(&backend)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_full_shard_evicts_least_recently_seen() {
+        // One key per shard, so each new key in a shard pushes out the last
+        let backend = MemoryBackend::new(SHARDS);
+        let shard_of = |key: &str| backend.hasher.hash_one(key) as usize % SHARDS;
+        let first = "10.0.0.1".to_string();
+        let second = (2..)
+            .map(|i| format!("10.0.0.{}", i))
+            .find(|key| shard_of(key) == shard_of(&first))
+            .unwrap();
+
+        check(&backend, &first).await;
+        check(&backend, &second).await;
+        let entries = backend.shard(&first).lock().unwrap();
+        assert!(!entries.contains(&first));
+        assert!(entries.contains(&second));
+        drop(entries);
+        assert_eq!(backend.stats().unwrap().evicted_keys, 1);
+    }
+
+    #[tokio::test]
+    async fn test_sweeper_task_evicts_idle_keys() {
+        let backend = Arc::new(MemoryBackend::new(1000));
+        // Refills instantly, so the bucket is idle right after the request
+        let fast = BucketPolicy {
+            burst: 1,
+            refill_per_sec: 1_000_000.0,
+        };
+        backend.acquire("idle", 1, &fast).await.unwrap();
+        assert_eq!(tracked(&backend), 1);
+
+        let sweeper = Arc::clone(&backend).spawn_sweeper(Duration::from_millis(5));
+        for _ in 0..100 {
+            if tracked(&backend) == 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        sweeper.abort();
+        assert_eq!(tracked(&backend), 0);
+    }
+}
@@ -0,0 +1,219 @@
+use super::{get_rate_limit_key, BucketPolicy, RateLimitDecision};
+use crate::api_keys::ApiKey;
+use crate::client_ip::ClientIp;
+use crate::config::LimitsConfig;
+use crate::{AppState, GenerateResponse};
+use axum::{
+    body::{Body, Bytes},
+    extract::{Json, Request, State},
+    http::{HeaderMap, HeaderName, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+use std::net::IpAddr;
+use std::time::Duration;
+use tracing::{error, warn};
+
+const RATE_LIMIT_LIMIT: HeaderName = HeaderName::from_static("ratelimit-limit");
+const RATE_LIMIT_REMAINING: HeaderName = HeaderName::from_static("ratelimit-remaining");
+const RATE_LIMIT_RESET: HeaderName = HeaderName::from_static("ratelimit-reset");
+
+/// The only field that changes what a request costs.
+#[derive(Deserialize)]
+struct CostProbe {
+    #[serde(rename = "generateAngles", default)]
+    generate_angles: bool,
+}
+
+/// The one generation route that makes the front view even when angles are
+/// asked for, streaming both.
+const STREAM_PATH: &str = "/api/generate/stream";
+
+/// Tokens a generation request to `path` spends: one for the front view and
+/// `rate_limit_angles_cost` for the side and back views, for whichever of
+/// them the route will generate. Bodies that don't parse cost one; the
+/// handler rejects them anyway.
+fn request_cost(path: &str, body: &[u8], limits: &LimitsConfig) -> u32 {
+    let generate_angles =
+        serde_json::from_slice::<CostProbe>(body).is_ok_and(|probe| probe.generate_angles);
+    let angles_cost = limits.rate_limit_angles_cost.max(1);
+    match (generate_angles, path == STREAM_PATH) {
+        (true, true) => 1 + angles_cost,
+        (true, false) => angles_cost,
+        (false, _) => 1,
+    }
+}
+
+/// Round up, so clients never retry a moment too early.
+fn whole_seconds(duration: Duration) -> u64 {
+    duration
+        .as_secs()
+        .saturating_add(u64::from(duration.subsec_nanos() > 0))
+}
+
+fn set_headers(headers: &mut HeaderMap, decision: &RateLimitDecision) {
+    headers.insert(RATE_LIMIT_LIMIT, HeaderValue::from(decision.limit));
+    headers.insert(RATE_LIMIT_REMAINING, HeaderValue::from(decision.remaining));
+    headers.insert(
+        RATE_LIMIT_RESET,
+        HeaderValue::from(whole_seconds(decision.reset)),
+    );
+    if !decision.allowed {
+        headers.insert(
+            axum::http::header::RETRY_AFTER,
+            HeaderValue::from(whole_seconds(decision.retry_after).max(1)),
+        );
+    }
+}
+
+/// Charge the client's bucket for a generation request before it reaches the
+/// handler, and report the bucket in `RateLimit-*` headers on whatever
+/// response comes back. Rejections are 429s with `Retry-After`.
+///
+/// Requests made with an API key share that key's bucket, sized by its own
+/// limits; anonymous ones are bucketed by client IP.
+///
+/// The body is read up front since `generateAngles` decides the cost; it is
+/// handed on to the handler untouched.
+pub async fn limit_generation(
+    State(state): State<AppState>,
+    ClientIp(client_ip): ClientIp,
+    request: Request,
+    next: Next,
+) -> Response {
+    let (parts, body) = request.into_parts();
+    let api_key = parts.extensions.get::<ApiKey>();
+    let (key, limits) = match api_key {
+        Some(api_key) => (
+            format!("key:{}", api_key.id),
+            api_key.limits(&state.config.limits),
+        ),
+        None => (
+            get_rate_limit_key(&client_ip, state.config.rate_limit.ipv6_prefix),
+            state.config.limits.clone(),
+        ),
+    };
+    let limits = &limits;
+    let body: Bytes = match axum::body::to_bytes(body, limits.body_limit()).await {
+        Ok(body) => body,
+        Err(_) => {
+            return (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                Json(GenerateResponse::error("Request body too large")),
+            )
+                .into_response()
+        }
+    };
+
+    let cost = request_cost(parts.uri.path(), &body, limits);
+    let policy = BucketPolicy::from_limits(limits);
+    let request = Request::from_parts(parts, Body::from(body));
+    charge(&state, client_ip, &key, cost, &policy, request, next).await
+}
+
+/// Charge a login or registration attempt one token from a per-IP bucket of
+/// its own, so password guessing, and the Argon2 hashing each guess sets
+/// off, is held to the same pace as generating without spending the
+/// client's generation budget.
+pub async fn limit_auth(
+    State(state): State<AppState>,
+    ClientIp(client_ip): ClientIp,
+    request: Request,
+    next: Next,
+) -> Response {
+    let key = format!(
+        "auth:{}",
+        get_rate_limit_key(&client_ip, state.config.rate_limit.ipv6_prefix)
+    );
+    let policy = BucketPolicy::from_limits(&state.config.limits);
+    charge(&state, client_ip, &key, 1, &policy, request, next).await
+}
+
+/// Take `cost` tokens from `key`'s bucket and either run the request or turn
+/// it away, with the bucket in the response headers.
+async fn charge(
+    state: &AppState,
+    client_ip: IpAddr,
+    key: &str,
+    cost: u32,
+    policy: &BucketPolicy,
+    request: Request,
+    next: Next,
+) -> Response {
+    let decision = state.rate_limiter.acquire(key, cost, policy).await;
+    let decision = match decision {
+        Ok(decision) => Some(decision),
+        // An unreachable store shouldn't take the whole API down with it
+        Err(err) => {
+            error!(
+                backend = state.rate_limiter.name(),
+                error = %err,
+                "Rate limit check failed, allowing request"
+            );
+            None
+        }
+    };
+
+    let mut response = match &decision {
+        Some(decision) if !decision.allowed => {
+            let retry_after = whole_seconds(decision.retry_after).max(1);
+            warn!(%client_ip, key, cost, retry_after, "Rate limit exceeded");
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                Json(GenerateResponse::error(format!(
+                    "Rate limit exceeded. Please try again in {} seconds.",
+                    retry_after
+                ))),
+            )
+                .into_response()
+        }
+        _ => next.run(request).await,
+    };
+    if let Some(decision) = &decision {
+        set_headers(response.headers_mut(), decision);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_angles_requests_cost_more() {
+        let limits = LimitsConfig::default();
+        let cost = |path, body| request_cost(path, body, &limits);
+        assert_eq!(cost("/api/generate", br#"{"prompt":"fade"}"#), 1);
+        assert_eq!(
+            cost(
+                "/api/generate",
+                br#"{"prompt":"fade","generateAngles":false}"#
+            ),
+            1
+        );
+        assert_eq!(
+            cost(
+                "/api/generate",
+                br#"{"prompt":"fade","generateAngles":true}"#
+            ),
+            2
+        );
+        assert_eq!(cost("/api/jobs", b"not json"), 1);
+    }
+
+    #[test]
+    fn test_streamed_angles_pay_for_the_front_view_too() {
+        let limits = LimitsConfig::default();
+        let cost = |body| request_cost(STREAM_PATH, body, &limits);
+        assert_eq!(cost(br#"{"prompt":"fade"}"#), 1);
+        assert_eq!(cost(br#"{"prompt":"fade","generateAngles":true}"#), 3);
+    }
+
+    #[test]
+    fn test_whole_seconds_rounds_up() {
+        assert_eq!(whole_seconds(Duration::ZERO), 0);
+        assert_eq!(whole_seconds(Duration::from_millis(5_001)), 6);
+        assert_eq!(whole_seconds(Duration::from_secs(6)), 6);
+    }
+}
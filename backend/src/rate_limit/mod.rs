@@ -0,0 +1,328 @@
+//! Per-client request limits.
+//!
+//! Each client gets a token bucket: it holds up to `burst` tokens, refills
+//! continuously, and each request spends tokens according to how much work it
+//! asks for. Handlers never see this; [`middleware::limit_generation`] charges
+//! the bucket and reports the outcome in `RateLimit-*` headers.
+//!
+//! Which store holds the buckets is a deployment choice behind
+//! [`RateLimitBackend`]. The in-memory store is per process, so with several
+//! replicas (or across restarts) use Redis or SQLite instead.
+
+pub mod memory;
+pub mod middleware;
+pub mod redis;
+pub mod sqlite;
+
+use crate::config::{LimitsConfig, RateLimitConfig};
+use async_trait::async_trait;
+use ipnet::Ipv6Net;
+use std::error::Error;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub type RateLimitError = Box<dyn Error + Send + Sync>;
+
+/// Bucket size and refill speed, shared by every client.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BucketPolicy {
+    pub burst: u32,
+    pub refill_per_sec: f64,
+}
+
+impl BucketPolicy {
+    pub fn from_limits(limits: &LimitsConfig) -> Self {
+        BucketPolicy {
+            burst: limits.burst(),
+            refill_per_sec: limits.rate_limit_requests as f64
+                / limits.rate_limit_window_secs.max(1) as f64,
+        }
+    }
+
+    fn refill_per_ms(&self) -> f64 {
+        self.refill_per_sec / 1000.0
+    }
+
+    /// Requests costing more than a full bucket could never pass, so they
+    /// cost a full bucket instead. Every request costs at least one token, so
+    /// a bucket of size zero denies everything rather than nothing.
+    fn clamp_cost(&self, cost: u32) -> u32 {
+        cost.min(self.burst).max(1)
+    }
+
+    /// How long until `missing` tokens are back; `Duration::MAX` if they
+    /// never come back.
+    fn time_to_refill(&self, missing: f64) -> Duration {
+        if missing <= 0.0 {
+            return Duration::ZERO;
+        }
+        if self.refill_per_sec <= 0.0 {
+            return Duration::MAX;
+        }
+        Duration::from_secs_f64(missing / self.refill_per_sec)
+    }
+}
+
+/// The outcome of charging a bucket, enough to fill in the response headers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimitDecision {
+    pub allowed: bool,
+    /// Bucket size
+    pub limit: u32,
+    /// Whole tokens left after this request
+    pub remaining: u32,
+    /// How long until this request would have fit; zero when allowed
+    pub retry_after: Duration,
+    /// How long until the bucket is full again
+    pub reset: Duration,
+}
+
+impl RateLimitDecision {
+    /// Describe a bucket left holding `tokens` after a request costing `cost`.
+    pub fn new(allowed: bool, tokens: f64, cost: u32, policy: &BucketPolicy) -> Self {
+        let cost = policy.clamp_cost(cost);
+        RateLimitDecision {
+            allowed,
+            limit: policy.burst,
+            remaining: tokens.max(0.0).floor() as u32,
+            retry_after: if allowed {
+                Duration::ZERO
+            } else {
+                policy.time_to_refill(cost as f64 - tokens)
+            },
+            reset: policy.time_to_refill(policy.burst as f64 - tokens),
+        }
+    }
+}
+
+/// One client's bucket as stored by a backend.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bucket {
+    pub tokens: f64,
+    pub updated_ms: u64,
+}
+
+impl Bucket {
+    pub fn full(policy: &BucketPolicy, now_ms: u64) -> Self {
+        Bucket {
+            tokens: policy.burst as f64,
+            updated_ms: now_ms,
+        }
+    }
+
+    /// Refill for the time since the last update, then spend `cost` tokens if
+    /// there are enough. Rejected requests spend nothing.
+    pub fn take(&mut self, cost: u32, policy: &BucketPolicy, now_ms: u64) -> RateLimitDecision {
+        let elapsed = now_ms.saturating_sub(self.updated_ms) as f64;
+        self.tokens = (self.tokens + elapsed * policy.refill_per_ms()).min(policy.burst as f64);
+        self.updated_ms = now_ms.max(self.updated_ms);
+
+        let needed = policy.clamp_cost(cost) as f64;
+        let allowed = self.tokens >= needed;
+        if allowed {
+            self.tokens -= needed;
+        }
+        RateLimitDecision::new(allowed, self.tokens, cost, policy)
+    }
+
+    /// When the bucket will be full again, after which it is no different
+    /// from not tracking the key at all. `u64::MAX` if it never refills.
+    pub fn full_at_ms(&self, policy: &BucketPolicy) -> u64 {
+        let missing = policy.burst as f64 - self.tokens;
+        let refill_ms =
+            u64::try_from(policy.time_to_refill(missing).as_millis()).unwrap_or(u64::MAX);
+        self.updated_ms.saturating_add(refill_ms)
+    }
+}
+
+/// Store size for `/metrics`, from backends that track it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimitStats {
+    pub tracked_keys: usize,
+    pub evicted_keys: u64,
+}
+
+/// A store of token buckets keyed by client.
+#[async_trait]
+pub trait RateLimitBackend: Send + Sync {
+    /// Short backend name used in logs.
+    fn name(&self) -> &'static str;
+
+    /// Charge `cost` tokens to `key`'s bucket, creating a full one for new
+    /// keys. Must be atomic per key, since replicas may share the store.
+    async fn acquire(
+        &self,
+        key: &str,
+        cost: u32,
+        policy: &BucketPolicy,
+    ) -> Result<RateLimitDecision, RateLimitError>;
+
+    /// Local store size, for backends whose memory this process owns.
+    fn stats(&self) -> Option<RateLimitStats> {
+        None
+    }
+}
+
+/// The bucket key for `ip`: the address itself for IPv4, or its
+/// `ipv6_prefix`-bit network for IPv6.
+pub fn get_rate_limit_key(ip: &IpAddr, ipv6_prefix: u8) -> String {
+    match ip {
+        IpAddr::V6(v6) if ipv6_prefix < 128 => Ipv6Net::new(*v6, ipv6_prefix)
+            .map(|net| net.trunc().to_string())
+            .unwrap_or_else(|_| ip.to_string()),
+        _ => ip.to_string(),
+    }
+}
+
+/// Build the backend selected by `config.backend`.
+pub async fn build_backend(
+    config: &RateLimitConfig,
+) -> Result<Arc<dyn RateLimitBackend>, RateLimitError> {
+    match config.backend.as_str() {
+        "memory" => {
+            let backend = Arc::new(memory::MemoryBackend::new(config.max_keys));
+            if config.sweep_interval_secs > 0 {
+                Arc::clone(&backend).spawn_sweeper(Duration::from_secs(config.sweep_interval_secs));
+            }
+            Ok(backend)
+        }
+        "redis" => Ok(Arc::new(
+            self::redis::RedisBackend::connect(&config.redis_url).await?,
+        )),
+        "sqlite" => {
+            let backend = Arc::new(sqlite::SqliteBackend::open(&config.sqlite_path)?);
+            if config.sweep_interval_secs > 0 {
+                Arc::clone(&backend).spawn_sweeper(Duration::from_secs(config.sweep_interval_secs));
+            }
+            Ok(backend)
+        }
+        other => Err(format!("Unknown rate limit backend: {}", other).into()),
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    // 10 tokens, one back every 6 seconds
+    fn policy() -> BucketPolicy {
+        BucketPolicy::from_limits(&LimitsConfig::default())
+    }
+
+    #[test]
+    fn test_get_rate_limit_key() {
+        let ipv4 = IpAddr::from_str("192.168.1.1").unwrap();
+        let ipv6 = IpAddr::from_str("::1").unwrap();
+
+        assert_eq!(get_rate_limit_key(&ipv4, 64), "192.168.1.1");
+        assert_eq!(get_rate_limit_key(&ipv6, 128), "::1");
+    }
+
+    #[test]
+    fn test_ipv6_clients_are_grouped_by_prefix() {
+        let a = IpAddr::from_str("2001:db8:1:2:aaaa::1").unwrap();
+        let b = IpAddr::from_str("2001:db8:1:2:bbbb::2").unwrap();
+        let other = IpAddr::from_str("2001:db8:1:3::1").unwrap();
+
+        assert_eq!(get_rate_limit_key(&a, 64), "2001:db8:1:2::/64");
+        assert_eq!(get_rate_limit_key(&a, 64), get_rate_limit_key(&b, 64));
+        assert_ne!(get_rate_limit_key(&a, 64), get_rate_limit_key(&other, 64));
+        assert_eq!(get_rate_limit_key(&a, 48), get_rate_limit_key(&other, 48));
+    }
+
+    #[test]
+    fn test_policy_defaults_to_previous_limit() {
+        assert_eq!(
+            policy(),
+            BucketPolicy {
+                burst: 10,
+                refill_per_sec: 10.0 / 60.0
+            }
+        );
+    }
+
+    #[test]
+    fn test_bucket_spends_cost_and_refills() {
+        let policy = policy();
+        let mut bucket = Bucket::full(&policy, 0);
+
+        let decision = bucket.take(3, &policy, 0);
+        assert!(decision.allowed);
+        assert_eq!(decision.limit, 10);
+        assert_eq!(decision.remaining, 7);
+        assert_eq!(decision.reset.as_secs_f64().round(), 18.0);
+
+        for _ in 0..7 {
+            assert!(bucket.take(1, &policy, 0).allowed);
+        }
+        let denied = bucket.take(2, &policy, 0);
+        assert!(!denied.allowed);
+        assert_eq!(denied.remaining, 0);
+        assert_eq!(denied.retry_after.as_secs_f64().round(), 12.0);
+
+        // One token back after six seconds
+        assert!(bucket.take(1, &policy, 6_001).allowed);
+        assert!(!bucket.take(1, &policy, 6_001).allowed);
+    }
+
+    #[test]
+    fn test_bucket_never_exceeds_burst() {
+        let policy = policy();
+        let mut bucket = Bucket::full(&policy, 0);
+        let decision = bucket.take(1, &policy, 3_600_000);
+        assert_eq!(decision.remaining, 9);
+    }
+
+    #[test]
+    fn test_cost_above_burst_needs_a_full_bucket() {
+        let policy = policy();
+        let mut bucket = Bucket::full(&policy, 0);
+        assert!(bucket.take(50, &policy, 0).allowed);
+        let denied = bucket.take(50, &policy, 0);
+        assert!(!denied.allowed);
+        assert_eq!(denied.retry_after.as_secs_f64().round(), 60.0);
+    }
+
+    #[test]
+    fn test_zero_burst_denies_everything() {
+        let policy = BucketPolicy {
+            burst: 0,
+            refill_per_sec: 1.0,
+        };
+        let mut bucket = Bucket::full(&policy, 0);
+        assert!(!bucket.take(1, &policy, 0).allowed);
+        assert!(!bucket.take(1, &policy, 3_600_000).allowed);
+    }
+
+    #[test]
+    fn test_bucket_without_refill_is_never_full_again() {
+        let policy = BucketPolicy {
+            burst: 2,
+            refill_per_sec: 0.0,
+        };
+        let mut bucket = Bucket::full(&policy, 0);
+        assert_eq!(bucket.full_at_ms(&policy), 0);
+        assert!(bucket.take(2, &policy, 0).allowed);
+        assert_eq!(bucket.full_at_ms(&policy), u64::MAX);
+        assert!(!bucket.take(1, &policy, 3_600_000).allowed);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_backend_is_rejected() {
+        let config = RateLimitConfig {
+            backend: "memcached".to_string(),
+            ..RateLimitConfig::default()
+        };
+        let err = build_backend(&config).await.err().unwrap();
+        assert_eq!(err.to_string(), "Unknown rate limit backend: memcached");
+    }
+}
@@ -0,0 +1,134 @@
+use super::{BucketPolicy, RateLimitBackend, RateLimitDecision, RateLimitError};
+use ::redis::{aio::ConnectionManager, Client, Script};
+use async_trait::async_trait;
+
+const KEY_PREFIX: &str = "helpmybarber:ratelimit:";
+
+// The same refill-then-spend as `Bucket::take`, in one hash per key. Runs
+// atomically on the server and uses the server's clock, so replicas with
+// skewed clocks still agree. Needs Redis 5+ for writes after TIME. Tokens go
+// back as a string since Lua numbers are truncated to integers on return.
+const ACQUIRE_SCRIPT: &str = r#"
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+local burst = tonumber(ARGV[1])
+local refill_per_ms = tonumber(ARGV[2])
+local cost = tonumber(ARGV[3])
+
+local state = redis.call('HMGET', KEYS[1], 'tokens', 'updated_ms')
+local tokens = tonumber(state[1]) or burst
+local updated = tonumber(state[2]) or now
+tokens = math.min(burst, tokens + math.max(0, now - updated) * refill_per_ms)
+
+local allowed = 0
+if tokens >= cost then
+    tokens = tokens - cost
+    allowed = 1
+end
+
+redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'updated_ms', now)
+-- A bucket that has refilled is the same as no bucket
+local ttl = 60000
+if refill_per_ms > 0 then
+    ttl = math.ceil((burst - tokens) / refill_per_ms) + 1000
+end
+redis.call('PEXPIRE', KEYS[1], ttl)
+return {allowed, tostring(tokens)}
+"#;
+
+/// Buckets shared by every replica pointed at the same Redis (or anything
+/// that speaks its protocol, like Valkey or KeyDB).
+pub struct RedisBackend {
+    connection: ConnectionManager,
+    script: Script,
+}
+
+impl RedisBackend {
+    pub async fn connect(url: &str) -> Result<Self, RateLimitError> {
+        let client = Client::open(url)?;
+        let connection = ConnectionManager::new(client)
+            .await
+            .map_err(|err| format!("Failed to connect to Redis at {}: {}", url, err))?;
+        Ok(RedisBackend {
+            connection,
+            script: Script::new(ACQUIRE_SCRIPT),
+        })
+    }
+}
+
+#[async_trait]
+impl RateLimitBackend for RedisBackend {
+    fn name(&self) -> &'static str {
+        "redis"
+    }
+
+    async fn acquire(
+        &self,
+        key: &str,
+        cost: u32,
+        policy: &BucketPolicy,
+    ) -> Result<RateLimitDecision, RateLimitError> {
+        let mut connection = self.connection.clone();
+        let (allowed, tokens): (i64, String) = self
+            .script
+            .key(format!("{}{}", KEY_PREFIX, key))
+            .arg(policy.burst)
+            .arg(policy.refill_per_ms().to_string())
+            .arg(policy.clamp_cost(cost))
+            .invoke_async(&mut connection)
+            .await?;
+        let tokens: f64 = tokens
+            .parse()
+            .map_err(|_| format!("Unexpected token count from Redis: {}", tokens))?;
+        Ok(RateLimitDecision::new(allowed == 1, tokens, cost, policy))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    // Needs a running server, e.g.
+    // REDIS_URL=redis://127.0.0.1:6379 cargo test -- --ignored
+    async fn backend() -> RedisBackend {
+        let url = std::env::var("REDIS_URL").expect("REDIS_URL is set");
+        RedisBackend::connect(&url).await.unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis server; set REDIS_URL"]
+    async fn test_redis_charges_cost_per_key() {
+        let backend = backend().await;
+        let policy = BucketPolicy {
+            burst: 3,
+            refill_per_sec: 0.01,
+        };
+        let key = Uuid::new_v4().to_string();
+        let other = Uuid::new_v4().to_string();
+
+        let decision = backend.acquire(&key, 2, &policy).await.unwrap();
+        assert!(decision.allowed);
+        assert_eq!(decision.remaining, 1);
+        assert!(!backend.acquire(&key, 2, &policy).await.unwrap().allowed);
+        assert!(backend.acquire(&key, 1, &policy).await.unwrap().allowed);
+        assert!(backend.acquire(&other, 3, &policy).await.unwrap().allowed);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis server; set REDIS_URL"]
+    async fn test_redis_bucket_refills() {
+        let backend = backend().await;
+        let policy = BucketPolicy {
+            burst: 1,
+            refill_per_sec: 5.0,
+        };
+        let key = Uuid::new_v4().to_string();
+
+        assert!(backend.acquire(&key, 1, &policy).await.unwrap().allowed);
+        assert!(!backend.acquire(&key, 1, &policy).await.unwrap().allowed);
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        assert!(backend.acquire(&key, 1, &policy).await.unwrap().allowed);
+    }
+}
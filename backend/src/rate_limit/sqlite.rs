@@ -0,0 +1,221 @@
+use super::{
+    now_millis, Bucket, BucketPolicy, RateLimitBackend, RateLimitDecision, RateLimitError,
+};
+use async_trait::async_trait;
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{debug, warn};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS rate_limit_buckets (
+        key TEXT PRIMARY KEY,
+        tokens REAL NOT NULL,
+        updated_ms INTEGER NOT NULL,
+        full_at_ms INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS rate_limit_buckets_full_at
+        ON rate_limit_buckets (full_at_ms);
+";
+
+/// Buckets kept in a SQLite file, so they survive restarts and are shared by
+/// every process on the host that opens the same file.
+///
+/// Each row records when its bucket will be full again, computed with that
+/// key's own policy; past then the row is no different from a missing one,
+/// and [`SqliteBackend::sweep`] deletes it.
+pub struct SqliteBackend {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl SqliteBackend {
+    pub fn open(path: &Path) -> Result<Self, RateLimitError> {
+        let connection = Connection::open(path)
+            .map_err(|err| format!("Failed to open {}: {}", path.display(), err))?;
+        // WAL plus a busy timeout lets several processes share the file
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.busy_timeout(Duration::from_secs(5))?;
+        connection.execute_batch(SCHEMA)?;
+        Ok(SqliteBackend {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    /// Delete every bucket that has refilled. Returns how many went.
+    pub async fn sweep(&self) -> Result<usize, RateLimitError> {
+        let connection = Arc::clone(&self.connection);
+        let removed = tokio::task::spawn_blocking(move || {
+            connection.lock().unwrap().execute(
+                "DELETE FROM rate_limit_buckets WHERE full_at_ms <= ?1",
+                params![now_millis() as i64],
+            )
+        })
+        .await??;
+        Ok(removed)
+    }
+
+    pub fn spawn_sweeper(self: Arc<Self>, interval: Duration) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                match self.sweep().await {
+                    Ok(removed) => debug!(removed, "Swept refilled rate limit buckets"),
+                    Err(err) => warn!(error = %err, "Failed to sweep rate limit buckets"),
+                }
+            }
+        })
+    }
+}
+
+fn acquire_blocking(
+    connection: &mut Connection,
+    key: &str,
+    cost: u32,
+    policy: &BucketPolicy,
+) -> Result<RateLimitDecision, rusqlite::Error> {
+    let now = now_millis();
+    // Immediate so two processes can't both read the same bucket, then both spend it
+    let tx = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let mut bucket = tx
+        .query_row(
+            "SELECT tokens, updated_ms FROM rate_limit_buckets WHERE key = ?1",
+            params![key],
+            |row| {
+                Ok(Bucket {
+                    tokens: row.get(0)?,
+                    updated_ms: row.get::<_, i64>(1)? as u64,
+                })
+            },
+        )
+        .optional()?
+        .unwrap_or_else(|| Bucket::full(policy, now));
+
+    let decision = bucket.take(cost, policy, now);
+    tx.execute(
+        "INSERT INTO rate_limit_buckets (key, tokens, updated_ms, full_at_ms)
+         VALUES (?1, ?2, ?3, ?4)
+         ON CONFLICT (key) DO UPDATE SET tokens = excluded.tokens,
+             updated_ms = excluded.updated_ms, full_at_ms = excluded.full_at_ms",
+        params![
+            key,
+            bucket.tokens,
+            bucket.updated_ms as i64,
+            i64::try_from(bucket.full_at_ms(policy)).unwrap_or(i64::MAX)
+        ],
+    )?;
+    tx.commit()?;
+    Ok(decision)
+}
+
+#[async_trait]
+impl RateLimitBackend for SqliteBackend {
+    fn name(&self) -> &'static str {
+        "sqlite"
+    }
+
+    async fn acquire(
+        &self,
+        key: &str,
+        cost: u32,
+        policy: &BucketPolicy,
+    ) -> Result<RateLimitDecision, RateLimitError> {
+        let connection = Arc::clone(&self.connection);
+        let key = key.to_string();
+        let policy = *policy;
+        let decision = tokio::task::spawn_blocking(move || {
+            let mut connection = connection.lock().unwrap();
+            acquire_blocking(&mut connection, &key, cost, &policy)
+        })
+        .await??;
+        Ok(decision)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn temp_db(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("hmb-ratelimit-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn policy(burst: u32, refill_per_sec: f64) -> BucketPolicy {
+        BucketPolicy {
+            burst,
+            refill_per_sec,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_charges_cost_per_key() {
+        let backend = SqliteBackend::open(&temp_db("limits.db")).unwrap();
+        let policy = policy(3, 0.01);
+
+        let decision = backend.acquire("a", 2, &policy).await.unwrap();
+        assert!(decision.allowed);
+        assert_eq!(decision.remaining, 1);
+        assert!(!backend.acquire("a", 2, &policy).await.unwrap().allowed);
+        assert!(backend.acquire("a", 1, &policy).await.unwrap().allowed);
+        assert!(backend.acquire("b", 3, &policy).await.unwrap().allowed);
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_is_shared_and_survives_reopen() {
+        let path = temp_db("shared.db");
+        let policy = policy(2, 0.01);
+
+        // Two handles on one file stand in for two replicas
+        let first = SqliteBackend::open(&path).unwrap();
+        let second = SqliteBackend::open(&path).unwrap();
+        assert!(first.acquire("client", 1, &policy).await.unwrap().allowed);
+        assert!(second.acquire("client", 1, &policy).await.unwrap().allowed);
+        assert!(!first.acquire("client", 1, &policy).await.unwrap().allowed);
+
+        drop((first, second));
+        let reopened = SqliteBackend::open(&path).unwrap();
+        assert!(
+            !reopened
+                .acquire("client", 1, &policy)
+                .await
+                .unwrap()
+                .allowed
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_bucket_refills() {
+        let backend = SqliteBackend::open(&temp_db("refill.db")).unwrap();
+        let policy = policy(1, 20.0);
+
+        assert!(backend.acquire("a", 1, &policy).await.unwrap().allowed);
+        assert!(!backend.acquire("a", 1, &policy).await.unwrap().allowed);
+        tokio::time::sleep(Duration::from_millis(80)).await;
+        assert!(backend.acquire("a", 1, &policy).await.unwrap().allowed);
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_sweep_drops_refilled_buckets() {
+        let backend = SqliteBackend::open(&temp_db("sweep.db")).unwrap();
+        // Refills instantly, so the bucket is full right after the request
+        backend
+            .acquire("idle", 1, &policy(1, 1_000_000.0))
+            .await
+            .unwrap();
+        backend.acquire("busy", 1, &policy(5, 0.01)).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(5)).await;
+
+        assert_eq!(backend.sweep().await.unwrap(), 1);
+        assert_eq!(backend.sweep().await.unwrap(), 0);
+        // The drained bucket is still there and still drained
+        let decision = backend.acquire("busy", 1, &policy(5, 0.01)).await.unwrap();
+        assert_eq!(decision.remaining, 3);
+    }
+}
@@ -0,0 +1,460 @@
+use crate::config::GeneratorConfig;
+use crate::services::generator::{
+    AnglePrompts, GenerateError, ImageGenerator, ImageVariation, InputImage,
+};
+use async_trait::async_trait;
+use base64::{engine::general_purpose, Engine as _};
+use reqwest::multipart::{Form, Part};
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{error, info};
+
+const DEFAULT_URL: &str = "http://127.0.0.1:8188";
+const CLIENT_ID: &str = "helpmybarber";
+
+/// Generator that runs a ComfyUI workflow on a local server.
+///
+/// The workflow is an API-format JSON export. Any string value containing
+/// `{prompt}` gets the rendered prompt and any containing `{image}` gets the
+/// name of the uploaded selfie. Workflows save one image, so every view is a
+/// prompt of its own that takes the first output image, and the side and back
+/// views each use their own single-angle template.
+pub struct ComfyUiGenerator {
+    client: reqwest::Client,
+    base_url: String,
+    workflow: Value,
+    timeout: Duration,
+    poll_interval: Duration,
+}
+
+impl ComfyUiGenerator {
+    pub fn new(base_url: &str, workflow: Value, timeout: Duration) -> Self {
+        ComfyUiGenerator {
+            client: reqwest::Client::new(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            workflow,
+            timeout,
+            poll_interval: Duration::from_secs(1),
+        }
+    }
+
+    pub fn load_workflow(path: &Path) -> Result<Value, GenerateError> {
+        let contents = fs::read_to_string(path)
+            .map_err(|err| format!("Failed to read {}: {}", path.display(), err))?;
+        let workflow: Value = serde_json::from_str(&contents)
+            .map_err(|err| format!("Invalid workflow JSON in {}: {}", path.display(), err))?;
+        if !workflow.is_object() {
+            return Err(format!("Workflow in {} must be a JSON object", path.display()).into());
+        }
+        Ok(workflow)
+    }
+
+    pub fn from_config(config: &GeneratorConfig) -> Result<Self, GenerateError> {
+        let workflow = Self::load_workflow(&config.workflow_path)?;
+        Ok(Self::new(
+            config.base_url.as_deref().unwrap_or(DEFAULT_URL),
+            workflow,
+            Duration::from_secs(config.timeout_secs),
+        ))
+    }
+
+    async fn upload_image(&self, image: &InputImage) -> Result<String, GenerateError> {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos();
+        let image_part = Part::bytes(image.data.clone())
+            .file_name(format!(
+                "helpmybarber-{}.{}",
+                nanos,
+                image.format.extension()
+            ))
+            .mime_str(image.format.mime_type())?;
+        let form = Form::new()
+            .part("image", image_part)
+            .text("overwrite", "true");
+
+        let response = self
+            .client
+            .post(format!("{}/upload/image", self.base_url))
+            .multipart(form)
+            .send()
+            .await?;
+        let upload: Value = check_status(response, "upload").await?.json().await?;
+
+        let name = upload
+            .get("name")
+            .and_then(|v| v.as_str())
+            .ok_or("ComfyUI upload response missing name")?;
+        Ok(match upload.get("subfolder").and_then(|v| v.as_str()) {
+            Some(subfolder) if !subfolder.is_empty() => format!("{}/{}", subfolder, name),
+            _ => name.to_string(),
+        })
+    }
+
+    async fn queue_prompt(&self, workflow: Value) -> Result<String, GenerateError> {
+        let response = self
+            .client
+            .post(format!("{}/prompt", self.base_url))
+            .json(&serde_json::json!({ "prompt": workflow, "client_id": CLIENT_ID }))
+            .send()
+            .await?;
+        let queued: Value = check_status(response, "prompt").await?.json().await?;
+
+        queued
+            .get("prompt_id")
+            .and_then(|v| v.as_str())
+            .map(str::to_string)
+            .ok_or_else(|| "ComfyUI prompt response missing prompt_id".into())
+    }
+
+    /// Poll `/history` until the prompt has finished, returning its entry.
+    async fn wait_for_completion(&self, prompt_id: &str) -> Result<Value, GenerateError> {
+        let started = Instant::now();
+        loop {
+            let response = self
+                .client
+                .get(format!("{}/history/{}", self.base_url, prompt_id))
+                .send()
+                .await?;
+            let mut history: Value = check_status(response, "history").await?.json().await?;
+
+            if let Some(entry) = history.get_mut(prompt_id).map(Value::take) {
+                let status = entry.get("status");
+                if status
+                    .and_then(|s| s.get("status_str"))
+                    .and_then(|s| s.as_str())
+                    == Some("error")
+                {
+                    error!(prompt_id, status = ?status, "ComfyUI workflow failed");
+                    return Err("ComfyUI workflow failed".into());
+                }
+                let completed = status
+                    .and_then(|s| s.get("completed"))
+                    .and_then(|c| c.as_bool())
+                    .unwrap_or(true);
+                if completed {
+                    return Ok(entry);
+                }
+            }
+
+            if started.elapsed() >= self.timeout {
+                error!(prompt_id, "Timed out waiting for ComfyUI workflow");
+                return Err("Timed out waiting for ComfyUI".into());
+            }
+            tokio::time::sleep(self.poll_interval).await;
+        }
+    }
+
+    async fn download_image(&self, image: &Value) -> Result<String, GenerateError> {
+        let field = |name: &str| image.get(name).and_then(|v| v.as_str()).unwrap_or("");
+        let response = self
+            .client
+            .get(format!("{}/view", self.base_url))
+            .query(&[
+                ("filename", field("filename")),
+                ("subfolder", field("subfolder")),
+                ("type", field("type")),
+            ])
+            .send()
+            .await?;
+        let response = check_status(response, "view").await?;
+
+        let mime_type = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("image/png")
+            .to_string();
+        let bytes = response.bytes().await?;
+        Ok(format!(
+            "data:{};base64,{}",
+            mime_type,
+            general_purpose::STANDARD.encode(&bytes)
+        ))
+    }
+
+    /// Run the workflow once on an uploaded selfie, for one view.
+    async fn run(
+        &self,
+        prompt: &str,
+        image_name: &str,
+        angle: &str,
+    ) -> Result<ImageVariation, GenerateError> {
+        let workflow = render_workflow(&self.workflow, prompt, image_name);
+        let prompt_id = self.queue_prompt(workflow).await?;
+        info!(prompt_id = %prompt_id, angle, "Queued ComfyUI workflow");
+
+        let entry = self.wait_for_completion(&prompt_id).await?;
+
+        // Node ids are strings, so order by (length, id) to get a stable numeric order
+        let mut outputs: Vec<(String, Value)> = match entry.get("outputs") {
+            Some(Value::Object(outputs)) => outputs
+                .iter()
+                .map(|(node_id, output)| (node_id.clone(), output.clone()))
+                .collect(),
+            _ => Vec::new(),
+        };
+        outputs.sort_by(|(a, _), (b, _)| (a.len(), a).cmp(&(b.len(), b)));
+
+        let image = outputs
+            .iter()
+            .filter_map(|(_, output)| output.get("images").and_then(|v| v.as_array()))
+            .flatten()
+            .find(|image| image.get("type").and_then(|t| t.as_str()) != Some("temp"));
+        let Some(image) = image else {
+            error!(prompt_id = %prompt_id, "ComfyUI workflow produced zero images");
+            return Err("No images generated".into());
+        };
+
+        Ok(ImageVariation {
+            image: self.download_image(image).await?,
+            angle: angle.to_string(),
+        })
+    }
+}
+
+async fn check_status(
+    response: reqwest::Response,
+    endpoint: &str,
+) -> Result<reqwest::Response, GenerateError> {
+    if response.status().is_success() {
+        return Ok(response);
+    }
+    let status = response.status();
+    let error_text = response
+        .text()
+        .await
+        .unwrap_or_else(|err| format!("Failed to read error body: {}", err));
+    error!(%status, body = %error_text, endpoint, "ComfyUI API error");
+    Err(format!("ComfyUI API error: {} - {}", status, error_text).into())
+}
+
+/// Substitute `{prompt}` and `{image}` in every string value of the workflow.
+fn render_workflow(template: &Value, prompt: &str, image_name: &str) -> Value {
+    match template {
+        Value::String(s) => {
+            Value::String(s.replace("{prompt}", prompt).replace("{image}", image_name))
+        }
+        Value::Array(items) => Value::Array(
+            items
+                .iter()
+                .map(|item| render_workflow(item, prompt, image_name))
+                .collect(),
+        ),
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .map(|(k, v)| (k.clone(), render_workflow(v, prompt, image_name)))
+                .collect(),
+        ),
+        other => other.clone(),
+    }
+}
+
+#[async_trait]
+impl ImageGenerator for ComfyUiGenerator {
+    fn name(&self) -> &'static str {
+        "comfyui"
+    }
+
+    async fn generate_front_view(
+        &self,
+        prompt: &str,
+        image: &InputImage,
+    ) -> Result<Vec<ImageVariation>, GenerateError> {
+        info!(
+            prompt_len = prompt.len(),
+            "Running ComfyUI workflow for front view"
+        );
+        let image_name = self.upload_image(image).await?;
+        Ok(vec![self.run(prompt, &image_name, "front").await?])
+    }
+
+    async fn generate_angle_views(
+        &self,
+        prompts: &AnglePrompts,
+        image: &InputImage,
+    ) -> Result<Vec<ImageVariation>, GenerateError> {
+        info!(
+            prompt_len = prompts.side.len() + prompts.back.len(),
+            "Running ComfyUI workflow for side and back views"
+        );
+        let image_name = self.upload_image(image).await?;
+        let (side, back) = tokio::try_join!(
+            self.run(&prompts.side, &image_name, "side"),
+            self.run(&prompts.back, &image_name, "back"),
+        )?;
+        Ok(vec![side, back])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::image_format::ImageFormat;
+    use axum::{
+        extract::{Multipart, Path as UrlPath, Query, State},
+        http::header,
+        routing::{get, post},
+        Json, Router,
+    };
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    fn selfie() -> InputImage {
+        InputImage {
+            data: b"selfie-bytes".to_vec(),
+            format: ImageFormat::Jpeg,
+        }
+    }
+
+    #[derive(Default)]
+    struct StandIn {
+        uploaded: Vec<u8>,
+        workflows: Vec<Value>,
+        history_polls: usize,
+    }
+
+    type Shared = Arc<Mutex<StandIn>>;
+
+    async fn upload(State(state): State<Shared>, mut multipart: Multipart) -> Json<Value> {
+        while let Some(field) = multipart.next_field().await.unwrap() {
+            if field.name() == Some("image") {
+                state.lock().unwrap().uploaded = field.bytes().await.unwrap().to_vec();
+            }
+        }
+        Json(serde_json::json!({ "name": "selfie.jpg", "subfolder": "", "type": "input" }))
+    }
+
+    async fn prompt(State(state): State<Shared>, Json(body): Json<Value>) -> Json<Value> {
+        let mut state = state.lock().unwrap();
+        state.workflows.push(body["prompt"].clone());
+        let number = state.workflows.len();
+        Json(serde_json::json!({ "prompt_id": format!("p{}", number), "number": number }))
+    }
+
+    // Reports a job as pending on the first poll, then finished with two images
+    // named after the prompt
+    async fn history(State(state): State<Shared>, UrlPath(id): UrlPath<String>) -> Json<Value> {
+        let mut state = state.lock().unwrap();
+        state.history_polls += 1;
+        if state.history_polls == 1 {
+            return Json(serde_json::json!({}));
+        }
+        let (first, second) = (format!("{}_1.png", id), format!("{}_2.png", id));
+        Json(serde_json::json!({
+            id: {
+                "status": { "status_str": "success", "completed": true },
+                "outputs": {
+                    "8": { "images": [
+                        { "filename": first, "subfolder": "", "type": "output" },
+                        { "filename": second, "subfolder": "", "type": "output" }
+                    ]}
+                }
+            }
+        }))
+    }
+
+    async fn view(
+        Query(params): Query<HashMap<String, String>>,
+    ) -> impl axum::response::IntoResponse {
+        (
+            [(header::CONTENT_TYPE, "image/png")],
+            params["filename"].clone().into_bytes(),
+        )
+    }
+
+    async fn spawn_stand_in() -> (String, Shared) {
+        let state = Shared::default();
+        let app = Router::new()
+            .route("/upload/image", post(upload))
+            .route("/prompt", post(prompt))
+            .route("/history/{id}", get(history))
+            .route("/view", get(view))
+            .with_state(Arc::clone(&state));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (format!("http://{}", addr), state)
+    }
+
+    fn test_generator(base_url: &str) -> ComfyUiGenerator {
+        let workflow = ComfyUiGenerator::load_workflow(Path::new("comfyui_workflow.json")).unwrap();
+        let mut generator = ComfyUiGenerator::new(base_url, workflow, Duration::from_secs(5));
+        generator.poll_interval = Duration::from_millis(10);
+        generator
+    }
+
+    #[test]
+    fn test_render_workflow_substitutes_placeholders() {
+        let template = serde_json::json!({
+            "1": { "inputs": { "text": "photo, {prompt}", "seed": 7 } },
+            "2": { "inputs": { "image": "{image}", "pair": ["1", 0] } }
+        });
+
+        let rendered = render_workflow(&template, "buzz cut", "selfie.jpg");
+
+        assert_eq!(rendered["1"]["inputs"]["text"], "photo, buzz cut");
+        assert_eq!(rendered["1"]["inputs"]["seed"], 7);
+        assert_eq!(rendered["2"]["inputs"]["image"], "selfie.jpg");
+        assert_eq!(rendered["2"]["inputs"]["pair"], serde_json::json!(["1", 0]));
+    }
+
+    #[tokio::test]
+    async fn test_front_view_runs_workflow_and_downloads_output() {
+        let (base_url, state) = spawn_stand_in().await;
+
+        let variations = test_generator(&base_url)
+            .generate_front_view("a mullet", &selfie())
+            .await
+            .unwrap();
+
+        assert_eq!(variations.len(), 1);
+        assert_eq!(variations[0].angle, "front");
+        assert_eq!(
+            variations[0].image,
+            format!(
+                "data:image/png;base64,{}",
+                general_purpose::STANDARD.encode("p1_1.png")
+            )
+        );
+
+        let state = state.lock().unwrap();
+        assert_eq!(state.uploaded, b"selfie-bytes");
+        assert_eq!(state.history_polls, 2);
+        let workflow = &state.workflows[0];
+        assert_eq!(workflow["2"]["inputs"]["image"], "selfie.jpg");
+        assert_eq!(workflow["4"]["inputs"]["text"], "a mullet");
+    }
+
+    #[tokio::test]
+    async fn test_each_angle_is_its_own_prompt() {
+        let (base_url, state) = spawn_stand_in().await;
+
+        let variations = test_generator(&base_url)
+            .generate_angle_views(
+                &AnglePrompts {
+                    side_and_back: "a mullet, side and back".to_string(),
+                    side: "a mullet, side".to_string(),
+                    back: "a mullet, back".to_string(),
+                },
+                &selfie(),
+            )
+            .await
+            .unwrap();
+
+        let angles: Vec<&str> = variations.iter().map(|v| v.angle.as_str()).collect();
+        assert_eq!(angles, vec!["side", "back"]);
+        // Each view is the first image of a different run
+        assert_ne!(variations[0].image, variations[1].image);
+
+        let state = state.lock().unwrap();
+        let mut prompts: Vec<&str> = state
+            .workflows
+            .iter()
+            .map(|workflow| workflow["4"]["inputs"]["text"].as_str().unwrap())
+            .collect();
+        // The prompts are queued concurrently, so they may arrive in either order
+        prompts.sort();
+        assert_eq!(prompts, vec!["a mullet, back", "a mullet, side"]);
+    }
+}
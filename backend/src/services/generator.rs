@@ -0,0 +1,63 @@
+use super::image_format::ImageFormat;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+
+pub type GenerateError = Box<dyn Error + Send + Sync>;
+
+/// The client's photo, decoded, with the format its bytes were sniffed as.
+#[derive(Debug, Clone)]
+pub struct InputImage {
+    pub data: Vec<u8>,
+    pub format: ImageFormat,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageVariation {
+    pub image: String,
+    pub angle: String, // "front", "side", or "back"
+}
+
+/// The rendered side and back prompts. Providers that return several images
+/// per call ask for both views with `side_and_back`; the others make one call
+/// per angle with `side` and `back`.
+#[derive(Debug, Clone)]
+pub struct AnglePrompts {
+    pub side_and_back: String,
+    pub side: String,
+    pub back: String,
+}
+
+impl AnglePrompts {
+    /// All three prompts, for cache keys: changing any of them changes what
+    /// some provider would be asked.
+    pub fn joined(&self) -> String {
+        [&self.side_and_back, &self.side, &self.back]
+            .map(String::as_str)
+            .join("\n")
+    }
+}
+
+/// A backend capable of turning a selfie plus a rendered prompt into haircut images.
+///
+/// Prompts are rendered by the caller, so implementations only deal with their
+/// provider's wire protocol.
+#[async_trait]
+pub trait ImageGenerator: Send + Sync {
+    /// Short provider name used in logs.
+    fn name(&self) -> &'static str;
+
+    /// Generate the front view, tagged with the "front" angle.
+    async fn generate_front_view(
+        &self,
+        prompt: &str,
+        image: &InputImage,
+    ) -> Result<Vec<ImageVariation>, GenerateError>;
+
+    /// Generate the side and back views, tagged "side" and "back" in that order.
+    async fn generate_angle_views(
+        &self,
+        prompts: &AnglePrompts,
+        image: &InputImage,
+    ) -> Result<Vec<ImageVariation>, GenerateError>;
+}
@@ -0,0 +1,106 @@
+/// Photo formats accepted from clients, recognized by their leading bytes
+/// rather than whatever the client claims.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Jpeg,
+    Png,
+    Webp,
+    Gif,
+    Heic,
+}
+
+/// HEIF brands for still images; `avif` and friends are deliberately absent.
+const HEIC_BRANDS: [&[u8; 4]; 8] = [
+    b"heic", b"heix", b"hevc", b"hevx", b"heim", b"heis", b"mif1", b"msf1",
+];
+
+impl ImageFormat {
+    /// Identify `data` by its magic bytes.
+    pub fn sniff(data: &[u8]) -> Option<Self> {
+        if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+            Some(ImageFormat::Jpeg)
+        } else if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Some(ImageFormat::Png)
+        } else if data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+            Some(ImageFormat::Webp)
+        } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+            Some(ImageFormat::Gif)
+        } else if data.len() >= 12
+            && &data[4..8] == b"ftyp"
+            && HEIC_BRANDS.iter().any(|brand| &data[8..12] == *brand)
+        {
+            Some(ImageFormat::Heic)
+        } else {
+            None
+        }
+    }
+
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            ImageFormat::Jpeg => "image/jpeg",
+            ImageFormat::Png => "image/png",
+            ImageFormat::Webp => "image/webp",
+            ImageFormat::Gif => "image/gif",
+            ImageFormat::Heic => "image/heic",
+        }
+    }
+
+    /// File extension for providers that want a file name.
+    pub fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::Jpeg => "jpg",
+            ImageFormat::Png => "png",
+            ImageFormat::Webp => "webp",
+            ImageFormat::Gif => "gif",
+            ImageFormat::Heic => "heic",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sniff_known_formats() {
+        let cases: [(&[u8], ImageFormat); 6] = [
+            (b"\xFF\xD8\xFF\xE0\x00\x10JFIF", ImageFormat::Jpeg),
+            (b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR", ImageFormat::Png),
+            (b"RIFF\x24\x00\x00\x00WEBPVP8 ", ImageFormat::Webp),
+            (b"GIF89a\x01\x00\x01\x00", ImageFormat::Gif),
+            (
+                b"\x00\x00\x00\x18ftypheic\x00\x00\x00\x00",
+                ImageFormat::Heic,
+            ),
+            (
+                b"\x00\x00\x00\x1cftypmif1\x00\x00\x00\x00",
+                ImageFormat::Heic,
+            ),
+        ];
+        for (data, format) in cases {
+            assert_eq!(ImageFormat::sniff(data), Some(format), "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn test_sniff_rejects_non_images() {
+        for data in [
+            &b""[..],
+            b"AAAAAAAAAAAA",
+            b"<svg xmlns=\"http://www.w3.org/2000/svg\">",
+            b"%PDF-1.7",
+            b"RIFF\x24\x00\x00\x00WAVEfmt ",
+            b"\x00\x00\x00\x1cftypavif\x00\x00\x00\x00",
+            b"\x89PNG",
+        ] {
+            assert_eq!(ImageFormat::sniff(data), None, "{:?}", data);
+        }
+    }
+
+    #[test]
+    fn test_mime_types() {
+        assert_eq!(ImageFormat::Jpeg.mime_type(), "image/jpeg");
+        assert_eq!(ImageFormat::Webp.mime_type(), "image/webp");
+        assert_eq!(ImageFormat::Heic.extension(), "heic");
+    }
+}
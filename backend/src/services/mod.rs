@@ -1,2 +1,21 @@
+pub mod comfyui;
 pub mod gemini;
+pub mod generator;
+pub mod image_format;
+pub mod openai;
 pub mod prompts;
+pub mod template;
+
+use crate::config::GeneratorConfig;
+use generator::{GenerateError, ImageGenerator};
+use std::sync::Arc;
+
+/// Build the image generator selected by `config.provider`.
+pub fn build_generator(config: &GeneratorConfig) -> Result<Arc<dyn ImageGenerator>, GenerateError> {
+    match config.provider.as_str() {
+        "gemini" => Ok(Arc::new(gemini::GeminiGenerator::from_config(config)?)),
+        "openai" => Ok(Arc::new(openai::OpenAiGenerator::from_config(config))),
+        "comfyui" => Ok(Arc::new(comfyui::ComfyUiGenerator::from_config(config)?)),
+        other => Err(format!("Unknown image provider: {}", other).into()),
+    }
+}
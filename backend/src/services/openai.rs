@@ -0,0 +1,317 @@
+use crate::config::GeneratorConfig;
+use crate::services::generator::{
+    AnglePrompts, GenerateError, ImageGenerator, ImageVariation, InputImage,
+};
+use async_trait::async_trait;
+use base64::{engine::general_purpose, Engine as _};
+use reqwest::multipart::{Form, Part};
+use tracing::{error, info};
+
+const DEFAULT_BASE_URL: &str = "https://api.openai.com/v1";
+pub const DEFAULT_MODEL: &str = "gpt-image-1";
+
+/// Generator for servers speaking the OpenAI-style `/images/edits` protocol.
+///
+/// Every image in one edits call comes from the same prompt, so each view is
+/// its own call with `n = 1`, and the side and back views each use their own
+/// single-angle template.
+pub struct OpenAiGenerator {
+    client: reqwest::Client,
+    api_key: Option<String>,
+    url: String,
+    model: String,
+}
+
+impl OpenAiGenerator {
+    pub fn new(api_key: Option<String>, base_url: &str, model: &str) -> Self {
+        OpenAiGenerator {
+            client: reqwest::Client::new(),
+            api_key,
+            url: format!("{}/images/edits", base_url.trim_end_matches('/')),
+            model: model.to_string(),
+        }
+    }
+
+    /// The API key is optional since many self-hosted servers don't check it.
+    pub fn from_config(config: &GeneratorConfig) -> Self {
+        Self::new(
+            config.api_key.clone(),
+            config.base_url.as_deref().unwrap_or(DEFAULT_BASE_URL),
+            config.model.as_deref().unwrap_or(DEFAULT_MODEL),
+        )
+    }
+
+    /// One image of `angle`.
+    async fn edit(
+        &self,
+        prompt: &str,
+        image: &InputImage,
+        angle: &str,
+    ) -> Result<ImageVariation, GenerateError> {
+        let image_part = Part::bytes(image.data.clone())
+            .file_name(format!("selfie.{}", image.format.extension()))
+            .mime_str(image.format.mime_type())?;
+
+        let form = Form::new()
+            .part("image", image_part)
+            .text("prompt", prompt.to_string())
+            .text("model", self.model.clone())
+            .text("n", "1");
+
+        let mut request = self.client.post(&self.url).multipart(form);
+        if let Some(api_key) = &self.api_key {
+            request = request.bearer_auth(api_key);
+        }
+
+        let response = request.send().await?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            let error_text = response
+                .text()
+                .await
+                .unwrap_or_else(|err| format!("Failed to read error body: {}", err));
+            error!(
+                %status,
+                body = %error_text,
+                "OpenAI-compatible images API error"
+            );
+            return Err(format!("Images API error: {} - {}", status, error_text).into());
+        }
+
+        let response_text = response.text().await?;
+        let edit_response: serde_json::Value =
+            serde_json::from_str(&response_text).map_err(|_| "JSON parse error")?;
+
+        let mime_type = match edit_response.get("output_format").and_then(|v| v.as_str()) {
+            Some("jpeg") => "image/jpeg",
+            Some("webp") => "image/webp",
+            _ => "image/png",
+        };
+
+        let image = edit_response
+            .get("data")
+            .and_then(|d| d.as_array())
+            .and_then(|images| images.first());
+        let data = if let Some(b64) = image
+            .and_then(|image| image.get("b64_json"))
+            .and_then(|v| v.as_str())
+        {
+            b64.to_string()
+        } else if let Some(url) = image
+            .and_then(|image| image.get("url"))
+            .and_then(|v| v.as_str())
+        {
+            let bytes = self.client.get(url).send().await?.bytes().await?;
+            general_purpose::STANDARD.encode(&bytes)
+        } else {
+            error!(angle, "Images API returned zero images");
+            return Err("No images generated".into());
+        };
+
+        Ok(ImageVariation {
+            image: format!("data:{};base64,{}", mime_type, data),
+            angle: angle.to_string(),
+        })
+    }
+}
+
+#[async_trait]
+impl ImageGenerator for OpenAiGenerator {
+    fn name(&self) -> &'static str {
+        "openai"
+    }
+
+    async fn generate_front_view(
+        &self,
+        prompt: &str,
+        image: &InputImage,
+    ) -> Result<Vec<ImageVariation>, GenerateError> {
+        info!(
+            prompt_len = prompt.len(),
+            model = %self.model,
+            "Calling images/edits for front view"
+        );
+        Ok(vec![self.edit(prompt, image, "front").await?])
+    }
+
+    async fn generate_angle_views(
+        &self,
+        prompts: &AnglePrompts,
+        image: &InputImage,
+    ) -> Result<Vec<ImageVariation>, GenerateError> {
+        info!(
+            prompt_len = prompts.side.len() + prompts.back.len(),
+            model = %self.model,
+            "Calling images/edits for side and back views"
+        );
+        let (side, back) = tokio::try_join!(
+            self.edit(&prompts.side, image, "side"),
+            self.edit(&prompts.back, image, "back"),
+        )?;
+        Ok(vec![side, back])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::image_format::ImageFormat;
+    use axum::{
+        extract::{Multipart, State},
+        http::{HeaderMap, StatusCode},
+        routing::post,
+        Json, Router,
+    };
+    use std::sync::{Arc, Mutex};
+
+    fn selfie() -> InputImage {
+        InputImage {
+            data: b"selfie-bytes".to_vec(),
+            format: ImageFormat::Jpeg,
+        }
+    }
+
+    #[derive(Default, Debug)]
+    struct ReceivedEdit {
+        authorization: Option<String>,
+        fields: Vec<(String, String)>,
+        image: Vec<u8>,
+    }
+
+    type Received = Arc<Mutex<Vec<ReceivedEdit>>>;
+
+    // Minimal stand-in for an images/edits server that echoes `n` base64 images
+    async fn images_edits(
+        State(received): State<Received>,
+        headers: HeaderMap,
+        mut multipart: Multipart,
+    ) -> Result<Json<serde_json::Value>, StatusCode> {
+        let mut edit = ReceivedEdit {
+            authorization: headers
+                .get("authorization")
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string),
+            ..Default::default()
+        };
+
+        while let Some(field) = multipart.next_field().await.unwrap() {
+            let name = field.name().unwrap_or_default().to_string();
+            if name == "image" {
+                edit.image = field.bytes().await.unwrap().to_vec();
+            } else {
+                edit.fields.push((name, field.text().await.unwrap()));
+            }
+        }
+
+        let n: usize = edit
+            .fields
+            .iter()
+            .find(|(name, _)| name == "n")
+            .and_then(|(_, value)| value.parse().ok())
+            .ok_or(StatusCode::BAD_REQUEST)?;
+        received.lock().unwrap().push(edit);
+
+        let data: Vec<_> = (0..n)
+            .map(|i| serde_json::json!({ "b64_json": general_purpose::STANDARD.encode([i as u8]) }))
+            .collect();
+        Ok(Json(serde_json::json!({ "data": data })))
+    }
+
+    async fn spawn_stand_in() -> (String, Received) {
+        let received = Received::default();
+        let app = Router::new()
+            .route("/v1/images/edits", post(images_edits))
+            .with_state(Arc::clone(&received));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (format!("http://{}/v1", addr), received)
+    }
+
+    #[tokio::test]
+    async fn test_front_view_uploads_selfie_and_prompt() {
+        let (base_url, received) = spawn_stand_in().await;
+        let generator = OpenAiGenerator::new(Some("sk-test".to_string()), &base_url, "test-model");
+
+        let variations = generator
+            .generate_front_view("front prompt", &selfie())
+            .await
+            .unwrap();
+
+        assert_eq!(variations.len(), 1);
+        assert_eq!(variations[0].angle, "front");
+        assert_eq!(variations[0].image, "data:image/png;base64,AA==");
+
+        let edits = received.lock().unwrap();
+        let edit = &edits[0];
+        assert_eq!(edit.authorization.as_deref(), Some("Bearer sk-test"));
+        assert_eq!(edit.image, b"selfie-bytes");
+        assert!(edit
+            .fields
+            .contains(&("prompt".to_string(), "front prompt".to_string())));
+        assert!(edit
+            .fields
+            .contains(&("model".to_string(), "test-model".to_string())));
+        assert!(edit.fields.contains(&("n".to_string(), "1".to_string())));
+    }
+
+    #[tokio::test]
+    async fn test_each_angle_is_its_own_call_with_its_own_prompt() {
+        let (base_url, received) = spawn_stand_in().await;
+        let generator = OpenAiGenerator::new(None, &base_url, "test-model");
+
+        let variations = generator
+            .generate_angle_views(
+                &AnglePrompts {
+                    side_and_back: "both prompt".to_string(),
+                    side: "side prompt".to_string(),
+                    back: "back prompt".to_string(),
+                },
+                &selfie(),
+            )
+            .await
+            .unwrap();
+
+        let angles: Vec<&str> = variations.iter().map(|v| v.angle.as_str()).collect();
+        assert_eq!(angles, vec!["side", "back"]);
+
+        let edits = received.lock().unwrap();
+        assert_eq!(edits.len(), 2);
+        let mut prompts: Vec<&str> = edits
+            .iter()
+            .map(|edit| {
+                assert!(edit.authorization.is_none());
+                assert!(edit.fields.contains(&("n".to_string(), "1".to_string())));
+                let (_, prompt) = edit
+                    .fields
+                    .iter()
+                    .find(|(name, _)| name == "prompt")
+                    .unwrap();
+                prompt.as_str()
+            })
+            .collect();
+        // The calls run concurrently, so they may arrive in either order
+        prompts.sort();
+        assert_eq!(prompts, vec!["back prompt", "side prompt"]);
+    }
+
+    #[tokio::test]
+    async fn test_error_status_is_reported() {
+        let app = Router::new().route(
+            "/v1/images/edits",
+            post(|| async { (StatusCode::BAD_REQUEST, "bad image") }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let generator = OpenAiGenerator::new(None, &format!("http://{}/v1", addr), "test-model");
+        let err = generator
+            .generate_front_view("front prompt", &selfie())
+            .await
+            .unwrap_err();
+
+        assert!(err.to_string().contains("400"));
+    }
+}
@@ -1,39 +1,611 @@
+use crate::services::generator::AnglePrompts;
+use crate::services::template::{PromptVars, Template};
 use serde::Deserialize;
+use std::collections::BTreeMap;
 use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tracing::{error, info};
+
+type PromptError = Box<dyn std::error::Error + Send + Sync>;
 
 #[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 struct PromptTemplate {
     template: String,
+    /// Placeholders that must appear unconditionally
+    #[serde(default = "default_required")]
+    required: Vec<String>,
+}
+
+fn default_required() -> Vec<String> {
+    vec!["haircut".to_string()]
 }
 
 #[derive(Debug, Deserialize)]
-struct PromptConfig {
+#[serde(deny_unknown_fields)]
+struct VersionConfig {
     front_view: PromptTemplate,
+    /// Both views at once, for providers that return several images per call
     side_and_back_views: PromptTemplate,
+    /// One view each, for providers that return one image per call
+    side_view: PromptTemplate,
+    back_view: PromptTemplate,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ExperimentConfig {
+    name: String,
+    /// Version name -> percentage of clients. Clients outside the total fall
+    /// through to the next experiment, then to the default version.
+    arms: BTreeMap<String, u32>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PromptConfig {
+    default_version: Option<String>,
+    #[serde(default)]
+    versions: BTreeMap<String, VersionConfig>,
+    #[serde(default)]
+    experiments: Vec<ExperimentConfig>,
+    // Single-version layout, loaded as the "default" version
+    front_view: Option<PromptTemplate>,
+    side_and_back_views: Option<PromptTemplate>,
+    side_view: Option<PromptTemplate>,
+    back_view: Option<PromptTemplate>,
+}
+
+const LEGACY_VERSION: &str = "default";
+
+pub struct PromptVersion {
+    front_view: Template,
+    side_and_back_views: Template,
+    side_view: Template,
+    back_view: Template,
+}
+
+impl PromptVersion {
+    pub fn front_view(&self, vars: &PromptVars) -> String {
+        self.front_view.render(vars)
+    }
+
+    /// The side and back prompts, both together and one per angle; each
+    /// provider uses whichever matches how many images it returns per call.
+    pub fn angle_views(&self, vars: &PromptVars) -> AnglePrompts {
+        AnglePrompts {
+            side_and_back: self.side_and_back_views.render(vars),
+            side: self.side_view.render(vars),
+            back: self.back_view.render(vars),
+        }
+    }
+}
+
+struct Experiment {
+    name: String,
+    arms: Vec<(String, u32)>,
+}
+
+/// The version a client was assigned, and the experiment that assigned it.
+pub struct Selection<'a> {
+    pub version: &'a str,
+    pub experiment: Option<&'a str>,
+    pub templates: &'a PromptVersion,
 }
 
 pub struct Prompts {
-    config: PromptConfig,
+    default_version: String,
+    versions: BTreeMap<String, PromptVersion>,
+    experiments: Vec<Experiment>,
 }
 
 impl Prompts {
-    pub fn load() -> Result<Self, Box<dyn std::error::Error>> {
-        let config_content = fs::read_to_string("prompts.toml")?;
-        let config: PromptConfig = toml::from_str(&config_content)?;
-        Ok(Prompts { config })
+    pub fn load(path: &Path) -> Result<Self, PromptError> {
+        let config_content = fs::read_to_string(path)
+            .map_err(|err| format!("Failed to read {}: {}", path.display(), err))?;
+        Self::parse(&config_content)
     }
 
-    pub fn front_view(&self, haircut_description: &str) -> String {
-        self.config
-            .front_view
-            .template
-            .replace("{haircut}", haircut_description)
+    pub fn parse(config_content: &str) -> Result<Self, PromptError> {
+        let mut config: PromptConfig = toml::from_str(config_content)?;
+
+        match (
+            config.front_view.take(),
+            config.side_and_back_views.take(),
+            config.side_view.take(),
+            config.back_view.take(),
+        ) {
+            (None, None, None, None) => {}
+            _ if !config.versions.is_empty() => {
+                return Err("use either top-level templates or [versions.*], not both".into())
+            }
+            (Some(front_view), Some(side_and_back_views), Some(side_view), Some(back_view)) => {
+                config.versions.insert(
+                    LEGACY_VERSION.to_string(),
+                    VersionConfig {
+                        front_view,
+                        side_and_back_views,
+                        side_view,
+                        back_view,
+                    },
+                );
+            }
+            _ => return Err(
+                "top-level templates need front_view, side_and_back_views, side_view and back_view"
+                    .into(),
+            ),
+        }
+
+        let compile = |name: String, prompt: &PromptTemplate| {
+            Template::parse(&prompt.template, &prompt.required)
+                .map_err(|err| format!("{}: {}", name, err))
+        };
+        let mut versions = BTreeMap::new();
+        for (name, version) in &config.versions {
+            // Keep the old error prefix for the single-version layout
+            let prefix = if name == LEGACY_VERSION {
+                String::new()
+            } else {
+                format!("versions.{}.", name)
+            };
+            versions.insert(
+                name.clone(),
+                PromptVersion {
+                    front_view: compile(format!("{}front_view", prefix), &version.front_view)?,
+                    side_and_back_views: compile(
+                        format!("{}side_and_back_views", prefix),
+                        &version.side_and_back_views,
+                    )?,
+                    side_view: compile(format!("{}side_view", prefix), &version.side_view)?,
+                    back_view: compile(format!("{}back_view", prefix), &version.back_view)?,
+                },
+            );
+        }
+
+        let default_version = match config.default_version {
+            Some(name) => name,
+            None if versions.len() == 1 => versions.keys().next().unwrap().clone(),
+            None => return Err("default_version is required with several versions".into()),
+        };
+        if !versions.contains_key(&default_version) {
+            return Err(format!("default_version {} is not defined", default_version).into());
+        }
+
+        let mut experiments: Vec<Experiment> = Vec::new();
+        for experiment in config.experiments {
+            if experiments.iter().any(|e| e.name == experiment.name) {
+                return Err(format!("experiment {} is defined twice", experiment.name).into());
+            }
+            if let Some(unknown) = experiment.arms.keys().find(|v| !versions.contains_key(*v)) {
+                return Err(format!(
+                    "experiment {} uses undefined version {}",
+                    experiment.name, unknown
+                )
+                .into());
+            }
+            if experiment.arms.values().sum::<u32>() > 100 {
+                return Err(
+                    format!("experiment {} arms add up to over 100%", experiment.name).into(),
+                );
+            }
+            experiments.push(Experiment {
+                name: experiment.name,
+                arms: experiment.arms.into_iter().collect(),
+            });
+        }
+
+        Ok(Prompts {
+            default_version,
+            versions,
+            experiments,
+        })
+    }
+
+    /// Pick the prompt version for a client. Assignment is a pure function of
+    /// the client key and experiment name, so a client keeps its arm across
+    /// requests, restarts and replicas.
+    pub fn select(&self, client_key: &str) -> Selection<'_> {
+        for experiment in &self.experiments {
+            let bucket = (fnv1a(&format!("{}:{}", experiment.name, client_key)) % 100) as u32;
+            let mut upper = 0;
+            for (version, weight) in &experiment.arms {
+                upper += weight;
+                if bucket < upper {
+                    return Selection {
+                        version,
+                        experiment: Some(&experiment.name),
+                        templates: &self.versions[version],
+                    };
+                }
+            }
+        }
+        Selection {
+            version: &self.default_version,
+            experiment: None,
+            templates: &self.versions[&self.default_version],
+        }
+    }
+}
+
+/// 64-bit FNV-1a; unlike `DefaultHasher` it is stable across Rust releases.
+fn fnv1a(input: &str) -> u64 {
+    input.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// The live prompt templates, swappable at runtime.
+///
+/// Requests take an `Arc` snapshot via `current()`, so a reload never changes
+/// the templates under an in-flight request.
+pub struct PromptStore {
+    path: PathBuf,
+    current: RwLock<Arc<Prompts>>,
+}
+
+impl PromptStore {
+    pub fn load(path: &Path) -> Result<Self, PromptError> {
+        Ok(Self::new(path, Prompts::load(path)?))
+    }
+
+    pub fn new(path: &Path, prompts: Prompts) -> Self {
+        PromptStore {
+            path: path.to_path_buf(),
+            current: RwLock::new(Arc::new(prompts)),
+        }
     }
 
-    pub fn side_and_back_views(&self, haircut_description: &str) -> String {
-        self.config
-            .side_and_back_views
-            .template
-            .replace("{haircut}", haircut_description)
+    pub fn current(&self) -> Arc<Prompts> {
+        Arc::clone(&self.current.read().unwrap())
+    }
+
+    /// Re-read the file and swap it in. On error the old templates stay live.
+    pub fn reload(&self) -> Result<(), PromptError> {
+        match Prompts::load(&self.path) {
+            Ok(prompts) => {
+                *self.current.write().unwrap() = Arc::new(prompts);
+                info!(path = %self.path.display(), "Reloaded prompt templates");
+                Ok(())
+            }
+            Err(err) => {
+                error!(
+                    path = %self.path.display(),
+                    error = %err,
+                    "Rejected prompt templates, keeping previous version"
+                );
+                Err(err)
+            }
+        }
+    }
+
+    fn modified(&self) -> Option<SystemTime> {
+        fs::metadata(&self.path).and_then(|m| m.modified()).ok()
+    }
+
+    /// Poll the file's modification time and reload whenever it changes.
+    pub fn spawn_watcher(self: Arc<Self>, interval: Duration) -> tokio::task::JoinHandle<()> {
+        let mut last_modified = self.modified();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let modified = self.modified();
+                if modified != last_modified {
+                    last_modified = modified;
+                    // Errors are logged by reload; keep watching for a fixed file
+                    let _ = self.reload();
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VALID: &str = r#"
+        [front_view]
+        template = "front v1: {haircut}"
+
+        [side_and_back_views]
+        template = "angles v1: {haircut}"
+
+        [side_view]
+        template = "side v1: {haircut}"
+
+        [back_view]
+        template = "back v1: {haircut}"
+    "#;
+
+    fn temp_prompts_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("hmb-{}-{}.toml", name, std::process::id()));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_shipped_prompts_file_is_valid() {
+        let prompts = Prompts::load(Path::new("prompts.toml")).unwrap();
+        let mut vars = PromptVars::new("mullet");
+        vars.color = Some("bleached".to_string());
+
+        let rendered = prompts.select("client").templates.front_view(&vars);
+        assert!(rendered.contains("with a mullet haircut"));
+        assert!(rendered.contains("Color the hair bleached."));
+        let angles = prompts.select("client").templates.angle_views(&vars);
+        assert!(!angles.side_and_back.contains("Avoid"));
+        assert!(angles.side.contains("side profile"));
+        assert!(!angles.side.contains("back of the head"));
+        assert!(angles.back.contains("back of the head"));
+        assert!(!angles.back.contains("side profile"));
+    }
+
+    #[test]
+    fn test_templates_without_haircut_are_rejected() {
+        let err = Prompts::parse(
+            r#"
+            [front_view]
+            template = "front"
+
+            [side_and_back_views]
+            template = "angles: {haircut}"
+
+            [side_view]
+            template = "side: {haircut}"
+
+            [back_view]
+            template = "back: {haircut}"
+            "#,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "front_view: required placeholder {haircut} is missing"
+        );
+    }
+
+    #[test]
+    fn test_per_template_required_placeholders() {
+        let err = Prompts::parse(
+            r#"
+            [front_view]
+            template = "front: {haircut}"
+            required = ["haircut", "color"]
+
+            [side_and_back_views]
+            template = "angles: {haircut}"
+
+            [side_view]
+            template = "side: {haircut}"
+
+            [back_view]
+            template = "back: {haircut}"
+            "#,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "front_view: required placeholder {color} is missing"
+        );
+    }
+
+    const VERSIONED: &str = r#"
+        default_version = "v1"
+
+        [versions.v1.front_view]
+        template = "front v1: {haircut}"
+        [versions.v1.side_and_back_views]
+        template = "angles v1: {haircut}"
+        [versions.v1.side_view]
+        template = "side v1: {haircut}"
+        [versions.v1.back_view]
+        template = "back v1: {haircut}"
+
+        [versions.v2.front_view]
+        template = "front v2: {haircut}"
+        [versions.v2.side_and_back_views]
+        template = "angles v2: {haircut}"
+        [versions.v2.side_view]
+        template = "side v2: {haircut}"
+        [versions.v2.back_view]
+        template = "back v2: {haircut}"
+
+        [[experiments]]
+        name = "joint-angles"
+        arms = { v1 = 50, v2 = 50 }
+    "#;
+
+    #[test]
+    fn test_every_angle_template_is_required_and_validated() {
+        let err = Prompts::parse(&VALID.replace("[back_view]", "[unused]"))
+            .err()
+            .unwrap();
+        assert!(
+            err.to_string().contains("unknown field `unused`"),
+            "{}",
+            err
+        );
+
+        let err = Prompts::parse(VALID.split("[back_view]").next().unwrap())
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "top-level templates need front_view, side_and_back_views, side_view and back_view"
+        );
+
+        let err = Prompts::parse(&VALID.replace("side v1: {haircut}", "side v1"))
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "side_view: required placeholder {haircut} is missing"
+        );
+
+        let err = Prompts::parse(&VERSIONED.replace("back v2: {haircut}", "back v2"))
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "versions.v2.back_view: required placeholder {haircut} is missing"
+        );
+
+        let angles = Prompts::parse(VALID)
+            .unwrap()
+            .select("client")
+            .templates
+            .angle_views(&PromptVars::new("fade"));
+        assert_eq!(angles.side_and_back, "angles v1: fade");
+        assert_eq!(angles.side, "side v1: fade");
+        assert_eq!(angles.back, "back v1: fade");
+    }
+
+    #[test]
+    fn test_single_version_layout_is_the_default_version() {
+        let prompts = Prompts::parse(VALID).unwrap();
+        let selection = prompts.select("client");
+        assert_eq!(selection.version, "default");
+        assert!(selection.experiment.is_none());
+    }
+
+    #[test]
+    fn test_experiment_assignment_is_sticky_and_split() {
+        let prompts = Prompts::parse(VERSIONED).unwrap();
+
+        let mut counts = BTreeMap::new();
+        for i in 0..1000 {
+            let client = format!("client-{}", i);
+            let first = prompts.select(&client);
+            let again = prompts.select(&client);
+            assert_eq!(first.version, again.version);
+            assert_eq!(first.experiment, Some("joint-angles"));
+            assert_eq!(
+                first.templates.front_view(&PromptVars::new("fade")),
+                format!("front {}: fade", first.version)
+            );
+            *counts.entry(first.version.to_string()).or_insert(0) += 1;
+        }
+
+        // Roughly 50/50
+        assert!(counts["v1"] > 400 && counts["v2"] > 400, "{:?}", counts);
+    }
+
+    #[test]
+    fn test_clients_outside_experiments_get_default() {
+        let prompts =
+            Prompts::parse(&VERSIONED.replace("{ v1 = 50, v2 = 50 }", "{ v2 = 0 }")).unwrap();
+        let selection = prompts.select("client");
+        assert_eq!(selection.version, "v1");
+        assert!(selection.experiment.is_none());
+    }
+
+    #[test]
+    fn test_invalid_experiments_are_rejected() {
+        let undefined = VERSIONED.replace("v2 = 50", "v3 = 50");
+        assert_eq!(
+            Prompts::parse(&undefined).err().unwrap().to_string(),
+            "experiment joint-angles uses undefined version v3"
+        );
+
+        let too_much = VERSIONED.replace("v2 = 50", "v2 = 60");
+        assert_eq!(
+            Prompts::parse(&too_much).err().unwrap().to_string(),
+            "experiment joint-angles arms add up to over 100%"
+        );
+
+        let bad_default = VERSIONED.replace("default_version = \"v1\"", "default_version = \"v9\"");
+        assert_eq!(
+            Prompts::parse(&bad_default).err().unwrap().to_string(),
+            "default_version v9 is not defined"
+        );
+    }
+
+    #[test]
+    fn test_reload_swaps_templates_but_keeps_snapshots() {
+        let path = temp_prompts_file("reload", VALID);
+        let store = PromptStore::load(&path).unwrap();
+        let in_flight = store.current();
+
+        fs::write(&path, VALID.replace("v1", "v2")).unwrap();
+        store.reload().unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            in_flight
+                .select("client")
+                .templates
+                .front_view(&PromptVars::new("fade")),
+            "front v1: fade"
+        );
+        assert_eq!(
+            store
+                .current()
+                .select("client")
+                .templates
+                .front_view(&PromptVars::new("fade")),
+            "front v2: fade"
+        );
+    }
+
+    #[test]
+    fn test_bad_reload_keeps_previous_templates() {
+        let path = temp_prompts_file("bad-reload", VALID);
+        let store = PromptStore::load(&path).unwrap();
+
+        fs::write(&path, "[front_view]\ntemplate = \"no placeholder\"\n").unwrap();
+        assert!(store.reload().is_err());
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            store
+                .current()
+                .select("client")
+                .templates
+                .front_view(&PromptVars::new("fade")),
+            "front v1: fade"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_watcher_picks_up_changes() {
+        let path = temp_prompts_file("watch", VALID);
+        let store = Arc::new(PromptStore::load(&path).unwrap());
+        let watcher = Arc::clone(&store).spawn_watcher(Duration::from_millis(10));
+
+        // Make sure the new mtime differs even on coarse-grained filesystems
+        let file = fs::File::options().write(true).open(&path).unwrap();
+        fs::write(&path, VALID.replace("v1", "v2")).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(5))
+            .unwrap();
+
+        for _ in 0..100 {
+            if store
+                .current()
+                .select("client")
+                .templates
+                .front_view(&PromptVars::new("fade"))
+                == "front v2: fade"
+            {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        watcher.abort();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            store
+                .current()
+                .select("client")
+                .templates
+                .front_view(&PromptVars::new("fade")),
+            "front v2: fade"
+        );
     }
 }
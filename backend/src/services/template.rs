@@ -0,0 +1,231 @@
+//! Minimal prompt template language.
+//!
+//! `{name}` inserts a variable and `{?name}...{/name}` keeps its body only when
+//! `name` is set. Templates are parsed once, so unknown or unbalanced
+//! placeholders are caught when the prompts file is loaded, not mid-request.
+
+use std::fmt;
+
+/// Every variable a template may reference.
+pub const VARIABLES: &[&str] = &[
+    "haircut",
+    "texture",
+    "length",
+    "color",
+    "style_notes",
+    "avoid",
+];
+
+/// Values for one render. Unset optional fields make their sections vanish.
+#[derive(Debug, Default, Clone)]
+pub struct PromptVars {
+    pub haircut: String,
+    pub texture: Option<String>,
+    pub length: Option<String>,
+    pub color: Option<String>,
+    pub style_notes: Option<String>,
+    pub avoid: Vec<String>,
+}
+
+impl PromptVars {
+    pub fn new(haircut: &str) -> Self {
+        PromptVars {
+            haircut: haircut.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn get(&self, name: &str) -> Option<String> {
+        let value = match name {
+            "haircut" => Some(self.haircut.clone()),
+            "texture" => self.texture.clone(),
+            "length" => self.length.clone(),
+            "color" => self.color.clone(),
+            "style_notes" => self.style_notes.clone(),
+            "avoid" => Some(self.avoid.join(", ")),
+            _ => None,
+        };
+        value.filter(|v| !v.trim().is_empty())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct TemplateError(String);
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+#[derive(Debug)]
+enum Node {
+    Text(String),
+    Var(&'static str),
+    Section(&'static str, Vec<Node>),
+}
+
+#[derive(Debug)]
+pub struct Template {
+    nodes: Vec<Node>,
+}
+
+impl Template {
+    /// Parse `source`, rejecting unknown placeholders and any of `required`
+    /// that never appear.
+    pub fn parse(source: &str, required: &[String]) -> Result<Self, TemplateError> {
+        let mut stack: Vec<(&'static str, Vec<Node>)> = Vec::new();
+        let mut nodes = Vec::new();
+        let mut rest = source;
+
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                nodes.push(Node::Text(rest[..start].to_string()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .map(|i| start + i)
+                .ok_or_else(|| TemplateError("unclosed '{' in template".to_string()))?;
+            let tag = &rest[start + 1..end];
+            rest = &rest[end + 1..];
+
+            if let Some(name) = tag.strip_prefix('?') {
+                stack.push((lookup(name)?, std::mem::take(&mut nodes)));
+            } else if let Some(name) = tag.strip_prefix('/') {
+                let name = lookup(name)?;
+                match stack.pop() {
+                    Some((open, parent)) if open == name => {
+                        let body = std::mem::replace(&mut nodes, parent);
+                        nodes.push(Node::Section(name, body));
+                    }
+                    Some((open, _)) => {
+                        return Err(TemplateError(format!("{{/{}}} closes {{?{}}}", name, open)))
+                    }
+                    None => {
+                        return Err(TemplateError(format!(
+                            "{{/{}}} has no matching {{?{}}}",
+                            name, name
+                        )))
+                    }
+                }
+            } else {
+                nodes.push(Node::Var(lookup(tag)?));
+            }
+        }
+        if !rest.is_empty() {
+            nodes.push(Node::Text(rest.to_string()));
+        }
+
+        if let Some((open, _)) = stack.pop() {
+            return Err(TemplateError(format!("{{?{}}} is never closed", open)));
+        }
+
+        let template = Template { nodes };
+        for name in required {
+            let name = lookup(name)?;
+            if !template.uses(name) {
+                return Err(TemplateError(format!(
+                    "required placeholder {{{}}} is missing",
+                    name
+                )));
+            }
+        }
+        Ok(template)
+    }
+
+    /// Whether `{name}` is used outside any conditional section.
+    fn uses(&self, name: &str) -> bool {
+        self.nodes
+            .iter()
+            .any(|node| matches!(node, Node::Var(var) if *var == name))
+    }
+
+    pub fn render(&self, vars: &PromptVars) -> String {
+        let mut out = String::new();
+        render_nodes(&self.nodes, vars, &mut out);
+        out
+    }
+}
+
+fn lookup(name: &str) -> Result<&'static str, TemplateError> {
+    VARIABLES
+        .iter()
+        .find(|known| **known == name)
+        .copied()
+        .ok_or_else(|| TemplateError(format!("unknown placeholder {{{}}}", name)))
+}
+
+fn render_nodes(nodes: &[Node], vars: &PromptVars, out: &mut String) {
+    for node in nodes {
+        match node {
+            Node::Text(text) => out.push_str(text),
+            Node::Var(name) => out.push_str(&vars.get(name).unwrap_or_default()),
+            Node::Section(name, body) => {
+                if vars.get(name).is_some() {
+                    render_nodes(body, vars, out);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn required() -> Vec<String> {
+        vec!["haircut".to_string()]
+    }
+
+    #[test]
+    fn test_renders_variables_and_sections() {
+        let template = Template::parse(
+            "A {haircut} cut.{?color} Dye it {color}.{/color}{?avoid} Avoid: {avoid}.{/avoid}",
+            &required(),
+        )
+        .unwrap();
+
+        let mut vars = PromptVars::new("buzz");
+        assert_eq!(template.render(&vars), "A buzz cut.");
+
+        vars.color = Some("platinum".to_string());
+        vars.avoid = vec!["bangs".to_string(), "shaved sides".to_string()];
+        assert_eq!(
+            template.render(&vars),
+            "A buzz cut. Dye it platinum. Avoid: bangs, shaved sides."
+        );
+    }
+
+    #[test]
+    fn test_blank_values_count_as_unset() {
+        let template =
+            Template::parse("{haircut}{?texture} ({texture}){/texture}", &required()).unwrap();
+        let mut vars = PromptVars::new("fade");
+        vars.texture = Some("  ".to_string());
+        assert_eq!(template.render(&vars), "fade");
+    }
+
+    #[test]
+    fn test_unknown_placeholder_is_rejected() {
+        let err = Template::parse("{haircut} {vibe}", &required()).unwrap_err();
+        assert_eq!(err.to_string(), "unknown placeholder {vibe}");
+    }
+
+    #[test]
+    fn test_missing_required_placeholder_is_rejected() {
+        let err = Template::parse("{?haircut}{haircut}{/haircut}", &required()).unwrap_err();
+        assert_eq!(err.to_string(), "required placeholder {haircut} is missing");
+    }
+
+    #[test]
+    fn test_unbalanced_sections_are_rejected() {
+        assert!(Template::parse("{haircut}{?color}", &required()).is_err());
+        assert!(Template::parse("{haircut}{/color}", &required()).is_err());
+        assert!(
+            Template::parse("{haircut}{?color}{?length}{/color}{/length}", &required()).is_err()
+        );
+        assert!(Template::parse("{haircut", &required()).is_err());
+    }
+}
@@ -0,0 +1,137 @@
+//! Skin-tone segmentation shared by the quality checks and the built-in face
+//! detector.
+
+use image::RgbImage;
+
+/// Skin in YCbCr, after Chai and Ngan. Chroma only, so it holds across skin
+/// tones and most lighting.
+pub fn is_skin([r, g, b]: [u8; 3]) -> bool {
+    let (r, g, b) = (r as f64, g as f64, b as f64);
+    let cb = 128.0 - 0.168736 * r - 0.331264 * g + 0.5 * b;
+    let cr = 128.0 + 0.5 * r - 0.418688 * g - 0.081312 * b;
+    (77.0..=127.0).contains(&cb) && (133.0..=173.0).contains(&cr)
+}
+
+/// A 4-connected run of skin pixels.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Region {
+    /// Skin pixels in the region
+    pub area: usize,
+    pub min_x: usize,
+    pub min_y: usize,
+    /// Inclusive
+    pub max_x: usize,
+    pub max_y: usize,
+}
+
+impl Region {
+    pub fn width(&self) -> usize {
+        self.max_x - self.min_x + 1
+    }
+
+    pub fn height(&self) -> usize {
+        self.max_y - self.min_y + 1
+    }
+}
+
+/// Every skin region in `rgb`, alongside the skin mask they came from.
+pub fn regions(rgb: &RgbImage) -> (Vec<bool>, Vec<Region>) {
+    let width = rgb.width() as usize;
+    let height = rgb.height() as usize;
+    let mask: Vec<bool> = rgb.pixels().map(|pixel| is_skin(pixel.0)).collect();
+    let mut unvisited = mask.clone();
+    let mut regions = Vec::new();
+    let mut stack = Vec::new();
+    for start in 0..unvisited.len() {
+        if !unvisited[start] {
+            continue;
+        }
+        unvisited[start] = false;
+        stack.push(start);
+        let mut region = Region {
+            area: 0,
+            min_x: start % width,
+            min_y: start / width,
+            max_x: start % width,
+            max_y: start / width,
+        };
+        while let Some(index) = stack.pop() {
+            let (x, y) = (index % width, index / width);
+            region.area += 1;
+            region.min_x = region.min_x.min(x);
+            region.min_y = region.min_y.min(y);
+            region.max_x = region.max_x.max(x);
+            region.max_y = region.max_y.max(y);
+            let neighbours = [
+                (x > 0).then(|| index - 1),
+                (x + 1 < width).then(|| index + 1),
+                (y > 0).then(|| index - width),
+                (y + 1 < height).then(|| index + width),
+            ];
+            for neighbour in neighbours.into_iter().flatten() {
+                if unvisited[neighbour] {
+                    unvisited[neighbour] = false;
+                    stack.push(neighbour);
+                }
+            }
+        }
+        regions.push(region);
+    }
+    (mask, regions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgb;
+
+    #[test]
+    fn test_skin_tones_across_the_range() {
+        for tone in [
+            [255, 224, 196],
+            [224, 172, 140],
+            [141, 85, 36],
+            [92, 51, 23],
+        ] {
+            assert!(is_skin(tone), "{:?}", tone);
+        }
+        for other in [[0, 0, 0], [255, 255, 255], [60, 80, 100], [40, 160, 60]] {
+            assert!(!is_skin(other), "{:?}", other);
+        }
+    }
+
+    #[test]
+    fn test_regions_are_separated_and_bounded() {
+        let image = RgbImage::from_fn(20, 10, |x, y| {
+            let left = (2..6).contains(&x) && (1..4).contains(&y);
+            let right = (10..18).contains(&x) && (3..9).contains(&y);
+            if left || right {
+                Rgb([224, 172, 140])
+            } else {
+                Rgb([60, 80, 100])
+            }
+        });
+        let (mask, regions) = regions(&image);
+        assert_eq!(mask.iter().filter(|&&skin| skin).count(), 12 + 48);
+        assert_eq!(
+            regions,
+            vec![
+                Region {
+                    area: 12,
+                    min_x: 2,
+                    min_y: 1,
+                    max_x: 5,
+                    max_y: 3,
+                },
+                Region {
+                    area: 48,
+                    min_x: 10,
+                    min_y: 3,
+                    max_x: 17,
+                    max_y: 8,
+                },
+            ]
+        );
+        assert_eq!((regions[1].width(), regions[1].height()), (8, 6));
+    }
+}
@@ -0,0 +1,229 @@
+use crate::cache::cache_key;
+use crate::client_ip::ClientIp;
+use crate::crop::CropTransform;
+use crate::quality::QualityIssue;
+use crate::services::generator::ImageVariation;
+use crate::upload::Upload;
+use crate::{client_key, model_id, prepare_request, AppState, ErrorResponse, GenerateRequest};
+use axum::{
+    extract::{Json, State},
+    http::HeaderMap,
+    response::sse::{Event, KeepAlive, Sse},
+};
+use serde_json::json;
+use std::convert::Infallible;
+use tokio::sync::mpsc;
+use tokio_stream::{wrappers::ReceiverStream, Stream, StreamExt};
+use tracing::{error, info};
+
+/// One server-sent event on `/api/generate/stream`.
+#[derive(Debug)]
+pub enum GenerationEvent {
+    /// Carries advice about the photo and where it was cropped, if anywhere
+    Validated {
+        warnings: Vec<QualityIssue>,
+        crop: Option<CropTransform>,
+    },
+    /// Sent as a "front", "side" or "back" event depending on the angle
+    Variation(ImageVariation),
+    Done {
+        prompt_version: String,
+        experiment: Option<String>,
+        /// Every view came from the result cache
+        cached: bool,
+    },
+    Error(String),
+}
+
+impl GenerationEvent {
+    fn into_event(self) -> Event {
+        let (name, data) = match self {
+            GenerationEvent::Validated { warnings, crop } => (
+                "validated".to_string(),
+                json!({ "warnings": warnings, "crop": crop }),
+            ),
+            GenerationEvent::Variation(variation) => (
+                variation.angle.clone(),
+                json!({ "image": variation.image, "angle": variation.angle }),
+            ),
+            GenerationEvent::Done {
+                prompt_version,
+                experiment,
+                cached,
+            } => (
+                "done".to_string(),
+                json!({
+                    "promptVersion": prompt_version,
+                    "experiment": experiment,
+                    "cached": cached,
+                }),
+            ),
+            GenerationEvent::Error(message) => ("error".to_string(), json!({ "message": message })),
+        };
+        Event::default().event(name).data(data.to_string())
+    }
+}
+
+/// `POST /api/generate/stream`: like `/api/generate`, but streams each view as
+/// soon as it is ready. The front view is always generated; with
+/// `generateAngles` the side and back views are generated alongside it.
+///
+/// Rate limiting and validation failures are plain JSON errors, since the
+/// stream hasn't started yet.
+pub async fn generate_stream(
+    State(state): State<AppState>,
+    ClientIp(client_ip): ClientIp,
+    headers: HeaderMap,
+    Json(request): Json<GenerateRequest>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, ErrorResponse> {
+    info!(
+        %client_ip,
+        generate_angles = request.generate_angles,
+        prompt_len = request.prompt.len(),
+        "Incoming /api/generate/stream request"
+    );
+
+    let client_key = client_key(&headers, &client_ip);
+    let (request, upload) = prepare_request(request, &state, &client_key).await?;
+
+    let (tx, rx) = mpsc::channel(8);
+    tokio::spawn(async move {
+        // Stop paying for generation once nobody is listening
+        tokio::select! {
+            _ = tx.closed() => info!("Stream client disconnected, abandoning generation"),
+            _ = stream_generation(&request, &upload, &state, &client_key, &tx) => {}
+        }
+    });
+
+    let events = ReceiverStream::new(rx).map(|event| Ok(event.into_event()));
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+async fn stream_generation(
+    request: &GenerateRequest,
+    upload: &Upload,
+    state: &AppState,
+    client_key: &str,
+    tx: &mpsc::Sender<GenerationEvent>,
+) {
+    // Send errors only happen once the client is gone, which the caller handles
+    let _ = tx
+        .send(GenerationEvent::Validated {
+            warnings: upload.warnings.clone(),
+            crop: upload.crop,
+        })
+        .await;
+    let image = &upload.image;
+
+    let prompts = state.prompts.current();
+    let selection = prompts.select(client_key);
+    let generator = &state.generator;
+    let vars = request.prompt_vars();
+
+    info!(
+        provider = generator.name(),
+        prompt_version = selection.version,
+        experiment = selection.experiment,
+        generate_angles = request.generate_angles,
+        "Streaming haircut image generation"
+    );
+
+    let front_prompt = selection.templates.front_view(&vars);
+    let angle_prompts = selection.templates.angle_views(&vars);
+    let model = model_id(state);
+    let front = state.cache.get_or_generate(
+        cache_key(&image.data, &front_prompt, false, &model, selection.version),
+        generator.generate_front_view(&front_prompt, image),
+    );
+    let angles = state.cache.get_or_generate(
+        cache_key(
+            &image.data,
+            &angle_prompts.joined(),
+            true,
+            &model,
+            selection.version,
+        ),
+        generator.generate_angle_views(&angle_prompts, image),
+    );
+    tokio::pin!(front, angles);
+
+    let mut all_cached = true;
+    let mut front_pending = true;
+    let mut angles_pending = request.generate_angles;
+    while front_pending || angles_pending {
+        let (stage, result) = tokio::select! {
+            result = &mut front, if front_pending => {
+                front_pending = false;
+                ("front", result)
+            }
+            result = &mut angles, if angles_pending => {
+                angles_pending = false;
+                ("angles", result)
+            }
+        };
+
+        match result {
+            Ok((variations, cached)) => {
+                info!(
+                    stage,
+                    count = variations.len(),
+                    cached,
+                    "Streaming generated views"
+                );
+                all_cached &= cached;
+                let variations = state
+                    .moderation
+                    .filter_outputs(variations, client_key)
+                    .await;
+                if variations.is_empty() {
+                    let _ = tx
+                        .send(GenerationEvent::Error(
+                            "Every generated image was blocked by content moderation".to_string(),
+                        ))
+                        .await;
+                    return;
+                }
+                let variations = match state
+                    .images
+                    .present(variations, request.response_format)
+                    .await
+                {
+                    Ok(variations) => variations,
+                    Err(err) => {
+                        error!(stage, error = %err, "Failed to store generated images");
+                        let _ = tx
+                            .send(GenerationEvent::Error("Failed to store images".to_string()))
+                            .await;
+                        return;
+                    }
+                };
+                for variation in variations {
+                    let _ = tx.send(GenerationEvent::Variation(variation)).await;
+                }
+            }
+            Err(err) => {
+                error!(
+                    provider = generator.name(),
+                    prompt_version = selection.version,
+                    stage,
+                    error = %err,
+                    "Image generation failed"
+                );
+                let _ = tx
+                    .send(GenerationEvent::Error(
+                        "Failed to generate images".to_string(),
+                    ))
+                    .await;
+                return;
+            }
+        }
+    }
+
+    let _ = tx
+        .send(GenerationEvent::Done {
+            prompt_version: selection.version.to_string(),
+            experiment: selection.experiment.map(str::to_string),
+            cached: all_cached,
+        })
+        .await;
+}
@@ -0,0 +1,274 @@
+//! Everything that happens to an uploaded photo between request validation
+//! and the provider.
+//!
+//! Headers are checked against the limits before anything decodes. The
+//! upright, full-size pixels are then assessed for quality, searched for the
+//! face to restyle and cropped around it, before being downscaled and
+//! re-encoded. Metadata is stripped last so nothing passed through keeps it.
+//! All of it is CPU-bound; run [`UploadPipeline::process`] from a blocking
+//! task.
+
+use crate::config::Config;
+use crate::crop::{CropTransform, Cropper};
+use crate::faces::{self, FaceBox, FaceDetector, FaceError, FaceSelection};
+use crate::image_limits::ImageLimits;
+use crate::metadata;
+use crate::normalize::Normalizer;
+use crate::quality::{QualityChecker, QualityIssue};
+use crate::services::generator::InputImage;
+use image::DynamicImage;
+use std::error::Error;
+use std::sync::Arc;
+
+/// Wraps [`crate::image_limits::LimitError`],
+/// [`crate::quality::QualityError`] and [`FaceError`] for the rejections a
+/// client can act on; anything else means the photo couldn't be read.
+pub type UploadError = Box<dyn Error + Send + Sync>;
+
+/// A photo ready to send to a provider.
+#[derive(Debug, Clone)]
+pub struct Upload {
+    pub image: InputImage,
+    /// Problems that weren't bad enough to reject the photo
+    pub warnings: Vec<QualityIssue>,
+    /// The face to restyle, in the upright photo's own pixels. `None` when
+    /// the photo couldn't be decoded or face detection is off.
+    pub face: Option<FaceBox>,
+    /// Where `image` was cut from, when it was cropped to the face
+    pub crop: Option<CropTransform>,
+}
+
+pub struct UploadPipeline {
+    limits: ImageLimits,
+    normalizer: Normalizer,
+    quality: QualityChecker,
+    detector: Option<Arc<dyn FaceDetector>>,
+    cropper: Cropper,
+}
+
+impl UploadPipeline {
+    pub fn from_config(
+        config: &Config,
+        detector: Option<Arc<dyn FaceDetector>>,
+    ) -> Result<Self, UploadError> {
+        let limits = ImageLimits::from_config(&config.limits);
+        Ok(UploadPipeline {
+            limits,
+            normalizer: Normalizer::from_config(&config.normalize, limits)?,
+            quality: QualityChecker::new(&config.quality),
+            detector,
+            cropper: Cropper::from_config(&config.crop)?,
+        })
+    }
+
+    pub fn process(
+        &self,
+        image: InputImage,
+        selection: FaceSelection,
+    ) -> Result<Upload, UploadError> {
+        if self.detector.is_none() && matches!(selection, FaceSelection::Index(_)) {
+            return Err(FaceError::DetectionOff.into());
+        }
+        let info = self.limits.check(&image)?;
+        let Some(pixels) = self.normalizer.decode(&image)? else {
+            // HEIC can't be decoded here, so it can't be checked for a face
+            if self.detector.is_some() {
+                return Err(FaceError::UncheckableFormat.into());
+            }
+            return Ok(Upload {
+                image: metadata::strip(image)?,
+                warnings: Vec::new(),
+                face: None,
+                crop: None,
+            });
+        };
+
+        let warnings = self.quality.assess(&pixels, &info)?;
+        let face = self.find_face(&pixels, selection)?;
+        let crop =
+            face.and_then(|face| self.cropper.region(&face, pixels.width(), pixels.height()));
+        let pixels = match crop {
+            Some(crop) => pixels.crop_imm(crop.x, crop.y, crop.width, crop.height),
+            None => pixels,
+        };
+        let image = self.normalizer.finish(image, pixels, crop.is_some())?;
+        Ok(Upload {
+            image: metadata::strip(image)?,
+            warnings,
+            face,
+            crop,
+        })
+    }
+
+    fn find_face(
+        &self,
+        pixels: &DynamicImage,
+        selection: FaceSelection,
+    ) -> Result<Option<FaceBox>, UploadError> {
+        let selection = match selection {
+            FaceSelection::Box(chosen) => FaceSelection::Box(
+                chosen
+                    .clamped(pixels.width(), pixels.height())
+                    .ok_or(FaceError::InvalidBox)?,
+            ),
+            other => other,
+        };
+        let Some(detector) = &self.detector else {
+            // Without detection a client box is all there is to go on;
+            // `process` already refused a face index
+            return Ok(match selection {
+                FaceSelection::Box(chosen) => Some(chosen),
+                _ => None,
+            });
+        };
+        let mut found = detector.detect(pixels)?;
+        found.sort_by_key(|face| (face.x, face.y));
+        Ok(Some(faces::select(&found, selection)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::faces::FixedDetector;
+    use crate::services::image_format::ImageFormat;
+    use image::{Rgb, RgbImage};
+    use std::io::Cursor;
+
+    fn pipeline(faces: Vec<FaceBox>) -> UploadPipeline {
+        UploadPipeline::from_config(&Config::default(), Some(Arc::new(FixedDetector(faces))))
+            .unwrap()
+    }
+
+    /// A busy 2000x1500 photo that passes the quality checks.
+    fn photo() -> InputImage {
+        let image = RgbImage::from_fn(2000, 1500, |x, y| {
+            if (x / 16 + y / 16) % 2 == 0 {
+                Rgb([60, 80, 100])
+            } else {
+                Rgb([224, 172, 140])
+            }
+        });
+        let mut data = Vec::new();
+        image
+            .write_to(&mut Cursor::new(&mut data), image::ImageFormat::Jpeg)
+            .unwrap();
+        InputImage {
+            data,
+            format: ImageFormat::Jpeg,
+        }
+    }
+
+    fn dimensions(image: &InputImage) -> (u32, u32) {
+        let decoded = image::load_from_memory(&image.data).unwrap();
+        (decoded.width(), decoded.height())
+    }
+
+    #[test]
+    fn test_crops_to_head_and_shoulders() {
+        let face = FaceBox {
+            x: 900,
+            y: 300,
+            width: 100,
+            height: 100,
+        };
+        let upload = pipeline(vec![face])
+            .process(photo(), FaceSelection::Auto)
+            .unwrap();
+
+        assert_eq!(upload.face, Some(face));
+        let crop = upload.crop.unwrap();
+        assert_eq!(
+            (crop.x, crop.y, crop.width, crop.height),
+            (800, 230, 300, 300)
+        );
+        assert_eq!((crop.source_width, crop.source_height), (2000, 1500));
+        assert_eq!(dimensions(&upload.image), (300, 300));
+    }
+
+    #[test]
+    fn test_client_box_is_cropped_to() {
+        let chosen = FaceBox {
+            x: 100,
+            y: 1000,
+            width: 200,
+            height: 200,
+        };
+        let detected = FaceBox {
+            x: 120,
+            y: 1020,
+            width: 160,
+            height: 170,
+        };
+        let upload = pipeline(vec![detected])
+            .process(photo(), FaceSelection::Box(chosen))
+            .unwrap();
+        let crop = upload.crop.unwrap();
+        assert_eq!((crop.x, crop.y), (0, 860));
+        assert_eq!(dimensions(&upload.image), (crop.width, crop.height));
+    }
+
+    #[test]
+    fn test_client_box_still_needs_a_face() {
+        let chosen = FaceBox {
+            x: 100,
+            y: 1000,
+            width: 200,
+            height: 200,
+        };
+        let err = pipeline(vec![])
+            .process(photo(), FaceSelection::Box(chosen))
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<FaceError>(), Some(&FaceError::NoFace));
+    }
+
+    #[test]
+    fn test_heic_is_refused_when_faces_are_checked() {
+        // Just enough of a HEIC for the limits to read its size
+        fn sized_box(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+            let mut data = ((body.len() + 8) as u32).to_be_bytes().to_vec();
+            data.extend_from_slice(kind);
+            data.extend_from_slice(body);
+            data
+        }
+        let ispe = sized_box(b"ispe", &[0, 0, 0, 0, 0, 0, 3, 32, 0, 0, 2, 88]);
+        let ipco = sized_box(b"ipco", &ispe);
+        let meta = sized_box(
+            b"meta",
+            &[&[0u8; 4][..], &sized_box(b"iprp", &ipco)].concat(),
+        );
+        let data = [b"\x00\x00\x00\x10ftypheic\x00\x00\x00\x00".to_vec(), meta].concat();
+        let heic = InputImage {
+            data,
+            format: ImageFormat::Heic,
+        };
+        let err = pipeline(vec![]).process(heic, FaceSelection::Auto);
+        assert_eq!(
+            err.unwrap_err().downcast_ref::<FaceError>(),
+            Some(&FaceError::UncheckableFormat)
+        );
+    }
+
+    #[test]
+    fn test_face_index_without_detection_is_refused() {
+        let err = UploadPipeline::from_config(&Config::default(), None)
+            .unwrap()
+            .process(photo(), FaceSelection::Index(0));
+        assert_eq!(
+            err.unwrap_err().downcast_ref::<FaceError>(),
+            Some(&FaceError::DetectionOff)
+        );
+    }
+
+    #[test]
+    fn test_without_a_face_the_whole_photo_is_kept() {
+        let upload = UploadPipeline::from_config(&Config::default(), None)
+            .unwrap()
+            .process(photo(), FaceSelection::Auto)
+            .unwrap();
+        assert_eq!(upload.face, None);
+        assert_eq!(upload.crop, None);
+        // Downscaled to the default max_edge
+        assert_eq!(dimensions(&upload.image), (1536, 1152));
+    }
+}
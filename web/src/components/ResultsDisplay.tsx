@@ -157,7 +157,7 @@ export default function ResultsDisplay({
                   Generating {item.angle} view...
                 </p>
               </div>
-            ) : 'image' in item && item.image.startsWith('data:') ? (
+            ) : 'image' in item && /^(data:|https?:)/.test(item.image) ? (
               <div style={{
                 position: 'relative',
                 width: '100%',
@@ -171,6 +171,8 @@ export default function ResultsDisplay({
                   alt={`${item.angle} view haircut reference`}
                   fill
                   sizes="(max-width: 768px) 100vw, 450px"
+                  // Signed URLs expire, so let the browser fetch them directly
+                  unoptimized
                   style={{
                     objectFit: 'contain',
                     backgroundColor: 'white'
@@ -2,6 +2,22 @@ export interface GenerateHaircutsRequest {
     prompt: string;
     imageData: string;
     generateAngles?: boolean;
+    hairTexture?: string;
+    desiredLength?: string;
+    hairColor?: string;
+    styleNotes?: string;
+    avoid?: string[];
+    // Which person to restyle, from the `faces` of a "multiple_faces" reply
+    faceIndex?: number;
+    // Or a box around the face, in the photo's pixels
+    faceBox?: FaceBox;
+}
+
+export interface FaceBox {
+    x: number;
+    y: number;
+    width: number;
+    height: number;
 }
 
 export interface ImageVariation {
@@ -9,17 +25,73 @@ export interface ImageVariation {
     angle: string;
 }
 
+export interface QualityWarning {
+    // e.g. "photo_too_dark" or "subject_too_small"
+    code: string;
+    message: string;
+}
+
+// Where the photo was cropped before generation. Scale a result to
+// width x height and draw it at (x, y) to put it back into the original.
+export interface CropTransform {
+    x: number;
+    y: number;
+    width: number;
+    height: number;
+    sourceWidth: number;
+    sourceHeight: number;
+}
+
 export interface GenerateHaircutsResponse {
     success: boolean;
     variations: ImageVariation[];
     message?: string;
+    promptVersion?: string;
+    experiment?: string;
+    // Machine-readable reason for a rejection, e.g. "content_blocked"
+    code?: string;
+    // True when the backend reused an earlier result for the same photo and prompt
+    cached?: boolean;
+    // Advice about the photo that didn't stop the generation
+    warnings?: QualityWarning[];
+    // Every face found when the photo has several, left to right
+    faces?: FaceBox[];
+    crop?: CropTransform;
+    // Seconds until a rate-limited request would be accepted
+    retryAfter?: number;
 }
 
+// Stable per-browser id so prompt experiments assign the same version each visit
+const getClientId = (): string | undefined => {
+    if (typeof window === 'undefined') return undefined;
+    try {
+        let id = window.localStorage.getItem('hmb-client-id');
+        if (!id) {
+            id = crypto.randomUUID();
+            window.localStorage.setItem('hmb-client-id', id);
+        }
+        return id;
+    } catch {
+        return undefined;
+    }
+};
+
+const getRetryAfter = (response: Response): number | undefined => {
+    const seconds = Number(response.headers.get('Retry-After'));
+    return Number.isFinite(seconds) && seconds > 0 ? seconds : undefined;
+};
+
+const resolveImageUrl = (apiBase: string, image: string): string =>
+    image.startsWith('/') ? `${apiBase}${image}` : image;
+
 // Simple error message utility
 const getErrorMessage = (error: unknown, response?: Response): string => {
     if (response) {
         if (response.status === 429) {
-            return "Too many requests. Please wait a minute before trying again.";
+            const retryAfter = getRetryAfter(response);
+            return retryAfter
+                ? `Too many requests. Please try again in ${retryAfter} seconds.`
+                : "Too many requests. Please wait a minute before trying again.";
         }
         if (response.status >= 500) {
             return "Server error. Please try again.";
@@ -37,20 +109,39 @@ export const ApiService = {
     generateHaircuts: async (request: GenerateHaircutsRequest): Promise<GenerateHaircutsResponse> => {
         try {
             const API_BASE = process.env.NEXT_PUBLIC_API_URL || 'http://localhost:3001';
+            // The web app's own key; shipped to browsers, so scope it to "generate"
+            const API_KEY = process.env.NEXT_PUBLIC_API_KEY;
 
+            const clientId = getClientId();
             const response = await fetch(`${API_BASE}/api/generate`, {
                 method: 'POST',
                 headers: {
                     'Content-Type': 'application/json',
+                    ...(clientId ? { 'X-Client-Id': clientId } : {}),
+                    ...(API_KEY ? { 'X-Api-Key': API_KEY } : {}),
                 },
                 body: JSON.stringify(request),
             });
 
+            if (response.status === 429) {
+                return {
+                    success: false,
+                    variations: [],
+                    message: getErrorMessage(null, response),
+                    retryAfter: getRetryAfter(response),
+                };
+            }
+
             if (!response.ok) {
                 throw new Error(getErrorMessage(null, response));
             }
 
             const data: GenerateHaircutsResponse = await response.json();
+            // Image URLs are relative to the API unless it sets PUBLIC_BASE_URL
+            data.variations = data.variations.map((variation) => ({
+                ...variation,
+                image: resolveImageUrl(API_BASE, variation.image),
+            }));
 
             if (!data.success && data.message) {
                 return {
@@ -71,3 +162,76 @@ export const ApiService = {
     },
 }
 
+export interface User {
+    id: string;
+    email: string;
+    createdAt: number;
+}
+
+export interface Look {
+    id: string;
+    collection: string;
+    prompt: string;
+    inputImageRef?: string;
+    promptVersion?: string;
+    variations: ImageVariation[];
+    createdAt: number;
+}
+
+export interface SaveLookRequest {
+    collection: string;
+    prompt: string;
+    inputImageRef?: string;
+    promptVersion?: string;
+    variations: ImageVariation[];
+}
+
+// Account calls ride on the backend's session cookie. Cross-origin setups need
+// the web origin listed in the backend's CORS_ORIGINS.
+const accountFetch = async <T>(path: string, init: RequestInit = {}): Promise<T> => {
+    const API_BASE = process.env.NEXT_PUBLIC_API_URL || 'http://localhost:3001';
+    const response = await fetch(`${API_BASE}${path}`, {
+        ...init,
+        credentials: 'include',
+        headers: { 'Content-Type': 'application/json', ...init.headers },
+    });
+    if (!response.ok) {
+        const data = await response.json().catch(() => undefined);
+        throw new Error(data?.message || getErrorMessage(null, response));
+    }
+    return response.status === 204 ? (undefined as T) : response.json();
+};
+
+export const AccountService = {
+    register: (email: string, password: string) =>
+        accountFetch<{ user: User }>('/api/auth/register', {
+            method: 'POST',
+            body: JSON.stringify({ email, password }),
+        }).then(({ user }) => user),
+
+    login: (email: string, password: string) =>
+        accountFetch<{ user: User }>('/api/auth/login', {
+            method: 'POST',
+            body: JSON.stringify({ email, password }),
+        }).then(({ user }) => user),
+
+    logout: () => accountFetch<void>('/api/auth/logout', { method: 'POST' }),
+
+    // Resolves to undefined when nobody is logged in
+    me: () =>
+        accountFetch<{ user: User }>('/api/auth/me').then(
+            ({ user }) => user,
+            () => undefined,
+        ),
+
+    saveLook: (look: SaveLookRequest) =>
+        accountFetch<Look>('/api/looks', { method: 'POST', body: JSON.stringify(look) }),
+
+    listLooks: (collection?: string) =>
+        accountFetch<Look[]>(
+            collection ? `/api/looks?collection=${encodeURIComponent(collection)}` : '/api/looks',
+        ),
+
+    deleteLook: (id: string) =>
+        accountFetch<void>(`/api/looks/${encodeURIComponent(id)}`, { method: 'DELETE' }),
+};